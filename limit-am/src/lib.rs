@@ -1,4 +1,4 @@
-use std::error::Error;
+use std::{error::Error, fmt::Display, str::FromStr};
 
 use aes::{
     cipher::{BlockDecrypt, BlockEncrypt, KeyInit},
@@ -7,35 +7,267 @@ use aes::{
 use anyhow::Context;
 use elliptic_curve::{ecdh::SharedSecret, generic_array::GenericArray, sec1::ToEncodedPoint};
 use limit_deps::*;
-use p256::{NistP256, PublicKey, SecretKey};
+use p256::{
+    ecdsa::signature::{Signer, Verifier},
+    NistP256,
+};
+use serde::{Deserialize, Serialize};
+
+pub mod sender_key;
+pub mod signature;
+
+/// Algorithm of a key pair, encoded keys are prefixed with its tag like
+/// `x25519:<base64>`. Untagged keys are legacy P-256 SEC1 keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub enum KeyType {
+    /// NIST P-256, ECDH and ECDSA
+    P256,
+    /// Curve25519 ECDH
+    X25519,
+    /// Curve25519 EdDSA signatures
+    Ed25519,
+}
+
+impl KeyType {
+    const ALL: [KeyType; 3] = [KeyType::P256, KeyType::X25519, KeyType::Ed25519];
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Self::P256 => "p256",
+            Self::X25519 => "x25519",
+            Self::Ed25519 => "ed25519",
+        }
+    }
+
+    /// split an encoded key into its algorithm and base64 key material
+    pub fn detect(encoded: &str) -> (Self, &str) {
+        Self::ALL
+            .into_iter()
+            .find_map(|t| {
+                encoded
+                    .strip_prefix(t.tag())
+                    .and_then(|rest| rest.strip_prefix(':'))
+                    .map(|rest| (t, rest))
+            })
+            .unwrap_or((Self::P256, encoded))
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.tag() == s)
+            .ok_or_else(|| format!("unknown key type {s}"))
+    }
+}
 
+impl Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.tag())
+    }
+}
+
+/// A secret key of any supported [`KeyType`]
+#[derive(Clone)]
+pub enum SecretKey {
+    P256(p256::SecretKey),
+    X25519(x25519_dalek::StaticSecret),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+/// A public key of any supported [`KeyType`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublicKey {
+    P256(p256::PublicKey),
+    X25519(x25519_dalek::PublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl SecretKey {
+    pub fn random(key_type: KeyType) -> Self {
+        let mut rng = rand::rngs::OsRng;
+        match key_type {
+            KeyType::P256 => Self::P256(p256::SecretKey::random(&mut rng)),
+            KeyType::X25519 => Self::X25519(x25519_dalek::StaticSecret::random_from_rng(rng)),
+            KeyType::Ed25519 => Self::Ed25519(ed25519_dalek::SigningKey::generate(&mut rng)),
+        }
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            Self::P256(_) => KeyType::P256,
+            Self::X25519(_) => KeyType::X25519,
+            Self::Ed25519(_) => KeyType::Ed25519,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            Self::P256(key) => PublicKey::P256(key.public_key()),
+            Self::X25519(key) => PublicKey::X25519(key.into()),
+            Self::Ed25519(key) => PublicKey::Ed25519(key.verifying_key()),
+        }
+    }
+
+    /// tagged base64 encoding
+    pub fn encode(&self) -> Result<String, Box<dyn Error>> {
+        let bytes = match self {
+            Self::P256(key) => key.to_sec1_der().map_err(|err| err.to_string())?.to_vec(),
+            Self::X25519(key) => key.to_bytes().to_vec(),
+            Self::Ed25519(key) => key.to_bytes().to_vec(),
+        };
+        Ok(format!("{}:{}", self.key_type(), base64::encode(bytes)))
+    }
+
+    /// base64 signature of `message`, only P-256 (ECDSA) and Ed25519 keys can
+    /// sign
+    pub fn sign(&self, message: &[u8]) -> Result<String, Box<dyn Error>> {
+        match self {
+            Self::P256(key) => {
+                let signature: p256::ecdsa::Signature =
+                    p256::ecdsa::SigningKey::from(key).sign(message);
+                Ok(base64::encode(signature.as_ref()))
+            }
+            Self::Ed25519(key) => Ok(base64::encode(
+                ed25519_dalek::Signer::sign(key, message).to_bytes(),
+            )),
+            Self::X25519(_) => Err("x25519 keys can't sign".into()),
+        }
+    }
+}
+
+impl PublicKey {
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            Self::P256(_) => KeyType::P256,
+            Self::X25519(_) => KeyType::X25519,
+            Self::Ed25519(_) => KeyType::Ed25519,
+        }
+    }
+
+    /// tagged base64 encoding
+    pub fn encode(&self) -> String {
+        let bytes = match self {
+            Self::P256(key) => key.to_encoded_point(false).as_bytes().to_vec(),
+            Self::X25519(key) => key.as_bytes().to_vec(),
+            Self::Ed25519(key) => key.as_bytes().to_vec(),
+        };
+        format!("{}:{}", self.key_type(), base64::encode(bytes))
+    }
+
+    /// verify a base64 signature made by [`SecretKey::sign`]
+    pub fn verify(&self, message: &[u8], signature: &str) -> Result<(), Box<dyn Error>> {
+        let signature = base64::decode(signature)?;
+        match self {
+            Self::P256(key) => {
+                let signature = p256::ecdsa::Signature::try_from(signature.as_slice())
+                    .map_err(|err| err.to_string())?;
+                p256::ecdsa::VerifyingKey::from(key)
+                    .verify(message, &signature)
+                    .map_err(|err| err.to_string())?;
+            }
+            Self::Ed25519(key) => {
+                let signature = ed25519_dalek::Signature::from_slice(&signature)
+                    .map_err(|err| err.to_string())?;
+                key.verify_strict(message, &signature)
+                    .map_err(|err| err.to_string())?;
+            }
+            Self::X25519(_) => return Err("x25519 keys can't verify signatures".into()),
+        }
+        Ok(())
+    }
+}
+
+fn to_array(bytes: &[u8]) -> Result<[u8; 32], Box<dyn Error>> {
+    Ok(bytes.try_into().map_err(|_| "invalid key length")?)
+}
+
+/// create a P-256 key pair, see [`create_random_secret_of`]
 pub fn create_random_secret() -> Result<(String, String), Box<dyn Error>> {
-    let secret_key = SecretKey::random(&mut rand::rngs::OsRng);
-    let der = secret_key.to_sec1_der().map_err(|err| err.to_string())?;
-    let binding = secret_key.public_key().to_encoded_point(false);
-    let derp = binding.as_bytes();
-    let der_base64 = base64::encode(&der);
-    let derp_base64 = base64::encode(derp);
-    Ok((der_base64, derp_base64))
+    create_random_secret_of(KeyType::P256)
+}
+
+/// create a key pair and return the tagged `(secret, public)` encoding
+pub fn create_random_secret_of(key_type: KeyType) -> Result<(String, String), Box<dyn Error>> {
+    let secret_key = SecretKey::random(key_type);
+    Ok((secret_key.encode()?, secret_key.public_key().encode()))
 }
 
 pub fn decode_secret(secret: &str) -> Result<SecretKey, Box<dyn Error>> {
+    let (key_type, secret) = KeyType::detect(secret);
     let der = base64::decode(secret).map_err(|err| err.to_string())?;
-    let secret_key = SecretKey::from_sec1_der(&der).map_err(|err| err.to_string())?;
+    let secret_key = match key_type {
+        KeyType::P256 => {
+            SecretKey::P256(p256::SecretKey::from_sec1_der(&der).map_err(|err| err.to_string())?)
+        }
+        KeyType::X25519 => SecretKey::X25519(to_array(&der)?.into()),
+        KeyType::Ed25519 => {
+            SecretKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&to_array(&der)?))
+        }
+    };
     Ok(secret_key)
 }
 
 pub fn decode_public(public: &str) -> Result<PublicKey, Box<dyn Error>> {
+    let (key_type, public) = KeyType::detect(public);
     let der = base64::decode(public).map_err(|err| err.to_string())?;
-    let public_key = PublicKey::from_sec1_bytes(&der).map_err(|err| err.to_string())?;
+    let public_key = match key_type {
+        KeyType::P256 => {
+            PublicKey::P256(p256::PublicKey::from_sec1_bytes(&der).map_err(|err| err.to_string())?)
+        }
+        KeyType::X25519 => PublicKey::X25519(to_array(&der)?.into()),
+        KeyType::Ed25519 => PublicKey::Ed25519(
+            ed25519_dalek::VerifyingKey::from_bytes(&to_array(&der)?)
+                .map_err(|err| err.to_string())?,
+        ),
+    };
     Ok(public_key)
 }
 
-pub fn key_exchange(privkey1: SecretKey, pubkey2: PublicKey) -> String {
-    let shared_secret =
-        elliptic_curve::ecdh::diffie_hellman(privkey1.to_nonzero_scalar(), pubkey2.as_affine());
-    let encoded = base64::encode(shared_secret.raw_secret_bytes());
-    encoded
+/// ECDH between keys of the same algorithm, returns the base64 shared secret
+pub fn key_exchange(privkey1: SecretKey, pubkey2: PublicKey) -> Result<String, Box<dyn Error>> {
+    let shared_secret = match (privkey1, pubkey2) {
+        (SecretKey::P256(privkey1), PublicKey::P256(pubkey2)) => {
+            elliptic_curve::ecdh::diffie_hellman(privkey1.to_nonzero_scalar(), pubkey2.as_affine())
+                .raw_secret_bytes()
+                .to_vec()
+        }
+        (SecretKey::X25519(privkey1), PublicKey::X25519(pubkey2)) => {
+            privkey1.diffie_hellman(&pubkey2).as_bytes().to_vec()
+        }
+        (privkey1, pubkey2) => {
+            return Err(format!(
+                "no key exchange between {} and {} keys",
+                privkey1.key_type(),
+                pubkey2.key_type()
+            )
+            .into());
+        }
+    };
+    Ok(base64::encode(shared_secret))
+}
+
+/// encrypt `plaintext` for the owner of `public` with the secret shared
+/// between the two keys, see [`key_exchange`]
+pub fn seal(
+    secret: &SecretKey,
+    public: &PublicKey,
+    plaintext: &str,
+) -> Result<String, Box<dyn Error>> {
+    aes256_encrypt_string(&key_exchange(secret.clone(), public.clone())?, plaintext)
+}
+
+/// decrypt what the owner of `public` sealed for `secret` with [`seal`]
+pub fn open(
+    secret: &SecretKey,
+    public: &PublicKey,
+    ciphertext: &str,
+) -> Result<String, Box<dyn Error>> {
+    aes256_decrypt_string(&key_exchange(secret.clone(), public.clone())?, ciphertext)
 }
 
 pub fn decode_shared_key(encoded: String) -> SharedSecret<NistP256> {
@@ -91,8 +323,8 @@ fn test_key_exchange_encode_decode() {
     let user1_pubkey = decode_public(&user1_public).unwrap();
     let user2_pubkey = decode_public(&user2_public).unwrap();
 
-    let user1_to_2_shared_secret = key_exchange(user1_secret_decoded, user2_pubkey);
-    let user2_to_1_shared_secret = key_exchange(user2_secret_decoded, user1_pubkey);
+    let user1_to_2_shared_secret = key_exchange(user1_secret_decoded, user2_pubkey).unwrap();
+    let user2_to_1_shared_secret = key_exchange(user2_secret_decoded, user1_pubkey).unwrap();
     assert_eq!(user1_to_2_shared_secret, user2_to_1_shared_secret);
     println!("Shared secret: {user1_to_2_shared_secret}");
 
@@ -118,3 +350,70 @@ fn test_key_exchange_encode_decode() {
     println!("decoded from user1: {decoded2_to_1}");
     assert_eq!(plaintext, decoded2_to_1);
 }
+
+#[test]
+fn test_key_types() {
+    // legacy untagged P-256 keys
+    let secret = p256::SecretKey::random(&mut rand::rngs::OsRng);
+    let legacy_secret = base64::encode(secret.to_sec1_der().unwrap());
+    let legacy_public = base64::encode(secret.public_key().to_encoded_point(false).as_bytes());
+    assert_eq!(
+        decode_secret(&legacy_secret).unwrap().key_type(),
+        KeyType::P256
+    );
+    assert_eq!(
+        decode_public(&legacy_public).unwrap(),
+        PublicKey::P256(secret.public_key())
+    );
+
+    for key_type in KeyType::ALL {
+        let (secret, public) = create_random_secret_of(key_type).unwrap();
+        assert_eq!(KeyType::detect(&secret).0, key_type);
+        assert_eq!(KeyType::detect(&public).0, key_type);
+        let secret = decode_secret(&secret).unwrap();
+        let public = decode_public(&public).unwrap();
+        assert_eq!(secret.public_key(), public);
+
+        let signature = secret.sign(b"hello");
+        match key_type {
+            KeyType::X25519 => assert!(signature.is_err()),
+            _ => {
+                let signature = signature.unwrap();
+                assert!(public.verify(b"hello", &signature).is_ok());
+                assert!(public.verify(b"hell0", &signature).is_err());
+            }
+        }
+
+        let (other_secret, other_public) = create_random_secret_of(key_type).unwrap();
+        let exchanged = key_exchange(secret.clone(), decode_public(&other_public).unwrap());
+        match key_type {
+            KeyType::Ed25519 => assert!(exchanged.is_err()),
+            _ => assert_eq!(
+                exchanged.unwrap(),
+                key_exchange(decode_secret(&other_secret).unwrap(), public).unwrap()
+            ),
+        }
+    }
+
+    // sealed pairwise
+    let (alice, bob) = (
+        SecretKey::random(KeyType::X25519),
+        SecretKey::random(KeyType::X25519),
+    );
+    let sealed = seal(&alice, &bob.public_key(), "for bob only").unwrap();
+    assert_eq!(
+        open(&bob, &alice.public_key(), &sealed).unwrap(),
+        "for bob only"
+    );
+
+    // mismatched algorithms
+    let (x25519_secret, _) = create_random_secret_of(KeyType::X25519).unwrap();
+    let (_, p256_public) = create_random_secret_of(KeyType::P256).unwrap();
+    assert!(
+        key_exchange(
+            decode_secret(&x25519_secret).unwrap(),
+            decode_public(&p256_public).unwrap()
+        )
+        .is_err()
+    );
+}
@@ -0,0 +1,329 @@
+//! Sender-key encryption for channels with more than two members.
+//!
+//! Every member owns a sending chain per channel. The chain key is ratcheted
+//! with HMAC-SHA256 for each message, so a message is encrypted once and fanned
+//! out to all members. Members learn the chain from a
+//! [`SenderKeyDistributionMessage`], which is sealed pairwise to each of them
+//! so only members can read the chain key.
+
+use std::{collections::BTreeMap, error::Error};
+
+use hmac::{Hmac, Mac};
+use limit_deps::*;
+use p256::ecdsa::{
+    signature::{Signer, Verifier},
+    Signature, SigningKey, VerifyingKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{aes256_decrypt_string, aes256_encrypt_string, PublicKey, SecretKey};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// max number of message keys a receiver derives ahead of its chain
+pub const MAX_SKIPPED_MESSAGE_KEYS: u32 = 2000;
+/// max number of skipped message keys a receiver keeps, the oldest are
+/// dropped first
+pub const MAX_STORED_MESSAGE_KEYS: usize = 2000;
+
+const MESSAGE_KEY_SEED: &[u8] = &[0x01];
+const CHAIN_KEY_SEED: &[u8] = &[0x02];
+
+/// One step of the symmetric ratchet
+#[derive(Clone)]
+struct ChainKey {
+    iteration: u32,
+    key: [u8; 32],
+}
+
+impl ChainKey {
+    fn derive(&self, seed: &[u8]) -> [u8; 32] {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("hmac accepts keys of any length");
+        mac.update(seed);
+        let mut derived = [0; 32];
+        derived.copy_from_slice(&mac.finalize().into_bytes());
+        derived
+    }
+
+    /// base64 AES-256 key of the message at the current iteration
+    fn message_key(&self) -> String {
+        base64::encode(self.derive(MESSAGE_KEY_SEED))
+    }
+
+    fn next(&self) -> Self {
+        Self {
+            iteration: self.iteration + 1,
+            key: self.derive(CHAIN_KEY_SEED),
+        }
+    }
+}
+
+/// Hands a sending chain to another member of the channel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct SenderKeyDistributionMessage {
+    /// identifies the chain, a new one is created on every rotation
+    pub chain_id: String,
+    /// the iteration of `chain_key`
+    pub iteration: u32,
+    /// base64 chain key
+    pub chain_key: String,
+    /// base64 SEC1 public key verifying the sender's signatures
+    pub signing_key: String,
+}
+
+/// A message encrypted with a sender key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct SenderKeyMessage {
+    pub chain_id: String,
+    pub iteration: u32,
+    /// base64 ciphertext
+    pub ciphertext: String,
+    /// base64 ECDSA P-256 signature over the other fields
+    pub signature: String,
+}
+
+macro_rules! impl_encoding {
+    ($t:ty) => {
+        impl $t {
+            /// encode as base64 json, suitable for a message text
+            pub fn encode(&self) -> Result<String, Box<dyn Error>> {
+                Ok(base64::encode(serde_json::to_vec(self)?))
+            }
+
+            pub fn decode(encoded: &str) -> Result<Self, Box<dyn Error>> {
+                Ok(serde_json::from_slice(&base64::decode(encoded)?)?)
+            }
+        }
+    };
+}
+
+impl_encoding!(SenderKeyDistributionMessage);
+impl_encoding!(SenderKeyMessage);
+
+impl SenderKeyDistributionMessage {
+    /// encode sealed for one member, the message text sent to it
+    pub fn seal(&self, sender: &SecretKey, member: &PublicKey) -> Result<String, Box<dyn Error>> {
+        crate::seal(sender, member, &self.encode()?)
+    }
+
+    /// decode a distribution the owner of `sender` sealed for `member`
+    pub fn open(
+        sealed: &str,
+        member: &SecretKey,
+        sender: &PublicKey,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::decode(&crate::open(member, sender, sealed)?)
+    }
+}
+
+impl SenderKeyMessage {
+    fn signing_payload(&self) -> Vec<u8> {
+        format!("{}:{}:{}", self.chain_id, self.iteration, self.ciphertext).into_bytes()
+    }
+}
+
+/// The sending side of a chain
+pub struct SenderKeyState {
+    chain_id: String,
+    chain: ChainKey,
+    signing_key: SigningKey,
+}
+
+impl SenderKeyState {
+    pub fn new() -> Self {
+        use rand::RngCore;
+
+        let mut key = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        Self {
+            chain_id: uuid::Uuid::new_v4().to_string(),
+            chain: ChainKey { iteration: 0, key },
+            signing_key: SigningKey::random(&mut rand::rngs::OsRng),
+        }
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    /// distribution message starting at the current iteration, members
+    /// receiving it can't read earlier messages
+    pub fn distribution_message(&self) -> SenderKeyDistributionMessage {
+        SenderKeyDistributionMessage {
+            chain_id: self.chain_id.clone(),
+            iteration: self.chain.iteration,
+            chain_key: base64::encode(self.chain.key),
+            signing_key: base64::encode(
+                self.signing_key
+                    .verifying_key()
+                    .to_encoded_point(false)
+                    .as_bytes(),
+            ),
+        }
+    }
+
+    pub fn encrypt(&mut self, plaintext: &str) -> Result<SenderKeyMessage, Box<dyn Error>> {
+        let mut message = SenderKeyMessage {
+            chain_id: self.chain_id.clone(),
+            iteration: self.chain.iteration,
+            ciphertext: aes256_encrypt_string(&self.chain.message_key(), plaintext)?,
+            signature: String::new(),
+        };
+        let signature: Signature = self.signing_key.sign(&message.signing_payload());
+        message.signature = base64::encode(signature.as_ref());
+        self.chain = self.chain.next();
+        Ok(message)
+    }
+}
+
+impl Default for SenderKeyState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The receiving side of another member's chain
+pub struct SenderKeyReceiver {
+    chain_id: String,
+    chain: ChainKey,
+    verifying_key: VerifyingKey,
+    /// keys of messages skipped while catching up, kept for late deliveries,
+    /// by iteration
+    skipped: BTreeMap<u32, String>,
+}
+
+impl SenderKeyReceiver {
+    pub fn from_distribution(
+        distribution: &SenderKeyDistributionMessage,
+    ) -> Result<Self, Box<dyn Error>> {
+        let key = base64::decode(&distribution.chain_key)?;
+        let key: [u8; 32] = key
+            .as_slice()
+            .try_into()
+            .map_err(|_| "invalid chain key length")?;
+        let verifying_key =
+            VerifyingKey::from_sec1_bytes(&base64::decode(&distribution.signing_key)?)
+                .map_err(|err| err.to_string())?;
+        Ok(Self {
+            chain_id: distribution.chain_id.clone(),
+            chain: ChainKey {
+                iteration: distribution.iteration,
+                key,
+            },
+            verifying_key,
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    pub fn decrypt(&mut self, message: &SenderKeyMessage) -> Result<String, Box<dyn Error>> {
+        if message.chain_id != self.chain_id {
+            return Err("unknown sender key chain".into());
+        }
+        let signature = Signature::try_from(base64::decode(&message.signature)?.as_slice())
+            .map_err(|err| err.to_string())?;
+        self.verifying_key
+            .verify(&message.signing_payload(), &signature)
+            .map_err(|err| err.to_string())?;
+
+        let message_key = if message.iteration < self.chain.iteration {
+            self.skipped
+                .remove(&message.iteration)
+                .ok_or("message key already used or expired")?
+        } else {
+            if message.iteration - self.chain.iteration > MAX_SKIPPED_MESSAGE_KEYS {
+                return Err("too many skipped messages".into());
+            }
+            while self.chain.iteration < message.iteration {
+                self.skipped
+                    .insert(self.chain.iteration, self.chain.message_key());
+                self.chain = self.chain.next();
+            }
+            while self.skipped.len() > MAX_STORED_MESSAGE_KEYS {
+                self.skipped.pop_first();
+            }
+            let message_key = self.chain.message_key();
+            self.chain = self.chain.next();
+            message_key
+        };
+        aes256_decrypt_string(&message_key, &message.ciphertext)
+    }
+}
+
+#[test]
+fn test_sender_key_encrypt_decrypt() {
+    let (sender_identity, member_identity) = (
+        SecretKey::random(crate::KeyType::P256),
+        SecretKey::random(crate::KeyType::P256),
+    );
+    let mut sender = SenderKeyState::new();
+    let sealed = sender
+        .distribution_message()
+        .seal(&sender_identity, &member_identity.public_key())
+        .unwrap();
+    assert!(SenderKeyDistributionMessage::decode(&sealed).is_err());
+    let distribution = SenderKeyDistributionMessage::open(
+        &sealed,
+        &member_identity,
+        &sender_identity.public_key(),
+    )
+    .unwrap();
+    let mut member1 = SenderKeyReceiver::from_distribution(&distribution).unwrap();
+    let mut member2 = SenderKeyReceiver::from_distribution(&distribution).unwrap();
+
+    let messages = ["hello group", "how do you do", "bye"]
+        .iter()
+        .map(|text| sender.encrypt(text).unwrap())
+        .collect::<Vec<_>>();
+
+    // in order
+    for (message, text) in messages.iter().zip(["hello group", "how do you do", "bye"]) {
+        assert_eq!(member1.decrypt(message).unwrap(), text);
+    }
+    // out of order
+    assert_eq!(member2.decrypt(&messages[2]).unwrap(), "bye");
+    assert_eq!(member2.decrypt(&messages[0]).unwrap(), "hello group");
+    assert_eq!(member2.decrypt(&messages[1]).unwrap(), "how do you do");
+    // replayed
+    assert!(member2.decrypt(&messages[1]).is_err());
+
+    // tampered
+    let mut tampered = sender.encrypt("secret").unwrap();
+    tampered.ciphertext = messages[0].ciphertext.clone();
+    assert!(member1.decrypt(&tampered).is_err());
+
+    // joined late
+    let mut late = SenderKeyReceiver::from_distribution(&sender.distribution_message()).unwrap();
+    let message = sender.encrypt("welcome").unwrap();
+    assert!(late.decrypt(&messages[0]).is_err());
+    assert_eq!(late.decrypt(&message).unwrap(), "welcome");
+}
+
+#[test]
+fn test_sender_key_skipped_keys_bounded() {
+    let mut sender = SenderKeyState::new();
+    let mut receiver =
+        SenderKeyReceiver::from_distribution(&sender.distribution_message()).unwrap();
+    let messages = (0..MAX_STORED_MESSAGE_KEYS + 3)
+        .map(|i| sender.encrypt(&i.to_string()).unwrap())
+        .collect::<Vec<_>>();
+
+    // skipping ahead twice, past the bound, drops the oldest skipped key
+    let last = MAX_STORED_MESSAGE_KEYS + 2;
+    receiver.decrypt(&messages[last / 2]).unwrap();
+    receiver.decrypt(&messages[last]).unwrap();
+    assert_eq!(receiver.skipped.len(), MAX_STORED_MESSAGE_KEYS);
+    assert!(receiver.decrypt(&messages[0]).is_err());
+    assert_eq!(receiver.decrypt(&messages[1]).unwrap(), "1");
+    assert_eq!(
+        receiver.decrypt(&messages[last - 1]).unwrap(),
+        (last - 1).to_string()
+    );
+}
@@ -0,0 +1,194 @@
+//! Detached event signatures.
+//!
+//! The sender signs the canonical form of an event, which leaves out the event
+//! id since the server assigns it. The origin server then countersigns the id,
+//! the canonical event and the sender signature, so peers can check both who
+//! wrote an event and which server accepted it.
+//!
+//! A server rotating its key signs the new public key with the old one, so
+//! peers which trust the old key can follow the rotation.
+
+use std::error::Error;
+
+use limit_deps::*;
+use serde::Serialize;
+
+use crate::{PublicKey, SecretKey};
+
+/// Canonical bytes of an event: compact json with sorted keys, so every server
+/// produces the same bytes for the same event
+pub fn canonical_event<B: Serialize>(
+    timestamp: i64,
+    sender: &str,
+    event_type: &str,
+    body: &B,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    // `serde_json::Map` is ordered by key
+    let value = serde_json::json!({
+        "timestamp": timestamp,
+        "sender": sender,
+        "event_type": event_type,
+        "body": serde_json::to_value(body)?,
+    });
+    Ok(serde_json::to_vec(&value)?)
+}
+
+/// What the origin server vouches for
+#[derive(Debug, Clone, Copy)]
+pub struct Countersigned<'a> {
+    pub event_id: &'a str,
+    /// see [`canonical_event`]
+    pub canonical: &'a [u8],
+    pub sender_signature: Option<&'a str>,
+    /// depth of the event in its conversation
+    pub depth: i64,
+    /// the events of the conversation this event follows
+    pub prev_events: &'a [String],
+}
+
+impl Countersigned<'_> {
+    fn payload(&self) -> Vec<u8> {
+        [
+            self.event_id.as_bytes(),
+            b"\n",
+            self.canonical,
+            b"\n",
+            self.sender_signature.unwrap_or_default().as_bytes(),
+            b"\n",
+            self.depth.to_string().as_bytes(),
+            b"\n",
+            self.prev_events.join(",").as_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// signature of the sender over [`canonical_event`]
+pub fn sign_event(key: &SecretKey, canonical: &[u8]) -> Result<String, Box<dyn Error>> {
+    key.sign(canonical)
+}
+
+pub fn verify_event(
+    key: &PublicKey,
+    canonical: &[u8],
+    signature: &str,
+) -> Result<(), Box<dyn Error>> {
+    key.verify(canonical, signature)
+}
+
+/// signature of the origin server over the event id, the canonical event, the
+/// sender signature if any and the position of the event
+pub fn countersign_event(
+    server_key: &SecretKey,
+    countersigned: &Countersigned,
+) -> Result<String, Box<dyn Error>> {
+    server_key.sign(&countersigned.payload())
+}
+
+pub fn verify_countersignature(
+    server_key: &PublicKey,
+    countersigned: &Countersigned,
+    countersignature: &str,
+) -> Result<(), Box<dyn Error>> {
+    server_key.verify(&countersigned.payload(), countersignature)
+}
+
+fn key_rotation_payload(new_public_key: &str) -> Vec<u8> {
+    format!("limit key rotation\n{new_public_key}").into_bytes()
+}
+
+/// signature of a key being replaced over the tagged public key replacing it
+pub fn sign_key_rotation(
+    old_key: &SecretKey,
+    new_public_key: &str,
+) -> Result<String, Box<dyn Error>> {
+    old_key.sign(&key_rotation_payload(new_public_key))
+}
+
+pub fn verify_key_rotation(
+    old_key: &PublicKey,
+    new_public_key: &str,
+    signature: &str,
+) -> Result<(), Box<dyn Error>> {
+    old_key.verify(&key_rotation_payload(new_public_key), signature)
+}
+
+#[test]
+fn test_event_signature() {
+    use crate::{create_random_secret_of, decode_public, decode_secret, KeyType};
+
+    let body = serde_json::json!({ "text": "hello", "receiver_id": "b" });
+    let canonical = canonical_event(1, "a", "message", &body).unwrap();
+    // key order doesn't matter
+    assert_eq!(
+        canonical,
+        canonical_event(
+            1,
+            "a",
+            "message",
+            &serde_json::json!({ "receiver_id": "b", "text": "hello" })
+        )
+        .unwrap()
+    );
+
+    for key_type in [KeyType::P256, KeyType::Ed25519] {
+        let (user_secret, user_public) = create_random_secret_of(key_type).unwrap();
+        let (server_secret, server_public) = create_random_secret_of(KeyType::P256).unwrap();
+        let (user_secret, user_public) = (
+            decode_secret(&user_secret).unwrap(),
+            decode_public(&user_public).unwrap(),
+        );
+        let (server_secret, server_public) = (
+            decode_secret(&server_secret).unwrap(),
+            decode_public(&server_public).unwrap(),
+        );
+
+        let signature = sign_event(&user_secret, &canonical).unwrap();
+        assert!(verify_event(&user_public, &canonical, &signature).is_ok());
+        let forged = canonical_event(1, "c", "message", &body).unwrap();
+        assert!(verify_event(&user_public, &forged, &signature).is_err());
+
+        let prev_events = ["prev".to_string()];
+        let countersigned = Countersigned {
+            event_id: "id",
+            canonical: &canonical,
+            sender_signature: Some(&signature),
+            depth: 2,
+            prev_events: &prev_events,
+        };
+        let countersignature = countersign_event(&server_secret, &countersigned).unwrap();
+        assert!(verify_countersignature(&server_public, &countersigned, &countersignature).is_ok());
+        for tampered in [
+            Countersigned {
+                event_id: "other id",
+                ..countersigned
+            },
+            Countersigned {
+                sender_signature: None,
+                ..countersigned
+            },
+            Countersigned {
+                depth: 1,
+                ..countersigned
+            },
+            Countersigned {
+                prev_events: &[],
+                ..countersigned
+            },
+        ] {
+            assert!(verify_countersignature(&server_public, &tampered, &countersignature).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_key_rotation() {
+    use crate::KeyType;
+
+    let old_key = SecretKey::random(KeyType::Ed25519);
+    let new_key = SecretKey::random(KeyType::P256).public_key().encode();
+    let signature = sign_key_rotation(&old_key, &new_key).unwrap();
+    assert!(verify_key_rotation(&old_key.public_key(), &new_key, &signature).is_ok());
+    let other_key = SecretKey::random(KeyType::P256).public_key().encode();
+    assert!(verify_key_rotation(&old_key.public_key(), &other_key, &signature).is_err());
+}
@@ -1,4 +1,8 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    error::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use limit_deps::{url::Url, *};
 use once_cell::sync::OnceCell;
@@ -26,11 +30,31 @@ pub enum DeployMode {
     Master {
         /// bind address
         addr: SocketAddr,
-        /// Url of slave nodes
+        /// url other nodes reach this node at. A master without one, as
+        /// configured before clusters were sharded, holds no keys and its
+        /// slaves split them, default is none
+        #[serde(default)]
+        url: Option<Url>,
+        /// Url of slave nodes, any node may register when empty
         slaves: Vec<Url>,
     },
-    /// the url of the master
-    Slave { master: Url },
+    /// slave node of cluster
+    Slave {
+        /// bind address
+        addr: SocketAddr,
+        /// url other nodes reach this node at, one of the master's `slaves`
+        url: Url,
+        /// the url of the master
+        master: Url,
+    },
+}
+
+impl Default for DeployMode {
+    fn default() -> Self {
+        Self::StandAlone {
+            addr: ([0, 0, 0, 0], 1313).into(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,6 +68,52 @@ pub enum Metrics {
     Terminal,
 }
 
+/// A federated server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct Peer {
+    /// the server url, as used in `receiver_server` of messages
+    pub name: String,
+    /// grpc endpoint of the server, like `https://limit.example.com:1313`
+    pub endpoint: String,
+    /// server public key of the peer
+    pub public_key: String,
+}
+
+/// Which servers to federate with and how much, patterns match server names
+/// with `*` as a wildcard like `*.example.com`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct FederationPolicy {
+    /// only matching servers are allowed, every server when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// matching servers are denied, even if allowed
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// events per minute from or to a peer, unlimited when absent
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    /// max bytes of the text and extensions of an event, unlimited when absent
+    #[serde(default)]
+    pub max_event_size: Option<usize>,
+    /// limits of matching peers, the first match wins
+    #[serde(default)]
+    pub peer_limits: Vec<PeerLimits>,
+}
+
+/// Limits of the peers matching `pattern`, absent limits fall back to
+/// [`FederationPolicy`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct PeerLimits {
+    pub pattern: String,
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    #[serde(default)]
+    pub max_event_size: Option<usize>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(crate = "limit_deps::serde")]
 pub struct Config {
@@ -53,6 +123,11 @@ pub struct Config {
     /// Database config
     pub database: Database,
 
+    /// standalone or a node of a cluster
+    /// default is standalone on port 1313
+    #[serde(default)]
+    pub deploy_mode: DeployMode,
+
     /// Database connection pool thread count
     /// default is 3
     pub database_pool_thread_count: usize,
@@ -60,20 +135,169 @@ pub struct Config {
     /// metrics config
     pub metrics: Metrics,
 
+    /// keystore file holding the secrets below, see `limit-keystore`
+    /// when set, the secrets may be left out of the config file
+    #[serde(default)]
+    pub keystore: Option<PathBuf>,
+
     /// remember to set this to a random string
     /// also reset when you update the server
+    #[serde(default)]
     pub jwt_secret: String,
 
     /// generated when you first run the server
+    #[serde(default)]
     pub admin_jwt: String,
 
     /// server secret key
+    /// a P-256 or Ed25519 key is needed to countersign events
+    #[serde(default)]
     pub server_secret_key: String,
 
     /// server public key
+    #[serde(default)]
     pub server_public_key: String,
 
-    /// per user message on-the-fly limit
+    /// public keys used before the last rotations, newest first
+    /// peers accept a new key when they know one of these
+    #[serde(default)]
+    pub previous_public_keys: Vec<String>,
+
+    /// signature by each of `previous_public_keys` over the key which replaced
+    /// it, peers follow a rotation only when it is signed
+    #[serde(default)]
+    pub previous_key_signatures: Vec<String>,
+
+    /// per user message on-the-fly limit, messages accepted and not yet
+    /// stored and published. More are refused until some of them are.
     /// default is 100
     pub per_user_message_on_the_fly_limit: usize,
+
+    /// reject events without a valid sender signature
+    /// default is false
+    #[serde(default)]
+    pub require_event_signatures: bool,
+
+    /// answer `send_event` before the event is stored, an event may be lost
+    /// on a crash
+    /// default is false
+    #[serde(default)]
+    pub fast_ack: bool,
+
+    /// seconds a transaction id of `send_event` is remembered, a retry with it
+    /// gets the event of the first send back
+    /// default is 86400
+    #[serde(default = "default_transaction_id_window")]
+    pub transaction_id_window: u64,
+
+    /// servers with a pinned endpoint and key, other servers are discovered
+    #[serde(default)]
+    pub peers: Vec<Peer>,
+
+    /// scheme to reach servers which aren't in `peers`
+    /// default is https
+    #[serde(default = "default_federation_scheme")]
+    pub federation_scheme: String,
+
+    /// servers to federate with, more rules can be added with the admin api
+    #[serde(default)]
+    pub federation_policy: FederationPolicy,
+
+    /// events kept in the redis stream of a channel, older ones are trimmed
+    /// default is 10000
+    #[serde(default = "default_event_stream_max_len")]
+    pub event_stream_max_len: usize,
+}
+
+fn default_federation_scheme() -> String {
+    "https".to_string()
+}
+
+fn default_event_stream_max_len() -> usize {
+    10000
+}
+
+fn default_transaction_id_window() -> u64 {
+    86400
+}
+
+impl Config {
+    /// read a config file, the secrets come from its keystore when it has one
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut config: Self = toml::from_str(&std::fs::read_to_string(path)?)?;
+        config.load_keystore()?;
+        Ok(config)
+    }
+
+    /// fill the secrets from [`Config::keystore`], an encrypted keystore is
+    /// opened with the `LIMIT_KEYSTORE_PASSPHRASE` environment variable
+    pub fn load_keystore(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &self.keystore {
+            let secrets = limit_keystore::load(path, None)?;
+            self.jwt_secret = secrets.jwt_secret;
+            self.admin_jwt = secrets.admin_jwt;
+            self.server_secret_key = secrets.server_secret_key;
+            self.server_public_key = secrets.server_public_key;
+            self.previous_public_keys = secrets.previous_public_keys;
+            self.previous_key_signatures = secrets.previous_key_signatures;
+        }
+        Ok(())
+    }
+}
+
+/// load the config file into [`GLOBAL_CONFIG`], once at startup
+pub fn init(path: &Path) -> Result<&'static Config, Box<dyn Error>> {
+    GLOBAL_CONFIG
+        .set(Config::load(path)?)
+        .map_err(|_| "config already loaded")?;
+    Ok(GLOBAL_CONFIG.get().expect("config was just loaded"))
+}
+
+#[test]
+fn test_load_keystore() {
+    let dir = std::env::temp_dir();
+    let id = uuid::Uuid::new_v4();
+    let keystore = dir.join(format!("{id}.keystore"));
+    let secrets = limit_keystore::ServerSecrets::generate("p256".parse().unwrap()).unwrap();
+    limit_keystore::save(&keystore, &secrets, None).unwrap();
+
+    let path = dir.join(format!("{id}.toml"));
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+url = "127.0.0.1:1313"
+database_pool_thread_count = 3
+per_user_message_on_the_fly_limit = 100
+keystore = "{}"
+jwt_secret = "inline"
+metrics = "Terminal"
+database = {{ Sqlite = {{ path = "test.sqlite" }} }}
+"#,
+            keystore.display()
+        ),
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.jwt_secret, secrets.jwt_secret);
+    assert_eq!(config.server_secret_key, secrets.server_secret_key);
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(keystore).unwrap();
+}
+
+#[test]
+fn test_master_without_url() {
+    #[derive(Deserialize)]
+    #[serde(crate = "limit_deps::serde")]
+    struct Mode {
+        deploy_mode: DeployMode,
+    }
+    let mode = toml::from_str::<Mode>(
+        r#"deploy_mode = { Master = { addr = "0.0.0.0:1313", slaves = [] } }"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        mode.deploy_mode,
+        DeployMode::Master { url: None, .. }
+    ));
 }
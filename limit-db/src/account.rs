@@ -0,0 +1,202 @@
+//! Accounts moving between servers.
+//!
+//! The server of a user exports its [`Account`], the new server imports it
+//! under the same uuid, and the server the user left records where it went in
+//! a [`Moved`] record it signs. Servers follow moved records from the server
+//! named in a `receiver_server` to the one the receiver lives on now.
+
+use diesel::{
+    Connection, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, QueryResult, Queryable,
+    RunQueryDsl, Selectable,
+};
+use limit_deps::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    event::EventSubscriptions,
+    id::UserId,
+    schema::*,
+    user::{PrivacySettings, Profile, User},
+    SqliteConn,
+};
+
+/// moved records followed at most from one server
+pub const MAX_HOPS: usize = 8;
+
+/// What a server knows about one of its users. The shared key is left out,
+/// the importing server derives its own from [`Account::pubkey`].
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "limit_deps::serde")]
+pub struct Account {
+    /// the uuid of the user
+    pub id: String,
+    /// see [`User::pubkey`]
+    pub pubkey: String,
+    pub profile: Option<Profile>,
+    pub privacy: Option<PrivacySettings>,
+    pub subscriptions: Vec<EventSubscriptions>,
+}
+
+/// A user who moved from one server to another
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Queryable, Insertable, Selectable,
+)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = USER_MOVED)]
+pub struct Moved {
+    /// the uuid of the user, kept by the move
+    #[diesel(column_name = "ID")]
+    pub id: String,
+    /// the server the user left
+    #[diesel(column_name = "FROM_SERVER")]
+    pub from_server: String,
+    #[diesel(column_name = "TO_SERVER")]
+    pub to_server: String,
+    /// unix timestamp in milliseconds of the server the user left
+    #[diesel(column_name = "TS")]
+    pub ts: i64,
+    /// signature of the server the user left over [`Moved::canonical`]
+    #[diesel(column_name = "SIGNATURE")]
+    pub signature: String,
+}
+
+impl Moved {
+    /// the bytes signed by the server the user left
+    pub fn canonical(&self) -> Vec<u8> {
+        [
+            self.id.as_str(),
+            &self.from_server,
+            &self.to_server,
+            &self.ts.to_string(),
+        ]
+        .join("\n")
+        .into_bytes()
+    }
+}
+
+/// the account of a user of this server
+pub fn export(conn: &mut impl SqliteConn, id: &str) -> QueryResult<Account> {
+    let user = USER::table.find(id).first::<User>(conn)?;
+    Ok(Account {
+        id: user.id,
+        pubkey: user.pubkey,
+        profile: USER_PROFILE::table.find(id).first(conn).optional()?,
+        privacy: USER_PRIVACY_SETTINGS::table
+            .find(id)
+            .first(conn)
+            .optional()?,
+        subscriptions: EVENT_SUBSCRIPTIONS::table
+            .filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(id))
+            .load(conn)?,
+    })
+}
+
+/// store an account exported by another server with the shared key of this
+/// server and the user, replacing what is known of a user moving back
+pub fn import(conn: &mut impl SqliteConn, account: &Account, sharedkey: &str) -> QueryResult<()> {
+    let id = account.id.as_str();
+    Connection::transaction(conn, |conn| {
+        diesel::replace_into(USER::table)
+            .values(User {
+                id: account.id.clone(),
+                pubkey: account.pubkey.clone(),
+                sharedkey: sharedkey.to_string(),
+            })
+            .execute(conn)?;
+        diesel::delete(USER_PROFILE::table.find(id)).execute(conn)?;
+        if let Some(profile) = &account.profile {
+            diesel::insert_into(USER_PROFILE::table)
+                .values(profile.clone())
+                .execute(conn)?;
+        }
+        diesel::delete(USER_PRIVACY_SETTINGS::table.find(id)).execute(conn)?;
+        if let Some(privacy) = &account.privacy {
+            diesel::insert_into(USER_PRIVACY_SETTINGS::table)
+                .values(privacy.clone())
+                .execute(conn)?;
+        }
+        diesel::delete(EVENT_SUBSCRIPTIONS::table.filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(id)))
+            .execute(conn)?;
+        diesel::insert_into(EVENT_SUBSCRIPTIONS::table)
+            .values(account.subscriptions.clone())
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// store a moved record unless a later one of the same user and server is
+/// known, returns whether it was stored
+pub fn insert_moved(conn: &mut impl SqliteConn, moved: &Moved) -> QueryResult<bool> {
+    Connection::transaction(conn, |conn| {
+        let ts = USER_MOVED::table
+            .find((moved.id.as_str(), moved.from_server.as_str()))
+            .select(USER_MOVED::TS)
+            .first::<i64>(conn)
+            .optional()?;
+        if ts.map_or(false, |ts| ts >= moved.ts) {
+            return Ok(false);
+        }
+        diesel::replace_into(USER_MOVED::table)
+            .values(moved.clone())
+            .execute(conn)?;
+        Ok(true)
+    })
+}
+
+/// where `user` lives now. Each hop has to be later than the one before, so a
+/// user moving back to a server it left ends there.
+pub fn follow_moved(conn: &mut impl SqliteConn, user: &UserId) -> QueryResult<UserId> {
+    let id = user.uuid().to_string();
+    let mut current = user.clone();
+    let mut ts = i64::MIN;
+    for _ in 0..MAX_HOPS {
+        let moved = USER_MOVED::table
+            .find((id.as_str(), current.server().as_str()))
+            .first::<Moved>(conn)
+            .optional()?;
+        let Some(moved) = moved.filter(|moved| moved.ts > ts) else {
+            break;
+        };
+        let Ok(server) = moved.to_server.parse() else {
+            break;
+        };
+        current = UserId::new(user.uuid(), server);
+        ts = moved.ts;
+    }
+    Ok(current)
+}
+
+#[test]
+fn test_follow_moved() {
+    let uuid = uuid::Uuid::new_v4();
+    let user = |server: &str| UserId::new(uuid, server.parse().unwrap());
+    let moved = |from_server: &str, to_server: &str, ts: i64| Moved {
+        id: uuid.to_string(),
+        from_server: from_server.to_string(),
+        to_server: to_server.to_string(),
+        ts,
+        signature: String::new(),
+    };
+
+    let mut conn = diesel::sqlite::SqliteConnection::establish("../test.sqlite").unwrap();
+    assert_eq!(follow_moved(&mut conn, &user("a")).unwrap(), user("a"));
+
+    assert!(insert_moved(&mut conn, &moved("a", "b", 1)).unwrap());
+    assert!(insert_moved(&mut conn, &moved("b", "c", 2)).unwrap());
+    assert_eq!(follow_moved(&mut conn, &user("a")).unwrap(), user("c"));
+    assert_eq!(follow_moved(&mut conn, &user("b")).unwrap(), user("c"));
+    assert_eq!(follow_moved(&mut conn, &user("c")).unwrap(), user("c"));
+
+    // older records are ignored
+    assert!(!insert_moved(&mut conn, &moved("b", "d", 2)).unwrap());
+    assert_eq!(follow_moved(&mut conn, &user("a")).unwrap(), user("c"));
+
+    // back to the first server
+    assert!(insert_moved(&mut conn, &moved("c", "a", 3)).unwrap());
+    assert_eq!(follow_moved(&mut conn, &user("a")).unwrap(), user("a"));
+    assert_eq!(follow_moved(&mut conn, &user("b")).unwrap(), user("a"));
+
+    // and off again
+    assert!(insert_moved(&mut conn, &moved("a", "d", 4)).unwrap());
+    assert_eq!(follow_moved(&mut conn, &user("b")).unwrap(), user("d"));
+}
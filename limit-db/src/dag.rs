@@ -0,0 +1,368 @@
+//! Events of a conversation form a DAG.
+//!
+//! A new event follows the frontier of its conversation, the events nothing
+//! follows yet, and is one deeper than the deepest of them. Servers accepting
+//! events at the same time fork the DAG, the next event joins the forks.
+//! Ordering by depth, then by id, is a topological order all servers agree on.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use diesel::{ExpressionMethods, QueryDsl, QueryResult, RunQueryDsl, SelectableHelper};
+use limit_deps::*;
+
+use crate::{
+    event::{Event, EventSignature, Message, SREvent},
+    schema::{
+        CONVERSATION_FRONTIER, CONVERSATION_SEQUENCE, EVENT, EVENT_EDGES, EVENT_SIGNATURE, MESSAGE,
+    },
+    SqliteConn,
+};
+
+/// conversation of a message, messages to the same receiver share a history
+pub fn conversation_id(message: &Message) -> &str {
+    conversation_of(&message.receiver_id)
+}
+
+/// the conversation of the messages to a receiver, a user or a group. Nodes
+/// of a cluster split conversations by it.
+pub fn conversation_of(receiver_id: &str) -> &str {
+    receiver_id
+}
+
+/// the events a new event of a conversation follows and its depth, the
+/// frontier moves once the event is stored, see [`insert_event`]
+pub fn frontier(
+    conn: &mut impl SqliteConn,
+    conversation_id: &str,
+) -> QueryResult<(Vec<String>, i64)> {
+    let frontier = CONVERSATION_FRONTIER::table
+        .filter(CONVERSATION_FRONTIER::CONVERSATION_ID.eq(conversation_id))
+        .select((
+            CONVERSATION_FRONTIER::EVENT_ID,
+            CONVERSATION_FRONTIER::DEPTH,
+        ))
+        .order(CONVERSATION_FRONTIER::EVENT_ID.asc())
+        .load::<(String, i64)>(conn)?;
+    let depth = frontier.iter().map(|(_, depth)| *depth).max().unwrap_or(0) + 1;
+    Ok((frontier.into_iter().map(|(id, _)| id).collect(), depth))
+}
+
+/// the depth of an event following `prev_events`, `None` when some of them
+/// are unknown
+pub fn expected_depth(
+    conn: &mut impl SqliteConn,
+    prev_events: &[String],
+) -> QueryResult<Option<i64>> {
+    let depths = EVENT::table
+        .filter(EVENT::ID.eq_any(prev_events))
+        .select((EVENT::ID, EVENT::DEPTH))
+        .load::<(String, i64)>(conn)?;
+    let known = depths.iter().map(|(id, _)| id).collect::<HashSet<_>>();
+    if prev_events.iter().any(|id| !known.contains(id)) {
+        return Ok(None);
+    }
+    Ok(Some(
+        depths.iter().map(|(_, depth)| *depth).max().unwrap_or(0) + 1,
+    ))
+}
+
+/// store the edges from an event to the events it follows
+pub fn insert_edges(
+    conn: &mut impl SqliteConn,
+    event_id: &str,
+    prev_events: &[String],
+) -> QueryResult<usize> {
+    diesel::insert_or_ignore_into(EVENT_EDGES::table)
+        .values(
+            prev_events
+                .iter()
+                .map(|prev| {
+                    (
+                        EVENT_EDGES::EVENT_ID.eq(event_id),
+                        EVENT_EDGES::PREV_EVENT_ID.eq(prev),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)
+}
+
+/// add a stored event to the DAG, it replaces the events it follows in the
+/// frontier unless a known event follows it already
+pub fn insert_event(
+    conn: &mut impl SqliteConn,
+    conversation_id: &str,
+    event_id: &str,
+    depth: i64,
+    prev_events: &[String],
+) -> QueryResult<()> {
+    conn.transaction(|conn| {
+        insert_edges(conn, event_id, prev_events)?;
+        diesel::delete(
+            CONVERSATION_FRONTIER::table
+                .filter(CONVERSATION_FRONTIER::CONVERSATION_ID.eq(conversation_id))
+                .filter(CONVERSATION_FRONTIER::EVENT_ID.eq_any(prev_events)),
+        )
+        .execute(conn)?;
+        let followed = EVENT_EDGES::table
+            .filter(EVENT_EDGES::PREV_EVENT_ID.eq(event_id))
+            .count()
+            .get_result::<i64>(conn)?
+            > 0;
+        if !followed {
+            diesel::insert_or_ignore_into(CONVERSATION_FRONTIER::table)
+                .values((
+                    CONVERSATION_FRONTIER::CONVERSATION_ID.eq(conversation_id),
+                    CONVERSATION_FRONTIER::EVENT_ID.eq(event_id),
+                    CONVERSATION_FRONTIER::DEPTH.eq(depth),
+                ))
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+}
+
+/// the next position in a conversation on this server, see
+/// [`Event::seq`]. Positions of a transaction rolled back are reused.
+pub fn next_sequence(conn: &mut impl SqliteConn, conversation_id: &str) -> QueryResult<i64> {
+    conn.transaction(|conn| {
+        diesel::insert_into(CONVERSATION_SEQUENCE::table)
+            .values((
+                CONVERSATION_SEQUENCE::CONVERSATION_ID.eq(conversation_id),
+                CONVERSATION_SEQUENCE::SEQ.eq(1),
+            ))
+            .on_conflict(CONVERSATION_SEQUENCE::CONVERSATION_ID)
+            .do_update()
+            .set(CONVERSATION_SEQUENCE::SEQ.eq(CONVERSATION_SEQUENCE::SEQ + 1))
+            .execute(conn)?;
+        CONVERSATION_SEQUENCE::table
+            .find(conversation_id)
+            .select(CONVERSATION_SEQUENCE::SEQ)
+            .first(conn)
+    })
+}
+
+/// up to `limit` events of a conversation which `before` follow, directly or
+/// not, deepest first. Starts at the frontier when `before` is empty.
+pub fn ancestors(
+    conn: &mut impl SqliteConn,
+    conversation_id: &str,
+    before: &[String],
+    limit: usize,
+) -> QueryResult<Vec<String>> {
+    let start = if before.is_empty() {
+        CONVERSATION_FRONTIER::table
+            .filter(CONVERSATION_FRONTIER::CONVERSATION_ID.eq(conversation_id))
+            .select(CONVERSATION_FRONTIER::EVENT_ID)
+            .load::<String>(conn)?
+    } else {
+        EVENT_EDGES::table
+            .filter(EVENT_EDGES::EVENT_ID.eq_any(before))
+            .select(EVENT_EDGES::PREV_EVENT_ID)
+            .distinct()
+            .load::<String>(conn)?
+    };
+    let mut seen = before.iter().chain(&start).cloned().collect::<HashSet<_>>();
+    let mut queue = depths_in(conn, conversation_id, &start)?
+        .into_iter()
+        .collect::<BinaryHeap<_>>();
+    let mut res = vec![];
+    while res.len() < limit {
+        let Some((_, id)) = queue.pop() else {
+            break;
+        };
+        let prevs = EVENT_EDGES::table
+            .filter(EVENT_EDGES::EVENT_ID.eq(&id))
+            .select(EVENT_EDGES::PREV_EVENT_ID)
+            .load::<String>(conn)?
+            .into_iter()
+            .filter(|prev| seen.insert(prev.clone()))
+            .collect::<Vec<_>>();
+        queue.extend(depths_in(conn, conversation_id, &prevs)?);
+        res.push(id);
+    }
+    Ok(res)
+}
+
+/// depths of the stored events of a conversation among `ids`
+fn depths_in(
+    conn: &mut impl SqliteConn,
+    conversation_id: &str,
+    ids: &[String],
+) -> QueryResult<Vec<(i64, String)>> {
+    EVENT::table
+        .inner_join(MESSAGE::table)
+        .filter(EVENT::ID.eq_any(ids))
+        .filter(MESSAGE::RECEIVER_ID.eq(conversation_id))
+        .select((EVENT::DEPTH, EVENT::ID))
+        .load(conn)
+}
+
+/// stored events of conversations which follow events that aren't stored,
+/// as event id, conversation and server of the conversation
+pub fn horizon(
+    conn: &mut impl SqliteConn,
+    conversation_ids: &[String],
+    limit: i64,
+) -> QueryResult<Vec<(String, String, String)>> {
+    let events = EVENT_EDGES::table
+        .filter(
+            EVENT_EDGES::EVENT_ID.eq_any(
+                MESSAGE::table
+                    .filter(MESSAGE::RECEIVER_ID.eq_any(conversation_ids))
+                    .select(MESSAGE::EVENT_ID),
+            ),
+        )
+        .filter(EVENT_EDGES::PREV_EVENT_ID.ne_all(EVENT::table.select(EVENT::ID)))
+        .select(EVENT_EDGES::EVENT_ID)
+        .distinct()
+        .limit(limit)
+        .load::<String>(conn)?;
+    MESSAGE::table
+        .filter(MESSAGE::EVENT_ID.eq_any(events))
+        .select((
+            MESSAGE::EVENT_ID,
+            MESSAGE::RECEIVER_ID,
+            MESSAGE::RECEIVER_SERVER,
+        ))
+        .load(conn)
+}
+
+/// stored events with their signatures and the events they follow, events
+/// without a signature are left out
+pub fn load_events(conn: &mut impl SqliteConn, ids: &[String]) -> QueryResult<Vec<SREvent>> {
+    let mut prev_events = HashMap::<String, Vec<String>>::new();
+    for (event_id, prev) in EVENT_EDGES::table
+        .filter(EVENT_EDGES::EVENT_ID.eq_any(ids))
+        .order((
+            EVENT_EDGES::EVENT_ID.asc(),
+            EVENT_EDGES::PREV_EVENT_ID.asc(),
+        ))
+        .load::<(String, String)>(conn)?
+    {
+        prev_events.entry(event_id).or_default().push(prev);
+    }
+    Ok(EVENT::table
+        .inner_join(MESSAGE::table)
+        .inner_join(EVENT_SIGNATURE::table)
+        .filter(EVENT::ID.eq_any(ids))
+        .select((
+            Event::as_select(),
+            Message::as_select(),
+            EventSignature::as_select(),
+        ))
+        .load::<(Event, Message, EventSignature)>(conn)?
+        .into_iter()
+        .map(|(event, message, signature)| {
+            let mut event = SREvent::from((event, message));
+            event.prev_events = prev_events.remove(&event.head.id).unwrap_or_default();
+            event.signature = Some(signature);
+            event
+        })
+        .collect())
+}
+
+/// sort events so each comes after the events it follows, ties are broken by
+/// depth then id. Edges to events not in `events` are ignored.
+pub fn topological_order(events: Vec<SREvent>) -> Vec<SREvent> {
+    let ids = events
+        .iter()
+        .map(|event| event.head.id.clone())
+        .collect::<HashSet<_>>();
+    let mut pending = HashMap::with_capacity(events.len());
+    let mut followers: HashMap<String, Vec<String>> = HashMap::new();
+    for event in &events {
+        let prevs = event
+            .prev_events
+            .iter()
+            .filter(|prev| ids.contains(*prev))
+            .collect::<HashSet<_>>();
+        pending.insert(event.head.id.clone(), prevs.len());
+        for prev in prevs {
+            followers
+                .entry(prev.clone())
+                .or_default()
+                .push(event.head.id.clone());
+        }
+    }
+
+    let mut by_id = events
+        .into_iter()
+        .map(|event| (event.head.id.clone(), event))
+        .collect::<HashMap<_, _>>();
+    let mut ready = pending
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(id, _)| Reverse((by_id[id].head.depth, id.clone())))
+        .collect::<BinaryHeap<_>>();
+    let mut res = Vec::with_capacity(by_id.len());
+    while let Some(Reverse((_, id))) = ready.pop() {
+        for follower in followers.remove(&id).unwrap_or_default() {
+            let count = pending.get_mut(&follower).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push(Reverse((by_id[&follower].head.depth, follower)));
+            }
+        }
+        res.push(by_id.remove(&id).unwrap());
+    }
+    // events in a cycle can't come from honest servers, keep them last
+    let mut rest = by_id.into_values().collect::<Vec<_>>();
+    rest.sort_by(|a, b| (a.head.depth, &a.head.id).cmp(&(b.head.depth, &b.head.id)));
+    res.extend(rest);
+    res
+}
+
+#[test]
+fn test_topological_order() {
+    let event = |id: &str, depth: i64, prev_events: &[&str]| {
+        let mut event = SREvent::from((
+            Event {
+                id: id.to_string(),
+                timestamp: 0,
+                sender: "sender".to_string(),
+                event_type: crate::event::MESSAGE_EVENT_TYPE.to_string(),
+                device_id: None,
+                depth,
+                received_at: 0,
+                seq: 0,
+            },
+            Message {
+                event_id: id.to_string(),
+                receiver_id: "receiver".to_string(),
+                receiver_server: "server".to_string(),
+                text: id.to_string(),
+                extensions: "{}".to_string(),
+            },
+        ));
+        event.prev_events = prev_events.iter().map(|id| id.to_string()).collect();
+        event
+    };
+    // a forks into b and c, d joins them, e follows an unknown event
+    let events = || {
+        vec![
+            event("d", 3, &["b", "c"]),
+            event("c", 2, &["a"]),
+            event("e", 1, &["unknown"]),
+            event("b", 2, &["a"]),
+            event("a", 1, &[]),
+        ]
+    };
+    let ids = |events: Vec<SREvent>| {
+        events
+            .into_iter()
+            .map(|event| event.head.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids(topological_order(events())), ["a", "e", "b", "c", "d"]);
+    let mut reversed = events();
+    reversed.reverse();
+    assert_eq!(ids(topological_order(reversed)), ["a", "e", "b", "c", "d"]);
+
+    // lying about depth can't put an event before the events it follows
+    let events = vec![event("b", 0, &["a"]), event("a", 5, &[])];
+    assert_eq!(ids(topological_order(events)), ["a", "b"]);
+}
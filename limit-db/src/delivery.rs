@@ -0,0 +1,133 @@
+//! What each device of a user received.
+//!
+//! Every device reads the channels of its user in a consumer group of its own,
+//! see [`crate::stream`], and keeps a cursor here, the last event delivered to
+//! it in the order the server received them. A device reconnecting resumes
+//! after its cursor with the stored events it missed, see
+//! [`crate::event::load_after`].
+
+use diesel::{
+    ExpressionMethods, Insertable, OptionalExtension, QueryDsl, QueryResult, Queryable,
+    RunQueryDsl, Selectable,
+};
+use limit_deps::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{event::Event, schema::*, SqliteConn};
+
+/// The cursor of a device
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Queryable, Insertable, Selectable,
+)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = DEVICE_DELIVERY)]
+pub struct DeviceDelivery {
+    #[diesel(column_name = "USER_ID")]
+    pub user_id: String,
+    /// the device id of [`crate::event::Event::device_id`]
+    #[diesel(column_name = "DEVICE_ID")]
+    pub device_id: String,
+    /// when this server received the last event delivered to the device
+    #[diesel(column_name = "RECEIVED_AT")]
+    pub received_at: i64,
+    #[diesel(column_name = "EVENT_ID")]
+    pub event_id: String,
+    /// unix timestamp in milliseconds of the last delivery
+    #[diesel(column_name = "UPDATED_AT")]
+    pub updated_at: i64,
+}
+
+impl DeviceDelivery {
+    /// receive time and id of the last event delivered
+    pub fn cursor(&self) -> (i64, String) {
+        (self.received_at, self.event_id.clone())
+    }
+
+    /// whether an event was delivered to the device
+    pub fn delivered(&self, event: &Event) -> bool {
+        (event.received_at, event.id.as_str()) <= (self.received_at, self.event_id.as_str())
+    }
+}
+
+/// the cursor of a device, none until an event was delivered to it
+pub fn get(
+    conn: &mut impl SqliteConn,
+    user_id: &str,
+    device_id: &str,
+) -> QueryResult<Option<DeviceDelivery>> {
+    DEVICE_DELIVERY::table
+        .find((user_id, device_id))
+        .first(conn)
+        .optional()
+}
+
+/// record an event delivered to a device, the cursor only moves forward
+pub fn advance(
+    conn: &mut impl SqliteConn,
+    user_id: &str,
+    device_id: &str,
+    event: &Event,
+) -> QueryResult<()> {
+    conn.transaction(|conn| {
+        if get(conn, user_id, device_id)?.map_or(false, |delivery| delivery.delivered(event)) {
+            return Ok(());
+        }
+        diesel::replace_into(DEVICE_DELIVERY::table)
+            .values(DeviceDelivery {
+                user_id: user_id.to_string(),
+                device_id: device_id.to_string(),
+                received_at: event.received_at,
+                event_id: event.id.clone(),
+                updated_at: chrono::Utc::now().timestamp_millis(),
+            })
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// the cursors of the devices of a user
+pub fn devices(conn: &mut impl SqliteConn, user_id: &str) -> QueryResult<Vec<DeviceDelivery>> {
+    DEVICE_DELIVERY::table
+        .filter(DEVICE_DELIVERY::USER_ID.eq(user_id))
+        .order(DEVICE_DELIVERY::DEVICE_ID.asc())
+        .load(conn)
+}
+
+#[test]
+fn test_advance() {
+    use diesel::Connection;
+
+    let user_id = uuid::Uuid::new_v4().to_string();
+    let event = |received_at: i64| Event {
+        id: crate::id::new_event_id().to_string(),
+        timestamp: 0,
+        sender: uuid::Uuid::new_v4().to_string(),
+        event_type: crate::event::MESSAGE_EVENT_TYPE.to_string(),
+        device_id: None,
+        depth: 1,
+        received_at,
+        seq: 1,
+    };
+    let (first, second) = (event(1), event(2));
+
+    let mut conn = diesel::sqlite::SqliteConnection::establish("../test.sqlite").unwrap();
+    assert_eq!(get(&mut conn, &user_id, "phone").unwrap(), None);
+    advance(&mut conn, &user_id, "phone", &second).unwrap();
+    // an older event doesn't move the cursor back
+    advance(&mut conn, &user_id, "phone", &first).unwrap();
+    advance(&mut conn, &user_id, "laptop", &first).unwrap();
+
+    let phone = get(&mut conn, &user_id, "phone").unwrap().unwrap();
+    assert_eq!(phone.cursor(), (2, second.id.clone()));
+    assert!(phone.delivered(&first) && phone.delivered(&second));
+    let laptop = get(&mut conn, &user_id, "laptop").unwrap().unwrap();
+    assert!(!laptop.delivered(&second));
+    assert_eq!(
+        devices(&mut conn, &user_id)
+            .unwrap()
+            .into_iter()
+            .map(|delivery| delivery.device_id)
+            .collect::<Vec<_>>(),
+        ["laptop", "phone"]
+    );
+}
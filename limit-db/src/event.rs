@@ -1,8 +1,25 @@
-use diesel::{Insertable, Queryable, Selectable};
+use std::error::Error;
+
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, QueryResult,
+    Queryable, RunQueryDsl, Selectable, SelectableHelper,
+};
 use limit_deps::*;
 use serde::{Deserialize, Serialize};
 
-use crate::schema::*;
+use crate::{
+    id::{ParseIdError, UserId},
+    schema::*,
+    SqliteConn,
+};
+
+/// event type of a plain [`Message`]
+pub const MESSAGE_EVENT_TYPE: &str = "message";
+
+/// event type of a sender key distribution message, sent pairwise to every
+/// member of a channel before its group messages, see
+/// [`crate::event_type::SenderKeyDistribution`]
+pub const SENDER_KEY_DISTRIBUTION_EVENT_TYPE: &str = "sender_key_distribution";
 
 /// A event for sending and receiving
 #[derive(Serialize, Deserialize)]
@@ -10,23 +27,82 @@ use crate::schema::*;
 pub struct SREvent {
     pub head: Event,
     pub body: SREventBody,
+    /// absent until the origin server countersigned the event
+    #[serde(default)]
+    pub signature: Option<EventSignature>,
+    /// the frontier of the conversation when the event was created, see
+    /// [`crate::dag`]
+    #[serde(default)]
+    pub prev_events: Vec<String>,
 }
 
+/// The body of an event of any type, see [`crate::event_type`]
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "limit_deps::serde")]
 pub enum SREventBody {
+    /// the [`Message`] row holding the body
+    #[serde(alias = "SenderKeyDistribution")]
     Message(Message),
 }
 
+impl SREventBody {
+    /// the [`Message`] row of the event
+    pub fn message(&self) -> &Message {
+        match self {
+            Self::Message(m) => m,
+        }
+    }
+
+    pub fn message_mut(&mut self) -> &mut Message {
+        match self {
+            Self::Message(m) => m,
+        }
+    }
+
+    /// the typed body of an event of type `T`
+    pub fn decode<T: crate::event_type::EventType>(&self) -> serde_json::Result<T> {
+        crate::event_type::decode(self.message())
+    }
+}
+
 impl From<(Event, Message)> for SREvent {
     fn from(value: (Event, Message)) -> Self {
         Self {
             head: value.0,
             body: SREventBody::Message(value.1),
+            signature: None,
+            prev_events: vec![],
         }
     }
 }
 
+impl SREvent {
+    /// what the origin server countersigns, see [`limit_am::signature`]
+    pub fn countersigned<'a>(
+        &'a self,
+        canonical: &'a [u8],
+        sender_signature: Option<&'a str>,
+    ) -> limit_am::signature::Countersigned<'a> {
+        limit_am::signature::Countersigned {
+            event_id: &self.head.id,
+            canonical,
+            sender_signature,
+            depth: self.head.depth,
+            prev_events: &self.prev_events,
+        }
+    }
+
+    /// the bytes signed by the sender, see [`limit_am::signature`]
+    pub fn canonical(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        limit_am::signature::canonical_event(
+            self.head.timestamp,
+            &self.head.sender,
+            &self.head.event_type,
+            &self.body.message().canonical_body()?,
+        )
+    }
+}
+
 /// A event
 #[derive(Serialize, Deserialize, Clone, Queryable, Insertable, Selectable)]
 #[serde(crate = "limit_deps::serde")]
@@ -36,7 +112,7 @@ pub struct Event {
     #[diesel(column_name = "ID")]
     #[diesel(serialize_as = crate::orm::Uuid)]
     pub id: String,
-    /// the timestamp UTC of the message
+    /// the timestamp UTC of the message its sender claims, signed by it
     #[diesel(column_name = "TS")]
     pub timestamp: i64,
     /// the sender uuid
@@ -45,6 +121,25 @@ pub struct Event {
     /// the event type
     #[diesel(column_name = "EVENT_TYPE")]
     pub event_type: String,
+    /// the device the sender used, absent for events from other servers
+    #[diesel(column_name = "DEVICE_ID")]
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// longest path from the first event of the conversation, see
+    /// [`crate::dag`]
+    #[diesel(column_name = "DEPTH")]
+    #[serde(default)]
+    pub depth: i64,
+    /// the timestamp UTC this server received the event at, events are
+    /// ordered by it
+    #[diesel(column_name = "RECEIVED_AT")]
+    #[serde(default)]
+    pub received_at: i64,
+    /// position of the event in its conversation on this server, from 1
+    /// without gaps, see [`crate::dag::next_sequence`]
+    #[diesel(column_name = "SEQ")]
+    #[serde(default)]
+    pub seq: i64,
 }
 
 /// A message
@@ -69,6 +164,43 @@ pub struct Message {
     pub extensions: String,
 }
 
+impl Message {
+    /// the receiver, a user of this server or of another one
+    pub fn receiver(&self) -> Result<UserId, ParseIdError> {
+        UserId::from_parts(&self.receiver_id, &self.receiver_server)
+    }
+
+    /// the signed fields of the message, without the server assigned id
+    pub fn canonical_body(&self) -> Result<serde_json::Value, Box<dyn Error>> {
+        Ok(serde_json::json!({
+            "receiver_id": self.receiver_id,
+            "receiver_server": self.receiver_server,
+            "text": self.text,
+            "extensions": serde_json::from_str::<serde_json::Value>(&self.extensions)?,
+        }))
+    }
+}
+
+/// Signatures of an event
+#[derive(Serialize, Deserialize, Clone, Queryable, Insertable, Selectable)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = EVENT_SIGNATURE)]
+pub struct EventSignature {
+    /// foreign key to [`Event`]
+    #[diesel(column_name = "EVENT_ID")]
+    #[diesel(serialize_as = crate::orm::Uuid)]
+    pub event_id: String,
+    /// the sender's signature over [`SREvent::canonical`]
+    #[diesel(column_name = "SENDER_SIGNATURE")]
+    pub sender_signature: Option<String>,
+    /// the origin server's countersignature
+    #[diesel(column_name = "SERVER_SIGNATURE")]
+    pub server_signature: String,
+    /// the server which accepted the event from its sender
+    #[diesel(column_name = "ORIGIN_SERVER")]
+    pub origin_server: String,
+}
+
 /// user subscribe to message queue
 #[derive(Serialize, Deserialize, Clone, Queryable, Insertable, Selectable)]
 #[serde(crate = "limit_deps::serde")]
@@ -82,3 +214,188 @@ pub struct EventSubscriptions {
     #[diesel(column_name = "CHANNEL_TYPE")]
     pub channel_type: String,
 }
+
+/// An event stored and waiting to be published to the channel of its receiver
+#[derive(Clone, Queryable, Selectable)]
+#[diesel(table_name = EVENT_OUTBOX)]
+pub struct OutboxEvent {
+    /// events are published in this order
+    #[diesel(column_name = "SEQ")]
+    pub seq: i32,
+    #[diesel(column_name = "EVENT_ID")]
+    pub event_id: String,
+    #[diesel(column_name = "RECEIVER_ID")]
+    pub receiver_id: String,
+    /// json of the countersigned [`SREvent`]
+    #[diesel(column_name = "PAYLOAD")]
+    pub payload: String,
+}
+
+/// queue a stored event for publication, in the transaction storing it. An
+/// event a device of this server sent goes to the other devices of its sender
+/// too, unless they read the channel of the receiver already.
+pub fn enqueue(conn: &mut impl SqliteConn, event: &SREvent) -> QueryResult<()> {
+    let payload = serde_json::to_string(event)
+        .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+    let receiver_id = &event.body.message().receiver_id;
+    let mut channels = vec![receiver_id];
+    if event.head.device_id.is_some() && &event.head.sender != receiver_id {
+        let subscribed = EVENT_SUBSCRIPTIONS::table
+            .filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(&event.head.sender))
+            .filter(EVENT_SUBSCRIPTIONS::SUBSCRIBED_TO.eq(receiver_id))
+            .filter(EVENT_SUBSCRIPTIONS::CHANNEL_TYPE.eq("message"))
+            .count()
+            .get_result::<i64>(conn)?
+            > 0;
+        if !subscribed {
+            channels.push(&event.head.sender);
+        }
+    }
+    for channel in channels {
+        diesel::insert_into(EVENT_OUTBOX::table)
+            .values((
+                EVENT_OUTBOX::EVENT_ID.eq(&event.head.id),
+                EVENT_OUTBOX::RECEIVER_ID.eq(channel),
+                EVENT_OUTBOX::PAYLOAD.eq(&payload),
+            ))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// store an event of this server or of a peer with its message, signature and
+/// place in the DAG, and queue it for publication, all or nothing. The event
+/// gets the next position of its conversation and joins its frontier.
+pub fn store(conn: &mut impl SqliteConn, event: &mut SREvent) -> QueryResult<()> {
+    conn.transaction(|conn| {
+        event.head.seq =
+            crate::dag::next_sequence(conn, crate::dag::conversation_id(event.body.message()))?;
+        diesel::insert_into(EVENT::table)
+            .values(event.head.clone())
+            .execute(conn)?;
+        diesel::insert_into(MESSAGE::table)
+            .values(event.body.message().clone())
+            .execute(conn)?;
+        if let Some(signature) = &event.signature {
+            diesel::insert_into(EVENT_SIGNATURE::table)
+                .values(signature.clone())
+                .execute(conn)?;
+        }
+        crate::dag::insert_event(
+            conn,
+            crate::dag::conversation_id(event.body.message()),
+            &event.head.id,
+            event.head.depth,
+            &event.prev_events,
+        )?;
+        crate::event_type::index(conn, event)?;
+        enqueue(conn, event)
+    })
+}
+
+/// when this server received a stored event
+pub fn received_at(conn: &mut impl SqliteConn, id: &str) -> QueryResult<Option<i64>> {
+    EVENT::table
+        .find(id)
+        .select(EVENT::RECEIVED_AT)
+        .first(conn)
+        .optional()
+}
+
+/// stored messages of the channels `user_id` subscribed to and the messages
+/// its devices sent, after the event received at `(received_at, id)`, in the
+/// order they were received
+pub fn load_after(
+    conn: &mut impl SqliteConn,
+    user_id: &str,
+    (received_at, id): (i64, &str),
+    limit: i64,
+) -> QueryResult<Vec<SREvent>> {
+    let subscribed = EVENT_SUBSCRIPTIONS::table
+        .filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(user_id))
+        .filter(EVENT_SUBSCRIPTIONS::CHANNEL_TYPE.eq("message"))
+        .select(EVENT_SUBSCRIPTIONS::SUBSCRIBED_TO);
+    Ok(EVENT::table
+        .inner_join(MESSAGE::table)
+        .filter(
+            MESSAGE::RECEIVER_ID.eq_any(subscribed).or(EVENT::SENDER
+                .eq(user_id)
+                .and(EVENT::DEVICE_ID.is_not_null())),
+        )
+        .filter(
+            EVENT::RECEIVED_AT
+                .gt(received_at)
+                .or(EVENT::RECEIVED_AT.eq(received_at).and(EVENT::ID.gt(id))),
+        )
+        .order((EVENT::RECEIVED_AT.asc(), EVENT::ID.asc()))
+        .limit(limit)
+        .select((Event::as_select(), Message::as_select()))
+        .load::<(Event, Message)>(conn)?
+        .into_iter()
+        .map(SREvent::from)
+        .collect())
+}
+
+#[test]
+fn test_store() {
+    use diesel::Connection;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let mut event = SREvent::from((
+        Event {
+            id: id.clone(),
+            timestamp: 0,
+            sender: uuid::Uuid::new_v4().to_string(),
+            event_type: MESSAGE_EVENT_TYPE.to_string(),
+            device_id: None,
+            depth: 1,
+            received_at: 0,
+            seq: 0,
+        },
+        Message {
+            event_id: id.clone(),
+            receiver_id: uuid::Uuid::new_v4().to_string(),
+            receiver_server: "127.0.0.1:1313".to_string(),
+            text: "stored".to_string(),
+            extensions: "{}".to_string(),
+        },
+    ));
+    event.prev_events = vec![uuid::Uuid::new_v4().to_string()];
+    let queued = |conn: &mut diesel::sqlite::SqliteConnection| {
+        EVENT_OUTBOX::table
+            .filter(EVENT_OUTBOX::EVENT_ID.eq(&id))
+            .load::<OutboxEvent>(conn)
+            .unwrap()
+    };
+
+    let conversation = event.body.message().receiver_id.clone();
+    let frontier = |conn: &mut diesel::sqlite::SqliteConnection| {
+        crate::dag::frontier(conn, &conversation).unwrap()
+    };
+
+    let mut conn = diesel::sqlite::SqliteConnection::establish("../test.sqlite").unwrap();
+    assert_eq!(frontier(&mut conn), (vec![], 1));
+    store(&mut conn, &mut event).unwrap();
+    assert_eq!(event.head.seq, 1);
+    assert_eq!(frontier(&mut conn), (vec![id.clone()], 2));
+    let outbox = queued(&mut conn);
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(outbox[0].receiver_id, event.body.message().receiver_id);
+    let queued_event = serde_json::from_str::<SREvent>(&outbox[0].payload).unwrap();
+    assert_eq!(queued_event.prev_events, event.prev_events);
+    assert_eq!(queued_event.head.seq, 1);
+
+    // nothing of a failing store is kept, its position included
+    event.prev_events.push(uuid::Uuid::new_v4().to_string());
+    assert!(store(&mut conn, &mut event).is_err());
+    assert_eq!(queued(&mut conn).len(), 1);
+    assert_eq!(frontier(&mut conn), (vec![id.clone()], 2));
+    let next = uuid::Uuid::new_v4().to_string();
+    event.head.id = next.clone();
+    event.body.message_mut().event_id = next;
+    event.prev_events = vec![id.clone()];
+    event.head.depth = 2;
+    store(&mut conn, &mut event).unwrap();
+    assert_eq!(event.head.seq, 2);
+    assert_eq!(frontier(&mut conn), (vec![event.head.id.clone()], 3));
+}
@@ -0,0 +1,147 @@
+//! Registry of event types.
+//!
+//! Events of every type are stored alike, the [`Message`] addressing an event
+//! to its receiver holds its body as a text and string extensions, the form
+//! clients send and receive it in. Together they make the generic body, an
+//! object of the extensions and a `text` field. A registered type decodes its
+//! typed body from it, which is checked when the event is accepted, and may
+//! index fields of it in side tables. Events of other types are stored and
+//! delivered as they came.
+
+use std::collections::HashMap;
+
+use diesel::QueryResult;
+use limit_deps::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    event::{Message, SREvent, MESSAGE_EVENT_TYPE, SENDER_KEY_DISTRIBUTION_EVENT_TYPE},
+    SqliteConn,
+};
+
+/// field of the generic body holding the text of the message
+pub const TEXT_FIELD: &str = "text";
+
+/// A registered event type, its typed body is the generic body with string
+/// fields only
+pub trait EventType: Serialize + DeserializeOwned {
+    /// the stable type string of [`crate::event::Event::event_type`]
+    const EVENT_TYPE: &'static str;
+
+    /// store the rows of side tables indexing fields of the body, in the
+    /// transaction storing the event
+    fn index(&self, _conn: &mut impl SqliteConn, _event_id: &str) -> QueryResult<()> {
+        Ok(())
+    }
+}
+
+/// Body of a plain message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct TextMessage {
+    pub text: String,
+}
+
+impl EventType for TextMessage {
+    const EVENT_TYPE: &'static str = MESSAGE_EVENT_TYPE;
+}
+
+/// Body of a sender key distribution message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct SenderKeyDistribution {
+    /// the `limit_am::sender_key::SenderKeyDistributionMessage` sealed for the
+    /// receiver
+    #[serde(rename = "text")]
+    pub distribution: String,
+}
+
+impl EventType for SenderKeyDistribution {
+    const EVENT_TYPE: &'static str = SENDER_KEY_DISTRIBUTION_EVENT_TYPE;
+}
+
+/// the generic body of a message
+pub fn generic_body(message: &Message) -> serde_json::Result<Value> {
+    let mut body: serde_json::Map<String, Value> = serde_json::from_str(&message.extensions)?;
+    body.insert(TEXT_FIELD.to_string(), Value::String(message.text.clone()));
+    Ok(Value::Object(body))
+}
+
+/// the typed body of a message
+pub fn decode<T: EventType>(message: &Message) -> serde_json::Result<T> {
+    serde_json::from_value(generic_body(message)?)
+}
+
+/// the text and extensions of a message holding a typed body
+pub fn encode<T: EventType>(body: &T) -> serde_json::Result<(String, HashMap<String, String>)> {
+    let mut body: HashMap<String, String> = serde_json::from_value(serde_json::to_value(body)?)?;
+    let text = body.remove(TEXT_FIELD).unwrap_or_default();
+    Ok((text, body))
+}
+
+macro_rules! registry {
+    ($($ty:ty),* $(,)?) => {
+        /// the event types this server knows
+        pub const EVENT_TYPES: &[&str] = &[$(<$ty as EventType>::EVENT_TYPE),*];
+
+        /// check the generic body of an event decodes, and its typed body when
+        /// its type is registered
+        pub fn validate(event_type: &str, message: &Message) -> serde_json::Result<()> {
+            generic_body(message)?;
+            $(
+                if event_type == <$ty as EventType>::EVENT_TYPE {
+                    decode::<$ty>(message)?;
+                }
+            )*
+            Ok(())
+        }
+
+        /// index the body of an event of a registered type, in the transaction
+        /// storing it
+        pub fn index(conn: &mut impl SqliteConn, event: &SREvent) -> QueryResult<()> {
+            $(
+                if event.head.event_type == <$ty as EventType>::EVENT_TYPE {
+                    return decode::<$ty>(event.body.message())
+                        .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?
+                        .index(conn, &event.head.id);
+                }
+            )*
+            Ok(())
+        }
+    };
+}
+
+registry!(TextMessage, SenderKeyDistribution);
+
+#[test]
+fn test_registry() {
+    let message = |text: &str, extensions: &str| Message {
+        event_id: uuid::Uuid::new_v4().to_string(),
+        receiver_id: uuid::Uuid::new_v4().to_string(),
+        receiver_server: "127.0.0.1:1313".to_string(),
+        text: text.to_string(),
+        extensions: extensions.to_string(),
+    };
+
+    let distribution = message("encoded", r#"{"event_type":"sender_key_distribution"}"#);
+    assert_eq!(
+        decode::<SenderKeyDistribution>(&distribution).unwrap(),
+        SenderKeyDistribution {
+            distribution: "encoded".to_string()
+        }
+    );
+    let (text, extensions) = encode(&TextMessage {
+        text: "hello".to_string(),
+    })
+    .unwrap();
+    assert_eq!(text, "hello");
+    assert!(extensions.is_empty());
+
+    assert!(validate(MESSAGE_EVENT_TYPE, &distribution).is_ok());
+    assert!(validate(MESSAGE_EVENT_TYPE, &message("hello", "not json")).is_err());
+    // only the generic body of unknown types is checked
+    assert!(validate("org.example.unknown", &message("", "{}")).is_ok());
+    assert!(validate("org.example.unknown", &message("", "[]")).is_err());
+    assert!(EVENT_TYPES.contains(&SENDER_KEY_DISTRIBUTION_EVENT_TYPE));
+}
@@ -0,0 +1,90 @@
+use diesel::{Insertable, Queryable, Selectable};
+use limit_deps::*;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::*;
+
+/// waiting for delivery
+pub const OUTBOX_STATE_PENDING: &str = "pending";
+/// given up after too many attempts, until retried by an admin
+pub const OUTBOX_STATE_DEAD: &str = "dead";
+
+/// rule action allowing matching servers
+pub const POLICY_ACTION_ALLOW: &str = "allow";
+/// rule action denying matching servers
+pub const POLICY_ACTION_DENY: &str = "deny";
+
+/// An event queued for delivery to a peer server
+#[derive(Serialize, Deserialize, Clone, Queryable, Selectable)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = FEDERATION_OUTBOX)]
+pub struct OutboxEvent {
+    /// events of a destination are delivered in this order
+    #[diesel(column_name = "SEQ")]
+    pub seq: i32,
+    #[diesel(column_name = "EVENT_ID")]
+    pub event_id: String,
+    /// the peer server name
+    #[diesel(column_name = "DESTINATION")]
+    pub destination: String,
+    /// json of the countersigned [`crate::event::SREvent`]
+    #[diesel(column_name = "PAYLOAD")]
+    pub payload: String,
+    #[diesel(column_name = "ATTEMPTS")]
+    pub attempts: i32,
+    /// unix timestamp in milliseconds
+    #[diesel(column_name = "NEXT_ATTEMPT_AT")]
+    pub next_attempt_at: i64,
+    #[diesel(column_name = "LAST_ERROR")]
+    pub last_error: Option<String>,
+    /// [`OUTBOX_STATE_PENDING`] or [`OUTBOX_STATE_DEAD`]
+    #[diesel(column_name = "STATE")]
+    pub state: String,
+}
+
+/// An [`OutboxEvent`] to enqueue, `SEQ` is assigned by the database
+#[derive(Serialize, Deserialize, Clone, Insertable)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = FEDERATION_OUTBOX)]
+pub struct NewOutboxEvent {
+    #[diesel(column_name = "EVENT_ID")]
+    pub event_id: String,
+    #[diesel(column_name = "DESTINATION")]
+    pub destination: String,
+    #[diesel(column_name = "PAYLOAD")]
+    pub payload: String,
+    #[diesel(column_name = "NEXT_ATTEMPT_AT")]
+    pub next_attempt_at: i64,
+    #[diesel(column_name = "STATE")]
+    pub state: String,
+}
+
+/// The key a discovered server was first seen with, or rotated to
+#[derive(Serialize, Deserialize, Clone, Queryable, Insertable, Selectable)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = PEER_SERVER_KEY)]
+pub struct PeerServerKey {
+    #[diesel(column_name = "SERVER_NAME")]
+    pub server_name: String,
+    #[diesel(column_name = "PUBLIC_KEY")]
+    pub public_key: String,
+    /// unix timestamp in milliseconds
+    #[diesel(column_name = "FIRST_SEEN")]
+    pub first_seen: i64,
+    /// unix timestamp in milliseconds of the last rotation
+    #[diesel(column_name = "UPDATED_AT")]
+    pub updated_at: i64,
+}
+
+/// An allow or deny rule added with the admin api
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Queryable, Insertable, Selectable)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = FEDERATION_POLICY_RULE)]
+pub struct FederationPolicyRule {
+    /// server name, `*` matches anything
+    #[diesel(column_name = "PATTERN")]
+    pub pattern: String,
+    /// [`POLICY_ACTION_ALLOW`] or [`POLICY_ACTION_DENY`]
+    #[diesel(column_name = "ACTION")]
+    pub action: String,
+}
@@ -0,0 +1,592 @@
+//! Group state replicated between servers as a CRDT.
+//!
+//! Every change of a group is a [`GroupStateEvent`] holding a [`GroupOp`].
+//! Name, topic, settings and roles are last-writer-wins registers ordered by
+//! [`Stamp`], membership is an observed-remove set. Events are applied in
+//! [`Stamp`] order and only when their sender had the role for the change at
+//! that point, so applying the same events in any order gives the same
+//! [`GroupState`] and servers only have to exchange the events they miss.
+//!
+//! A group is created by the event with the id of the group, its sender is an
+//! admin of the group for good.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use diesel::{
+    ExpressionMethods, Insertable, QueryDsl, QueryResult, Queryable, RunQueryDsl, Selectable,
+};
+use limit_deps::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{schema::*, SqliteConn};
+
+/// Orders concurrent writes, the later timestamp wins, then the greater origin
+/// server and event id
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(crate = "limit_deps::serde")]
+pub struct Stamp {
+    pub ts: i64,
+    pub origin_server: String,
+    pub event_id: String,
+}
+
+/// A value kept by the write with the greatest [`Stamp`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(crate = "limit_deps::serde")]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    stamp: Option<Stamp>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            stamp: None,
+        }
+    }
+}
+
+impl<T: Clone> LwwRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    /// write `value`, `None` clears the register
+    pub fn set(&mut self, value: Option<T>, stamp: Stamp) {
+        if self.stamp.as_ref().map_or(true, |current| &stamp > current) {
+            self.value = value;
+            self.stamp = Some(stamp);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        if let Some(stamp) = &other.stamp {
+            self.set(other.value.clone(), stamp.clone());
+        }
+    }
+}
+
+/// An observed-remove set, an element is present while it has an add tag no
+/// remove has observed. Concurrent adds win over removes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(crate = "limit_deps::serde")]
+pub struct OrSet<T: Ord> {
+    adds: BTreeMap<T, BTreeSet<String>>,
+    removed: BTreeSet<String>,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            adds: BTreeMap::new(),
+            removed: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> OrSet<T> {
+    /// add `element`, `tag` is unique to this add
+    pub fn add(&mut self, element: T, tag: String) {
+        self.adds.entry(element).or_default().insert(tag);
+    }
+
+    /// remove the adds with `tags`, usually the [`OrSet::tags`] of an element
+    pub fn remove(&mut self, tags: impl IntoIterator<Item = String>) {
+        self.removed.extend(tags);
+    }
+
+    /// tags of the adds of `element` not removed yet
+    pub fn tags(&self, element: &T) -> Vec<String> {
+        self.adds
+            .get(element)
+            .into_iter()
+            .flatten()
+            .filter(|tag| !self.removed.contains(*tag))
+            .cloned()
+            .collect()
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        !self.tags(element).is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.adds
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|tag| !self.removed.contains(tag)))
+            .map(|(element, _)| element)
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (element, tags) in &other.adds {
+            self.adds
+                .entry(element.clone())
+                .or_default()
+                .extend(tags.iter().cloned());
+        }
+        self.removed.extend(other.removed.iter().cloned());
+    }
+}
+
+/// A member of a group
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(crate = "limit_deps::serde")]
+pub struct Member {
+    pub user_id: String,
+    /// the server of the user, which takes part in the group
+    pub server: String,
+}
+
+/// Role of a member, members without one are [`Role::Member`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(crate = "limit_deps::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Member,
+    Moderator,
+    Admin,
+}
+
+/// A change of a group, `None` values clear a field
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(crate = "limit_deps::serde")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GroupOp {
+    SetName {
+        name: Option<String>,
+    },
+    SetTopic {
+        topic: Option<String>,
+    },
+    SetSetting {
+        key: String,
+        value: Option<String>,
+    },
+    /// tagged with the id of its event
+    AddMember {
+        member: Member,
+    },
+    /// `tags` are the [`OrSet::tags`] of the member seen by the origin server
+    RemoveMember {
+        member: Member,
+        tags: Vec<String>,
+    },
+    SetRole {
+        user_id: String,
+        role: Option<Role>,
+    },
+}
+
+/// A change of a group as accepted by its origin server
+#[derive(
+    Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Queryable, Insertable, Selectable,
+)]
+#[serde(crate = "limit_deps::serde")]
+#[diesel(table_name = GROUP_STATE_EVENT)]
+pub struct GroupStateEvent {
+    /// should be unique
+    #[diesel(column_name = "ID")]
+    #[diesel(serialize_as = crate::orm::Uuid)]
+    pub id: String,
+    #[diesel(column_name = "GROUP_ID")]
+    pub group_id: String,
+    /// the user changing the group
+    #[diesel(column_name = "SENDER")]
+    pub sender: String,
+    /// the server which accepted the event from its sender
+    #[diesel(column_name = "ORIGIN_SERVER")]
+    pub origin_server: String,
+    /// unix timestamp in milliseconds of the origin server
+    #[diesel(column_name = "TS")]
+    pub ts: i64,
+    /// json of the [`GroupOp`]
+    #[diesel(column_name = "OP")]
+    pub op: String,
+    /// signature of the origin server over [`GroupStateEvent::canonical`]
+    #[diesel(column_name = "SIGNATURE")]
+    pub signature: String,
+}
+
+impl GroupStateEvent {
+    pub fn op(&self) -> Result<GroupOp, serde_json::Error> {
+        serde_json::from_str(&self.op)
+    }
+
+    /// the sender as a member, on the origin server
+    pub fn sender(&self) -> Member {
+        Member {
+            user_id: self.sender.clone(),
+            server: self.origin_server.clone(),
+        }
+    }
+
+    pub fn stamp(&self) -> Stamp {
+        Stamp {
+            ts: self.ts,
+            origin_server: self.origin_server.clone(),
+            event_id: self.id.clone(),
+        }
+    }
+
+    /// the bytes signed by the origin server
+    pub fn canonical(&self) -> Vec<u8> {
+        [
+            self.id.as_str(),
+            &self.group_id,
+            &self.sender,
+            &self.origin_server,
+            &self.ts.to_string(),
+            &self.op,
+        ]
+        .join("\n")
+        .into_bytes()
+    }
+}
+
+/// The state of a group, see the module documentation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(crate = "limit_deps::serde")]
+pub struct GroupState {
+    /// the sender of the event creating the group
+    pub creator: Option<Member>,
+    pub name: LwwRegister<String>,
+    pub topic: LwwRegister<String>,
+    pub settings: BTreeMap<String, LwwRegister<String>>,
+    pub members: OrSet<Member>,
+    pub roles: BTreeMap<String, LwwRegister<Role>>,
+}
+
+impl GroupState {
+    /// the state of `events` applied in [`Stamp`] order, duplicates are
+    /// applied once
+    pub fn from_events<'a>(
+        events: impl IntoIterator<Item = &'a GroupStateEvent>,
+    ) -> Result<Self, serde_json::Error> {
+        let mut events = events.into_iter().collect::<Vec<_>>();
+        events.sort_by_key(|event| event.stamp());
+        events.dedup_by(|a, b| a.id == b.id);
+        let mut state = Self::default();
+        for event in events {
+            state.apply(event)?;
+        }
+        Ok(state)
+    }
+
+    /// apply an event following the events of this state in [`Stamp`] order.
+    /// Returns false when its sender may not make the change, which is
+    /// ignored then.
+    pub fn apply(&mut self, event: &GroupStateEvent) -> Result<bool, serde_json::Error> {
+        if !self.permits(event)? {
+            return Ok(false);
+        }
+        if event.id == event.group_id {
+            self.creator = Some(event.sender());
+        }
+        let stamp = event.stamp();
+        match event.op()? {
+            GroupOp::SetName { name } => self.name.set(name, stamp),
+            GroupOp::SetTopic { topic } => self.topic.set(topic, stamp),
+            GroupOp::SetSetting { key, value } => {
+                self.settings.entry(key).or_default().set(value, stamp)
+            }
+            GroupOp::AddMember { member } => self.members.add(member, event.id.clone()),
+            GroupOp::RemoveMember { tags, .. } => self.members.remove(tags),
+            GroupOp::SetRole { user_id, role } => {
+                self.roles.entry(user_id).or_default().set(role, stamp)
+            }
+        }
+        Ok(true)
+    }
+
+    /// whether the sender of `event` has the role for its change in this
+    /// state. Roles are changed by admins, members may leave and moderators
+    /// make the other changes. Nothing is permitted before the group is
+    /// created.
+    pub fn permits(&self, event: &GroupStateEvent) -> Result<bool, serde_json::Error> {
+        let op = event.op()?;
+        let sender = event.sender();
+        let Some(creator) = &self.creator else {
+            return Ok(event.id == event.group_id);
+        };
+        let role = if creator == &sender {
+            Role::Admin
+        } else if self.members.contains(&sender) {
+            self.role_of(&sender.user_id).unwrap_or(Role::Member)
+        } else {
+            return Ok(false);
+        };
+        Ok(match op {
+            GroupOp::SetRole { .. } => role >= Role::Admin,
+            GroupOp::RemoveMember { member, .. } if member == sender => true,
+            _ => role >= Role::Moderator,
+        })
+    }
+
+    /// the operation removing `member` as seen by this state
+    pub fn remove_member(&self, member: Member) -> GroupOp {
+        let tags = self.members.tags(&member);
+        GroupOp::RemoveMember { member, tags }
+    }
+
+    /// role of a member, `None` for users who aren't members
+    pub fn role_of(&self, user_id: &str) -> Option<Role> {
+        self.members
+            .iter()
+            .any(|member| member.user_id == user_id)
+            .then(|| {
+                self.roles
+                    .get(user_id)
+                    .and_then(LwwRegister::get)
+                    .copied()
+                    .unwrap_or(Role::Member)
+            })
+    }
+}
+
+/// store events, returns how many were new
+pub fn insert_events(conn: &mut impl SqliteConn, events: &[GroupStateEvent]) -> QueryResult<usize> {
+    diesel::insert_or_ignore_into(GROUP_STATE_EVENT::table)
+        .values(events.to_vec())
+        .execute(conn)
+}
+
+/// stored events of a group, ordered by id
+pub fn load_events(
+    conn: &mut impl SqliteConn,
+    group_id: &str,
+) -> QueryResult<Vec<GroupStateEvent>> {
+    GROUP_STATE_EVENT::table
+        .filter(GROUP_STATE_EVENT::GROUP_ID.eq(group_id))
+        .order(GROUP_STATE_EVENT::ID.asc())
+        .load(conn)
+}
+
+/// ids of the groups with stored events
+pub fn group_ids(conn: &mut impl SqliteConn) -> QueryResult<Vec<String>> {
+    GROUP_STATE_EVENT::table
+        .select(GROUP_STATE_EVENT::GROUP_ID)
+        .distinct()
+        .load(conn)
+}
+
+/// servers taking part in a group, the origins of its events and the servers
+/// of its members
+pub fn participants(state: &GroupState, events: &[GroupStateEvent]) -> BTreeSet<String> {
+    events
+        .iter()
+        .map(|event| event.origin_server.clone())
+        .chain(state.members.iter().map(|member| member.server.clone()))
+        .collect()
+}
+
+#[test]
+fn test_or_set() {
+    let alice = || "alice".to_string();
+    let mut a = OrSet::default();
+    a.add(alice(), "1".to_string());
+    let mut b = a.clone();
+    // a removes what it saw while b adds again
+    a.remove(a.tags(&alice()));
+    b.add(alice(), "2".to_string());
+    assert!(!a.contains(&alice()));
+    a.merge(&b);
+    assert!(a.contains(&alice()));
+    assert_eq!(a.tags(&alice()), ["2"]);
+
+    // a remove arriving before its add
+    let mut c = OrSet::default();
+    c.remove(["3".to_string()]);
+    c.add(alice(), "3".to_string());
+    assert!(!c.contains(&alice()));
+    assert_eq!(c.iter().count(), 0);
+}
+
+#[test]
+fn test_lww_register() {
+    let stamp = |ts, origin_server: &str| Stamp {
+        ts,
+        origin_server: origin_server.to_string(),
+        event_id: "event".to_string(),
+    };
+    let mut register = LwwRegister::default();
+    register.set(Some("b"), stamp(2, "a.example.com"));
+    register.set(Some("a"), stamp(1, "b.example.com"));
+    assert_eq!(register.get(), Some(&"b"));
+    // same time, the greater server wins
+    register.set(Some("c"), stamp(2, "b.example.com"));
+    assert_eq!(register.get(), Some(&"c"));
+    register.set(None, stamp(3, "a.example.com"));
+    assert_eq!(register.get(), None);
+}
+
+#[test]
+fn test_group_state_convergence() {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    let servers = ["a.example.com", "b.example.com", "c.example.com"];
+    let users = ["alice", "bob", "carol", "dave"];
+    for seed in 0..64 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        // alice of the first server creates the group, every server changes
+        // it while it knows part of the events
+        let mut events = vec![GroupStateEvent {
+            id: "group".to_string(),
+            group_id: "group".to_string(),
+            sender: "alice".to_string(),
+            origin_server: servers[0].to_string(),
+            ts: -100,
+            op: serde_json::to_string(&GroupOp::AddMember {
+                member: Member {
+                    user_id: "bob".to_string(),
+                    server: servers[1].to_string(),
+                },
+            })
+            .unwrap(),
+            signature: "".to_string(),
+        }];
+        let mut known = vec![events.clone(); servers.len()];
+        for i in 0..120 {
+            let server = rng.gen_range(0..servers.len());
+            if rng.gen_bool(0.3) {
+                let event = events.choose(&mut rng).cloned();
+                if let Some(event) = event {
+                    if !known[server].contains(&event) {
+                        known[server].push(event);
+                    }
+                }
+                continue;
+            }
+            let state = GroupState::from_events(&known[server]).unwrap();
+            let member = Member {
+                user_id: users.choose(&mut rng).unwrap().to_string(),
+                server: servers.choose(&mut rng).unwrap().to_string(),
+            };
+            let op = match rng.gen_range(0..6) {
+                0 => GroupOp::SetName {
+                    name: Some(format!("name {i}")),
+                },
+                1 => GroupOp::SetTopic {
+                    topic: rng.gen_bool(0.8).then(|| format!("topic {i}")),
+                },
+                2 => GroupOp::SetSetting {
+                    key: ["mute", "slow_mode"].choose(&mut rng).unwrap().to_string(),
+                    value: rng.gen_bool(0.8).then(|| i.to_string()),
+                },
+                3 | 4 => GroupOp::AddMember { member },
+                _ => state.remove_member(member),
+            };
+            let op = if rng.gen_bool(0.15) {
+                GroupOp::SetRole {
+                    user_id: users.choose(&mut rng).unwrap().to_string(),
+                    role: [Some(Role::Admin), Some(Role::Moderator), None]
+                        .choose(&mut rng)
+                        .copied()
+                        .unwrap(),
+                }
+            } else {
+                op
+            };
+            let event = GroupStateEvent {
+                id: format!("{i:04}"),
+                group_id: "group".to_string(),
+                sender: users.choose(&mut rng).unwrap().to_string(),
+                origin_server: servers[server].to_string(),
+                // clocks of servers disagree
+                ts: i as i64 + rng.gen_range(-10..10),
+                op: serde_json::to_string(&op).unwrap(),
+                signature: "".to_string(),
+            };
+            known[server].push(event.clone());
+            events.push(event);
+        }
+
+        let expected = GroupState::from_events(&events).unwrap();
+        for _ in 0..8 {
+            // any delivery order, with duplicates
+            let mut shuffled = events.clone();
+            shuffled.extend(events.choose_multiple(&mut rng, 20).cloned());
+            shuffled.shuffle(&mut rng);
+            assert_eq!(GroupState::from_events(&shuffled).unwrap(), expected);
+        }
+    }
+}
+
+#[test]
+fn test_group_permissions() {
+    let member = |user_id: &str, server: &str| Member {
+        user_id: user_id.to_string(),
+        server: server.to_string(),
+    };
+    let event = |id: &str, sender: &Member, ts, op: GroupOp| GroupStateEvent {
+        id: id.to_string(),
+        group_id: "group".to_string(),
+        sender: sender.user_id.clone(),
+        origin_server: sender.server.clone(),
+        ts,
+        op: serde_json::to_string(&op).unwrap(),
+        signature: "".to_string(),
+    };
+    let rename = |name: &str| GroupOp::SetName {
+        name: Some(name.to_string()),
+    };
+    let alice = member("alice", "a.example.com");
+    let bob = member("bob", "b.example.com");
+    let mallory = member("bob", "m.example.com");
+
+    let created = event(
+        "group",
+        &alice,
+        1,
+        GroupOp::AddMember {
+            member: bob.clone(),
+        },
+    );
+    // nothing happens before the group is created
+    let early = event("early", &alice, 0, rename("early"));
+    // members may neither rename the group nor promote themselves
+    let rename_member = event("rename_member", &bob, 2, rename("member"));
+    let promote_self = event(
+        "promote_self",
+        &bob,
+        3,
+        GroupOp::SetRole {
+            user_id: bob.user_id.clone(),
+            role: Some(Role::Admin),
+        },
+    );
+    let promote = event(
+        "promote",
+        &alice,
+        4,
+        GroupOp::SetRole {
+            user_id: bob.user_id.clone(),
+            role: Some(Role::Moderator),
+        },
+    );
+    let rename_moderator = event("rename_moderator", &bob, 5, rename("moderator"));
+    // a user of the same name on another server isn't the member
+    let impostor = event("impostor", &mallory, 6, rename("impostor"));
+    let mut events = vec![
+        impostor,
+        rename_moderator,
+        promote,
+        promote_self,
+        rename_member,
+        early,
+        created,
+    ];
+    let state = GroupState::from_events(&events).unwrap();
+    assert_eq!(state.creator, Some(alice));
+    assert_eq!(state.name.get().map(String::as_str), Some("moderator"));
+    assert_eq!(state.role_of("bob"), Some(Role::Moderator));
+
+    // members may leave
+    let leave = event("leave", &bob, 7, state.remove_member(bob.clone()));
+    let mut uncreated = GroupState::from_events(&events[..6]).unwrap();
+    assert_eq!(uncreated, GroupState::default());
+    assert!(!uncreated.apply(&leave).unwrap());
+    events.push(leave);
+    let state = GroupState::from_events(&events).unwrap();
+    assert_eq!(state.role_of("bob"), None);
+}
@@ -0,0 +1,246 @@
+//! Identifiers of servers and users across the federation.
+//!
+//! A user is `uuid@server`, where the server is a [`ServerName`]. Tables store
+//! the uuid and the server apart, [`UserId::from_parts`] joins them again.
+//! Events are identified by time ordered uuids, see [`new_event_id`].
+
+use std::{error::Error, fmt, str::FromStr, sync::Mutex};
+
+use limit_config::GLOBAL_CONFIG;
+use limit_deps::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use uuid::{Builder, Uuid};
+
+/// the event id generated last
+static LAST_EVENT_ID: Lazy<Mutex<u128>> = Lazy::new(|| Mutex::new(0));
+
+/// Why an identifier is invalid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseIdError {
+    ServerName(String),
+    UserId(String),
+}
+
+impl fmt::Display for ParseIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ServerName(name) => write!(f, "invalid server name {name:?}"),
+            Self::UserId(id) => write!(f, "invalid user id {id:?}"),
+        }
+    }
+}
+
+impl Error for ParseIdError {}
+
+/// Name of a server, a host with an optional port as in
+/// [`limit_config::Config::url`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(crate = "limit_deps::serde")]
+#[serde(try_from = "String", into = "String")]
+pub struct ServerName(String);
+
+impl ServerName {
+    /// the name of this server
+    pub fn current() -> Self {
+        Self(GLOBAL_CONFIG.get().unwrap().url.clone())
+    }
+
+    /// whether this is the name of this server, users of other servers are
+    /// reached through the federation
+    pub fn is_local(&self) -> bool {
+        self.0 == GLOBAL_CONFIG.get().unwrap().url
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for ServerName {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = url::Url::parse(&format!("https://{s}"))
+            .map(|url| {
+                url.host_str().is_some()
+                    && url.path() == "/"
+                    && url.query().is_none()
+                    && url.username().is_empty()
+                    && !s.ends_with('/')
+            })
+            .unwrap_or(false);
+        if !valid {
+            return Err(ParseIdError::ServerName(s.to_string()));
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for ServerName {
+    type Error = ParseIdError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ServerName> for String {
+    fn from(value: ServerName) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for ServerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for ServerName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A user of a server, `uuid@server`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(crate = "limit_deps::serde")]
+#[serde(try_from = "String", into = "String")]
+pub struct UserId {
+    uuid: Uuid,
+    server: ServerName,
+}
+
+impl UserId {
+    pub fn new(uuid: Uuid, server: ServerName) -> Self {
+        Self { uuid, server }
+    }
+
+    /// a user of this server
+    pub fn local(uuid: Uuid) -> Self {
+        Self::new(uuid, ServerName::current())
+    }
+
+    /// join a uuid and a server name stored apart
+    pub fn from_parts(uuid: &str, server: &str) -> Result<Self, ParseIdError> {
+        Ok(Self {
+            uuid: Uuid::parse_str(uuid).map_err(|_| ParseIdError::UserId(uuid.to_string()))?,
+            server: server.parse()?,
+        })
+    }
+
+    /// the id of the user on its server, as stored in `USER::ID`
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    pub fn server(&self) -> &ServerName {
+        &self.server
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.server.is_local()
+    }
+}
+
+impl FromStr for UserId {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (uuid, server) = s
+            .split_once('@')
+            .ok_or_else(|| ParseIdError::UserId(s.to_string()))?;
+        Self::from_parts(uuid, server)
+    }
+}
+
+impl TryFrom<String> for UserId {
+    type Error = ParseIdError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<UserId> for String {
+    fn from(value: UserId) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.uuid, self.server)
+    }
+}
+
+/// a version 7 uuid, later than the ones generated before by this process
+/// even within a millisecond or when the clock goes back
+pub fn new_event_id() -> Uuid {
+    let mut last = LAST_EVENT_ID.lock().unwrap();
+    let mut id = Uuid::now_v7().as_u128();
+    if id <= *last {
+        // the random bits count up from the last id
+        id = Builder::from_u128(*last + 1)
+            .with_version(uuid::Version::SortRand)
+            .with_variant(uuid::Variant::RFC4122)
+            .into_uuid()
+            .as_u128();
+    }
+    *last = id;
+    Uuid::from_u128(id)
+}
+
+#[test]
+fn test_server_name() {
+    assert!("limit.example.com".parse::<ServerName>().is_ok());
+    assert!("127.0.0.1:1313".parse::<ServerName>().is_ok());
+    assert!("[::1]:1313".parse::<ServerName>().is_ok());
+    assert!("not a server".parse::<ServerName>().is_err());
+    assert!("limit.example.com/path".parse::<ServerName>().is_err());
+    assert!("user@limit.example.com".parse::<ServerName>().is_err());
+    assert!("".parse::<ServerName>().is_err());
+}
+
+#[test]
+fn test_user_id() {
+    let uuid = Uuid::new_v4();
+    let id = format!("{uuid}@limit.example.com:1313")
+        .parse::<UserId>()
+        .unwrap();
+    assert_eq!(id.uuid(), uuid);
+    assert_eq!(id.server().as_str(), "limit.example.com:1313");
+    assert_eq!(id.to_string(), format!("{uuid}@limit.example.com:1313"));
+    assert_eq!(
+        UserId::from_parts(&uuid.to_string(), "limit.example.com:1313").unwrap(),
+        id
+    );
+
+    let json = serde_json::to_string(&id).unwrap();
+    assert_eq!(json, format!("\"{uuid}@limit.example.com:1313\""));
+    assert_eq!(serde_json::from_str::<UserId>(&json).unwrap(), id);
+    assert!(serde_json::from_str::<UserId>("\"not a user\"").is_err());
+
+    assert!(uuid.to_string().parse::<UserId>().is_err());
+    assert!(format!("{uuid}@").parse::<UserId>().is_err());
+    assert!("alice@limit.example.com".parse::<UserId>().is_err());
+    assert!(
+        format!("{uuid}@a@limit.example.com")
+            .parse::<UserId>()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_new_event_id() {
+    let ids = (0..1000).map(|_| new_event_id()).collect::<Vec<_>>();
+    assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+    assert!(
+        ids.iter()
+            .all(|id| id.get_version() == Some(uuid::Version::SortRand))
+    );
+    // the text form sorts the same
+    let text = ids.iter().map(Uuid::to_string).collect::<Vec<_>>();
+    assert!(text.windows(2).all(|ids| ids[0] < ids[1]));
+}
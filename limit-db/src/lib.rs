@@ -7,14 +7,27 @@ use std::{
     task::{Context, Poll},
 };
 
-use diesel::{r2d2::ConnectionManager, SqliteConnection};
+use diesel::{
+    connection::LoadConnection, r2d2::ConnectionManager, sqlite::Sqlite, Connection,
+    SqliteConnection,
+};
 use limit_deps::{hyper::Body, tonic::body::BoxBody, *};
 use r2d2::Pool;
 use tower::Service;
 
+pub mod account;
+pub mod dag;
+pub mod delivery;
 pub mod event;
+pub mod event_type;
+pub mod federation;
+pub mod group;
+pub mod id;
 pub mod macros;
 pub mod orm;
+pub mod stream;
+pub mod sync;
+pub mod txn;
 pub mod user;
 
 pub mod schema {
@@ -25,6 +38,11 @@ pub mod schema {
 
 pub type RedisClient = redis::Client;
 
+/// a sqlite connection, pooled or not
+pub trait SqliteConn: Connection<Backend = Sqlite> + LoadConnection {}
+
+impl<C: Connection<Backend = Sqlite> + LoadConnection> SqliteConn for C {}
+
 /// add extension DB to `hyper::Request`
 #[derive(Clone)]
 pub struct DBService<Inner> {
@@ -1,11 +1,65 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    CONVERSATION_FRONTIER (CONVERSATION_ID, EVENT_ID) {
+        CONVERSATION_ID -> Text,
+        EVENT_ID -> Text,
+        DEPTH -> BigInt,
+    }
+}
+
+diesel::table! {
+    CONVERSATION_SEQUENCE (CONVERSATION_ID) {
+        CONVERSATION_ID -> Text,
+        SEQ -> BigInt,
+    }
+}
+
+diesel::table! {
+    DEVICE_DELIVERY (USER_ID, DEVICE_ID) {
+        USER_ID -> Text,
+        DEVICE_ID -> Text,
+        RECEIVED_AT -> BigInt,
+        EVENT_ID -> Text,
+        UPDATED_AT -> BigInt,
+    }
+}
+
 diesel::table! {
     EVENT (ID) {
         ID -> Text,
         TS -> BigInt,
         SENDER -> Text,
         EVENT_TYPE -> Text,
+        DEVICE_ID -> Nullable<Text>,
+        DEPTH -> BigInt,
+        RECEIVED_AT -> BigInt,
+        SEQ -> BigInt,
+    }
+}
+
+diesel::table! {
+    EVENT_EDGES (EVENT_ID, PREV_EVENT_ID) {
+        EVENT_ID -> Text,
+        PREV_EVENT_ID -> Text,
+    }
+}
+
+diesel::table! {
+    EVENT_OUTBOX (SEQ) {
+        SEQ -> Integer,
+        EVENT_ID -> Text,
+        RECEIVER_ID -> Text,
+        PAYLOAD -> Text,
+    }
+}
+
+diesel::table! {
+    EVENT_SIGNATURE (EVENT_ID) {
+        EVENT_ID -> Text,
+        SENDER_SIGNATURE -> Nullable<Text>,
+        SERVER_SIGNATURE -> Text,
+        ORIGIN_SERVER -> Text,
     }
 }
 
@@ -17,6 +71,38 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    FEDERATION_OUTBOX (SEQ) {
+        SEQ -> Integer,
+        EVENT_ID -> Text,
+        DESTINATION -> Text,
+        PAYLOAD -> Text,
+        ATTEMPTS -> Integer,
+        NEXT_ATTEMPT_AT -> BigInt,
+        LAST_ERROR -> Nullable<Text>,
+        STATE -> Text,
+    }
+}
+
+diesel::table! {
+    FEDERATION_POLICY_RULE (PATTERN, ACTION) {
+        PATTERN -> Text,
+        ACTION -> Text,
+    }
+}
+
+diesel::table! {
+    GROUP_STATE_EVENT (ID) {
+        ID -> Text,
+        GROUP_ID -> Text,
+        SENDER -> Text,
+        ORIGIN_SERVER -> Text,
+        TS -> BigInt,
+        OP -> Text,
+        SIGNATURE -> Text,
+    }
+}
+
 diesel::table! {
     MESSAGE (EVENT_ID) {
         EVENT_ID -> Text,
@@ -27,6 +113,15 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    PEER_SERVER_KEY (SERVER_NAME) {
+        SERVER_NAME -> Text,
+        PUBLIC_KEY -> Text,
+        FIRST_SEEN -> BigInt,
+        UPDATED_AT -> BigInt,
+    }
+}
+
 diesel::table! {
     USER (ID) {
         ID -> Text,
@@ -42,6 +137,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    USER_MOVED (ID, FROM_SERVER) {
+        ID -> Text,
+        FROM_SERVER -> Text,
+        TO_SERVER -> Text,
+        TS -> BigInt,
+        SIGNATURE -> Text,
+    }
+}
+
 diesel::table! {
     USER_PRIVACY_SETTINGS (ID) {
         ID -> Text,
@@ -65,17 +170,30 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(EVENT_OUTBOX -> EVENT (EVENT_ID));
+diesel::joinable!(EVENT_SIGNATURE -> EVENT (EVENT_ID));
 diesel::joinable!(MESSAGE -> EVENT (EVENT_ID));
 diesel::joinable!(USER_LOGIN_PASSCODE -> USER (ID));
 diesel::joinable!(USER_PRIVACY_SETTINGS -> USER (ID));
 diesel::joinable!(USER_PROFILE -> USER (ID));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    CONVERSATION_FRONTIER,
+    CONVERSATION_SEQUENCE,
+    DEVICE_DELIVERY,
     EVENT,
+    EVENT_EDGES,
+    EVENT_OUTBOX,
+    EVENT_SIGNATURE,
     EVENT_SUBSCRIPTIONS,
+    FEDERATION_OUTBOX,
+    FEDERATION_POLICY_RULE,
+    GROUP_STATE_EVENT,
     MESSAGE,
+    PEER_SERVER_KEY,
     USER,
     USER_LOGIN_PASSCODE,
+    USER_MOVED,
     USER_PRIVACY_SETTINGS,
     USER_PROFILE,
 );
@@ -0,0 +1,118 @@
+//! Durable delivery of events over Redis Streams.
+//!
+//! The events of a channel such as `message:{user id}` are appended to a
+//! stream of the same name, capped at a configured length. Every device reads
+//! a channel in a consumer group of its own, see [`group`], and acknowledges
+//! what it received, so what was delivered but not acknowledged when it went
+//! away is replayed when it reconnects.
+
+use limit_deps::*;
+use redis::{
+    aio::Connection,
+    streams::{StreamMaxlen, StreamRangeReply, StreamReadOptions, StreamReadReply},
+    AsyncCommands, Commands, RedisResult,
+};
+
+/// field of a stream entry holding the json of the event
+pub const EVENT_FIELD: &str = "event";
+
+/// An event read from a stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub channel: String,
+    /// stream entry id
+    pub id: String,
+    /// none when the entry was trimmed before it was acknowledged
+    pub event: Option<String>,
+}
+
+/// the consumer group of a device of a user, devices of different users may
+/// share an id and read the same channels
+pub fn group(user_id: &str, device_id: &str) -> String {
+    format!("{user_id}:{device_id}")
+}
+
+/// append an event to a channel, keeping about `max_len` events of it
+pub fn append(
+    conn: &mut redis::Connection,
+    channel: &str,
+    event: &str,
+    max_len: usize,
+) -> RedisResult<String> {
+    conn.xadd_maxlen(
+        channel,
+        StreamMaxlen::Approx(max_len),
+        "*",
+        &[(EVENT_FIELD, event)],
+    )
+}
+
+/// create the consumer group of a device on a channel, reading the events
+/// appended from now on or with `retained` the events kept of the channel. An
+/// existing group is kept.
+pub async fn create_group(
+    conn: &mut Connection,
+    channel: &str,
+    group: &str,
+    retained: bool,
+) -> RedisResult<()> {
+    let start = if retained { "0" } else { "$" };
+    match conn
+        .xgroup_create_mkstream::<_, _, _, ()>(channel, group, start)
+        .await
+    {
+        Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+        res => res,
+    }
+}
+
+/// the events kept of a channel, oldest first
+pub async fn entries(conn: &mut Connection, channel: &str) -> RedisResult<Vec<Entry>> {
+    let reply: StreamRangeReply = conn.xrange_all(channel).await?;
+    Ok(reply
+        .ids
+        .into_iter()
+        .map(|id| Entry {
+            channel: channel.to_string(),
+            event: id.get(EVENT_FIELD),
+            id: id.id,
+        })
+        .collect())
+}
+
+/// read events of `channels` for the consumer group of a device. `pending`
+/// reads what was delivered before and not acknowledged, otherwise new events
+/// are waited for at most `block` milliseconds.
+pub async fn read(
+    conn: &mut Connection,
+    channels: &[String],
+    group: &str,
+    pending: bool,
+    count: usize,
+    block: usize,
+) -> RedisResult<Vec<Entry>> {
+    let mut options = StreamReadOptions::default()
+        .group(group, group)
+        .count(count);
+    if !pending {
+        options = options.block(block);
+    }
+    let ids = vec![if pending { "0" } else { ">" }; channels.len()];
+    let reply: Option<StreamReadReply> = conn.xread_options(channels, &ids, &options).await?;
+    Ok(reply
+        .into_iter()
+        .flat_map(|reply| reply.keys)
+        .flat_map(|key| {
+            key.ids.into_iter().map(move |id| Entry {
+                channel: key.key.clone(),
+                event: id.get(EVENT_FIELD),
+                id: id.id,
+            })
+        })
+        .collect())
+}
+
+/// acknowledge an entry delivered to a device
+pub async fn ack(conn: &mut Connection, group: &str, entry: &Entry) -> RedisResult<()> {
+    conn.xack(&entry.channel, group, &[&entry.id]).await
+}
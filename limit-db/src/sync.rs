@@ -0,0 +1,445 @@
+//! Paging through the history of the conversations of a user.
+//!
+//! The events of a conversation are paged in the topological order of
+//! [`crate::dag`], depths of different conversations don't compare so events
+//! of several are paged in the order this server received them, see [`Order`].
+//! Pages go forward from the oldest or backward from the newest. A page ends at
+//! the [`Position`] of its last event, handed to clients as an opaque cursor
+//! the next page starts after.
+
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, QueryResult, RunQueryDsl,
+};
+use limit_deps::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    event::{Event, Message},
+    schema::{EVENT, EVENT_SUBSCRIPTIONS, MESSAGE},
+    SqliteConn,
+};
+
+/// Which way a page goes through the history
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub enum Direction {
+    /// oldest first
+    Forward,
+    /// newest first
+    #[default]
+    Backward,
+}
+
+/// What events are paged by, then by id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub enum Order {
+    /// depth in a conversation, see [`Event::depth`]
+    Depth,
+    /// when this server received them, see [`Event::received_at`]
+    ReceivedAt,
+}
+
+/// Where an event is in an [`Order`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct Position {
+    pub order: Order,
+    /// the depth or the time the event was received
+    pub key: i64,
+    pub id: String,
+}
+
+impl Position {
+    pub fn of(event: &Event, order: Order) -> Self {
+        Self {
+            order,
+            key: match order {
+                Order::Depth => event.depth,
+                Order::ReceivedAt => event.received_at,
+            },
+            id: event.id.clone(),
+        }
+    }
+
+    /// the cursor continuing a page in `direction` after this position
+    pub fn encode(&self, direction: Direction) -> String {
+        let direction = match direction {
+            Direction::Forward => 'f',
+            Direction::Backward => 'b',
+        };
+        let order = match self.order {
+            Order::Depth => 'd',
+            Order::ReceivedAt => 'r',
+        };
+        base64::encode_config(
+            format!("{direction}{order}:{}:{}", self.key, self.id),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    /// the direction and position of a cursor, none when it's malformed
+    pub fn decode(cursor: &str) -> Option<(Direction, Self)> {
+        let cursor = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        let cursor = String::from_utf8(cursor).ok()?;
+        let mut parts = cursor.splitn(3, ':');
+        let (direction, order) = match parts.next()? {
+            "fd" => (Direction::Forward, Order::Depth),
+            "fr" => (Direction::Forward, Order::ReceivedAt),
+            "bd" => (Direction::Backward, Order::Depth),
+            "br" => (Direction::Backward, Order::ReceivedAt),
+            _ => return None,
+        };
+        let key = parts.next()?.parse().ok()?;
+        let id = parts.next()?.to_string();
+        Some((direction, Self { order, key, id }))
+    }
+}
+
+/// An end of the range of a page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub enum Bound {
+    Position(Position),
+    /// when this server received an event, see [`Event::received_at`]
+    ReceivedAt(i64),
+}
+
+/// A page of the events visible to a user
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct Query {
+    pub user_id: String,
+    pub direction: Direction,
+    /// the page starts after it, at the first event in `direction` when
+    /// absent
+    pub from: Option<Bound>,
+    /// the page ends at it, at the last event in `direction` when absent
+    pub to: Option<Bound>,
+    /// only the events of a conversation, see [`crate::dag::conversation_id`]
+    pub conversation: Option<String>,
+    /// only the events of these conversations in place of the ones the user
+    /// subscribed to, the part of them a node of a cluster holds
+    pub conversations: Option<Vec<String>>,
+    pub sender: Option<String>,
+    pub event_type: Option<String>,
+    pub limit: i64,
+}
+
+/// Events of a [`Query`] with their body
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct Page {
+    pub events: Vec<(Event, Message)>,
+    /// whether events follow in the range after the last one
+    pub has_more: bool,
+}
+
+impl Query {
+    /// the order of the events of the query, by depth when it's limited to a
+    /// conversation
+    pub fn order(&self) -> Order {
+        match self.conversation {
+            Some(_) => Order::Depth,
+            None => Order::ReceivedAt,
+        }
+    }
+}
+
+/// the position of a stored event in `order`
+pub fn position(
+    conn: &mut impl SqliteConn,
+    id: &str,
+    order: Order,
+) -> QueryResult<Option<Position>> {
+    EVENT::table
+        .find(id)
+        .select((EVENT::DEPTH, EVENT::RECEIVED_AT))
+        .first::<(i64, i64)>(conn)
+        .optional()
+        .map(|keys| {
+            keys.map(|(depth, received_at)| Position {
+                order,
+                key: match order {
+                    Order::Depth => depth,
+                    Order::ReceivedAt => received_at,
+                },
+                id: id.to_string(),
+            })
+        })
+}
+
+/// order a boxed query by `$key` then id in `$forward` direction, and keep the
+/// events after `$from` up to `$to`
+macro_rules! page_by {
+    ($sql:ident, $key:expr, $forward:expr, $from:expr, $to:expr) => {{
+        $sql = if $forward {
+            $sql.order(($key.asc(), EVENT::ID.asc()))
+        } else {
+            $sql.order(($key.desc(), EVENT::ID.desc()))
+        };
+        if let Some(Bound::Position(Position { key, id, .. })) = $from {
+            $sql = if $forward {
+                $sql.filter($key.gt(key).or($key.eq(key).and(EVENT::ID.gt(id))))
+            } else {
+                $sql.filter($key.lt(key).or($key.eq(key).and(EVENT::ID.lt(id))))
+            };
+        }
+        if let Some(Bound::Position(Position { key, id, .. })) = $to {
+            $sql = if $forward {
+                $sql.filter($key.lt(key).or($key.eq(key).and(EVENT::ID.le(id))))
+            } else {
+                $sql.filter($key.gt(key).or($key.eq(key).and(EVENT::ID.ge(id))))
+            };
+        }
+    }};
+}
+
+/// the conversations a user subscribed to
+pub fn subscriptions(conn: &mut impl SqliteConn, user_id: &str) -> QueryResult<Vec<String>> {
+    EVENT_SUBSCRIPTIONS::table
+        .filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(user_id))
+        .filter(EVENT_SUBSCRIPTIONS::CHANNEL_TYPE.eq("message"))
+        .select(EVENT_SUBSCRIPTIONS::SUBSCRIBED_TO)
+        .load(conn)
+}
+
+/// load a page of the events in the conversations the user subscribed to, or
+/// in [`Query::conversations`]. Positions bounding it have to be in the
+/// [`Query::order`].
+pub fn load(conn: &mut impl SqliteConn, query: &Query) -> QueryResult<Page> {
+    let mut sql = EVENT::table.inner_join(MESSAGE::table).into_boxed();
+    sql = match &query.conversations {
+        Some(conversations) => sql.filter(MESSAGE::RECEIVER_ID.eq_any(conversations)),
+        None => sql.filter(
+            MESSAGE::RECEIVER_ID.eq_any(
+                EVENT_SUBSCRIPTIONS::table
+                    .filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(&query.user_id))
+                    .filter(EVENT_SUBSCRIPTIONS::CHANNEL_TYPE.eq("message"))
+                    .select(EVENT_SUBSCRIPTIONS::SUBSCRIBED_TO),
+            ),
+        ),
+    };
+
+    let forward = query.direction == Direction::Forward;
+    match query.order() {
+        Order::Depth => page_by!(sql, EVENT::DEPTH, forward, &query.from, &query.to),
+        Order::ReceivedAt => page_by!(sql, EVENT::RECEIVED_AT, forward, &query.from, &query.to),
+    }
+    match &query.from {
+        Some(Bound::ReceivedAt(ts)) if forward => sql = sql.filter(EVENT::RECEIVED_AT.gt(ts)),
+        Some(Bound::ReceivedAt(ts)) => sql = sql.filter(EVENT::RECEIVED_AT.lt(ts)),
+        _ => {}
+    }
+    match &query.to {
+        Some(Bound::ReceivedAt(ts)) if forward => sql = sql.filter(EVENT::RECEIVED_AT.le(ts)),
+        Some(Bound::ReceivedAt(ts)) => sql = sql.filter(EVENT::RECEIVED_AT.ge(ts)),
+        _ => {}
+    }
+    if let Some(conversation) = &query.conversation {
+        sql = sql.filter(MESSAGE::RECEIVER_ID.eq(conversation));
+    }
+    if let Some(sender) = &query.sender {
+        sql = sql.filter(EVENT::SENDER.eq(sender));
+    }
+    if let Some(event_type) = &query.event_type {
+        sql = sql.filter(EVENT::EVENT_TYPE.eq(event_type));
+    }
+
+    // one more tells whether the range goes on
+    let mut events = sql.limit(query.limit + 1).load::<(Event, Message)>(conn)?;
+    let has_more = events.len() as i64 > query.limit;
+    events.truncate(query.limit as usize);
+    Ok(Page { events, has_more })
+}
+
+#[test]
+fn test_position_cursor() {
+    for order in [Order::Depth, Order::ReceivedAt] {
+        let position = Position {
+            order,
+            key: 42,
+            id: uuid::Uuid::new_v4().to_string(),
+        };
+        for direction in [Direction::Forward, Direction::Backward] {
+            let cursor = position.encode(direction);
+            assert_eq!(
+                Position::decode(&cursor),
+                Some((direction, position.clone()))
+            );
+        }
+    }
+    assert_eq!(Position::decode("not a cursor"), None);
+    assert_eq!(
+        Position::decode(&base64::encode_config("x:1:id", base64::URL_SAFE_NO_PAD)),
+        None
+    );
+}
+
+#[test]
+fn test_load() {
+    use diesel::Connection;
+
+    use crate::event::{SREvent, MESSAGE_EVENT_TYPE};
+
+    let user_id = uuid::Uuid::new_v4().to_string();
+    let other = uuid::Uuid::new_v4().to_string();
+    let mut conn = diesel::sqlite::SqliteConnection::establish("../test.sqlite").unwrap();
+    diesel::insert_into(EVENT_SUBSCRIPTIONS::table)
+        .values(crate::event::EventSubscriptions {
+            user_id: user_id.clone(),
+            sub_to: user_id.clone(),
+            channel_type: "message".to_string(),
+        })
+        .execute(&mut conn)
+        .unwrap();
+    let store = |conn: &mut diesel::sqlite::SqliteConnection,
+                 receiver: &str,
+                 sender: &str,
+                 depth: i64,
+                 received_at: i64| {
+        let id = crate::id::new_event_id().to_string();
+        let mut event = SREvent::from((
+            Event {
+                id: id.clone(),
+                timestamp: 0,
+                sender: sender.to_string(),
+                event_type: MESSAGE_EVENT_TYPE.to_string(),
+                device_id: None,
+                depth,
+                received_at,
+                seq: 0,
+            },
+            Message {
+                event_id: id.clone(),
+                receiver_id: receiver.to_string(),
+                receiver_server: "127.0.0.1:1313".to_string(),
+                text: depth.to_string(),
+                extensions: "{}".to_string(),
+            },
+        ));
+        crate::event::store(conn, &mut event).unwrap();
+        id
+    };
+    let ids = [
+        (&user_id, &user_id),
+        (&user_id, &other),
+        (&other, &other),
+        (&user_id, &other),
+        (&user_id, &user_id),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, (receiver, sender))| store(&mut conn, receiver, sender, i as i64 + 1, i as i64))
+    .collect::<Vec<_>>();
+    let ids_of = |page: &Page| {
+        page.events
+            .iter()
+            .map(|(event, _)| event.id.clone())
+            .collect::<Vec<_>>()
+    };
+    let query = Query {
+        user_id: user_id.clone(),
+        direction: Direction::Forward,
+        limit: 2,
+        ..Default::default()
+    };
+
+    // the event sent to another conversation is left out
+    let page = load(&mut conn, &query).unwrap();
+    assert_eq!(ids_of(&page), [ids[0].clone(), ids[1].clone()]);
+    assert!(page.has_more);
+    let after = Position::of(&page.events[1].0, query.order());
+    let page = load(
+        &mut conn,
+        &Query {
+            from: Some(Bound::Position(after)),
+            ..query.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(ids_of(&page), [ids[3].clone(), ids[4].clone()]);
+    assert!(!page.has_more);
+
+    let page = load(
+        &mut conn,
+        &Query {
+            direction: Direction::Backward,
+            to: Some(Bound::ReceivedAt(1)),
+            limit: 8,
+            ..query.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        ids_of(&page),
+        [ids[4].clone(), ids[3].clone(), ids[1].clone()]
+    );
+    assert!(!page.has_more);
+
+    let page = load(
+        &mut conn,
+        &Query {
+            sender: Some(other.clone()),
+            limit: 8,
+            ..query.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(ids_of(&page), [ids[1].clone(), ids[3].clone()]);
+    let page = load(
+        &mut conn,
+        &Query {
+            conversation: Some(other.clone()),
+            limit: 8,
+            ..query.clone()
+        },
+    )
+    .unwrap();
+    assert!(page.events.is_empty());
+    // the conversations a node holds in place of the subscribed ones
+    let page = load(
+        &mut conn,
+        &Query {
+            conversations: Some(vec![other]),
+            limit: 8,
+            ..query.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(ids_of(&page), [ids[2].clone()]);
+    assert_eq!(
+        subscriptions(&mut conn, &user_id).unwrap(),
+        [user_id.clone()]
+    );
+    assert_eq!(
+        position(&mut conn, &ids[2], Order::Depth).unwrap(),
+        Some(Position {
+            order: Order::Depth,
+            key: 3,
+            id: ids[2].clone()
+        })
+    );
+
+    // received last on a fork of the conversation
+    let late = store(&mut conn, &user_id, &user_id, 2, 10);
+    let page = load(
+        &mut conn,
+        &Query {
+            limit: 8,
+            ..query.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(ids_of(&page).last(), Some(&late));
+    let query = Query {
+        conversation: Some(user_id),
+        limit: 8,
+        ..query
+    };
+    assert_eq!(query.order(), Order::Depth);
+    let page = load(&mut conn, &query).unwrap();
+    assert_eq!(ids_of(&page).len(), 5);
+    assert_eq!(ids_of(&page).last(), Some(&ids[4]));
+    assert!(ids_of(&page)[..3].contains(&late));
+}
@@ -0,0 +1,76 @@
+//! Client transaction ids of sent events.
+//!
+//! A client retrying a send names it with the same transaction id. The first
+//! send claims the id in Redis, pending until the send is settled with the
+//! event it created, or released when it failed, and for a short while at
+//! most. Retries within the window get the id of that event back instead of
+//! creating another one, and are told to come back later while the claim is
+//! pending.
+
+use std::time::Duration;
+
+use limit_deps::*;
+use redis::{aio::Connection, AsyncCommands, RedisResult};
+
+/// the value of a claim whose send isn't settled yet
+const PENDING: &str = "pending";
+/// a claim pending this long was abandoned by a server failing during the
+/// send, a retry claims it again
+pub const PENDING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the key of a transaction id of a device of a user
+pub fn key(user_id: &str, device_id: &str, txn_id: &str) -> String {
+    format!("txn:{user_id}:{device_id}:{txn_id}")
+}
+
+/// What a send found claiming a transaction id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// the id is claimed by this send, pending until settled
+    Claimed,
+    /// a previous send claimed it and isn't settled yet
+    Pending,
+    /// a previous send created the event
+    Sent(String),
+}
+
+/// claim a transaction id, pending for `timeout` at most until settled, see
+/// [`PENDING_TIMEOUT`]
+pub async fn claim(conn: &mut Connection, key: &str, timeout: Duration) -> RedisResult<Claim> {
+    loop {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(PENDING)
+            .arg("NX")
+            .arg("PX")
+            .arg(timeout.as_millis() as u64)
+            .query_async(conn)
+            .await?;
+        if claimed.is_some() {
+            return Ok(Claim::Claimed);
+        }
+        // none when the claim expired meanwhile
+        match conn.get::<_, Option<String>>(key).await? {
+            Some(original) if original == PENDING => return Ok(Claim::Pending),
+            Some(original) => return Ok(Claim::Sent(original)),
+            None => {}
+        }
+    }
+}
+
+/// settle a claimed transaction id with the event its send created, retries
+/// get it back during `window`
+pub async fn settle(
+    conn: &mut Connection,
+    key: &str,
+    event_id: &str,
+    window: Duration,
+) -> RedisResult<()> {
+    conn.pset_ex(key, event_id, window.as_millis() as usize)
+        .await
+}
+
+/// release a transaction id whose send failed, so a retry sends again
+pub async fn release(conn: &mut Connection, key: &str) -> RedisResult<()> {
+    conn.del(key).await
+}
@@ -16,7 +16,7 @@ pub struct User {
     #[diesel(serialize_as = crate::orm::Uuid)]
     pub id: String,
     // TODO: web3 approach
-    /// the RSA public key of the user
+    /// the public key of the user, tagged with its `limit_am::KeyType`
     #[diesel(column_name = "PUBKEY")]
     pub pubkey: String,
     /// the shared key of the user
@@ -3,9 +3,15 @@
 
 // encryption
 pub use aes;
+pub use aes_gcm;
+pub use ed25519_dalek;
 pub use elliptic_curve;
+pub use hmac;
 pub use jsonwebtoken;
 pub use p256;
+pub use scrypt;
+pub use sha2;
+pub use x25519_dalek;
 
 // serialization
 pub use serde;
@@ -0,0 +1,426 @@
+//! Server secrets kept outside of the config file.
+//!
+//! A keystore is either a PEM file, protected only by the file system, or a
+//! container encrypted with AES-256-GCM under a scrypt-derived key. The public
+//! half of the server key pair is kept in the clear in both, so a keystore can
+//! be inspected without the passphrase.
+
+use std::{error::Error, fmt::Display, path::Path, str::FromStr};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use limit_am::KeyType;
+use limit_deps::*;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// environment variable holding the passphrase of an encrypted keystore
+pub const PASSPHRASE_ENV: &str = "LIMIT_KEYSTORE_PASSPHRASE";
+
+const PEM_BEGIN: &str = "-----BEGIN LIMIT KEYSTORE-----";
+const PEM_END: &str = "-----END LIMIT KEYSTORE-----";
+const CONTAINER_VERSION: u32 = 1;
+
+/// The secrets of a server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct ServerSecrets {
+    /// HS256 secret signing user tokens
+    pub jwt_secret: String,
+    /// token of the server administrator, signed with `jwt_secret`
+    pub admin_jwt: String,
+    /// tagged secret key of the server, see [`limit_am::decode_secret`]
+    pub server_secret_key: String,
+    pub server_public_key: String,
+    /// public keys replaced by rotation, newest first
+    pub previous_public_keys: Vec<String>,
+    /// signature by each of `previous_public_keys` over the key which replaced
+    /// it, see [`limit_am::signature::sign_key_rotation`]
+    #[serde(default)]
+    pub previous_key_signatures: Vec<String>,
+    /// UTC timestamp
+    pub created_at: i64,
+    /// UTC timestamp of the last rotation
+    pub rotated_at: i64,
+}
+
+/// A secret to replace with [`ServerSecrets::rotate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// a new server key pair of the given type
+    ServerKey(KeyType),
+    /// a new jwt secret, invalidates every issued token including the admin's
+    JwtSecret,
+    /// a new admin token
+    AdminJwt,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server-key" => Ok(Self::ServerKey(KeyType::P256)),
+            "jwt-secret" => Ok(Self::JwtSecret),
+            "admin-jwt" => Ok(Self::AdminJwt),
+            _ => Err(format!("unknown rotation target {s}")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "limit_deps::serde")]
+struct AdminClaim {
+    sub: String,
+    exp: i64,
+    iat: i64,
+}
+
+fn random_secret() -> String {
+    let mut secret = [0; 32];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    base64::encode(secret)
+}
+
+fn admin_jwt(jwt_secret: &str) -> Result<String, Box<dyn Error>> {
+    let iat = chrono::Utc::now();
+    let exp = iat + chrono::Duration::days(3650);
+    Ok(jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &AdminClaim {
+            sub: "admin".to_string(),
+            exp: exp.timestamp(),
+            iat: iat.timestamp(),
+        },
+        &jsonwebtoken::EncodingKey::from_secret(jwt_secret.as_bytes()),
+    )?)
+}
+
+impl ServerSecrets {
+    pub fn generate(key_type: KeyType) -> Result<Self, Box<dyn Error>> {
+        let (server_secret_key, server_public_key) = limit_am::create_random_secret_of(key_type)?;
+        let jwt_secret = random_secret();
+        let now = chrono::Utc::now().timestamp();
+        Ok(Self {
+            admin_jwt: admin_jwt(&jwt_secret)?,
+            jwt_secret,
+            server_secret_key,
+            server_public_key,
+            previous_public_keys: vec![],
+            previous_key_signatures: vec![],
+            created_at: now,
+            rotated_at: now,
+        })
+    }
+
+    pub fn rotate(&mut self, rotation: Rotation) -> Result<(), Box<dyn Error>> {
+        match rotation {
+            Rotation::ServerKey(key_type) => {
+                let (secret, public) = limit_am::create_random_secret_of(key_type)?;
+                // peers follow the rotation only when the old key vouches for
+                // the new one
+                let signature = limit_am::signature::sign_key_rotation(
+                    &limit_am::decode_secret(&self.server_secret_key)?,
+                    &public,
+                )?;
+                self.server_secret_key = secret;
+                let previous = std::mem::replace(&mut self.server_public_key, public);
+                self.previous_public_keys.insert(0, previous);
+                self.previous_key_signatures.insert(0, signature);
+            }
+            Rotation::JwtSecret => {
+                self.jwt_secret = random_secret();
+                self.admin_jwt = admin_jwt(&self.jwt_secret)?;
+            }
+            Rotation::AdminJwt => self.admin_jwt = admin_jwt(&self.jwt_secret)?,
+        }
+        self.rotated_at = chrono::Utc::now().timestamp();
+        Ok(())
+    }
+}
+
+/// scrypt cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+/// On disk format of a keystore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Pem,
+    Encrypted,
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pem => f.write_str("pem"),
+            Self::Encrypted => f.write_str("encrypted"),
+        }
+    }
+}
+
+/// What can be read from a keystore without its passphrase
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+pub struct PublicInfo {
+    pub server_public_key: String,
+    pub previous_public_keys: Vec<String>,
+    #[serde(default)]
+    pub previous_key_signatures: Vec<String>,
+    pub created_at: i64,
+    pub rotated_at: i64,
+}
+
+impl From<&ServerSecrets> for PublicInfo {
+    fn from(secrets: &ServerSecrets) -> Self {
+        Self {
+            server_public_key: secrets.server_public_key.clone(),
+            previous_public_keys: secrets.previous_public_keys.clone(),
+            previous_key_signatures: secrets.previous_key_signatures.clone(),
+            created_at: secrets.created_at,
+            rotated_at: secrets.rotated_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "limit_deps::serde")]
+struct Container {
+    version: u32,
+    kdf: KdfParams,
+    /// base64
+    salt: String,
+    /// base64 AES-256-GCM nonce
+    nonce: String,
+    /// base64 encrypted [`ServerSecrets`]
+    ciphertext: String,
+    public: PublicInfo,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<[u8; 32], Box<dyn Error>> {
+    let params = scrypt::Params::new(kdf.log_n, kdf.r, kdf.p, 32).map_err(|err| err.to_string())?;
+    let mut key = [0; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .map_err(|err| err.to_string())?;
+    Ok(key)
+}
+
+/// serialize secrets as a PEM keystore
+pub fn to_pem(secrets: &ServerSecrets) -> Result<String, Box<dyn Error>> {
+    let encoded = base64::encode(serde_json::to_vec(secrets)?);
+    let body = encoded
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).expect("base64 is ascii"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(format!("{PEM_BEGIN}\n{body}\n{PEM_END}\n"))
+}
+
+/// serialize secrets as an encrypted keystore
+pub fn to_encrypted(
+    secrets: &ServerSecrets,
+    passphrase: &str,
+    kdf: KdfParams,
+) -> Result<String, Box<dyn Error>> {
+    let mut salt = [0; 16];
+    let mut nonce = [0; 12];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let key = derive_key(passphrase, &salt, kdf)?;
+    let cipher = Aes256Gcm::new_from_slice(&key).map_err(|err| err.to_string())?;
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            serde_json::to_vec(secrets)?.as_slice(),
+        )
+        .map_err(|err| err.to_string())?;
+    Ok(serde_json::to_string_pretty(&Container {
+        version: CONTAINER_VERSION,
+        kdf,
+        salt: base64::encode(salt),
+        nonce: base64::encode(nonce),
+        ciphertext: base64::encode(ciphertext),
+        public: secrets.into(),
+    })?)
+}
+
+pub fn detect_format(content: &str) -> Format {
+    if content.trim_start().starts_with(PEM_BEGIN) {
+        Format::Pem
+    } else {
+        Format::Encrypted
+    }
+}
+
+/// parse a keystore of either format, `passphrase` is only needed for
+/// encrypted ones
+pub fn parse(content: &str, passphrase: Option<&str>) -> Result<ServerSecrets, Box<dyn Error>> {
+    match detect_format(content) {
+        Format::Pem => {
+            let body = content
+                .trim()
+                .strip_prefix(PEM_BEGIN)
+                .and_then(|body| body.strip_suffix(PEM_END))
+                .ok_or("malformed pem keystore")?
+                .split_whitespace()
+                .collect::<String>();
+            Ok(serde_json::from_slice(&base64::decode(body)?)?)
+        }
+        Format::Encrypted => {
+            let container: Container = serde_json::from_str(content)?;
+            if container.version != CONTAINER_VERSION {
+                return Err(format!("unsupported keystore version {}", container.version).into());
+            }
+            let passphrase = passphrase.ok_or("keystore is encrypted, passphrase required")?;
+            let key = derive_key(passphrase, &base64::decode(&container.salt)?, container.kdf)?;
+            let cipher = Aes256Gcm::new_from_slice(&key).map_err(|err| err.to_string())?;
+            let plaintext = cipher
+                .decrypt(
+                    Nonce::from_slice(&base64::decode(&container.nonce)?),
+                    base64::decode(&container.ciphertext)?.as_slice(),
+                )
+                .map_err(|_| "wrong passphrase or corrupted keystore")?;
+            Ok(serde_json::from_slice(&plaintext)?)
+        }
+    }
+}
+
+/// read the public part of a keystore without decrypting it
+pub fn inspect(content: &str) -> Result<(Format, PublicInfo), Box<dyn Error>> {
+    match detect_format(content) {
+        Format::Pem => Ok((Format::Pem, (&parse(content, None)?).into())),
+        Format::Encrypted => {
+            let container: Container = serde_json::from_str(content)?;
+            Ok((Format::Encrypted, container.public))
+        }
+    }
+}
+
+/// load a keystore file, encrypted keystores are opened with `passphrase` or
+/// the [`PASSPHRASE_ENV`] environment variable
+pub fn load(path: &Path, passphrase: Option<&str>) -> Result<ServerSecrets, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let env_passphrase = std::env::var(PASSPHRASE_ENV).ok();
+    parse(&content, passphrase.or(env_passphrase.as_deref()))
+}
+
+/// write a keystore file, encrypted when `passphrase` is set. Only the owner
+/// may read or write it.
+pub fn save(
+    path: &Path,
+    secrets: &ServerSecrets,
+    passphrase: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let content = match passphrase {
+        Some(passphrase) => to_encrypted(secrets, passphrase, KdfParams::default())?,
+        None => to_pem(secrets)?,
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        // the mode only applies to new files
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    std::io::Write::write_all(&mut options.open(path)?, content.as_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn test_keystore_round_trip() {
+    let kdf = KdfParams {
+        log_n: 10,
+        r: 8,
+        p: 1,
+    };
+    let secrets = ServerSecrets::generate(KeyType::P256).unwrap();
+
+    let pem = to_pem(&secrets).unwrap();
+    assert_eq!(detect_format(&pem), Format::Pem);
+    assert_eq!(parse(&pem, None).unwrap(), secrets);
+
+    let encrypted = to_encrypted(&secrets, "correct horse", kdf).unwrap();
+    assert_eq!(detect_format(&encrypted), Format::Encrypted);
+    assert!(!encrypted.contains(&secrets.jwt_secret));
+    assert!(!encrypted.contains(&secrets.server_secret_key));
+    assert_eq!(parse(&encrypted, Some("correct horse")).unwrap(), secrets);
+    assert!(parse(&encrypted, Some("battery staple")).is_err());
+    assert!(parse(&encrypted, None).is_err());
+
+    let (format, info) = inspect(&encrypted).unwrap();
+    assert_eq!(format, Format::Encrypted);
+    assert_eq!(info.server_public_key, secrets.server_public_key);
+
+    let path = std::env::temp_dir().join(format!("{}.keystore", uuid::Uuid::new_v4()));
+    save(&path, &secrets, None).unwrap();
+    assert_eq!(load(&path, None).unwrap(), secrets);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_keystore_rotate() {
+    let mut secrets = ServerSecrets::generate(KeyType::P256).unwrap();
+    let original = secrets.clone();
+
+    secrets
+        .rotate(Rotation::ServerKey(KeyType::X25519))
+        .unwrap();
+    assert_ne!(secrets.server_secret_key, original.server_secret_key);
+    assert_eq!(
+        KeyType::detect(&secrets.server_public_key).0,
+        KeyType::X25519
+    );
+    assert_eq!(
+        secrets.previous_public_keys,
+        vec![original.server_public_key.clone()]
+    );
+    assert!(
+        limit_am::signature::verify_key_rotation(
+            &limit_am::decode_public(&original.server_public_key).unwrap(),
+            &secrets.server_public_key,
+            &secrets.previous_key_signatures[0],
+        )
+        .is_ok()
+    );
+    assert_eq!(secrets.jwt_secret, original.jwt_secret);
+
+    secrets.rotate(Rotation::JwtSecret).unwrap();
+    assert_ne!(secrets.jwt_secret, original.jwt_secret);
+    assert_ne!(secrets.admin_jwt, original.admin_jwt);
+    let admin = jsonwebtoken::decode::<serde_json::Value>(
+        &secrets.admin_jwt,
+        &jsonwebtoken::DecodingKey::from_secret(secrets.jwt_secret.as_bytes()),
+        &jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256),
+    );
+    assert!(admin.is_ok());
+}
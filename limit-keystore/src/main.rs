@@ -0,0 +1,111 @@
+use std::{error::Error, io::Write, path::PathBuf};
+
+use chrono::TimeZone;
+use limit_am::KeyType;
+use limit_deps::*;
+use limit_keystore::{Format, Rotation, ServerSecrets, PASSPHRASE_ENV};
+
+const USAGE: &str = "\
+usage: limit-keystore <command> <path> [options]
+
+commands:
+    create <path> [--plain] [--key-type p256|x25519|ed25519]
+        generate server secrets into a new keystore
+    inspect <path>
+        print the public part of a keystore
+    rotate <path> <server-key|jwt-secret|admin-jwt> [--key-type p256|x25519|ed25519]
+        replace one secret, the format of the keystore is kept
+
+encrypted keystores read the passphrase from the LIMIT_KEYSTORE_PASSPHRASE
+environment variable, or from stdin when it is not set";
+
+/// passphrase from the environment, or read from stdin
+fn passphrase() -> Result<String, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    eprint!("passphrase: ");
+    std::io::stderr().flush()?;
+    let mut passphrase = String::new();
+    std::io::stdin().read_line(&mut passphrase)?;
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']).to_string();
+    if passphrase.is_empty() {
+        return Err("empty passphrase".into());
+    }
+    Ok(passphrase)
+}
+
+fn key_type(args: &[String]) -> Result<KeyType, Box<dyn Error>> {
+    match args.iter().position(|arg| arg == "--key-type") {
+        Some(i) => Ok(args
+            .get(i + 1)
+            .ok_or("--key-type needs a value")?
+            .parse::<KeyType>()?),
+        None => Ok(KeyType::P256),
+    }
+}
+
+fn print_public(path: &PathBuf) -> Result<(), Box<dyn Error>> {
+    let (format, info) = limit_keystore::inspect(&std::fs::read_to_string(path)?)?;
+    println!("format: {format}");
+    println!("server public key: {}", info.server_public_key);
+    for key in info.previous_public_keys {
+        println!("previous public key: {key}");
+    }
+    println!(
+        "created at: {}",
+        chrono::Utc.timestamp_opt(info.created_at, 0).unwrap()
+    );
+    println!(
+        "rotated at: {}",
+        chrono::Utc.timestamp_opt(info.rotated_at, 0).unwrap()
+    );
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (command, path) = match args {
+        [command, path, ..] => (command.as_str(), PathBuf::from(path)),
+        _ => return Err(USAGE.into()),
+    };
+    match command {
+        "create" => {
+            if path.exists() {
+                return Err(format!("{} already exists", path.display()).into());
+            }
+            let secrets = ServerSecrets::generate(key_type(args)?)?;
+            let passphrase = if args.iter().any(|arg| arg == "--plain") {
+                None
+            } else {
+                Some(passphrase()?)
+            };
+            limit_keystore::save(&path, &secrets, passphrase.as_deref())?;
+            print_public(&path)
+        }
+        "inspect" => print_public(&path),
+        "rotate" => {
+            let rotation = match args.get(2).ok_or(USAGE)?.parse::<Rotation>()? {
+                Rotation::ServerKey(_) => Rotation::ServerKey(key_type(args)?),
+                rotation => rotation,
+            };
+            let content = std::fs::read_to_string(&path)?;
+            let passphrase = match limit_keystore::detect_format(&content) {
+                Format::Pem => None,
+                Format::Encrypted => Some(passphrase()?),
+            };
+            let mut secrets = limit_keystore::parse(&content, passphrase.as_deref())?;
+            secrets.rotate(rotation)?;
+            limit_keystore::save(&path, &secrets, passphrase.as_deref())?;
+            print_public(&path)
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Err(e) = run(&args) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
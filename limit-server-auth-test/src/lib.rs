@@ -36,13 +36,15 @@ pub async fn test_do_auth(port: u16) -> anyhow::Result<()> {
     let shared_key = limit_am::key_exchange(
         limit_am::decode_secret(&GLOBAL_CONFIG.get().unwrap().server_secret_key).unwrap(),
         pubkey,
-    );
+    )
+    .unwrap();
     assert_eq!(
         shared_key,
         limit_am::key_exchange(
             limit_am::decode_secret(&user_sec_key).unwrap(),
             limit_am::decode_public(&GLOBAL_CONFIG.get().unwrap().server_public_key).unwrap()
         )
+        .unwrap()
     );
 
     let user = limit_db::user::User {
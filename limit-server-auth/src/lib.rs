@@ -6,7 +6,9 @@ use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use limit_config::GLOBAL_CONFIG;
 use limit_db::{
-    get_db_layer, run_sql,
+    get_db_layer,
+    id::UserId,
+    run_sql,
     schema::{USER, USER_LOGIN_PASSCODE, USER_PRIVACY_SETTINGS},
 };
 use limit_deps::{metrics::increment_counter, *};
@@ -18,7 +20,7 @@ use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct JWTSub {
-    pub id: Uuid,
+    pub id: UserId,
     pub device_id: String,
 }
 
@@ -31,7 +33,7 @@ impl JWTSub {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "limit_deps::serde")]
 pub struct JWTClaim {
-    /// device_id/uuid
+    /// device_id/uuid@server
     pub sub: String,
     /// expiration
     pub exp: i64,
@@ -50,6 +52,27 @@ impl JWTClaim {
             exp: exp.timestamp(),
         }
     }
+
+    /// parse `sub` back into the device id and user id, the user must be of
+    /// this server
+    pub fn parse_sub(&self) -> Result<JWTSub, Status> {
+        let (device_id, id) = self.sub.rsplit_once('/').ok_or_else(|| {
+            tracing::error!("invalid sub");
+            Status::unauthenticated("invalid sub")
+        })?;
+        let id = id.parse::<UserId>().map_err(|e| {
+            tracing::error!("{}", e);
+            Status::unauthenticated("invalid user id")
+        })?;
+        if !id.is_local() {
+            tracing::error!("{} is not a user of this server", id);
+            return Err(Status::unauthenticated("user of another server"));
+        }
+        Ok(JWTSub {
+            id,
+            device_id: device_id.to_string(),
+        })
+    }
 }
 
 pub fn decode_jwt(token: &str) -> Result<JWTClaim, Status> {
@@ -84,7 +107,7 @@ fn test_encode_decode() {
     mock_config();
     let claim = JWTClaim::new(
         JWTSub {
-            id: Uuid::new_v4(),
+            id: UserId::local(Uuid::new_v4()),
             device_id: "test".to_string(),
         },
         Duration::days(1),
@@ -92,6 +115,16 @@ fn test_encode_decode() {
     let token = encode_jwt(claim.clone()).unwrap();
     let decoded = decode_jwt(&token).unwrap();
     assert_eq!(claim, decoded);
+    assert_eq!(
+        decoded.parse_sub().unwrap().id.server().as_str(),
+        GLOBAL_CONFIG.get().unwrap().url
+    );
+
+    let remote = JWTClaim {
+        sub: format!("test/{}@other.example.com", Uuid::new_v4()),
+        ..claim
+    };
+    assert!(remote.parse_sub().is_err());
 }
 
 fn generate_random_passcode() -> String {
@@ -141,6 +174,15 @@ impl tonic_gen::auth::auth_service_server::AuthService for AuthService {
             Status::invalid_argument(e.to_string())
         })?;
 
+        // the auth cache of a user is kept by the node owning it
+        if let Some(node) = limit_server_cluster::route(&req, &id) {
+            return auth_service_client::AuthServiceClient::new(
+                limit_server_cluster::channel(&node).await?,
+            )
+            .request_auth(limit_server_cluster::forward_request(&req))
+            .await;
+        }
+
         let passcode = generate_random_passcode();
 
         m.renew("request_auth_update_cache");
@@ -200,6 +242,13 @@ impl tonic_gen::auth::auth_service_server::AuthService for AuthService {
             tracing::error!("{}", e);
             Status::invalid_argument(e.to_string())
         })?;
+        if let Some(node) = limit_server_cluster::route(&req, &id) {
+            return auth_service_client::AuthServiceClient::new(
+                limit_server_cluster::channel(&node).await?,
+            )
+            .do_auth(limit_server_cluster::forward_request(&req))
+            .await;
+        }
         let passcode = &req.get_ref().validated;
         // get needed user info
         let sql_get_user_info = USER::table
@@ -281,7 +330,7 @@ impl tonic_gen::auth::auth_service_server::AuthService for AuthService {
             tracing::info!("user login success: id: {}", id);
             let jwt = encode_jwt(JWTClaim::new(
                 JWTSub {
-                    id: uuid,
+                    id: UserId::local(uuid),
                     device_id: req.get_ref().device_id.clone(),
                 },
                 expire,
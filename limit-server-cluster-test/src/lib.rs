@@ -0,0 +1,278 @@
+use std::{future::Future, pin::Pin};
+
+use limit_config::GLOBAL_CONFIG;
+use limit_db::{DBLayer, RedisClient};
+use limit_deps::{tonic::transport::Server, *};
+use limit_server_cluster::{
+    cluster_service_client::ClusterServiceClient, cluster_service_server::ClusterServiceServer,
+    ClusterService, HeartbeatRequest, ListNodesRequest, LocateRequest, RegisterRequest,
+};
+use limit_test_utils::{do_with_port, test_service};
+
+/// the test server is the master, slaves are servers on other ports
+pub async fn test_cluster(port: u16, slave_port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_cluster started", module_path!());
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let slave = format!("http://127.0.0.1:{slave_port}/");
+    let user_id = uuid::Uuid::new_v4().to_string();
+    let mut client = ClusterServiceClient::connect(format!("http://127.0.0.1:{port}")).await?;
+    let heartbeat = |streams: &[&str]| HeartbeatRequest {
+        admin_jwt: config.admin_jwt.clone(),
+        url: slave.clone(),
+        streams: streams.iter().map(|user| (user.to_string(), 1)).collect(),
+    };
+    let locate = || LocateRequest {
+        admin_jwt: config.admin_jwt.clone(),
+        user_id: user_id.clone(),
+    };
+
+    let status = client
+        .register(RegisterRequest {
+            admin_jwt: "not admin".to_string(),
+            url: slave.clone(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    let status = client.heartbeat(heartbeat(&[])).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    let res = client
+        .register(RegisterRequest {
+            admin_jwt: config.admin_jwt.clone(),
+            url: slave.clone(),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(
+        res.heartbeat_interval,
+        limit_server_cluster::HEARTBEAT_INTERVAL.as_millis() as u64
+    );
+    // a standalone server owns every key, its ring is made of its slaves
+    assert_eq!(res.members, [slave.clone()]);
+    assert_eq!(limit_server_cluster::owner(&user_id), None);
+    // slaves learn where the streams are from the heartbeats
+    let res = client.heartbeat(heartbeat(&[&user_id])).await?.into_inner();
+    assert_eq!(res.streams[&user_id].urls, [slave.clone()]);
+    let res = client.locate(locate()).await?.into_inner();
+    assert_eq!(res.nodes, [slave.clone()]);
+    assert!(!res.master);
+    let nodes = client
+        .list_nodes(ListNodesRequest {
+            admin_jwt: config.admin_jwt.clone(),
+        })
+        .await?
+        .into_inner()
+        .nodes;
+    let node = nodes.iter().find(|node| node.url == slave).unwrap();
+    assert!(node.healthy);
+    assert_eq!(node.streams, 1);
+
+    // events are appended here and forwarded to the slave, both share redis
+    let redis = RedisClient::open("redis://127.0.0.1:6379/")?;
+    let mut conn = redis.get_async_connection().await?;
+    let channels = [format!("message:{user_id}")];
+    let group = "device";
+    limit_db::stream::create_group(&mut conn, &channels[0], group, false).await?;
+    limit_db::stream::create_group(&mut conn, &channels[0], group, false).await?;
+    limit_server_cluster::publish(&redis, &user_id, "{}".to_string()).await?;
+    let entries = limit_db::stream::read(&mut conn, &channels, group, false, 8, 1000).await?;
+    assert_eq!(entries.len(), 2);
+    assert!(
+        entries
+            .iter()
+            .all(|entry| entry.event.as_deref() == Some("{}"))
+    );
+
+    // what isn't acknowledged is replayed
+    limit_db::stream::ack(&mut conn, group, &entries[0]).await?;
+    let pending = limit_db::stream::read(&mut conn, &channels, group, true, 8, 0).await?;
+    assert_eq!(pending, entries[1..]);
+    limit_db::stream::ack(&mut conn, group, &entries[1]).await?;
+    assert!(
+        limit_db::stream::read(&mut conn, &channels, group, true, 8, 0)
+            .await?
+            .is_empty()
+    );
+
+    // the stream closed
+    client.heartbeat(heartbeat(&[])).await?;
+    assert!(client.locate(locate()).await?.into_inner().nodes.is_empty());
+
+    // streams of the master
+    let stream = limit_server_cluster::open_stream(&user_id);
+    assert!(client.locate(locate()).await?.into_inner().master);
+    drop(stream);
+    assert!(!client.locate(locate()).await?.into_inner().master);
+
+    tracing::info!("\t- test {}::test_cluster finished", module_path!());
+    Ok(())
+}
+
+/// messages in flight are counted in redis, shared by the nodes
+pub async fn test_in_flight() -> anyhow::Result<()> {
+    use limit_server_cluster::inflight::{acquire, count, land};
+
+    tracing::info!("\t- test {}::test_in_flight started", module_path!());
+    let redis = RedisClient::open("redis://127.0.0.1:6379/")?;
+    let sender = uuid::Uuid::new_v4().to_string();
+    let event_id = uuid::Uuid::new_v4().to_string();
+
+    let first = acquire(&redis, &sender, 2).await?.unwrap();
+    let second = acquire(&redis, &sender, 2).await?.unwrap();
+    assert!(acquire(&redis, &sender, 2).await?.is_none());
+    assert_eq!(count(&redis, &sender).await?, 2);
+
+    // a stored message lands once published
+    second.publishing(&event_id).await?;
+    assert_eq!(count(&redis, &sender).await?, 2);
+    land(&redis, &uuid::Uuid::new_v4().to_string()).await?;
+    assert_eq!(count(&redis, &sender).await?, 2);
+    land(&redis, &event_id).await?;
+    assert_eq!(count(&redis, &sender).await?, 1);
+    land(&redis, &event_id).await?;
+    assert_eq!(count(&redis, &sender).await?, 1);
+
+    // a dropped message lands in the background
+    drop(first);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(count(&redis, &sender).await?, 0);
+
+    tracing::info!("\t- test {}::test_in_flight finished", module_path!());
+    Ok(())
+}
+
+/// the history of a conversation owned by the slave is loaded from it and
+/// merged with the history held here
+pub async fn test_history(slave_port: u16) -> anyhow::Result<()> {
+    use diesel::RunQueryDsl;
+    use limit_db::{
+        event::{Event, Message, SREvent, MESSAGE_EVENT_TYPE},
+        run_sql,
+        sync::{Direction, Query},
+        DBPool,
+    };
+    use limit_server_cluster::history;
+
+    tracing::info!("\t- test {}::test_history started", module_path!());
+    let pool = DBPool::new(GLOBAL_CONFIG.get().unwrap());
+    let slave = format!("http://127.0.0.1:{slave_port}/");
+    let user_id = uuid::Uuid::new_v4().to_string();
+    let group_id = uuid::Uuid::new_v4().to_string();
+    run_sql!(
+        pool,
+        |mut conn| {
+            diesel::insert_into(limit_db::schema::EVENT_SUBSCRIPTIONS::table)
+                .values(limit_db::event::EventSubscriptions {
+                    user_id: user_id.clone(),
+                    sub_to: user_id.clone(),
+                    channel_type: "message".to_string(),
+                })
+                .execute(&mut conn)
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    let store = |receiver: &str, received_at: i64| {
+        let id = limit_db::id::new_event_id().to_string();
+        let mut event = SREvent::from((
+            Event {
+                id: id.clone(),
+                timestamp: received_at,
+                sender: user_id.clone(),
+                event_type: MESSAGE_EVENT_TYPE.to_string(),
+                device_id: None,
+                depth: 0,
+                received_at,
+                seq: 0,
+            },
+            Message {
+                event_id: id.clone(),
+                receiver_id: receiver.to_string(),
+                receiver_server: GLOBAL_CONFIG.get().unwrap().url.clone(),
+                text: "history".to_string(),
+                extensions: "{}".to_string(),
+            },
+        ));
+        run_sql!(
+            pool,
+            |mut conn| limit_db::event::store(&mut conn, &mut event),
+            |e| anyhow::anyhow!("{e}")
+        )?;
+        anyhow::Ok(id)
+    };
+    let now = chrono::Utc::now().timestamp_millis();
+    let ids = [
+        store(&user_id, now)?,
+        store(&group_id, now + 1)?,
+        store(&user_id, now + 2)?,
+    ];
+    let ids_of = |page: limit_db::sync::Page| {
+        page.events
+            .into_iter()
+            .map(|(event, _)| event.id)
+            .collect::<Vec<_>>()
+    };
+
+    // the group is owned by the slave, the user didn't subscribe to it here
+    let query = Query {
+        user_id: user_id.clone(),
+        direction: Direction::Forward,
+        limit: 8,
+        ..Default::default()
+    };
+    let remote = [(slave, vec![group_id.clone()])].into_iter().collect();
+    let page = history::load(&pool, &query, &Default::default()).await?;
+    assert_eq!(ids_of(page), [ids[0].clone(), ids[2].clone()]);
+    let page = history::load(&pool, &query, &remote).await?;
+    assert!(!page.has_more);
+    assert_eq!(ids_of(page), ids);
+    let page = history::load(&pool, &Query { limit: 2, ..query }, &remote).await?;
+    assert_eq!(page.events.len(), 2);
+    assert!(page.has_more);
+
+    // resuming after the first event
+    let events = history::load_after(&pool, &user_id, (now, &ids[0]), 8, &remote).await?;
+    assert_eq!(
+        events
+            .into_iter()
+            .map(|event| event.head.id)
+            .collect::<Vec<_>>(),
+        ids[1..]
+    );
+
+    tracing::info!("\t- test {}::test_history finished", module_path!());
+    Ok(())
+}
+
+pub async fn integration_test() {
+    do_with_port(|port| async move {
+        do_with_port(|slave_port| async move {
+            let tasks: Vec<_> = vec![
+                Box::pin(test_cluster(port, slave_port))
+                    as Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>,
+                Box::pin(test_in_flight()),
+                Box::pin(test_history(slave_port)),
+            ];
+
+            let slave_addr = format!("127.0.0.1:{slave_port}").parse().unwrap();
+            let slave = tokio::spawn(
+                Server::builder()
+                    .layer(DBLayer)
+                    .add_service(ClusterServiceServer::new(ClusterService))
+                    .serve(slave_addr),
+            );
+            test_service! {
+                port,
+                Server::builder()
+                    .layer(DBLayer)
+                    .add_service(ClusterServiceServer::new(ClusterService)),
+                tasks
+            };
+            slave.abort();
+        })
+        .await
+        .await
+    })
+    .await
+    .await;
+}
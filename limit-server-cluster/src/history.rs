@@ -0,0 +1,288 @@
+//! History of the conversations of a user across the nodes of a cluster.
+//!
+//! An event is stored on the node owning its conversation, see
+//! [`crate::owner`]. The history of a user subscribed to conversations of other
+//! nodes is loaded from each of them and merged in the order of the query, see
+//! [`load`].
+
+use std::collections::{HashMap, HashSet};
+
+use diesel::{OptionalExtension, QueryDsl, RunQueryDsl};
+use limit_config::GLOBAL_CONFIG;
+use limit_db::{
+    event::{Event, SREvent},
+    run_sql,
+    schema::EVENT,
+    sync::{Bound, Direction, Order, Page, Position, Query},
+    DBPool,
+};
+use limit_deps::*;
+use tonic::Status;
+
+use crate::{LoadEventRequest, LoadHistoryRequest};
+
+/// the conversations owned by other nodes, by node
+pub fn remote(conversations: &[String]) -> HashMap<String, Vec<String>> {
+    let mut nodes: HashMap<String, Vec<String>> = HashMap::new();
+    for conversation in conversations {
+        if let Some(node) = crate::owner(limit_db::dag::conversation_of(conversation)) {
+            nodes.entry(node).or_default().push(conversation.clone());
+        }
+    }
+    nodes
+}
+
+/// a page of `query` stored on this node
+pub(crate) fn load_local(pool: &DBPool, query: &Query) -> Result<Page, Status> {
+    run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::sync::load(&mut conn, query).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// a page of `query` in `conversations` stored on `node`
+async fn load_remote(node: &str, query: &Query, conversations: &[String]) -> Result<Page, Status> {
+    let mut query = query.clone();
+    query.conversations = Some(conversations.to_vec());
+    let query = serde_json::to_string(&query).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?;
+    let page = crate::connect(node)
+        .await?
+        .load_history(LoadHistoryRequest {
+            admin_jwt: GLOBAL_CONFIG.get().unwrap().admin_jwt.clone(),
+            query,
+        })
+        .await?
+        .into_inner()
+        .page;
+    serde_json::from_str(&page).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })
+}
+
+/// the pages of a query on several nodes as one, an event several of them
+/// hold is kept once
+pub fn merge(query: &Query, pages: impl IntoIterator<Item = Page>) -> Page {
+    let order = query.order();
+    let mut seen = HashSet::new();
+    let mut has_more = false;
+    let mut events = vec![];
+    for page in pages {
+        has_more |= page.has_more;
+        events.extend(
+            page.events
+                .into_iter()
+                .filter(|(event, _)| seen.insert(event.id.clone())),
+        );
+    }
+    events.sort_by_key(|(event, _)| {
+        let position = Position::of(event, order);
+        (position.key, position.id)
+    });
+    if query.direction == Direction::Backward {
+        events.reverse();
+    }
+    if events.len() as i64 > query.limit {
+        has_more = true;
+        events.truncate(query.limit as usize);
+    }
+    Page { events, has_more }
+}
+
+/// a page of `query` stored on this node and on the other nodes owning the
+/// conversations of `remote`, see [`self::remote`]. A node failing to answer
+/// fails the page, it would miss events otherwise.
+pub async fn load(
+    pool: &DBPool,
+    query: &Query,
+    remote: &HashMap<String, Vec<String>>,
+) -> Result<Page, Status> {
+    let local = load_local(pool, query)?;
+    if remote.is_empty() {
+        return Ok(local);
+    }
+    let pages = futures::future::try_join_all(
+        remote
+            .iter()
+            .map(|(node, conversations)| load_remote(node, query, conversations)),
+    )
+    .await?;
+    Ok(merge(query, std::iter::once(local).chain(pages)))
+}
+
+/// the stored events of the conversations of a user and the events its
+/// devices sent after the event received at `(received_at, id)`, on this node
+/// and on the other nodes owning the conversations of `remote`, in the order
+/// they were received
+pub async fn load_after(
+    pool: &DBPool,
+    user_id: &str,
+    (received_at, id): (i64, &str),
+    limit: i64,
+    remote: &HashMap<String, Vec<String>>,
+) -> Result<Vec<SREvent>, Status> {
+    let local = run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::event::load_after(&mut conn, user_id, (received_at, id), limit).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    if remote.is_empty() {
+        return Ok(local);
+    }
+    let query = Query {
+        user_id: user_id.to_string(),
+        direction: Direction::Forward,
+        from: Some(Bound::Position(Position {
+            order: Order::ReceivedAt,
+            key: received_at,
+            id: id.to_string(),
+        })),
+        limit,
+        ..Default::default()
+    };
+    let mut events = load(pool, &query, remote)
+        .await?
+        .events
+        .into_iter()
+        .map(SREvent::from)
+        .collect::<Vec<_>>();
+    events.extend(local);
+    let mut seen = HashSet::new();
+    events.retain(|event| seen.insert(event.head.id.clone()));
+    events.sort_by(|a, b| (a.head.received_at, &a.head.id).cmp(&(b.head.received_at, &b.head.id)));
+    events.truncate(limit as usize);
+    Ok(events)
+}
+
+/// an event stored on this node
+pub(crate) fn event_local(pool: &DBPool, id: &str) -> Result<Option<Event>, Status> {
+    run_sql!(
+        pool,
+        |mut conn| {
+            EVENT::table
+                .find(id)
+                .first::<Event>(&mut conn)
+                .optional()
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// an event stored on this node or on one of the nodes of `remote`
+pub async fn event(
+    pool: &DBPool,
+    remote: &HashMap<String, Vec<String>>,
+    id: &str,
+) -> Result<Option<Event>, Status> {
+    if let Some(event) = event_local(pool, id)? {
+        return Ok(Some(event));
+    }
+    for node in remote.keys() {
+        let event = crate::connect(node)
+            .await?
+            .load_event(LoadEventRequest {
+                admin_jwt: GLOBAL_CONFIG.get().unwrap().admin_jwt.clone(),
+                event_id: id.to_string(),
+            })
+            .await?
+            .into_inner()
+            .event;
+        if !event.is_empty() {
+            return serde_json::from_str(&event).map(Some).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            });
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_merge() {
+    use limit_db::event::Message;
+
+    let event = |id: &str, received_at: i64| {
+        (
+            Event {
+                id: id.to_string(),
+                timestamp: 0,
+                sender: String::new(),
+                event_type: String::new(),
+                device_id: None,
+                depth: 1,
+                received_at,
+                seq: 0,
+            },
+            Message {
+                event_id: id.to_string(),
+                receiver_id: String::new(),
+                receiver_server: String::new(),
+                text: String::new(),
+                extensions: String::new(),
+            },
+        )
+    };
+    let ids = |page: &Page| {
+        page.events
+            .iter()
+            .map(|(event, _)| event.id.clone())
+            .collect::<Vec<_>>()
+    };
+    let pages = || {
+        [
+            Page {
+                events: vec![event("a", 1), event("c", 3)],
+                has_more: false,
+            },
+            Page {
+                events: vec![event("b", 2), event("c", 3), event("d", 4)],
+                has_more: false,
+            },
+        ]
+    };
+    let mut query = Query {
+        direction: Direction::Forward,
+        limit: 3,
+        ..Default::default()
+    };
+
+    let page = merge(&query, pages());
+    assert_eq!(ids(&page), ["a", "b", "c"]);
+    assert!(page.has_more);
+
+    query.limit = 4;
+    let page = merge(&query, pages());
+    assert_eq!(ids(&page), ["a", "b", "c", "d"]);
+    assert!(!page.has_more);
+
+    query.direction = Direction::Backward;
+    let page = merge(&query, pages());
+    assert_eq!(ids(&page), ["d", "c", "b", "a"]);
+}
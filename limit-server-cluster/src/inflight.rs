@@ -0,0 +1,145 @@
+//! Messages in flight, accepted by `send_event` and not yet stored and
+//! published.
+//!
+//! A sender with [`limit_config::Config::per_user_message_on_the_fly_limit`]
+//! messages in flight is refused more until some of them land. They are
+//! counted in Redis, the limit holds across the nodes of a cluster. A message
+//! is counted from [`acquire`] until its guard is dropped, or once it's handed
+//! to the outbox with [`InFlight::publishing`], until [`crate::outbox`] on any
+//! node published it. The count of a sender expires [`PUBLICATION_TIMEOUT`]
+//! after its last message, messages of a node crashing with them in flight
+//! land then.
+
+use std::time::Duration;
+
+use limit_db::RedisClient;
+use limit_deps::{metrics::increment_counter, *};
+use once_cell::sync::Lazy;
+use redis::{AsyncCommands, RedisResult, Script};
+
+/// a message waiting for its publication this long isn't counted anymore,
+/// another node may have published it
+pub const PUBLICATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// land a message of the count at KEYS[1], dropping the count with the last
+static LAND: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local count = redis.call('DECR', KEYS[1])
+if count <= 0 then
+    redis.call('DEL', KEYS[1])
+end
+return count
+",
+    )
+});
+
+/// the key of the count of messages of a sender in flight
+fn key(sender: &str) -> String {
+    format!("in_flight:{sender}")
+}
+
+/// the key of the sender of a stored event waiting for its publication
+fn publishing_key(event_id: &str) -> String {
+    format!("in_flight:event:{event_id}")
+}
+
+/// A message of a sender in flight, landed on drop
+pub struct InFlight {
+    redis: RedisClient,
+    /// none once handed to the outbox
+    sender: Option<String>,
+}
+
+impl InFlight {
+    /// keep the message in flight until the event storing it is published,
+    /// before the event is queued so the outbox finds it. An event failing to
+    /// be stored lands with [`land`].
+    pub async fn publishing(mut self, event_id: &str) -> RedisResult<()> {
+        let sender = self.sender.as_deref().unwrap_or_default();
+        let mut conn = self.redis.get_async_connection().await?;
+        conn.set_ex(
+            publishing_key(event_id),
+            sender,
+            PUBLICATION_TIMEOUT.as_secs() as usize,
+        )
+        .await?;
+        self.sender = None;
+        Ok(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let Some(sender) = self.sender.take() else {
+            return;
+        };
+        let redis = self.redis.clone();
+        tokio::spawn(async move {
+            if let Err(e) = land_sender(&redis, &sender).await {
+                tracing::error!("landing a message of {} failed: {}", sender, e);
+            }
+        });
+    }
+}
+
+async fn land_sender(redis: &RedisClient, sender: &str) -> RedisResult<()> {
+    let mut conn = redis.get_async_connection().await?;
+    LAND.key(key(sender))
+        .invoke_async::<_, i64>(&mut conn)
+        .await?;
+    increment_counter!("messages_in_flight_landed");
+    Ok(())
+}
+
+/// count a message of `sender` in flight, none when `limit` of its messages
+/// already are
+pub async fn acquire(
+    redis: &RedisClient,
+    sender: &str,
+    limit: usize,
+) -> RedisResult<Option<InFlight>> {
+    let mut conn = redis.get_async_connection().await?;
+    let (count,): (usize,) = redis::pipe()
+        .atomic()
+        .incr(key(sender), 1)
+        .expire(key(sender), PUBLICATION_TIMEOUT.as_secs() as usize)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    if count > limit {
+        LAND.key(key(sender))
+            .invoke_async::<_, i64>(&mut conn)
+            .await?;
+        return Ok(None);
+    }
+    increment_counter!("messages_in_flight_acquired");
+    Ok(Some(InFlight {
+        redis: redis.clone(),
+        sender: Some(sender.to_string()),
+    }))
+}
+
+/// messages of `sender` in flight
+pub async fn count(redis: &RedisClient, sender: &str) -> RedisResult<usize> {
+    let mut conn = redis.get_async_connection().await?;
+    conn.get::<_, Option<usize>>(key(sender))
+        .await
+        .map(Option::unwrap_or_default)
+}
+
+/// land the message of an event published, or failing to be stored
+pub async fn land(redis: &RedisClient, event_id: &str) -> RedisResult<()> {
+    let mut conn = redis.get_async_connection().await?;
+    let (sender,): (Option<String>,) = redis::pipe()
+        .atomic()
+        .get(publishing_key(event_id))
+        .del(publishing_key(event_id))
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    match sender {
+        Some(sender) => land_sender(redis, &sender).await,
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,505 @@
+//! Nodes of a cluster from [`limit_config::DeployMode`].
+//!
+//! Slaves register with the master and send it a heartbeat every
+//! [`HEARTBEAT_INTERVAL`], with the users they hold a live `receive_events`
+//! stream of, see [`open_stream`]. The master answers with the streams of every
+//! node. An event is published on the node it arrives at and forwarded to the
+//! nodes holding a stream of its receiver, see [`publish`]. A standalone server
+//! is a master without slaves.
+//!
+//! Users and conversations are split across the healthy nodes of a cluster by
+//! a [`ring::HashRing`] the master keeps and hands to the slaves with every
+//! heartbeat. A node receiving a request about a key it doesn't own forwards
+//! it to the owner, see [`route`]. The history of a user subscribed to
+//! conversations of several nodes is loaded from each of them, see [`history`].
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Mutex, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use limit_config::{DeployMode, GLOBAL_CONFIG};
+use limit_db::{get_db_layer, RedisClient};
+use limit_deps::{metrics::increment_counter, *};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::Notify;
+use tonic::{
+    metadata::MetadataValue,
+    transport::{Channel, Endpoint},
+    Request, Response, Status,
+};
+pub use tonic_gen::cluster::*;
+
+pub mod history;
+pub mod inflight;
+pub mod outbox;
+pub mod registry;
+pub mod ring;
+
+use registry::Registry;
+use ring::HashRing;
+
+/// time between two heartbeats of a slave
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// a node missing its heartbeats this long is unhealthy, events aren't
+/// forwarded to it
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(15);
+/// a node missing its heartbeats this long is forgotten
+pub const NODE_EXPIRY: Duration = Duration::from_secs(10 * 60);
+/// metadata of a request forwarded to the node owning it
+pub const FORWARDED_METADATA: &str = "x-limit-forwarded";
+
+/// the nodes registered with this server as the master
+static REGISTRY: Lazy<RwLock<Registry>> = Lazy::new(|| RwLock::new(Registry::default()));
+/// live streams on this node by user id
+static STREAMS: Lazy<Mutex<HashMap<String, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static HEARTBEAT_WORKER: OnceCell<()> = OnceCell::new();
+static HEARTBEAT_NOTIFY: Lazy<Notify> = Lazy::new(Notify::new);
+/// the nodes splitting users and conversations, see [`owner`]
+static RING: Lazy<RwLock<HashRing>> = Lazy::new(|| RwLock::new(HashRing::default()));
+/// on a slave, the nodes holding a stream by user id as of the last heartbeat
+static HOLDERS: Lazy<RwLock<HashMap<String, Vec<String>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+/// channels to other nodes by url, see [`channel`]
+static CHANNELS: Lazy<RwLock<HashMap<String, Channel>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn deploy_mode() -> &'static DeployMode {
+    &GLOBAL_CONFIG.get().unwrap().deploy_mode
+}
+
+/// the url of this node, none on a standalone server and on a master
+/// without one
+fn self_url() -> Option<&'static str> {
+    match deploy_mode() {
+        DeployMode::StandAlone { .. } | DeployMode::Master { url: None, .. } => None,
+        DeployMode::Master { url: Some(url), .. } | DeployMode::Slave { url, .. } => {
+            Some(url.as_str())
+        }
+    }
+}
+
+/// check the admin jwt the nodes of a cluster and its operators share
+pub fn check_admin(admin_jwt: &str) -> Result<(), Status> {
+    if admin_jwt != GLOBAL_CONFIG.get().unwrap().admin_jwt {
+        tracing::error!("invalid admin jwt");
+        return Err(Status::permission_denied("invalid admin jwt"));
+    }
+    Ok(())
+}
+
+fn check_master() -> Result<(), Status> {
+    if let DeployMode::Slave { .. } = deploy_mode() {
+        tracing::error!("cluster call for the master on a slave");
+        return Err(Status::failed_precondition("not the master"));
+    }
+    Ok(())
+}
+
+/// A live `receive_events` stream of a user on this node, closed on drop
+pub struct StreamGuard {
+    user_id: String,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut streams = STREAMS.lock().unwrap();
+        if let Some(count) = streams.get_mut(&self.user_id) {
+            *count -= 1;
+            if *count == 0 {
+                streams.remove(&self.user_id);
+            }
+        }
+        HEARTBEAT_NOTIFY.notify_one();
+    }
+}
+
+/// record a live stream of `user_id` until the guard is dropped, the master
+/// learns of it with the next heartbeat
+pub fn open_stream(user_id: &str) -> StreamGuard {
+    start_heartbeat_worker();
+    *STREAMS
+        .lock()
+        .unwrap()
+        .entry(user_id.to_string())
+        .or_default() += 1;
+    HEARTBEAT_NOTIFY.notify_one();
+    StreamGuard {
+        user_id: user_id.to_string(),
+    }
+}
+
+fn local_streams() -> HashMap<String, u32> {
+    STREAMS.lock().unwrap().clone()
+}
+
+/// a channel to another node, connected once and shared by the requests to
+/// it. It reconnects by itself when the node restarts.
+pub async fn channel(url: &str) -> Result<Channel, Status> {
+    if let Some(channel) = CHANNELS.read().unwrap().get(url) {
+        return Ok(channel.clone());
+    }
+    let channel = Endpoint::from_shared(url.to_string())
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::invalid_argument(e.to_string())
+        })?
+        .connect()
+        .await
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::unavailable(e.to_string())
+        })?;
+    CHANNELS
+        .write()
+        .unwrap()
+        .insert(url.to_string(), channel.clone());
+    Ok(channel)
+}
+
+async fn connect(
+    url: &str,
+) -> Result<cluster_service_client::ClusterServiceClient<Channel>, Status> {
+    Ok(cluster_service_client::ClusterServiceClient::new(
+        channel(url).await?,
+    ))
+}
+
+/// on the master, this node and the healthy registered nodes
+fn members() -> BTreeSet<String> {
+    let now = now_millis();
+    REGISTRY
+        .read()
+        .unwrap()
+        .nodes()
+        .filter(|(_, node)| node.is_healthy(now))
+        .map(|(url, _)| url.clone())
+        .chain(self_url().map(str::to_string))
+        .collect()
+}
+
+/// rebalance the ring when nodes joined or left
+fn set_members(members: BTreeSet<String>) {
+    if RING.read().unwrap().nodes() == &members {
+        return;
+    }
+    tracing::info!("hash ring rebalanced to {:?}", members);
+    increment_counter!("cluster_ring_rebalanced");
+    RING.write().unwrap().set_nodes(&members);
+}
+
+/// the other node owning `key`, a user or conversation id. None when this
+/// node owns it, on a standalone server and on a slave which didn't reach the
+/// master yet.
+pub fn owner(key: &str) -> Option<String> {
+    match deploy_mode() {
+        DeployMode::StandAlone { .. } => return None,
+        DeployMode::Slave { .. } => start_heartbeat_worker(),
+        DeployMode::Master { .. } => set_members(members()),
+    }
+    RING.read()
+        .unwrap()
+        .get(key)
+        .filter(|owner| Some(*owner) != self_url())
+        .map(str::to_string)
+}
+
+/// the other node a request about `key` goes to, a request forwarded already
+/// is served where it arrives
+pub fn route<T>(req: &Request<T>, key: &str) -> Option<String> {
+    if req.metadata().contains_key(FORWARDED_METADATA) {
+        return None;
+    }
+    owner(key)
+}
+
+/// a copy of `req` with its metadata for the node owning it
+pub fn forward_request<T: Clone>(req: &Request<T>) -> Request<T> {
+    let mut forwarded = Request::new(req.get_ref().clone());
+    *forwarded.metadata_mut() = req.metadata().clone();
+    forwarded
+        .metadata_mut()
+        .insert(FORWARDED_METADATA, MetadataValue::from_static("1"));
+    increment_counter!("cluster_request_forwarded");
+    forwarded
+}
+
+/// register with the master, then send heartbeats until the master forgets
+/// this node
+async fn heartbeat(url: &str, master: &str) -> Result<(), Status> {
+    let admin_jwt = GLOBAL_CONFIG.get().unwrap().admin_jwt.clone();
+    let mut client = connect(master).await?;
+    let res = client
+        .register(RegisterRequest {
+            admin_jwt: admin_jwt.clone(),
+            url: url.to_string(),
+        })
+        .await?
+        .into_inner();
+    tracing::info!("registered with the master {}", master);
+    set_members(res.members.into_iter().collect());
+    loop {
+        let res = client
+            .heartbeat(HeartbeatRequest {
+                admin_jwt: admin_jwt.clone(),
+                url: url.to_string(),
+                streams: local_streams(),
+            })
+            .await?
+            .into_inner();
+        set_members(res.members.into_iter().collect());
+        *HOLDERS.write().unwrap() = res
+            .streams
+            .into_iter()
+            .map(|(user_id, nodes)| (user_id, nodes.urls))
+            .collect();
+        tokio::select! {
+            _ = HEARTBEAT_NOTIFY.notified() => {}
+            _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => {}
+        }
+    }
+}
+
+/// on a slave, register with the master and send heartbeats in the
+/// background. Only the first call starts a worker.
+pub fn start_heartbeat_worker() {
+    let DeployMode::Slave { url, master, .. } = deploy_mode() else {
+        return;
+    };
+    HEARTBEAT_WORKER.get_or_init(|| {
+        tokio::spawn(async move {
+            loop {
+                if let Err(status) = heartbeat(url.as_str(), master.as_str()).await {
+                    increment_counter!("cluster_heartbeat_failed");
+                    tracing::warn!("heartbeat to {} failed: {}", master, status);
+                }
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            }
+        });
+    });
+}
+
+fn publish_local(redis: &RedisClient, receiver_id: &str, event: &str) -> Result<(), Status> {
+    let mut redis = redis.get_connection().map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?;
+    limit_db::stream::append(
+        &mut redis,
+        &format!("message:{receiver_id}"),
+        event,
+        GLOBAL_CONFIG.get().unwrap().event_stream_max_len,
+    )
+    .map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?;
+    Ok(())
+}
+
+/// urls of the other nodes holding a stream of `user_id`, on a slave as of
+/// the last heartbeat
+fn holders(user_id: &str) -> Vec<String> {
+    match deploy_mode() {
+        DeployMode::Slave { url, .. } => HOLDERS
+            .read()
+            .unwrap()
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .filter(|node| *node != url.as_str())
+            .cloned()
+            .collect(),
+        _ => REGISTRY.read().unwrap().locate(user_id, now_millis()),
+    }
+}
+
+/// append a `limit_db::event::SREvent` in json to the channel of its
+/// receiver on this node and on the other nodes holding a stream of it. Nodes
+/// failing to receive it are skipped.
+pub async fn publish(redis: &RedisClient, receiver_id: &str, event: String) -> Result<(), Status> {
+    start_heartbeat_worker();
+    publish_local(redis, receiver_id, &event)?;
+    for node in holders(receiver_id) {
+        let res = match connect(&node).await {
+            Ok(mut client) => client
+                .forward(ForwardRequest {
+                    admin_jwt: GLOBAL_CONFIG.get().unwrap().admin_jwt.clone(),
+                    receiver_id: receiver_id.to_string(),
+                    event: event.clone(),
+                })
+                .await
+                .map(|_| ()),
+            Err(status) => Err(status),
+        };
+        match res {
+            Ok(()) => increment_counter!("cluster_forwarded", "node" => node.clone()),
+            Err(status) => {
+                increment_counter!("cluster_forward_failed", "node" => node.clone());
+                tracing::warn!("forwarding to {} failed: {}", node, status);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+// require db
+pub struct ClusterService;
+
+#[tonic::async_trait]
+impl cluster_service_server::ClusterService for ClusterService {
+    async fn register(
+        &self,
+        req: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+        check_master()?;
+        if let DeployMode::Master { slaves, .. } = deploy_mode() {
+            if !slaves.is_empty() && !slaves.iter().any(|slave| slave.as_str() == req.url) {
+                tracing::error!("{} is not a slave of this master", req.url);
+                return Err(Status::permission_denied("unknown slave"));
+            }
+        }
+        url::Url::parse(&req.url).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::invalid_argument(e.to_string())
+        })?;
+        REGISTRY.write().unwrap().register(&req.url, now_millis());
+        increment_counter!("cluster_node_registered", "node" => req.url.clone());
+        tracing::info!("node {} registered", req.url);
+        let members = members();
+        set_members(members.clone());
+        Ok(Response::new(RegisterResponse {
+            heartbeat_interval: HEARTBEAT_INTERVAL.as_millis() as u64,
+            members: members.into_iter().collect(),
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        req: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+        check_master()?;
+        let now = now_millis();
+        {
+            let mut registry = REGISTRY.write().unwrap();
+            registry.prune(now);
+            if !registry.heartbeat(&req.url, req.streams, now) {
+                tracing::warn!("heartbeat of the unregistered node {}", req.url);
+                return Err(Status::failed_precondition("not registered"));
+            }
+        }
+        let members = members();
+        set_members(members.clone());
+        let mut streams = REGISTRY.read().unwrap().holders(now);
+        if let Some(url) = self_url() {
+            for user_id in local_streams().into_keys() {
+                streams.entry(user_id).or_default().push(url.to_string());
+            }
+        }
+        Ok(Response::new(HeartbeatResponse {
+            members: members.into_iter().collect(),
+            streams: streams
+                .into_iter()
+                .map(|(user_id, urls)| (user_id, NodeUrls { urls }))
+                .collect(),
+        }))
+    }
+
+    async fn locate(
+        &self,
+        req: Request<LocateRequest>,
+    ) -> Result<Response<LocateResponse>, Status> {
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+        check_master()?;
+        Ok(Response::new(LocateResponse {
+            nodes: REGISTRY.read().unwrap().locate(&req.user_id, now_millis()),
+            master: STREAMS.lock().unwrap().contains_key(&req.user_id),
+        }))
+    }
+
+    async fn list_nodes(
+        &self,
+        req: Request<ListNodesRequest>,
+    ) -> Result<Response<ListNodesResponse>, Status> {
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+        check_master()?;
+        let now = now_millis();
+        let nodes = REGISTRY
+            .read()
+            .unwrap()
+            .nodes()
+            .map(|(url, node)| Node {
+                url: url.clone(),
+                healthy: node.is_healthy(now),
+                last_heartbeat: node.last_heartbeat,
+                streams: node.streams.values().sum(),
+            })
+            .collect();
+        Ok(Response::new(ListNodesResponse { nodes }))
+    }
+
+    async fn forward(
+        &self,
+        req: Request<ForwardRequest>,
+    ) -> Result<Response<ForwardResponse>, Status> {
+        let (_, redis, _) = get_db_layer!(req);
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+        publish_local(&redis, &req.receiver_id, &req.event)?;
+        Ok(Response::new(ForwardResponse {}))
+    }
+
+    async fn load_history(
+        &self,
+        req: Request<LoadHistoryRequest>,
+    ) -> Result<Response<LoadHistoryResponse>, Status> {
+        let (_, _, pool) = get_db_layer!(req);
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+        let query = serde_json::from_str::<limit_db::sync::Query>(&req.query).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::invalid_argument(e.to_string())
+        })?;
+        // the subscriptions of the user are kept on its own node
+        if query.conversations.is_none() {
+            tracing::error!("history of {} without conversations", query.user_id);
+            return Err(Status::invalid_argument("no conversations"));
+        }
+        let page = history::load_local(&pool, &query)?;
+        let page = serde_json::to_string(&page).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+        Ok(Response::new(LoadHistoryResponse { page }))
+    }
+
+    async fn load_event(
+        &self,
+        req: Request<LoadEventRequest>,
+    ) -> Result<Response<LoadEventResponse>, Status> {
+        let (_, _, pool) = get_db_layer!(req);
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+        let event = history::event_local(&pool, &req.event_id)?
+            .map(|event| serde_json::to_string(&event))
+            .transpose()
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })?
+            .unwrap_or_default();
+        Ok(Response::new(LoadEventResponse { event }))
+    }
+}
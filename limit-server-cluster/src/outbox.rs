@@ -0,0 +1,91 @@
+//! Publication of stored events.
+//!
+//! Events are queued in the transaction storing them, see
+//! [`limit_db::event::store`], and published from the queue in order once
+//! committed. An event is published at least once, a crash between its
+//! publication and its removal from the queue publishes it again.
+
+use std::time::Duration;
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use limit_db::{event::OutboxEvent, run_sql, schema::EVENT_OUTBOX, DBPool, RedisClient};
+use limit_deps::{metrics::increment_counter, *};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::Notify;
+use tonic::Status;
+
+/// events loaded in one pass of the publisher
+const PASS_SIZE: i64 = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+static PUBLISHER: OnceCell<()> = OnceCell::new();
+static PUBLISHER_NOTIFY: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// start publishing queued events in the background, only the first call
+/// starts a publisher
+pub fn start_publisher(pool: DBPool, redis: RedisClient) {
+    PUBLISHER.get_or_init(|| {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = publish_outbox(&pool, &redis).await {
+                    tracing::error!("outbox publication failed: {}", e);
+                }
+                tokio::select! {
+                    _ = PUBLISHER_NOTIFY.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        });
+    });
+}
+
+/// publish committed events now instead of at the next poll
+pub fn wake_publisher() {
+    PUBLISHER_NOTIFY.notify_one();
+}
+
+/// one pass over the queued events, stops at the first one failing to publish
+/// to keep the order. Returns the number of events published.
+pub async fn publish_outbox(pool: &DBPool, redis: &RedisClient) -> Result<usize, Status> {
+    let queued = run_sql!(
+        pool,
+        |mut conn| {
+            EVENT_OUTBOX::table
+                .order(EVENT_OUTBOX::SEQ.asc())
+                .limit(PASS_SIZE)
+                .load::<OutboxEvent>(&mut conn)
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    let count = queued.len();
+    for event in queued {
+        crate::publish(redis, &event.receiver_id, event.payload).await?;
+        run_sql!(
+            pool,
+            |mut conn| {
+                diesel::delete(EVENT_OUTBOX::table.find(event.seq))
+                    .execute(&mut conn)
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?;
+        if let Err(e) = crate::inflight::land(redis, &event.event_id).await {
+            tracing::error!("landing the message of {} failed: {}", event.event_id, e);
+        }
+        increment_counter!("event_outbox_published");
+    }
+    Ok(count)
+}
@@ -0,0 +1,131 @@
+//! The nodes of a cluster as the master sees them.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{NODE_EXPIRY, NODE_TIMEOUT};
+
+/// A registered node
+#[derive(Debug, Clone, Default)]
+pub struct NodeState {
+    /// unix timestamp in milliseconds
+    pub last_heartbeat: i64,
+    /// live streams by user id
+    pub streams: HashMap<String, u32>,
+}
+
+impl NodeState {
+    /// whether the node sent a heartbeat within [`NODE_TIMEOUT`]
+    pub fn is_healthy(&self, now: i64) -> bool {
+        now - self.last_heartbeat < NODE_TIMEOUT.as_millis() as i64
+    }
+}
+
+/// Registered nodes by url
+#[derive(Debug, Default)]
+pub struct Registry {
+    nodes: BTreeMap<String, NodeState>,
+}
+
+impl Registry {
+    /// add a node, a node registering again starts without streams
+    pub fn register(&mut self, url: &str, now: i64) {
+        self.nodes.insert(
+            url.to_string(),
+            NodeState {
+                last_heartbeat: now,
+                streams: HashMap::new(),
+            },
+        );
+    }
+
+    /// replace the streams of a node, returns false if it isn't registered
+    pub fn heartbeat(&mut self, url: &str, streams: HashMap<String, u32>, now: i64) -> bool {
+        match self.nodes.get_mut(url) {
+            Some(node) => {
+                node.last_heartbeat = now;
+                node.streams = streams;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// healthy nodes holding a stream of `user_id`
+    pub fn locate(&self, user_id: &str, now: i64) -> Vec<String> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.is_healthy(now) && node.streams.get(user_id) > Some(&0))
+            .map(|(url, _)| url.clone())
+            .collect()
+    }
+
+    /// healthy nodes holding a stream by user id
+    pub fn holders(&self, now: i64) -> HashMap<String, Vec<String>> {
+        let mut holders = HashMap::<String, Vec<String>>::new();
+        for (url, node) in self.nodes.iter().filter(|(_, node)| node.is_healthy(now)) {
+            for (user_id, _) in node.streams.iter().filter(|(_, count)| **count > 0) {
+                holders
+                    .entry(user_id.clone())
+                    .or_default()
+                    .push(url.clone());
+            }
+        }
+        holders
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (&String, &NodeState)> {
+        self.nodes.iter()
+    }
+
+    /// forget the nodes silent for [`NODE_EXPIRY`]
+    pub fn prune(&mut self, now: i64) {
+        self.nodes
+            .retain(|_, node| now - node.last_heartbeat < NODE_EXPIRY.as_millis() as i64);
+    }
+}
+
+#[test]
+fn test_registry() {
+    let interval = crate::HEARTBEAT_INTERVAL.as_millis() as i64;
+    let mut registry = Registry::default();
+    let streams = |users: &[&str]| {
+        users
+            .iter()
+            .map(|user| (user.to_string(), 1))
+            .collect::<HashMap<_, _>>()
+    };
+
+    assert!(!registry.heartbeat("http://a", streams(&["alice"]), 0));
+    registry.register("http://a", 0);
+    registry.register("http://b", 0);
+    assert!(registry.heartbeat("http://a", streams(&["alice", "bob"]), 0));
+    assert!(registry.heartbeat("http://b", streams(&["bob"]), 0));
+    assert_eq!(registry.locate("alice", 0), ["http://a"]);
+    assert_eq!(registry.locate("bob", 0), ["http://a", "http://b"]);
+    assert!(registry.locate("carol", 0).is_empty());
+    let holders = registry.holders(0);
+    assert_eq!(holders.len(), 2);
+    assert_eq!(holders["bob"], ["http://a", "http://b"]);
+
+    // b misses its heartbeats
+    let later = NODE_TIMEOUT.as_millis() as i64;
+    assert!(registry.heartbeat("http://a", streams(&["alice", "bob"]), later - interval));
+    assert_eq!(registry.locate("bob", later), ["http://a"]);
+    assert_eq!(registry.holders(later)["bob"], ["http://a"]);
+    let healthy = registry
+        .nodes()
+        .map(|(url, node)| (url.as_str(), node.is_healthy(later)))
+        .collect::<Vec<_>>();
+    assert_eq!(healthy, [("http://a", true), ("http://b", false)]);
+
+    // a stream closed
+    assert!(registry.heartbeat("http://a", streams(&["alice"]), later));
+    assert!(registry.locate("bob", later).is_empty());
+
+    registry.prune(NODE_EXPIRY.as_millis() as i64);
+    assert_eq!(registry.nodes().count(), 1);
+
+    // registering again drops the streams
+    registry.register("http://a", later);
+    assert!(registry.locate("alice", later).is_empty());
+}
@@ -0,0 +1,142 @@
+//! A consistent-hash ring splitting users and conversations across nodes.
+//!
+//! Every node is placed on the ring at [`VIRTUAL_NODES`] points, a key belongs
+//! to the node of the first point at or after its hash. A node joining or
+//! leaving only moves the keys between its points and the ones before them.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use limit_deps::*;
+use sha2::{Digest, Sha256};
+
+/// points of a node on the ring
+pub const VIRTUAL_NODES: usize = 160;
+
+fn hash(data: &str) -> u64 {
+    let digest = Sha256::digest(data.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Nodes by url on a consistent-hash ring
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    points: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new(VIRTUAL_NODES)
+    }
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize) -> Self {
+        Self {
+            virtual_nodes,
+            points: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    fn points_of(&self, node: &str) -> impl Iterator<Item = u64> + '_ {
+        let node = node.to_string();
+        (0..self.virtual_nodes).map(move |i| hash(&format!("{node}#{i}")))
+    }
+
+    pub fn add(&mut self, node: &str) {
+        if !self.nodes.insert(node.to_string()) {
+            return;
+        }
+        let points = self.points_of(node).collect::<Vec<_>>();
+        for point in points {
+            self.points.insert(point, node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        if !self.nodes.remove(node) {
+            return;
+        }
+        let points = self.points_of(node).collect::<Vec<_>>();
+        for point in points {
+            if self.points.get(&point).map(String::as_str) == Some(node) {
+                self.points.remove(&point);
+            }
+        }
+    }
+
+    /// add and remove nodes to end up with `nodes`
+    pub fn set_nodes(&mut self, nodes: &BTreeSet<String>) {
+        let removed = self.nodes.difference(nodes).cloned().collect::<Vec<_>>();
+        for node in removed {
+            self.remove(&node);
+        }
+        for node in nodes {
+            self.add(node);
+        }
+    }
+
+    pub fn nodes(&self) -> &BTreeSet<String> {
+        &self.nodes
+    }
+
+    /// the node owning `key`, none on an empty ring
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.points
+            .range(hash(key)..)
+            .chain(self.points.iter())
+            .next()
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+#[test]
+fn test_hash_ring() {
+    let keys = (0..10000).map(|i| format!("user{i}")).collect::<Vec<_>>();
+    let owners = |ring: &HashRing| {
+        keys.iter()
+            .map(|key| ring.get(key).unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let count = |owners: &[String], node: &str| owners.iter().filter(|o| *o == node).count();
+
+    let mut ring = HashRing::default();
+    assert_eq!(ring.get("user0"), None);
+    let nodes = ["http://a", "http://b", "http://c"]
+        .map(str::to_string)
+        .into_iter()
+        .collect();
+    ring.set_nodes(&nodes);
+    let before = owners(&ring);
+    for node in &nodes {
+        let share = count(&before, node);
+        assert!((2500..4200).contains(&share), "{node} owns {share}");
+    }
+
+    // a node joining only takes keys
+    ring.add("http://d");
+    let after = owners(&ring);
+    let moved = before.iter().zip(&after).filter(|(b, a)| b != a).count();
+    assert_eq!(moved, count(&after, "http://d"));
+    assert!((1800..3200).contains(&moved), "{moved} moved");
+
+    // a node leaving only gives its keys away
+    ring.remove("http://b");
+    let left = owners(&ring);
+    for (a, l) in after.iter().zip(&left) {
+        if a != "http://b" {
+            assert_eq!(a, l);
+        } else {
+            assert_ne!(l, "http://b");
+        }
+    }
+
+    // the order nodes join in doesn't matter
+    let mut other = HashRing::default();
+    for node in ["http://d", "http://c", "http://a"] {
+        other.add(node);
+    }
+    assert_eq!(owners(&other), left);
+}
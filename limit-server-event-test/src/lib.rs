@@ -1,25 +1,38 @@
 use std::{future::Future, pin::Pin};
 
-use diesel::RunQueryDsl;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
 use futures::StreamExt;
+use limit_am::sender_key::{
+    SenderKeyDistributionMessage, SenderKeyMessage, SenderKeyReceiver, SenderKeyState,
+};
 use limit_config::GLOBAL_CONFIG;
 use limit_db::{
-    event::EventSubscriptions,
+    event::{
+        EventSignature, EventSubscriptions, MESSAGE_EVENT_TYPE, SENDER_KEY_DISTRIBUTION_EVENT_TYPE,
+    },
     run_sql,
-    schema::{EVENT_SUBSCRIPTIONS, USER, USER_LOGIN_PASSCODE, USER_PRIVACY_SETTINGS},
+    schema::{
+        EVENT, EVENT_EDGES, EVENT_SIGNATURE, EVENT_SUBSCRIPTIONS, USER, USER_LOGIN_PASSCODE,
+        USER_PRIVACY_SETTINGS,
+    },
     DBLayer, DBPool,
 };
-use limit_deps::{tonic::transport::Server, *};
+use limit_deps::{
+    tonic::{transport::Server, Request},
+    *,
+};
 use limit_server_auth::{
-    auth_service_client::AuthServiceClient, auth_service_server::AuthServiceServer, AuthService,
-    DoAuthRequest,
+    auth_service_client::AuthServiceClient, auth_service_server::AuthServiceServer, Auth,
+    AuthService, DoAuthRequest,
 };
 use limit_server_event::{
     event_service_client::EventServiceClient, event_service_server::EventServiceServer, Detail,
     Event, EventService, From, Message, ReceiveEventsRequest, SendEventRequest, SynchronizeRequest,
-    To,
+    To, EVENT_TYPE_EXTENSION, RESUME_METADATA, RETRY_AFTER_METADATA, SEQUENCE_EXTENSION,
+    SIGNATURE_METADATA, SYNC_CURSOR_METADATA, SYNC_DIRECTION_METADATA, SYNC_HAS_MORE_METADATA,
+    SYNC_SENDER_METADATA, TXN_ID_METADATA,
 };
-use limit_test_utils::{do_with_port, test_service, test_tasks};
+use limit_test_utils::{do_with_port, test_service, test_tasks, wait_for_port};
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -27,180 +40,30 @@ pub fn add(left: usize, right: usize) -> usize {
 
 pub async fn test_send_message(port: u16) -> anyhow::Result<()> {
     tracing::info!("\t- test {}::test_send_message started", module_path!());
-    let _device_id = uuid::Uuid::new_v4().to_string();
-    let (user_sec_key, user_pubkey) = limit_am::create_random_secret().unwrap();
-    let pubkey = limit_am::decode_public(&user_pubkey).unwrap();
-    let shared_key = limit_am::key_exchange(
-        limit_am::decode_secret(&GLOBAL_CONFIG.get().unwrap().server_secret_key).unwrap(),
-        pubkey,
-    );
+    let Fixture {
+        addr,
+        user_secret,
+        shared_key,
+        id1,
+        id2,
+        auth1,
+        auth2,
+        ..
+    } = Fixture::new(port).await?;
+    // the users derive the key the server shares with them
     assert_eq!(
         shared_key,
         limit_am::key_exchange(
-            limit_am::decode_secret(&user_sec_key).unwrap(),
+            limit_am::decode_secret(&user_secret).unwrap(),
             limit_am::decode_public(&GLOBAL_CONFIG.get().unwrap().server_public_key).unwrap()
         )
+        .unwrap()
     );
-    tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
-    let device_id = uuid::Uuid::new_v4().to_string();
-    // set up user1
-    let id = uuid::Uuid::new_v4().to_string();
-    let id1 = id.clone();
-    {
-        let user = limit_db::user::User {
-            id: id.clone(),
-            pubkey: user_pubkey.clone(),
-            sharedkey: shared_key.clone(),
-        };
-
-        let user_privacy_settings = limit_db::user::PrivacySettings {
-            id: id.clone(),
-            avatar: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            last_seen: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            groups: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            forwards: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            jwt_expiration: limit_db::orm::Duration::from(std::time::Duration::from_secs(114514)).0,
-        };
-        let user_login_passcode = limit_db::user::UserLoginPasscode {
-            id: id.clone(),
-            passcode: "123456".to_string(),
-        };
-
-        // set up db
-        let config = || {
-            let pool = DBPool::new(limit_config::GLOBAL_CONFIG.get().unwrap());
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER::table)
-                    .values(user)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_PRIVACY_SETTINGS::table)
-                    .values(user_privacy_settings)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_LOGIN_PASSCODE::table)
-                    .values(user_login_passcode)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            Ok::<(), ()>(())
-        };
-        config().unwrap();
-    }
-    // set up user2
-    let id = uuid::Uuid::new_v4().to_string();
-    let id2 = id.clone();
-    {
-        let user = limit_db::user::User {
-            id: id.clone(),
-            pubkey: user_pubkey.clone(),
-            sharedkey: shared_key.clone(),
-        };
-
-        let user_privacy_settings = limit_db::user::PrivacySettings {
-            id: id.clone(),
-            avatar: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            last_seen: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            groups: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            forwards: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            jwt_expiration: limit_db::orm::Duration::from(std::time::Duration::from_secs(114514)).0,
-        };
-        let user_login_passcode = limit_db::user::UserLoginPasscode {
-            id: id.clone(),
-            passcode: "123456".to_string(),
-        };
-
-        // set up db
-        let config = || {
-            let pool = DBPool::new(limit_config::GLOBAL_CONFIG.get().unwrap());
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER::table)
-                    .values(user)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_PRIVACY_SETTINGS::table)
-                    .values(user_privacy_settings)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_LOGIN_PASSCODE::table)
-                    .values(user_login_passcode)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(EVENT_SUBSCRIPTIONS::table)
-                    .values(EventSubscriptions {
-                        user_id: id.clone(),
-                        sub_to: id.clone(),
-                        channel_type: "message".to_string(),
-                    })
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            Ok::<(), ()>(())
-        };
-        config().unwrap();
-    }
-    let addr = format!("http://127.0.0.1:{port}");
-    let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
-    let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
-    let res = auth_client
-        .do_auth(DoAuthRequest {
-            id: id1.clone(),
-            device_id: device_id.clone(),
-            validated: passcode.clone(),
-        })
-        .await;
-    let auth1 = res.unwrap();
-    let res = auth_client
-        .do_auth(DoAuthRequest {
-            id: id2.clone(),
-            device_id: device_id.clone(),
-            validated: passcode,
-        })
-        .await;
-    let auth2 = res.unwrap();
     let mut client1 = EventServiceClient::connect(addr.clone()).await?;
     let mut client2 = EventServiceClient::connect(addr.clone()).await?;
     let receive = client2
         .receive_events(ReceiveEventsRequest {
-            token: Some(auth2.get_ref().clone()),
+            token: Some(auth2.clone()),
         })
         .await;
     assert!(receive.is_ok());
@@ -208,7 +71,7 @@ pub async fn test_send_message(port: u16) -> anyhow::Result<()> {
     tracing::info!("client {:?} sending message", id1);
     let send_message = client1
         .send_event(SendEventRequest {
-            token: Some(auth1.get_ref().clone()),
+            token: Some(auth1.clone()),
             event: Some(Event {
                 event_id: "".to_string(),
                 ts: chrono::Utc::now().timestamp_millis() as u64,
@@ -247,175 +110,14 @@ pub async fn test_send_message(port: u16) -> anyhow::Result<()> {
 pub async fn test_sync_message(port: u16) -> anyhow::Result<()> {
     tracing::info!("\t- test {}::test_sync_message started", module_path!());
     let send_ts = chrono::Utc::now().timestamp_millis();
-    let _device_id = uuid::Uuid::new_v4().to_string();
-    let (user_sec_key, user_pubkey) = limit_am::create_random_secret().unwrap();
-    let pubkey = limit_am::decode_public(&user_pubkey).unwrap();
-    let shared_key = limit_am::key_exchange(
-        limit_am::decode_secret(&GLOBAL_CONFIG.get().unwrap().server_secret_key).unwrap(),
-        pubkey,
-    );
-    assert_eq!(
-        shared_key,
-        limit_am::key_exchange(
-            limit_am::decode_secret(&user_sec_key).unwrap(),
-            limit_am::decode_public(&GLOBAL_CONFIG.get().unwrap().server_public_key).unwrap()
-        )
-    );
-    tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
-    let device_id = uuid::Uuid::new_v4().to_string();
-    // set up user1
-    let id = uuid::Uuid::new_v4().to_string();
-    let id1 = id.clone();
-    {
-        let user = limit_db::user::User {
-            id: id.clone(),
-            pubkey: user_pubkey.clone(),
-            sharedkey: shared_key.clone(),
-        };
-
-        let user_privacy_settings = limit_db::user::PrivacySettings {
-            id: id.clone(),
-            avatar: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            last_seen: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            groups: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            forwards: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            jwt_expiration: limit_db::orm::Duration::from(std::time::Duration::from_secs(114514)).0,
-        };
-        let user_login_passcode = limit_db::user::UserLoginPasscode {
-            id: id.clone(),
-            passcode: "123456".to_string(),
-        };
-
-        // set up db
-        let config = || {
-            let pool = DBPool::new(limit_config::GLOBAL_CONFIG.get().unwrap());
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER::table)
-                    .values(user)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_PRIVACY_SETTINGS::table)
-                    .values(user_privacy_settings)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_LOGIN_PASSCODE::table)
-                    .values(user_login_passcode)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            Ok::<(), ()>(())
-        };
-        config().unwrap();
-    }
-    // set up user2
-    let id = uuid::Uuid::new_v4().to_string();
-    let id2 = id.clone();
-    {
-        let user = limit_db::user::User {
-            id: id.clone(),
-            pubkey: user_pubkey.clone(),
-            sharedkey: shared_key.clone(),
-        };
-
-        let user_privacy_settings = limit_db::user::PrivacySettings {
-            id: id.clone(),
-            avatar: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            last_seen: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            groups: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            forwards: limit_db::orm::Visibility::from(limit_db::user::Visibility::Private).0,
-            jwt_expiration: limit_db::orm::Duration::from(std::time::Duration::from_secs(114514)).0,
-        };
-        let user_login_passcode = limit_db::user::UserLoginPasscode {
-            id: id.clone(),
-            passcode: "123456".to_string(),
-        };
-
-        // set up db
-        let config = || {
-            let pool = DBPool::new(limit_config::GLOBAL_CONFIG.get().unwrap());
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER::table)
-                    .values(user)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_PRIVACY_SETTINGS::table)
-                    .values(user_privacy_settings)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(USER_LOGIN_PASSCODE::table)
-                    .values(user_login_passcode)
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            run_sql!(
-                pool,
-                |mut con| diesel::insert_into(EVENT_SUBSCRIPTIONS::table)
-                    .values(EventSubscriptions {
-                        user_id: id.clone(),
-                        sub_to: id.clone(),
-                        channel_type: "message".to_string(),
-                    })
-                    .execute(&mut con)
-                    .unwrap(),
-                |e| {
-                    tracing::error!("Error: {}", e);
-                }
-            );
-            Ok::<(), ()>(())
-        };
-        config().unwrap();
-    }
-    let addr = format!("http://127.0.0.1:{port}");
-    let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
-    let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
-    let res = auth_client
-        .do_auth(DoAuthRequest {
-            id: id1.clone(),
-            device_id: device_id.clone(),
-            validated: passcode.clone(),
-        })
-        .await;
-    let auth1 = res.unwrap();
-    let res = auth_client
-        .do_auth(DoAuthRequest {
-            id: id2.clone(),
-            device_id: device_id.clone(),
-            validated: passcode,
-        })
-        .await;
-    let auth2 = res.unwrap();
+    let Fixture {
+        addr,
+        id1,
+        id2,
+        auth1,
+        auth2,
+        ..
+    } = Fixture::new(port).await?;
     let mut client1 = EventServiceClient::connect(addr.clone()).await?;
     let mut client2 = EventServiceClient::connect(addr).await?;
 
@@ -423,7 +125,7 @@ pub async fn test_sync_message(port: u16) -> anyhow::Result<()> {
     tracing::info!("client {:?} sending message", id1);
     let send_message = client1
         .send_event(SendEventRequest {
-            token: Some(auth1.get_ref().clone()),
+            token: Some(auth1.clone()),
             event: Some(Event {
                 event_id: "".to_string(),
                 ts: chrono::Utc::now().timestamp_millis() as u64,
@@ -441,7 +143,7 @@ pub async fn test_sync_message(port: u16) -> anyhow::Result<()> {
     tracing::info!("client {:?} message sent", id1);
     let send_message = client1
         .send_event(SendEventRequest {
-            token: Some(auth1.get_ref().clone()),
+            token: Some(auth1.clone()),
             event: Some(Event {
                 event_id: "".to_string(),
                 ts: chrono::Utc::now().timestamp_millis() as u64,
@@ -459,7 +161,7 @@ pub async fn test_sync_message(port: u16) -> anyhow::Result<()> {
     tracing::info!("client {:?} message sent", id1);
     let send_message = client1
         .send_event(SendEventRequest {
-            token: Some(auth1.get_ref().clone()),
+            token: Some(auth1.clone()),
             event: Some(Event {
                 event_id: "".to_string(),
                 ts: chrono::Utc::now().timestamp_millis() as u64,
@@ -476,26 +178,665 @@ pub async fn test_sync_message(port: u16) -> anyhow::Result<()> {
     assert!(send_message.is_ok());
     tracing::info!("client {:?} message sent", id1);
     tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-    let sync = client2
-        .synchronize(SynchronizeRequest {
-            token: Some(auth2.get_ref().clone()),
-            count: 50,
-            from: Some(From::TsFrom(send_ts as u64)),
-            to: Some(To::TsTo(chrono::Utc::now().timestamp_millis() as u64)),
-        })
-        .await;
+    let mut req = Request::new(SynchronizeRequest {
+        token: Some(auth2.clone()),
+        count: 50,
+        from: Some(From::TsFrom(send_ts as u64)),
+        to: Some(To::TsTo(chrono::Utc::now().timestamp_millis() as u64)),
+    });
+    req.metadata_mut()
+        .insert(SYNC_DIRECTION_METADATA, "forward".parse().unwrap());
+    let sync = client2.synchronize(req).await;
     assert!(sync.is_ok());
     let sync = sync.unwrap();
     assert!(sync.get_ref().events.len() >= 3);
     tracing::info!("sync messages: {:#?}", sync.get_ref().events);
+    // each message follows the previous one in the conversation DAG
+    let texts = sync
+        .get_ref()
+        .events
+        .iter()
+        .filter_map(|event| match &event.detail {
+            Some(Detail::Message(message)) => Some(message.text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(texts, ["1", "2", "3"]);
+
+    // newest first by default, a page at a time
+    let page = |cursor: Option<&str>| {
+        let mut req = Request::new(SynchronizeRequest {
+            token: Some(auth2.clone()),
+            count: 2,
+            from: None,
+            to: Some(To::TsTo(send_ts as u64)),
+        });
+        req.metadata_mut()
+            .insert(SYNC_SENDER_METADATA, id1.parse().unwrap());
+        if let Some(cursor) = cursor {
+            req.metadata_mut()
+                .insert(SYNC_CURSOR_METADATA, cursor.parse().unwrap());
+        }
+        req
+    };
+    let text = |event: &Event| match &event.detail {
+        Some(Detail::Message(message)) => message.text.clone(),
+        _ => panic!("not a message"),
+    };
+    let res = client2.synchronize(page(None)).await?;
+    assert_eq!(res.metadata().get(SYNC_HAS_MORE_METADATA).unwrap(), "true");
+    let cursor = res.metadata().get(SYNC_CURSOR_METADATA).unwrap().to_str()?;
+    assert_eq!(
+        res.get_ref().events.iter().map(text).collect::<Vec<_>>(),
+        ["3", "2"]
+    );
+    let res = client2.synchronize(page(Some(cursor))).await?;
+    assert_eq!(res.metadata().get(SYNC_HAS_MORE_METADATA).unwrap(), "false");
+    assert_eq!(
+        res.get_ref().events.iter().map(text).collect::<Vec<_>>(),
+        ["1"]
+    );
+
+    let status = client2
+        .synchronize(SynchronizeRequest {
+            token: Some(auth2.clone()),
+            count: 8193,
+            from: None,
+            to: None,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
 
     tracing::info!("\t- test {}::test_sync_message finished", module_path!());
     Ok(())
 }
 
+/// Two users of the server at `port` logged in on a device each, the second
+/// subscribed to its own messages, see [`setup_user`]
+pub struct Fixture {
+    pub addr: String,
+    /// the secret key of both users
+    pub user_secret: String,
+    pub shared_key: String,
+    pub id1: String,
+    pub id2: String,
+    /// the device of the first user
+    pub device_id: String,
+    pub auth1: Auth,
+    pub auth2: Auth,
+}
+
+impl Fixture {
+    /// set up the users once the server accepts connections
+    pub async fn new(port: u16) -> anyhow::Result<Self> {
+        let config = GLOBAL_CONFIG.get().unwrap();
+        let (user_secret, user_pubkey) = limit_am::create_random_secret().unwrap();
+        let shared_key = limit_am::key_exchange(
+            limit_am::decode_secret(&config.server_secret_key).unwrap(),
+            limit_am::decode_public(&user_pubkey).unwrap(),
+        )
+        .unwrap();
+        wait_for_port(port).await;
+        let id1 = setup_user(&user_pubkey, &shared_key, false);
+        let id2 = setup_user(&user_pubkey, &shared_key, true);
+
+        let addr = format!("http://127.0.0.1:{port}");
+        let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
+        let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
+        let device_id = uuid::Uuid::new_v4().to_string();
+        let auth1 = auth_client
+            .do_auth(DoAuthRequest {
+                id: id1.clone(),
+                device_id: device_id.clone(),
+                validated: passcode.clone(),
+            })
+            .await?
+            .into_inner();
+        let auth2 = auth_client
+            .do_auth(DoAuthRequest {
+                id: id2.clone(),
+                device_id: uuid::Uuid::new_v4().to_string(),
+                validated: passcode,
+            })
+            .await?
+            .into_inner();
+        Ok(Self {
+            addr,
+            user_secret,
+            shared_key,
+            id1,
+            id2,
+            device_id,
+            auth1,
+            auth2,
+        })
+    }
+}
+
+/// insert a user with login passcode `123456`, subscribed to its own message
+/// channel when `subscribe` is set
+pub fn setup_user(pubkey: &str, sharedkey: &str, subscribe: bool) -> String {
+    let id = uuid::Uuid::new_v4().to_string();
+    let config = || {
+        let pool = DBPool::new(limit_config::GLOBAL_CONFIG.get().unwrap());
+        run_sql!(
+            pool,
+            |mut con| {
+                diesel::insert_into(USER::table)
+                    .values(limit_db::user::User {
+                        id: id.clone(),
+                        pubkey: pubkey.to_string(),
+                        sharedkey: sharedkey.to_string(),
+                    })
+                    .execute(&mut con)
+                    .unwrap();
+                diesel::insert_into(USER_PRIVACY_SETTINGS::table)
+                    .values(limit_db::user::PrivacySettings {
+                        id: id.clone(),
+                        avatar: limit_db::orm::Visibility::from(
+                            limit_db::user::Visibility::Private,
+                        )
+                        .0,
+                        last_seen: limit_db::orm::Visibility::from(
+                            limit_db::user::Visibility::Private,
+                        )
+                        .0,
+                        groups: limit_db::orm::Visibility::from(
+                            limit_db::user::Visibility::Private,
+                        )
+                        .0,
+                        forwards: limit_db::orm::Visibility::from(
+                            limit_db::user::Visibility::Private,
+                        )
+                        .0,
+                        jwt_expiration: limit_db::orm::Duration::from(
+                            std::time::Duration::from_secs(114514),
+                        )
+                        .0,
+                    })
+                    .execute(&mut con)
+                    .unwrap();
+                diesel::insert_into(USER_LOGIN_PASSCODE::table)
+                    .values(limit_db::user::UserLoginPasscode {
+                        id: id.clone(),
+                        passcode: "123456".to_string(),
+                    })
+                    .execute(&mut con)
+                    .unwrap();
+                if subscribe {
+                    diesel::insert_into(EVENT_SUBSCRIPTIONS::table)
+                        .values(EventSubscriptions {
+                            user_id: id.clone(),
+                            sub_to: id.clone(),
+                            channel_type: "message".to_string(),
+                        })
+                        .execute(&mut con)
+                        .unwrap();
+                }
+            },
+            |e| {
+                tracing::error!("Error: {}", e);
+            }
+        );
+        Ok::<(), ()>(())
+    };
+    config().unwrap();
+    id
+}
+
+pub async fn test_sender_key_distribution(port: u16) -> anyhow::Result<()> {
+    tracing::info!(
+        "\t- test {}::test_sender_key_distribution started",
+        module_path!()
+    );
+    let Fixture {
+        addr,
+        id1,
+        id2,
+        auth1,
+        auth2,
+        ..
+    } = Fixture::new(port).await?;
+    let mut client1 = EventServiceClient::connect(addr.clone()).await?;
+    let mut client2 = EventServiceClient::connect(addr).await?;
+    let mut receive = client2
+        .receive_events(ReceiveEventsRequest {
+            token: Some(auth2.clone()),
+        })
+        .await?;
+
+    // the identity keys of the members the distribution is sealed with
+    let (identity1, identity2) = (
+        limit_am::SecretKey::random(limit_am::KeyType::X25519),
+        limit_am::SecretKey::random(limit_am::KeyType::X25519),
+    );
+    let mut sender_key = SenderKeyState::new();
+    let distribution = sender_key
+        .distribution_message()
+        .seal(&identity1, &identity2.public_key())
+        .unwrap();
+    let group_message = sender_key.encrypt("hello group").unwrap().encode().unwrap();
+    let send = |text: String, event_type: Option<&str>| SendEventRequest {
+        token: Some(auth1.clone()),
+        event: Some(Event {
+            event_id: "".to_string(),
+            ts: chrono::Utc::now().timestamp_millis() as u64,
+            sender: id1.clone(),
+            detail: Some(Detail::Message(Message {
+                receiver_id: id2.clone(),
+                receiver_server: GLOBAL_CONFIG.get().unwrap().url.clone(),
+                text,
+                extensions: event_type
+                    .map(|t| (EVENT_TYPE_EXTENSION.to_string(), t.to_string()))
+                    .into_iter()
+                    .collect(),
+            })),
+        }),
+    };
+    client1
+        .send_event(send(distribution, Some(SENDER_KEY_DISTRIBUTION_EVENT_TYPE)))
+        .await?;
+    client1.send_event(send(group_message, None)).await?;
+
+    let Some(Detail::Message(distribution)) = receive.get_mut().next().await.unwrap()?.detail else {
+        anyhow::bail!("no message detail");
+    };
+    assert_eq!(
+        distribution.extensions.get(EVENT_TYPE_EXTENSION).unwrap(),
+        SENDER_KEY_DISTRIBUTION_EVENT_TYPE
+    );
+    // the server relays the chain key sealed
+    assert!(SenderKeyDistributionMessage::decode(&distribution.text).is_err());
+    let mut receiver = SenderKeyReceiver::from_distribution(
+        &SenderKeyDistributionMessage::open(
+            &distribution.text,
+            &identity2,
+            &identity1.public_key(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let Some(Detail::Message(group_message)) = receive.get_mut().next().await.unwrap()?.detail else {
+        anyhow::bail!("no message detail");
+    };
+    let decrypted = receiver
+        .decrypt(&SenderKeyMessage::decode(&group_message.text).unwrap())
+        .unwrap();
+    assert_eq!(decrypted, "hello group");
+
+    // types this server doesn't know are delivered as they were sent
+    client1
+        .send_event(send("custom".to_string(), Some("org.example.custom")))
+        .await?;
+    let Some(Detail::Message(custom)) = receive.get_mut().next().await.unwrap()?.detail else {
+        anyhow::bail!("no message detail");
+    };
+    assert_eq!(custom.text, "custom");
+    assert_eq!(
+        custom.extensions.get(EVENT_TYPE_EXTENSION).unwrap(),
+        "org.example.custom"
+    );
+
+    tracing::info!(
+        "\t- test {}::test_sender_key_distribution finished",
+        module_path!()
+    );
+    Ok(())
+}
+
+pub async fn test_signed_event(port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_signed_event started", module_path!());
+    let Fixture {
+        addr,
+        user_secret,
+        id1,
+        id2,
+        auth1,
+        ..
+    } = Fixture::new(port).await?;
+    let mut client = EventServiceClient::connect(addr).await?;
+
+    let ts = chrono::Utc::now().timestamp_millis();
+    let receiver_server = GLOBAL_CONFIG.get().unwrap().url.clone();
+    let canonical = limit_db::event::SREvent::from((
+        limit_db::event::Event {
+            id: "".to_string(),
+            timestamp: ts,
+            sender: id1.clone(),
+            event_type: MESSAGE_EVENT_TYPE.to_string(),
+            device_id: None,
+            depth: 0,
+            received_at: 0,
+            seq: 0,
+        },
+        limit_db::event::Message {
+            event_id: "".to_string(),
+            receiver_id: id2.clone(),
+            receiver_server: receiver_server.clone(),
+            text: "signed".to_string(),
+            extensions: "{}".to_string(),
+        },
+    ))
+    .canonical()
+    .unwrap();
+    let send = |signature: String| {
+        let mut req = Request::new(SendEventRequest {
+            token: Some(auth1.clone()),
+            event: Some(Event {
+                event_id: "".to_string(),
+                ts: ts as u64,
+                sender: id1.clone(),
+                detail: Some(Detail::Message(Message {
+                    receiver_id: id2.clone(),
+                    receiver_server: receiver_server.clone(),
+                    text: "signed".to_string(),
+                    extensions: Default::default(),
+                })),
+            }),
+        });
+        req.metadata_mut()
+            .insert(SIGNATURE_METADATA, signature.parse().unwrap());
+        req
+    };
+
+    let signature = limit_am::signature::sign_event(
+        &limit_am::decode_secret(&user_secret).unwrap(),
+        &canonical,
+    )
+    .unwrap();
+    let event_id = client
+        .send_event(send(signature.clone()))
+        .await?
+        .into_inner()
+        .event_id;
+
+    // signed by another key
+    let (forger_secret, _) = limit_am::create_random_secret().unwrap();
+    let forged = limit_am::signature::sign_event(
+        &limit_am::decode_secret(&forger_secret).unwrap(),
+        &canonical,
+    )
+    .unwrap();
+    let status = client.send_event(send(forged)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+    let pool = DBPool::new(GLOBAL_CONFIG.get().unwrap());
+    let stored = run_sql!(
+        pool,
+        |mut con| {
+            EVENT_SIGNATURE::table
+                .filter(EVENT_SIGNATURE::EVENT_ID.eq(&event_id))
+                .first::<EventSignature>(&mut con)
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    assert_eq!(stored.sender_signature.as_deref(), Some(signature.as_str()));
+    assert_eq!(stored.origin_server, receiver_server);
+    // the countersignature covers the place of the event in the DAG
+    let (depth, prev_events) = run_sql!(
+        pool,
+        |mut con| {
+            let depth = EVENT::table
+                .filter(EVENT::ID.eq(&event_id))
+                .select(EVENT::DEPTH)
+                .first::<i64>(&mut con)?;
+            let prev_events = EVENT_EDGES::table
+                .filter(EVENT_EDGES::EVENT_ID.eq(&event_id))
+                .select(EVENT_EDGES::PREV_EVENT_ID)
+                .order(EVENT_EDGES::PREV_EVENT_ID.asc())
+                .load::<String>(&mut con)?;
+            Ok::<_, diesel::result::Error>((depth, prev_events))
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    assert!(depth > 0);
+    assert!(
+        limit_am::signature::verify_countersignature(
+            &limit_am::decode_public(&GLOBAL_CONFIG.get().unwrap().server_public_key).unwrap(),
+            &limit_am::signature::Countersigned {
+                event_id: &event_id,
+                canonical: &canonical,
+                sender_signature: Some(&signature),
+                depth,
+                prev_events: &prev_events,
+            },
+            &stored.server_signature
+        )
+        .is_ok()
+    );
+
+    tracing::info!("\t- test {}::test_signed_event finished", module_path!());
+    Ok(())
+}
+
+pub async fn test_sender_from_token(port: u16) -> anyhow::Result<()> {
+    tracing::info!(
+        "\t- test {}::test_sender_from_token started",
+        module_path!()
+    );
+    let Fixture {
+        addr,
+        id1,
+        id2,
+        device_id,
+        auth1,
+        auth2,
+        ..
+    } = Fixture::new(port).await?;
+    let mut client1 = EventServiceClient::connect(addr.clone()).await?;
+    let mut client2 = EventServiceClient::connect(addr).await?;
+    let mut receive = client2
+        .receive_events(ReceiveEventsRequest {
+            token: Some(auth2.clone()),
+        })
+        .await?;
+
+    let send = |sender: String| SendEventRequest {
+        token: Some(auth1.clone()),
+        event: Some(Event {
+            event_id: "".to_string(),
+            ts: chrono::Utc::now().timestamp_millis() as u64,
+            sender,
+            detail: Some(Detail::Message(Message {
+                receiver_id: id2.clone(),
+                receiver_server: GLOBAL_CONFIG.get().unwrap().url.clone(),
+                text: "who am i".to_string(),
+                extensions: Default::default(),
+            })),
+        }),
+    };
+
+    // impersonating the receiver
+    let status = client1.send_event(send(id2.clone())).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    // the sender is filled from the token
+    let event_id = client1
+        .send_event(send("".to_string()))
+        .await?
+        .into_inner()
+        .event_id;
+    let received = receive.get_mut().next().await.unwrap()?;
+    assert_eq!(received.event_id, event_id);
+    assert_eq!(received.sender, id1);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+    let pool = DBPool::new(GLOBAL_CONFIG.get().unwrap());
+    let stored = run_sql!(
+        pool,
+        |mut con| {
+            EVENT::table
+                .filter(EVENT::ID.eq(&event_id))
+                .first::<limit_db::event::Event>(&mut con)
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    assert_eq!(stored.sender, id1);
+    assert_eq!(stored.device_id, Some(device_id.clone()));
+
+    // a retried send gets the event of the first one back
+    let send_txn = |txn_id: &str| {
+        let mut req = Request::new(send("".to_string()));
+        req.metadata_mut()
+            .insert(TXN_ID_METADATA, txn_id.parse().unwrap());
+        req
+    };
+    let txn_id = uuid::Uuid::new_v4().to_string();
+    let first = client1.send_event(send_txn(&txn_id)).await?.into_inner();
+    let retried = client1.send_event(send_txn(&txn_id)).await?.into_inner();
+    assert_eq!(retried.event_id, first.event_id);
+    let other = client1
+        .send_event(send_txn(&uuid::Uuid::new_v4().to_string()))
+        .await?
+        .into_inner();
+    assert_ne!(other.event_id, first.event_id);
+    assert_eq!(
+        receive.get_mut().next().await.unwrap()?.event_id,
+        first.event_id
+    );
+    assert_eq!(
+        receive.get_mut().next().await.unwrap()?.event_id,
+        other.event_id
+    );
+
+    // a retry while the first send is going on comes back later
+    let txn_id = uuid::Uuid::new_v4().to_string();
+    let redis = limit_db::RedisClient::open("redis://127.0.0.1:6379/")?;
+    let claim = limit_db::txn::claim(
+        &mut redis.get_async_connection().await?,
+        &limit_db::txn::key(&id1, &device_id, &txn_id),
+        std::time::Duration::from_secs(60),
+    )
+    .await?;
+    assert_eq!(claim, limit_db::txn::Claim::Claimed);
+    let status = client1.send_event(send_txn(&txn_id)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    assert!(status.metadata().get(RETRY_AFTER_METADATA).is_some());
+
+    // a claim abandoned by a failing server expires, the retry sends then
+    let txn_id = uuid::Uuid::new_v4().to_string();
+    let claim = limit_db::txn::claim(
+        &mut redis.get_async_connection().await?,
+        &limit_db::txn::key(&id1, &device_id, &txn_id),
+        std::time::Duration::from_millis(200),
+    )
+    .await?;
+    assert_eq!(claim, limit_db::txn::Claim::Claimed);
+    let status = client1.send_event(send_txn(&txn_id)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    let sent = client1.send_event(send_txn(&txn_id)).await?.into_inner();
+    let retried = client1.send_event(send_txn(&txn_id)).await?.into_inner();
+    assert_eq!(retried.event_id, sent.event_id);
+    assert_eq!(
+        receive.get_mut().next().await.unwrap()?.event_id,
+        sent.event_id
+    );
+
+    tracing::info!(
+        "\t- test {}::test_sender_from_token finished",
+        module_path!()
+    );
+    Ok(())
+}
+
+pub async fn test_resume_events(port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_resume_events started", module_path!());
+    let Fixture {
+        addr,
+        id1,
+        id2,
+        auth1,
+        auth2,
+        ..
+    } = Fixture::new(port).await?;
+    let mut client = EventServiceClient::connect(addr).await?;
+    let sender = client.clone();
+    let send = |text: &str| {
+        let req = SendEventRequest {
+            token: Some(auth1.clone()),
+            event: Some(Event {
+                event_id: "".to_string(),
+                ts: chrono::Utc::now().timestamp_millis() as u64,
+                sender: id1.clone(),
+                detail: Some(Detail::Message(Message {
+                    receiver_id: id2.clone(),
+                    receiver_server: GLOBAL_CONFIG.get().unwrap().url.clone(),
+                    text: text.to_string(),
+                    extensions: Default::default(),
+                })),
+            }),
+        };
+        let mut sender = sender.clone();
+        async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            anyhow::Ok(sender.send_event(req).await?.into_inner().event_id)
+        }
+    };
+    let receive = |cursor: Option<&str>| {
+        let mut req = Request::new(ReceiveEventsRequest {
+            token: Some(auth2.clone()),
+        });
+        if let Some(cursor) = cursor {
+            req.metadata_mut()
+                .insert(RESUME_METADATA, cursor.parse().unwrap());
+        }
+        req
+    };
+
+    let mut events = client.receive_events(receive(None)).await?.into_inner();
+    let first = send("first").await?;
+    assert_eq!(events.next().await.unwrap()?.event_id, first);
+    drop(events);
+
+    // sent while the device is away
+    let missed = [send("second").await?, send("third").await?];
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    let mut events = client
+        .receive_events(receive(Some(&first)))
+        .await?
+        .into_inner();
+    let mut sequence = vec![];
+    for id in &missed {
+        let event = events.next().await.unwrap()?;
+        assert_eq!(&event.event_id, id);
+        let Some(Detail::Message(message)) = event.detail else {
+            panic!("not a message");
+        };
+        sequence.push(message.extensions[SEQUENCE_EXTENSION].parse::<i64>()?);
+    }
+    assert_eq!(sequence[1], sequence[0] + 1);
+    // the missed events are in the stream too, they aren't sent twice
+    let live = send("fourth").await?;
+    assert_eq!(events.next().await.unwrap()?.event_id, live);
+    drop(events);
+    // event ids sort in the order they were sent
+    assert!(first < missed[0] && missed[0] < missed[1] && missed[1] < live);
+
+    let status = client
+        .receive_events(receive(Some(&uuid::Uuid::new_v4().to_string())))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    tracing::info!("\t- test {}::test_resume_events finished", module_path!());
+    Ok(())
+}
+
 pub async fn integration_test() {
     do_with_port(|port| async move {
-        let tasks: Vec<_> = test_tasks![port, test_send_message, test_sync_message];
+        let tasks: Vec<_> = test_tasks![
+            port,
+            test_send_message,
+            test_sync_message,
+            test_sender_key_distribution,
+            test_signed_event,
+            test_sender_from_token,
+            test_resume_events
+        ];
 
         test_service! {
             port,
@@ -1,17 +1,22 @@
-#![feature(iter_collect_into)]
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
 
 use anyhow::Context;
-use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
-use futures::StreamExt;
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
 use limit_config::GLOBAL_CONFIG;
 use limit_db::{
-    get_db_layer, run_sql,
-    schema::{EVENT, EVENT_SUBSCRIPTIONS, MESSAGE},
-    RedisClient,
+    event::{EventSignature, MESSAGE_EVENT_TYPE},
+    get_db_layer,
+    id::UserId,
+    run_sql,
+    schema::{EVENT_SUBSCRIPTIONS, USER},
+    DBPool, RedisClient,
 };
-use limit_deps::{diesel::JoinOnDsl, *};
+use limit_deps::{metrics::increment_counter, *};
 use limit_utils::{execute_background_task, BackgroundTask};
-use tonic::{codegen::BoxStream, Request, Response, Status};
+use tonic::{codegen::BoxStream, metadata::MetadataValue, Request, Response, Status};
 pub use tonic_gen::event::{event::*, synchronize_request::*, types::*, *};
 
 #[derive(Debug, Clone)]
@@ -20,49 +25,723 @@ pub use tonic_gen::event::{event::*, synchronize_request::*, types::*, *};
 // TODO: see if there is any message missing
 pub struct EventService;
 
-fn message_to_dbmessage(m: Event) -> limit_db::event::SREvent {
+/// Key in [`Message::extensions`] naming the event type carried by a message
+/// detail, plain messages may omit it
+pub const EVENT_TYPE_EXTENSION: &str = "event_type";
+
+/// Key in [`Message::extensions`] of events sent to clients holding the
+/// position of the event in its conversation, assigned by the server
+pub const SEQUENCE_EXTENSION: &str = "seq";
+
+/// the extensions of a stored message as sent to clients
+fn message_extensions(extensions: &str, seq: i64) -> Result<HashMap<String, String>, Status> {
+    let mut extensions: HashMap<String, String> =
+        serde_json::from_str(extensions).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+    extensions.insert(SEQUENCE_EXTENSION.to_string(), seq.to_string());
+    Ok(extensions)
+}
+
+/// Metadata key of a transaction id naming a `send_event` of a device, a
+/// retry with the same one within
+/// [`limit_config::Config::transaction_id_window`] gets the event of the first
+/// send back, or `unavailable` while the first send is still going on, see
+/// [`limit_db::txn`]
+pub const TXN_ID_METADATA: &str = "x-limit-txn-id";
+
+/// Metadata key of a `resource_exhausted` or `unavailable` status of
+/// `send_event`, the seconds to wait before sending again
+pub const RETRY_AFTER_METADATA: &str = "retry-after";
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Metadata key of the sender's signature over the canonical event, see
+/// [`limit_db::event::SREvent::canonical`]
+pub const SIGNATURE_METADATA: &str = "x-limit-signature";
+
+/// verify the sender's signature of an event if any, then countersign it with
+/// the server key
+fn sign_event(
+    event: &mut limit_db::event::SREvent,
+    sender_signature: Option<String>,
+    sender_pubkey: Option<String>,
+) -> Result<(), Status> {
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let canonical = event.canonical().map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    match (&sender_signature, sender_pubkey) {
+        (Some(signature), Some(pubkey)) => {
+            limit_am::decode_public(&pubkey)
+                .and_then(|key| limit_am::signature::verify_event(&key, &canonical, signature))
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::unauthenticated("invalid event signature")
+                })?;
+        }
+        (Some(_), None) => {
+            tracing::error!("sender not found");
+            return Err(Status::unauthenticated("sender not found"));
+        }
+        (None, _) if config.require_event_signatures => {
+            tracing::error!("event signature required");
+            return Err(Status::unauthenticated("event signature required"));
+        }
+        (None, _) => {}
+    }
+    let server_signature = limit_am::decode_secret(&config.server_secret_key)
+        .and_then(|key| {
+            limit_am::signature::countersign_event(
+                &key,
+                &event.countersigned(&canonical, sender_signature.as_deref()),
+            )
+        })
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+    event.signature = Some(EventSignature {
+        event_id: event.head.id.clone(),
+        sender_signature,
+        server_signature,
+        origin_server: config.url.clone(),
+    });
+    Ok(())
+}
+
+fn message_to_dbmessage(m: Event) -> Result<limit_db::event::SREvent, Status> {
     let msg = match m.detail {
         Some(Detail::Message(ref m)) => m,
-        _ => panic!(),
+        _ => return Err(Status::internal("no implementation")),
     };
-    (
+    let receiver = UserId::from_parts(&msg.receiver_id, &msg.receiver_server).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    let event_type = msg
+        .extensions
+        .get(EVENT_TYPE_EXTENSION)
+        .map(String::as_str)
+        .unwrap_or(MESSAGE_EVENT_TYPE)
+        .to_string();
+    let event: limit_db::event::SREvent = (
         limit_db::event::Event {
             id: m.event_id.clone(),
             timestamp: m.ts as i64,
             sender: m.sender,
-            event_type: "message".to_string(),
+            event_type,
+            device_id: None,
+            depth: 0,
+            received_at: chrono::Utc::now().timestamp_millis(),
+            seq: 0,
         },
         limit_db::event::Message {
             event_id: m.event_id,
-            receiver_id: msg.receiver_id.to_owned(),
-            receiver_server: msg.receiver_server.to_owned(),
+            receiver_id: receiver.uuid().to_string(),
+            receiver_server: receiver.server().to_string(),
             text: msg.text.to_owned(),
             extensions: serde_json::to_value(msg.extensions.to_owned())
                 .unwrap()
                 .to_string(),
         },
     )
-        .into()
+        .into();
+    // events of types this server doesn't know are kept as they came
+    limit_db::event_type::validate(&event.head.event_type, event.body.message()).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    Ok(event)
 }
 
 fn dbmessage_to_message(m: limit_db::event::SREvent) -> Result<Event, Status> {
-    match m.head.event_type.as_str() {
-        "message" => {
-            let limit_db::event::SREventBody::Message(body) = m.body;
-            Ok(Event {
-                event_id: m.head.id,
-                ts: m.head.timestamp as u64,
-                sender: m.head.sender,
-                detail: Some(Detail::Message(Message {
-                    receiver_id: body.receiver_id,
-                    receiver_server: body.receiver_server,
-                    text: body.text,
-                    extensions: serde_json::from_str(&body.extensions).unwrap(),
-                })),
+    let limit_db::event::SREventBody::Message(body) = m.body;
+    stored_event(m.head, body)
+}
+
+/// a stored event as sent to clients, an error when it was stored malformed.
+/// Callers leave such events out, they can't be sent again.
+fn stored_event(
+    event: limit_db::event::Event,
+    body: limit_db::event::Message,
+) -> Result<Event, Status> {
+    let extensions = message_extensions(&body.extensions, event.seq).map_err(|status| {
+        tracing::error!("stored event {} is malformed", event.id);
+        status
+    })?;
+    Ok(Event {
+        detail: Some(Detail::Message(Message {
+            receiver_id: body.receiver_id,
+            receiver_server: body.receiver_server,
+            text: body.text,
+            extensions,
+        })),
+        event_id: event.id,
+        ts: event.received_at as u64,
+        sender: event.sender,
+    })
+}
+
+/// Metadata key of the way `synchronize` pages, `forward` from the oldest
+/// event or `backward` from the newest. Default is backward.
+pub const SYNC_DIRECTION_METADATA: &str = "x-limit-sync-direction";
+/// Metadata key of a continuation cursor. A `synchronize` response holds the
+/// cursor of its last event, a request with one continues after it in the same
+/// direction in place of `from`.
+pub const SYNC_CURSOR_METADATA: &str = "x-limit-sync-cursor";
+/// Metadata key of a `synchronize` response, `true` when more events follow
+/// in the requested range
+pub const SYNC_HAS_MORE_METADATA: &str = "x-limit-sync-has-more";
+/// Metadata key limiting `synchronize` to a conversation, the receiver id of
+/// its messages
+pub const SYNC_CONVERSATION_METADATA: &str = "x-limit-sync-conversation";
+/// Metadata key limiting `synchronize` to the events of a sender
+pub const SYNC_SENDER_METADATA: &str = "x-limit-sync-sender";
+/// Metadata key limiting `synchronize` to an event type
+pub const SYNC_EVENT_TYPE_METADATA: &str = "x-limit-sync-event-type";
+
+/// events of a `synchronize` page when the request doesn't say
+const DEFAULT_SYNC_COUNT: u32 = 50;
+const MAX_SYNC_COUNT: u32 = 8192;
+
+/// the position of an event stored on this node or on one of the nodes of
+/// `remote`, see [`limit_server_cluster::history::remote`]
+async fn position(
+    pool: &DBPool,
+    remote: &HashMap<String, Vec<String>>,
+    id: &str,
+    order: limit_db::sync::Order,
+) -> Result<limit_db::sync::Bound, Status> {
+    limit_server_cluster::history::event(pool, remote, id)
+        .await?
+        .map(|event| limit_db::sync::Bound::Position(limit_db::sync::Position::of(&event, order)))
+        .ok_or_else(|| {
+            tracing::error!("unknown event {}", id);
+            Status::invalid_argument("unknown event")
+        })
+}
+
+/// the page of the history of `user_id` a `synchronize` request asks for, its
+/// conversations owned by other nodes in `remote`
+async fn sync_query(
+    req: &Request<SynchronizeRequest>,
+    pool: &DBPool,
+    user_id: String,
+    remote: &HashMap<String, Vec<String>>,
+) -> Result<limit_db::sync::Query, Status> {
+    let metadata = |key: &str| {
+        req.metadata()
+            .get(key)
+            .map(|value| {
+                value.to_str().map(str::to_string).map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::invalid_argument(e.to_string())
+                })
             })
+            .transpose()
+    };
+    let conversation = metadata(SYNC_CONVERSATION_METADATA)?;
+    let order = match conversation {
+        Some(_) => limit_db::sync::Order::Depth,
+        None => limit_db::sync::Order::ReceivedAt,
+    };
+    let sync_req = req.get_ref();
+
+    let limit = match sync_req.count {
+        0 => DEFAULT_SYNC_COUNT,
+        count @ 1..=MAX_SYNC_COUNT => count,
+        count => {
+            tracing::error!("invalid count {}", count);
+            return Err(Status::invalid_argument(format!(
+                "count must be at most {MAX_SYNC_COUNT}"
+            )));
+        }
+    };
+    let mut direction = match metadata(SYNC_DIRECTION_METADATA)?.as_deref() {
+        Some("forward") => limit_db::sync::Direction::Forward,
+        None | Some("backward") => limit_db::sync::Direction::Backward,
+        Some(direction) => {
+            tracing::error!("invalid direction {}", direction);
+            return Err(Status::invalid_argument("invalid direction"));
+        }
+    };
+    let from = match metadata(SYNC_CURSOR_METADATA)? {
+        Some(cursor) => {
+            let (cursor_direction, position) = limit_db::sync::Position::decode(&cursor)
+                .ok_or_else(|| {
+                    tracing::error!("invalid cursor {}", cursor);
+                    Status::invalid_argument("invalid cursor")
+                })?;
+            // a cursor of a conversation goes on in the conversation
+            if position.order != order {
+                tracing::error!("cursor {} of another query", cursor);
+                return Err(Status::invalid_argument("cursor of another query"));
+            }
+            direction = cursor_direction;
+            Some(limit_db::sync::Bound::Position(position))
+        }
+        None => match &sync_req.from {
+            Some(From::IdFrom(id)) => Some(position(pool, remote, id, order).await?),
+            Some(From::TsFrom(ts)) => Some(limit_db::sync::Bound::ReceivedAt(*ts as i64)),
+            None => None,
+        },
+    };
+    let to = match &sync_req.to {
+        Some(To::IdTo(id)) => Some(position(pool, remote, id, order).await?),
+        Some(To::TsTo(ts)) => Some(limit_db::sync::Bound::ReceivedAt(*ts as i64)),
+        None => None,
+    };
+
+    Ok(limit_db::sync::Query {
+        user_id,
+        direction,
+        from,
+        to,
+        conversation,
+        conversations: None,
+        sender: metadata(SYNC_SENDER_METADATA)?,
+        event_type: metadata(SYNC_EVENT_TYPE_METADATA)?,
+        limit: limit as i64,
+    })
+}
+
+/// Metadata key of the id of the last event a client received. A
+/// `receive_events` stream resuming after it sends the stored events which
+/// followed before the live ones, and each of them once. Without it, a device
+/// resumes after the last event delivered to it, see [`limit_db::delivery`].
+pub const RESUME_METADATA: &str = "x-limit-resume-after";
+
+/// milliseconds a `receive_events` stream waits for new events at once
+const DELIVERY_BLOCK: usize = 5000;
+/// events read from redis or the database at once
+const DELIVERY_BATCH: usize = 64;
+
+/// The stored events a `receive_events` stream resuming sends first
+struct Resume {
+    /// receive time and id of the event the client received last
+    cursor: (i64, String),
+    /// position of the stored event sent last, none once all of them were
+    position: Option<(i64, String)>,
+    buffer: VecDeque<limit_db::event::SREvent>,
+    /// stored events sent, skipped when they come live
+    sent: HashSet<String>,
+}
+
+impl Resume {
+    fn new(cursor: (i64, String)) -> Self {
+        Self {
+            position: Some(cursor.clone()),
+            cursor,
+            buffer: VecDeque::new(),
+            sent: HashSet::new(),
         }
-        _ => Err(Status::internal("event type not supported")),
     }
+
+    /// whether the client received a live event already
+    fn received(&self, event: &limit_db::event::SREvent) -> bool {
+        self.sent.contains(&event.head.id)
+            || (event.head.received_at, event.head.id.as_str())
+                <= (self.cursor.0, self.cursor.1.as_str())
+    }
+}
+
+/// A `receive_events` stream reading the channels of a user in the consumer
+/// group of the device, see [`limit_db::stream`]
+struct DeliveryState {
+    pool: DBPool,
+    user_id: String,
+    /// the conversations of the user owned by other nodes, see
+    /// [`limit_server_cluster::history::remote`]
+    remote: HashMap<String, Vec<String>>,
+    redis: redis::aio::Connection,
+    channels: Vec<String>,
+    device_id: String,
+    /// see [`limit_db::stream::group`]
+    group: String,
+    resume: Option<Resume>,
+    /// replaying what was delivered to the device before and not acknowledged
+    pending: bool,
+    buffer: VecDeque<limit_db::stream::Entry>,
+    /// the entry handed to the stream last, acknowledged once the next one is
+    /// asked for
+    delivered: Option<limit_db::stream::Entry>,
+    /// the event handed to the stream last, recorded as delivered to the
+    /// device once the next one is asked for
+    last: Option<limit_db::event::Event>,
+    _stream: limit_server_cluster::StreamGuard,
+}
+
+impl DeliveryState {
+    /// record the event delivered last and wait for the next event
+    async fn next(&mut self) -> Result<limit_db::event::SREvent, Status> {
+        if let Some(event) = self.last.take() {
+            let pool = &self.pool;
+            run_sql!(
+                pool,
+                |mut conn| {
+                    limit_db::delivery::advance(&mut conn, &self.user_id, &self.device_id, &event)
+                        .map_err(|e| {
+                            tracing::error!("{}", e);
+                            Status::internal(e.to_string())
+                        })
+                },
+                |e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                }
+            )?;
+        }
+        let event = self.next_event().await?;
+        self.last = Some(event.head.clone());
+        Ok(event)
+    }
+
+    /// whether this device sent an event, its other devices receive it
+    fn sent_here(&self, event: &limit_db::event::SREvent) -> bool {
+        event.head.sender == self.user_id && event.head.device_id.as_ref() == Some(&self.device_id)
+    }
+
+    /// acknowledge the entry delivered last and wait for the next event
+    async fn next_event(&mut self) -> Result<limit_db::event::SREvent, Status> {
+        if let Some(entry) = self.delivered.take() {
+            limit_db::stream::ack(&mut self.redis, &self.group, &entry)
+                .await
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })?;
+        }
+        if let Some(event) = self.next_stored().await? {
+            return Ok(event);
+        }
+        if self.channels.is_empty() {
+            futures::future::pending::<()>().await;
+        }
+        loop {
+            while let Some(entry) = self.buffer.pop_front() {
+                let event = entry
+                    .event
+                    .as_deref()
+                    .map(serde_json::from_str::<limit_db::event::SREvent>)
+                    .transpose()
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })?;
+                match event {
+                    Some(event)
+                        if !self.sent_here(&event)
+                            && !self.resume.as_ref().map_or(false, |r| r.received(&event)) =>
+                    {
+                        self.delivered = Some(entry);
+                        return Ok(event);
+                    }
+                    // trimmed before it was acknowledged, sent here or received
+                    // already
+                    _ => limit_db::stream::ack(&mut self.redis, &self.group, &entry)
+                        .await
+                        .map_err(|e| {
+                            tracing::error!("{}", e);
+                            Status::internal(e.to_string())
+                        })?,
+                }
+            }
+            let entries = limit_db::stream::read(
+                &mut self.redis,
+                &self.channels,
+                &self.group,
+                self.pending,
+                DELIVERY_BATCH,
+                DELIVERY_BLOCK,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })?;
+            if self.pending && entries.is_empty() {
+                self.pending = false;
+            }
+            self.buffer.extend(entries);
+        }
+    }
+
+    /// the next stored event after the resume cursor
+    async fn next_stored(&mut self) -> Result<Option<limit_db::event::SREvent>, Status> {
+        loop {
+            match self.next_resumed().await? {
+                Some(event) if self.sent_here(&event) => {}
+                event => return Ok(event),
+            }
+        }
+    }
+
+    async fn next_resumed(&mut self) -> Result<Option<limit_db::event::SREvent>, Status> {
+        let Some(resume) = &mut self.resume else {
+            return Ok(None);
+        };
+        if resume.buffer.is_empty() {
+            let Some((ts, id)) = &resume.position else {
+                return Ok(None);
+            };
+            let events = limit_server_cluster::history::load_after(
+                &self.pool,
+                &self.user_id,
+                (*ts, id),
+                DELIVERY_BATCH as i64,
+                &self.remote,
+            )
+            .await?;
+            resume.position = events
+                .last()
+                .map(|event| (event.head.received_at, event.head.id.clone()));
+            resume.buffer.extend(events);
+        }
+        let event = resume.buffer.pop_front();
+        if let Some(event) = &event {
+            resume.sent.insert(event.head.id.clone());
+        }
+        Ok(event)
+    }
+}
+
+/// receive time and id of the event a client resumes after, stored here or on
+/// a node of `remote`, or still in the stream of one of `channels` when its
+/// write is in flight
+async fn resume_cursor(
+    pool: &DBPool,
+    remote: &HashMap<String, Vec<String>>,
+    redis: &mut redis::aio::Connection,
+    channels: &[String],
+    cursor: &str,
+) -> Result<(i64, String), Status> {
+    if let Some(event) = limit_server_cluster::history::event(pool, remote, cursor).await? {
+        return Ok((event.received_at, cursor.to_string()));
+    }
+    for channel in channels {
+        let entries = limit_db::stream::entries(redis, channel)
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })?;
+        let found = entries
+            .iter()
+            .filter_map(|entry| entry.event.as_deref())
+            .filter_map(|event| serde_json::from_str::<limit_db::event::SREvent>(event).ok())
+            .find(|event| event.head.id == cursor);
+        if let Some(event) = found {
+            return Ok((event.head.received_at, cursor.to_string()));
+        }
+    }
+    tracing::error!("unknown resume cursor {}", cursor);
+    Err(Status::invalid_argument("unknown resume cursor"))
+}
+
+/// a transaction id claimed by a send, see [`limit_db::txn`]
+struct Txn {
+    conn: redis::aio::Connection,
+    key: String,
+    window: Duration,
+}
+
+impl Txn {
+    /// settle the claim with the event the send created, release it when none
+    async fn settle(mut self, event_id: Option<&str>) {
+        let res = match event_id {
+            Some(event_id) => {
+                limit_db::txn::settle(&mut self.conn, &self.key, event_id, self.window).await
+            }
+            None => limit_db::txn::release(&mut self.conn, &self.key).await,
+        };
+        if let Err(e) = res {
+            tracing::error!("{}", e);
+        }
+    }
+}
+
+/// store or relay an event sent by `sender`, named `event_id`. A transaction
+/// id claimed for it is taken to be settled once the event is stored when
+/// that's left to the background.
+async fn accept_event(
+    req: &Request<SendEventRequest>,
+    sub: limit_server_auth::JWTSub,
+    sender: String,
+    event: Event,
+    event_id: String,
+    txn: &mut Option<Txn>,
+) -> Result<Response<SendEventResponse>, Status> {
+    let (_, redis, pool) = get_db_layer!(req);
+    // until it's stored and published, see limit_server_cluster::inflight
+    let in_flight = limit_server_cluster::inflight::acquire(
+        &redis,
+        &sender,
+        GLOBAL_CONFIG
+            .get()
+            .unwrap()
+            .per_user_message_on_the_fly_limit,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?
+    .ok_or_else(|| {
+        increment_counter!("send_event_throttled");
+        tracing::warn!("user {} has too many messages in flight", sender);
+        let mut status = Status::resource_exhausted("too many messages in flight");
+        status.metadata_mut().insert(
+            RETRY_AFTER_METADATA,
+            MetadataValue::from(RETRY_AFTER_SECONDS),
+        );
+        status
+    })?;
+
+    let mut message = event.clone();
+    message.event_id = event_id;
+    message.sender = sender;
+    let message2 = message.clone();
+
+    if !matches!(event.detail, Some(Detail::Message(_))) {
+        return Err(Status::internal("no implementation"));
+    }
+
+    let mut message = message_to_dbmessage(message2)?;
+    message.head.device_id = Some(sub.device_id);
+    let sender_signature = req
+        .metadata()
+        .get(SIGNATURE_METADATA)
+        .map(|signature| signature.to_str().map(str::to_string))
+        .transpose()
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::invalid_argument(e.to_string())
+        })?;
+    let sender_pubkey = match sender_signature {
+        Some(_) => run_sql!(
+            pool,
+            |mut conn| {
+                USER::table
+                    .filter(USER::ID.eq(&message.head.sender))
+                    .select(USER::PUBKEY)
+                    .first::<String>(&mut conn)
+                    .optional()
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?,
+        None => None,
+    };
+    // the event follows the frontier of the conversation, signed with it. It
+    // joins the frontier once stored.
+    let (prev_events, depth) = run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::dag::frontier(
+                &mut conn,
+                limit_db::dag::conversation_id(message.body.message()),
+            )
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    message.head.depth = depth;
+    message.prev_events = prev_events;
+    sign_event(&mut message, sender_signature, sender_pubkey)?;
+    let body = message.body.message().clone();
+
+    // validated by message_to_dbmessage, users who moved are reached on
+    // their new server
+    let receiver = limit_server_federation::account::route(&pool, &body.receiver().unwrap())?;
+    let destination = if receiver.is_local() {
+        None
+    } else {
+        limit_server_federation::policy::check_peer(&pool, receiver.server().as_str())?;
+        limit_server_federation::policy::check_limits(
+            receiver.server().as_str(),
+            limit_server_federation::policy::Direction::Outbound,
+            limit_server_federation::policy::event_size(&body),
+        )?;
+        Some(receiver.server().to_string())
+    };
+
+    // stored with its body in one transaction, then published from the
+    // outbox. An event of a remote receiver is kept here too for the devices
+    // of the sender, and queued for the server of the receiver in the same
+    // transaction.
+    limit_server_cluster::outbox::start_publisher(pool.clone(), redis.clone());
+    let event_id = message.head.id.clone();
+    in_flight.publishing(&event_id).await.map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?;
+    let store = async move {
+        let mut message = message;
+        let stored = run_sql!(
+            pool,
+            |mut conn| {
+                Connection::transaction(&mut conn, |conn| {
+                    limit_db::event::store(conn, &mut message)?;
+                    match &destination {
+                        Some(destination) => {
+                            limit_server_federation::outbox::enqueue(conn, &message, destination)
+                        }
+                        None => Ok(()),
+                    }
+                })
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        );
+        if let Err(status) = stored {
+            if let Err(e) = limit_server_cluster::inflight::land(&redis, &message.head.id).await {
+                tracing::error!("{}", e);
+            }
+            return Err(status);
+        }
+        limit_server_cluster::outbox::wake_publisher();
+        if destination.is_some() {
+            limit_server_federation::outbox::start_outbox_worker(pool);
+            limit_server_federation::outbox::wake_outbox_worker();
+        }
+        Ok::<_, Status>(())
+    };
+    if GLOBAL_CONFIG.get().unwrap().fast_ack {
+        let txn = txn.take();
+        let event_id = event_id.clone();
+        execute_background_task(BackgroundTask::new("store_event", async move {
+            let stored = store.await;
+            if let Some(txn) = txn {
+                txn.settle(stored.as_ref().ok().map(|_| event_id.as_str()))
+                    .await;
+            }
+            stored
+        }))
+        .await;
+    } else {
+        store.await?;
+    }
+    Ok(Response::new(SendEventResponse { event_id }))
 }
 
 #[tonic::async_trait]
@@ -78,22 +757,26 @@ impl tonic_gen::event::event_service_server::EventService for EventService {
             tracing::error!("no auth token");
             Status::unauthenticated("no auth token")
         })?;
-        let claim = limit_server_auth::decode_jwt(&auth.jwt)?;
-        let (_, id) = claim.sub.split_once('/').ok_or_else(|| {
-            tracing::error!("invalid uuid");
-            Status::unauthenticated("invalid uuid")
-        })?;
+        let sub = limit_server_auth::decode_jwt(&auth.jwt)?.parse_sub()?;
+        let id = sub.id.uuid().to_string();
+
+        // the events of a user are published on the node owning its
+        // conversation
+        if let Some(node) = limit_server_cluster::route(&req, limit_db::dag::conversation_of(&id)) {
+            let stream = event_service_client::EventServiceClient::new(
+                limit_server_cluster::channel(&node).await?,
+            )
+            .receive_events(limit_server_cluster::forward_request(&req))
+            .await?
+            .into_inner();
+            return Ok(Response::new(Box::pin(stream)));
+        }
 
         let (_, redis, pool) = get_db_layer!(req);
         let mut redis_connection = redis.get_connection().map_err(|e| {
             tracing::error!("{}", e);
             Status::internal(e.to_string())
         })?;
-        let redis_async_connection = redis.get_async_connection().await.map_err(|e| {
-            tracing::error!("{}", e);
-            Status::internal(e.to_string())
-        })?;
-
         let subscriptions: Option<Vec<String>> = redis::cmd("GET")
             .arg(format!("{id}:subscribed"))
             .query(&mut redis_connection)
@@ -106,7 +789,8 @@ impl tonic_gen::event::event_service_server::EventService for EventService {
             subscriptions
         } else {
             tracing::info!("receive message cache miss");
-            let sql = EVENT_SUBSCRIPTIONS::table.filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(id));
+            let sql =
+                EVENT_SUBSCRIPTIONS::table.filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(id.clone()));
             let subs = run_sql!(
                 pool,
                 |mut conn| {
@@ -127,22 +811,110 @@ impl tonic_gen::event::event_service_server::EventService for EventService {
             subs
         };
 
-        let mut pubsub = redis_async_connection.into_pubsub();
-        for sub in subscriptions {
-            pubsub.subscribe(sub).await.map_err(|e| {
+        // the channel of the user holds what its other devices sent too
+        let mut subscriptions = subscriptions;
+        let own = format!("message:{id}");
+        if !subscriptions.contains(&own) {
+            subscriptions.push(own);
+        }
+
+        // stored events of conversations owned by other nodes are resumed
+        // from them
+        let remote = limit_server_cluster::history::remote(&run_sql!(
+            pool,
+            |mut conn| {
+                limit_db::sync::subscriptions(&mut conn, &id).map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+            },
+            |e| {
                 tracing::error!("{}", e);
                 Status::internal(e.to_string())
-            })?;
-        }
-        let res = pubsub.into_on_message().map(|msg| {
-            dbmessage_to_message(
-                msg.get_payload()
-                    .map(|payload: String| serde_json::from_str(&payload).unwrap())
-                    .map_err(|e| {
+            }
+        )?);
+
+        // other nodes of a cluster forward the events of the user here
+        let stream = limit_server_cluster::open_stream(&id);
+        let mut redis_async_connection = redis.get_async_connection().await.map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+        let resume = match req.metadata().get(RESUME_METADATA) {
+            Some(cursor) => {
+                let cursor = cursor.to_str().map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::invalid_argument(e.to_string())
+                })?;
+                Some(Resume::new(
+                    resume_cursor(
+                        &pool,
+                        &remote,
+                        &mut redis_async_connection,
+                        &subscriptions,
+                        cursor,
+                    )
+                    .await?,
+                ))
+            }
+            None => run_sql!(
+                pool,
+                |mut conn| {
+                    limit_db::delivery::get(&mut conn, &id, &sub.device_id).map_err(|e| {
                         tracing::error!("{}", e);
                         Status::internal(e.to_string())
-                    })?,
+                    })
+                },
+                |e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                }
+            )?
+            .map(|delivery| Resume::new(delivery.cursor())),
+        };
+        // resuming, the events kept in the streams may follow the cursor
+        let group = limit_db::stream::group(&id, &sub.device_id);
+        for channel in &subscriptions {
+            limit_db::stream::create_group(
+                &mut redis_async_connection,
+                channel,
+                &group,
+                resume.is_some(),
             )
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })?;
+        }
+        let state = DeliveryState {
+            pool,
+            user_id: id,
+            remote,
+            redis: redis_async_connection,
+            channels: subscriptions,
+            device_id: sub.device_id,
+            group,
+            resume,
+            pending: true,
+            buffer: VecDeque::new(),
+            delivered: None,
+            last: None,
+            _stream: stream,
+        };
+        // a stream failing to read its events ends
+        let res = futures::stream::unfold(Some(state), |state| async move {
+            let mut state = state?;
+            loop {
+                match state.next().await {
+                    Ok(event) => {
+                        if let Ok(event) = dbmessage_to_message(event) {
+                            return Some((Ok(event), Some(state)));
+                        }
+                    }
+                    Err(status) => return Some((Err(status), None)),
+                }
+            }
         });
         Ok(Response::new(Box::pin(res)))
     }
@@ -156,108 +928,95 @@ impl tonic_gen::event::event_service_server::EventService for EventService {
             tracing::error!("no auth token");
             Status::unauthenticated("no auth token")
         })?;
-        let _claim = limit_server_auth::decode_jwt(&auth.jwt)?;
+        let sub = limit_server_auth::decode_jwt(&auth.jwt)?.parse_sub()?;
         let event = req.get_ref().event.clone().ok_or_else(|| {
             tracing::error!("message is empty");
             Status::cancelled("message is empty")
         })?;
 
-        let current_server_url = GLOBAL_CONFIG.get().unwrap().url.as_str();
-        let mut message = event.clone();
-        message.event_id = uuid::Uuid::new_v4().to_string();
-        let message2 = message.clone();
+        // a conversation is stored on the node owning it
+        if let Some(Detail::Message(body)) = &event.detail {
+            if let Some(node) =
+                limit_server_cluster::route(&req, limit_db::dag::conversation_of(&body.receiver_id))
+            {
+                return event_service_client::EventServiceClient::new(
+                    limit_server_cluster::channel(&node).await?,
+                )
+                .send_event(limit_server_cluster::forward_request(&req))
+                .await;
+            }
+        }
 
-        let msg_detail = match event.detail {
-            Some(Detail::Message(ref msg)) => msg,
-            _ => return Err(Status::internal("no implementation")),
-        };
+        // the sender is the authenticated user, clients may leave it empty
+        let sender = sub.id.uuid().to_string();
+        if !event.sender.is_empty() && event.sender != sender {
+            increment_counter!("send_event_impersonation_rejected");
+            tracing::warn!(
+                "user {} on device {} tried to send as {}",
+                sender,
+                sub.device_id,
+                event.sender
+            );
+            return Err(Status::permission_denied("sender mismatch"));
+        }
 
-        if msg_detail.receiver_server == current_server_url {
-            let mut redis = req
-                .extensions()
-                .get::<RedisClient>()
-                .context("no redis extended to service")
-                .map_err(|e| {
+        let event_id = limit_db::id::new_event_id().to_string();
+        // a retry of a send gets the event of the first one back
+        let mut txn = match req.metadata().get(TXN_ID_METADATA) {
+            Some(txn_id) => {
+                let txn_id = txn_id.to_str().map_err(|e| {
                     tracing::error!("{}", e);
-                    Status::internal(e.to_string())
-                })?
-                .clone()
-                .get_connection()
-                .map_err(|e| {
+                    Status::invalid_argument(e.to_string())
+                })?;
+                let redis = req
+                    .extensions()
+                    .get::<RedisClient>()
+                    .context("no redis extended to service")
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })?;
+                let mut conn = redis.get_async_connection().await.map_err(|e| {
                     tracing::error!("{}", e);
                     Status::internal(e.to_string())
                 })?;
-
-            let message = message_to_dbmessage(message2);
-            match message.head.event_type.as_str() {
-                "message" => {
-                    let limit_db::event::SREventBody::Message(body) = &message.body;
-                    redis::cmd("PUBLISH")
-                        .arg(format!("message:{}", body.receiver_id))
-                        .arg(serde_json::to_string(&message).unwrap())
-                        .execute(&mut redis);
-
-                    // store message
-                    let pool = req
-                        .extensions()
-                        .get::<limit_db::DBPool>()
-                        .context("no db extended to service")
-                        .map_err(|e| {
-                            tracing::error!("{}", e);
-                            Status::internal(e.to_string())
-                        })?
-                        .clone();
-                    let insert_event_sql =
-                        diesel::insert_into(EVENT::table).values(message.head.clone());
-                    let insert_message_sql =
-                        diesel::insert_into(MESSAGE::table).values(body.clone());
-                    let pool2 = pool.clone();
-
-                    let _event_id = message.head.id.clone();
-                    let _event_id2 = message.head.id.clone();
-                    let event_id3 = message.head.id.clone();
-                    execute_background_task(BackgroundTask::new("store_event", async move {
-                        run_sql!(
-                            pool,
-                            |mut conn| {
-                                insert_event_sql.execute(&mut conn).map_err(|e| {
-                                    tracing::error!("{}", e);
-                                    Status::internal(e.to_string())
-                                })
-                            },
-                            |e| {
-                                tracing::error!("{}", e);
-                                Status::internal(e.to_string())
-                            }
-                        )
-                    }))
-                    .await;
-
-                    execute_background_task(BackgroundTask::new("store_message", async move {
-                        run_sql!(
-                            pool2,
-                            |mut conn| {
-                                insert_message_sql.execute(&mut conn).map_err(|e| {
-                                    tracing::error!("{}", e);
-                                    Status::internal(e.to_string())
-                                })
-                            },
-                            |e| {
-                                tracing::error!("{}", e);
-                                Status::internal(e.to_string())
-                            }
-                        )
-                    }))
-                    .await;
-                    Ok(Response::new(SendEventResponse {
-                        event_id: event_id3,
-                    }))
+                let key = limit_db::txn::key(&sender, &sub.device_id, txn_id);
+                let window =
+                    Duration::from_secs(GLOBAL_CONFIG.get().unwrap().transaction_id_window);
+                let claim = limit_db::txn::claim(&mut conn, &key, limit_db::txn::PENDING_TIMEOUT)
+                    .await
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })?;
+                match claim {
+                    limit_db::txn::Claim::Claimed => Some(Txn { conn, key, window }),
+                    limit_db::txn::Claim::Pending => {
+                        increment_counter!("send_event_pending");
+                        tracing::warn!("transaction {} of user {} is pending", txn_id, sender);
+                        let mut status = Status::unavailable("transaction pending");
+                        status.metadata_mut().insert(
+                            RETRY_AFTER_METADATA,
+                            MetadataValue::from(RETRY_AFTER_SECONDS),
+                        );
+                        return Err(status);
+                    }
+                    limit_db::txn::Claim::Sent(event_id) => {
+                        increment_counter!("send_event_deduplicated");
+                        return Ok(Response::new(SendEventResponse { event_id }));
+                    }
                 }
-                _ => Err(Status::internal("message type not supported")),
             }
-        } else {
-            todo!("send to other server")
+            None => None,
+        };
+
+        let res = accept_event(&req, sub, sender, event, event_id, &mut txn).await;
+        // unless it's left to the background
+        if let Some(txn) = txn {
+            txn.settle(res.as_ref().ok().map(|res| res.get_ref().event_id.as_str()))
+                .await;
         }
+        res
     }
 
     async fn synchronize(
@@ -272,118 +1031,81 @@ impl tonic_gen::event::event_service_server::EventService for EventService {
             Status::unauthenticated("no auth token")
         })?;
 
-        let claim = limit_server_auth::decode_jwt(&auth.jwt)?;
-        let id = claim
-            .sub
-            .split_once('/')
-            .map(|(_, id)| id.to_string())
-            .ok_or_else(|| {
-                tracing::error!("invalid uuid");
-                Status::unauthenticated("invalid uuid")
-            })?;
+        let id = limit_server_auth::decode_jwt(&auth.jwt)?
+            .parse_sub()?
+            .id
+            .uuid()
+            .to_string();
 
-        let from = sync_req.from.as_ref().ok_or_else(|| {
-            tracing::error!("no from");
-            Status::invalid_argument("no from")
-        })?;
-        let to = sync_req.to.as_ref().ok_or_else(|| {
-            tracing::error!("no to");
-            Status::invalid_argument("no to")
-        })?;
-        let sql = EVENT::table
-            .left_join(
-                MESSAGE::table
-                    .inner_join(EVENT_SUBSCRIPTIONS::table.on(EVENT_SUBSCRIPTIONS::USER_ID.eq(id))),
+        // a conversation is stored on the node owning it, the conversation of
+        // the user unless the sync is limited to another one
+        let conversation = req
+            .metadata()
+            .get(SYNC_CONVERSATION_METADATA)
+            .and_then(|conversation| conversation.to_str().ok())
+            .unwrap_or(&id);
+        if let Some(node) =
+            limit_server_cluster::route(&req, limit_db::dag::conversation_of(conversation))
+        {
+            return event_service_client::EventServiceClient::new(
+                limit_server_cluster::channel(&node).await?,
             )
-            // filter message
-            .filter(MESSAGE::RECEIVER_ID.eq(EVENT_SUBSCRIPTIONS::SUBSCRIBED_TO))
-            .filter(EVENT_SUBSCRIPTIONS::CHANNEL_TYPE.eq("message"))
-            .order(EVENT::ID.desc());
-
-        let mut sql_id_id = None;
-        let mut sql_id_ts = None;
-        let mut sql_ts_id = None;
-        let mut sql_ts_ts = None;
-
-        let count = match sync_req.count {
-            1..=8192 => sync_req.count as i64,
-            _ => 50,
-        };
+            .synchronize(limit_server_cluster::forward_request(&req))
+            .await;
+        }
 
-        match from {
-            From::IdFrom(from_id) => {
-                let sql = sql.filter(EVENT::ID.gt(from_id));
-                match to {
-                    To::IdTo(to_id) => {
-                        sql_id_id = Some(sql.filter(EVENT::ID.le(to_id)).limit(count));
-                    }
-                    To::TsTo(to_ts) => {
-                        sql_id_ts = Some(sql.filter(EVENT::TS.le((*to_ts) as i64)).limit(count));
-                    }
-                }
-            }
-            From::TsFrom(from_ts) => {
-                let sql = sql.filter(EVENT::TS.gt((*from_ts) as i64));
-                match to {
-                    To::IdTo(to_id) => {
-                        sql_ts_id = Some(sql.filter(EVENT::ID.le(to_id)).limit(count));
-                    }
-                    To::TsTo(to_ts) => {
-                        sql_ts_ts = Some(sql.filter(EVENT::TS.le((*to_ts) as i64)).limit(count));
-                    }
+        // the conversations the user subscribed to are stored on the nodes
+        // owning them
+        let remote = match req.metadata().get(SYNC_CONVERSATION_METADATA) {
+            Some(_) => HashMap::new(),
+            None => limit_server_cluster::history::remote(&run_sql!(
+                db_pool,
+                |mut conn| {
+                    limit_db::sync::subscriptions(&mut conn, &id).map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })
+                },
+                |e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
                 }
-            }
+            )?),
         };
-
-        macro_rules! send_sync {
-            ($e:expr) => {
-                if let Some(sql) = $e {
-                    let res : Vec<(limit_db::event::Event, Option<(limit_db::event::Message, limit_db::event::EventSubscriptions)>)> = run_sql!(
-                        db_pool,
-                        |mut conn| {
-                            // tracing::info!("sql: {:?}", diesel::debug_query::<diesel::sqlite::Sqlite, _>(&sql));
-                            sql.load::<(limit_db::event::Event, Option<(limit_db::event::Message, limit_db::event::EventSubscriptions)>)>(&mut conn).map_err(|e| {
-                                tracing::error!("{}", e);
-                                Status::internal(e.to_string())
-                            })
-                        },
-                        |e| {
-                            tracing::error!("{}", e);
-                            Status::internal(e.to_string())
-                        }
-                    )?;
-                    let mut ret = Vec::with_capacity(count as usize);
-                    res.into_iter().map(|(event, message)| {
-                        match (message,) {
-                            (Some((body, _)),) => {
-                                Event {
-                                    event_id : event.id,
-                                    ts : event.timestamp as u64,
-                                    sender : event.sender,
-                                    detail : Some(Detail::Message(Message {
-                                        receiver_id: body.receiver_id,
-                                        receiver_server: body.receiver_server,
-                                        text: body.text,
-                                        extensions: serde_json::from_str(&body.extensions).unwrap(),
-                                    })),
-                                }
-                            }
-                            _ => todo!()
-                        }
-                    })
-                    .collect_into(&mut ret);
-                    return Ok(Response::new(SynchronizeResponse {
-                        events: ret,
-                    }));
-                };
-            };
+        let query = sync_query(&req, &db_pool, id.clone(), &remote).await?;
+        let mut page = limit_server_cluster::history::load(&db_pool, &query, &remote).await?;
+        // paging back past the local horizon, fetch the history of remote
+        // conversations
+        if query.direction == limit_db::sync::Direction::Backward
+            && query.to.is_none()
+            && !page.has_more
+            && limit_server_federation::backfill::backfill_subscriptions(
+                &db_pool,
+                &id,
+                query.conversation.as_deref(),
+                (query.limit - page.events.len() as i64) as u32,
+            )
+            .await?
+                > 0
+        {
+            page = limit_server_cluster::history::load(&db_pool, &query, &remote).await?;
         }
 
-        send_sync!(sql_id_id);
-        send_sync!(sql_id_ts);
-        send_sync!(sql_ts_id);
-        send_sync!(sql_ts_ts);
-
-        Err(Status::internal("no implementation"))
+        let mut res = Response::new(SynchronizeResponse { events: vec![] });
+        let metadata = res.metadata_mut();
+        metadata.insert(
+            SYNC_HAS_MORE_METADATA,
+            MetadataValue::from_static(if page.has_more { "true" } else { "false" }),
+        );
+        if let Some((event, _)) = page.events.last() {
+            let cursor = limit_db::sync::Position::of(event, query.order()).encode(query.direction);
+            metadata.insert(SYNC_CURSOR_METADATA, cursor.parse().unwrap());
+        }
+        res.get_mut().events = page
+            .events
+            .into_iter()
+            .filter_map(|(event, body)| stored_event(event, body).ok())
+            .collect();
+        Ok(res)
     }
 }
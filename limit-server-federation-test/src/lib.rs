@@ -0,0 +1,1022 @@
+use std::{future::Future, pin::Pin};
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use futures::StreamExt;
+use limit_config::{Peer, GLOBAL_CONFIG};
+use limit_db::{
+    federation::PeerServerKey,
+    group::{GroupOp, Member, Role},
+    id::UserId,
+    run_sql,
+    schema::{EVENT, PEER_SERVER_KEY, USER},
+    DBLayer, DBPool,
+};
+use limit_deps::{tonic::transport::Server, *};
+use limit_server_auth::{
+    auth_service_client::AuthServiceClient, auth_service_server::AuthServiceServer, AuthService,
+    DoAuthRequest,
+};
+use limit_server_event::{
+    event_service_client::EventServiceClient, event_service_server::EventServiceServer, Detail,
+    Event, EventService, Message, ReceiveEventsRequest, SendEventRequest,
+};
+use limit_server_event_test::setup_user;
+use limit_server_federation::{
+    account::AccountService, account_service_client::AccountServiceClient,
+    account_service_server::AccountServiceServer, admin::FederationAdminService,
+    federation_admin_service_client::FederationAdminServiceClient,
+    federation_admin_service_server::FederationAdminServiceServer,
+    federation_service_client::FederationServiceClient,
+    federation_service_server::FederationServiceServer, BackfillRequest, DeliverEventsRequest,
+    ExportAccountRequest, FederationService, GetServerInfoRequest, GroupSyncRequest,
+    ListOutboxRequest, ListPolicyRulesRequest, PolicyAction, PolicyRuleRequest,
+    PublishMovedRequest, RetryOutboxRequest, SignedEvent,
+};
+use limit_test_utils::{do_with_port, test_service};
+
+/// name of the second server, both servers of a test share one config
+fn remote_server_name(port: u16) -> String {
+    format!("127.0.0.1:{port}")
+}
+
+pub async fn test_discovery(remote_port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_discovery started", module_path!());
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let remote_server = remote_server_name(remote_port);
+    tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
+
+    let info = FederationServiceClient::connect(format!("http://{remote_server}"))
+        .await?
+        .get_server_info(GetServerInfoRequest {})
+        .await?
+        .into_inner();
+    assert_eq!(info.server_name, remote_server);
+    assert_eq!(info.public_key, config.server_public_key);
+    assert!(info.features.iter().any(|f| f == "message"));
+
+    // keys of an earlier run
+    let pool = DBPool::new(config);
+    let forget_key = || {
+        run_sql!(
+            pool,
+            |mut con| {
+                diesel::delete(PEER_SERVER_KEY::table.find(&remote_server))
+                    .execute(&mut con)
+                    .map_err(|e| anyhow::anyhow!("{e}"))
+            },
+            |e| anyhow::anyhow!("{e}")
+        )
+    };
+    forget_key()?;
+
+    // trusted on first use
+    let peer = limit_server_federation::resolve_peer(&pool, &remote_server).await?;
+    assert_eq!(peer.endpoint, format!("http://{remote_server}"));
+    assert_eq!(peer.public_key, config.server_public_key);
+    let trusted = run_sql!(
+        pool,
+        |mut con| {
+            PEER_SERVER_KEY::table
+                .find(&remote_server)
+                .first::<PeerServerKey>(&mut con)
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    assert_eq!(trusted.public_key, config.server_public_key);
+
+    // the server presents another key than the trusted one
+    let (_, other_key) = limit_am::create_random_secret().unwrap();
+    run_sql!(
+        pool,
+        |mut con| {
+            diesel::update(PEER_SERVER_KEY::table.find(&remote_server))
+                .set(PEER_SERVER_KEY::PUBLIC_KEY.eq(&other_key))
+                .execute(&mut con)
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    limit_server_federation::resolver::forget_peer(&remote_server);
+    let status = limit_server_federation::resolve_peer(&pool, &remote_server)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    forget_key()?;
+    limit_server_federation::resolver::forget_peer(&remote_server);
+    tracing::info!("\t- test {}::test_discovery finished", module_path!());
+    Ok(())
+}
+
+pub async fn test_remote_message(port: u16, remote_port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_remote_message started", module_path!());
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let remote_server = remote_server_name(remote_port);
+    limit_server_federation::register_peer(Peer {
+        name: remote_server.clone(),
+        endpoint: format!("http://{remote_server}"),
+        public_key: config.server_public_key.clone(),
+    });
+    limit_server_federation::register_peer(Peer {
+        name: config.url.clone(),
+        endpoint: format!("http://127.0.0.1:{port}"),
+        public_key: config.server_public_key.clone(),
+    });
+
+    let (user_secret, user_pubkey) = limit_am::create_random_secret().unwrap();
+    let shared_key = limit_am::key_exchange(
+        limit_am::decode_secret(&config.server_secret_key).unwrap(),
+        limit_am::decode_public(&user_pubkey).unwrap(),
+    )
+    .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
+    let id1 = setup_user(&user_pubkey, &shared_key, false);
+    let id2 = setup_user(&user_pubkey, &shared_key, true);
+
+    let addr = format!("http://127.0.0.1:{port}");
+    let remote_addr = format!("http://{remote_server}");
+    let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
+    let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
+    let auth1 = auth_client
+        .do_auth(DoAuthRequest {
+            id: id1.clone(),
+            device_id: uuid::Uuid::new_v4().to_string(),
+            validated: passcode.clone(),
+        })
+        .await?;
+    let other_device = auth_client
+        .do_auth(DoAuthRequest {
+            id: id1.clone(),
+            device_id: uuid::Uuid::new_v4().to_string(),
+            validated: passcode.clone(),
+        })
+        .await?;
+    let auth2 = auth_client
+        .do_auth(DoAuthRequest {
+            id: id2.clone(),
+            device_id: uuid::Uuid::new_v4().to_string(),
+            validated: passcode,
+        })
+        .await?;
+    let mut client1 = EventServiceClient::connect(addr.clone()).await?;
+    // the receiver listens on the remote server
+    let mut client2 = EventServiceClient::connect(remote_addr.clone()).await?;
+    let mut receive = client2
+        .receive_events(ReceiveEventsRequest {
+            token: Some(auth2.get_ref().clone()),
+        })
+        .await?;
+    // the other device of the sender listens on its server
+    let mut own = EventServiceClient::connect(addr)
+        .await?
+        .receive_events(ReceiveEventsRequest {
+            token: Some(other_device.into_inner()),
+        })
+        .await?;
+
+    let send = |receiver_server: String| SendEventRequest {
+        token: Some(auth1.get_ref().clone()),
+        event: Some(Event {
+            event_id: "".to_string(),
+            ts: chrono::Utc::now().timestamp_millis() as u64,
+            sender: id1.clone(),
+            detail: Some(Detail::Message(Message {
+                receiver_id: id2.clone(),
+                receiver_server,
+                text: "hello from afar".to_string(),
+                extensions: Default::default(),
+            })),
+        }),
+    };
+
+    let status = client1
+        .send_event(send("not a server name".to_string()))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    let event_id = client1
+        .send_event(send(remote_server.clone()))
+        .await?
+        .into_inner()
+        .event_id;
+    let received = receive.get_mut().next().await.unwrap()?;
+    assert_eq!(received.event_id, event_id);
+    assert_eq!(received.sender, id1);
+    let Some(Detail::Message(message)) = received.detail else {
+        anyhow::bail!("no message detail");
+    };
+    assert_eq!(message.text, "hello from afar");
+    let received = own.get_mut().next().await.unwrap()?;
+    assert_eq!(received.event_id, event_id);
+
+    // envelopes not countersigned by the origin server are rejected
+    let mut federation_client = FederationServiceClient::connect(remote_addr).await?;
+    let forged_id = uuid::Uuid::new_v4().to_string();
+    let res = federation_client
+        .deliver_events(DeliverEventsRequest {
+            events: vec![SignedEvent {
+                event_id: forged_id.clone(),
+                ts: chrono::Utc::now().timestamp_millis() as u64,
+                sender: id1.clone(),
+                event_type: "message".to_string(),
+                receiver_id: id2.clone(),
+                receiver_server: remote_server.clone(),
+                text: "forged".to_string(),
+                extensions: Default::default(),
+                sender_signature: None,
+                server_signature: "forged".to_string(),
+                origin_server: config.url.clone(),
+                prev_events: vec![],
+                depth: 1,
+            }],
+        })
+        .await?
+        .into_inner();
+    assert!(res.accepted.is_empty());
+    assert_eq!(res.rejected.len(), 1);
+    assert_eq!(res.rejected[0].event_id, forged_id);
+    assert_eq!(
+        tonic::Code::from(res.rejected[0].code),
+        tonic::Code::PermissionDenied
+    );
+
+    // a peer can't send events of users of this server
+    let mut impersonated = SignedEvent {
+        event_id: uuid::Uuid::new_v4().to_string(),
+        ts: chrono::Utc::now().timestamp_millis() as u64,
+        sender: id2.clone(),
+        event_type: "message".to_string(),
+        receiver_id: id1.clone(),
+        receiver_server: config.url.clone(),
+        text: "impersonated".to_string(),
+        extensions: Default::default(),
+        sender_signature: None,
+        server_signature: String::new(),
+        origin_server: remote_server.clone(),
+        prev_events: vec![],
+        depth: 1,
+    };
+    let countersign = |event: &mut SignedEvent| -> anyhow::Result<()> {
+        let signed = limit_server_federation::from_signed_event(event.clone())?;
+        event.server_signature = limit_am::signature::countersign_event(
+            &limit_am::decode_secret(&config.server_secret_key).unwrap(),
+            &signed.countersigned(&signed.canonical().unwrap(), None),
+        )
+        .unwrap();
+        Ok(())
+    };
+    countersign(&mut impersonated)?;
+    // the events of its users delivered along are accepted regardless
+    let mut valid = SignedEvent {
+        event_id: uuid::Uuid::new_v4().to_string(),
+        sender: uuid::Uuid::new_v4().to_string(),
+        text: "delivered along".to_string(),
+        ..impersonated.clone()
+    };
+    countersign(&mut valid)?;
+    let res = FederationServiceClient::connect(format!("http://127.0.0.1:{port}"))
+        .await?
+        .deliver_events(DeliverEventsRequest {
+            events: vec![impersonated.clone(), valid.clone()],
+        })
+        .await?
+        .into_inner();
+    assert_eq!(res.accepted, [valid.event_id.clone()]);
+    assert_eq!(res.rejected.len(), 1);
+    assert_eq!(res.rejected[0].event_id, impersonated.event_id);
+    assert_eq!(
+        tonic::Code::from(res.rejected[0].code),
+        tonic::Code::PermissionDenied
+    );
+    assert!(res.rejected[0].message.contains("origin server"));
+    // the other device of the sender got the message to the remote receiver
+    // once, the valid event is next
+    let received = own.get_mut().next().await.unwrap()?;
+    assert_eq!(received.event_id, valid.event_id);
+
+    // the sender's signature is checked against the key of its origin server
+    let sign = |sender_secret: &str| -> anyhow::Result<SignedEvent> {
+        let mut event = SignedEvent {
+            event_id: uuid::Uuid::new_v4().to_string(),
+            sender: id1.clone(),
+            receiver_id: id2.clone(),
+            receiver_server: remote_server.clone(),
+            text: "signed".to_string(),
+            origin_server: config.url.clone(),
+            ..impersonated.clone()
+        };
+        let signed = limit_server_federation::from_signed_event(event.clone())?;
+        let canonical = signed.canonical().unwrap();
+        let sender_signature = limit_am::signature::sign_event(
+            &limit_am::decode_secret(sender_secret).unwrap(),
+            &canonical,
+        )
+        .unwrap();
+        event.server_signature = limit_am::signature::countersign_event(
+            &limit_am::decode_secret(&config.server_secret_key).unwrap(),
+            &signed.countersigned(&canonical, Some(&sender_signature)),
+        )
+        .unwrap();
+        event.sender_signature = Some(sender_signature);
+        Ok(event)
+    };
+    // signed by another key
+    let (forger_secret, _) = limit_am::create_random_secret().unwrap();
+    let (signed, forged) = (sign(&user_secret)?, sign(&forger_secret)?);
+    let res = federation_client
+        .deliver_events(DeliverEventsRequest {
+            events: vec![signed.clone(), forged.clone()],
+        })
+        .await?
+        .into_inner();
+    assert_eq!(res.accepted, [signed.event_id]);
+    assert_eq!(res.rejected.len(), 1);
+    assert_eq!(res.rejected[0].event_id, forged.event_id);
+    assert_eq!(
+        tonic::Code::from(res.rejected[0].code),
+        tonic::Code::Unauthenticated
+    );
+
+    tracing::info!("\t- test {}::test_remote_message finished", module_path!());
+    Ok(())
+}
+
+pub async fn test_outbox_retry(port: u16, remote_port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_outbox_retry started", module_path!());
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let remote_server = remote_server_name(remote_port);
+    // the remote server is down
+    limit_server_federation::register_peer(Peer {
+        name: remote_server.clone(),
+        endpoint: "http://127.0.0.1:1".to_string(),
+        public_key: config.server_public_key.clone(),
+    });
+
+    let (_, user_pubkey) = limit_am::create_random_secret().unwrap();
+    let shared_key = limit_am::key_exchange(
+        limit_am::decode_secret(&config.server_secret_key).unwrap(),
+        limit_am::decode_public(&user_pubkey).unwrap(),
+    )
+    .unwrap();
+    let id1 = setup_user(&user_pubkey, &shared_key, false);
+    let id2 = setup_user(&user_pubkey, &shared_key, true);
+
+    let addr = format!("http://127.0.0.1:{port}");
+    let remote_addr = format!("http://{remote_server}");
+    let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
+    let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
+    let auth1 = auth_client
+        .do_auth(DoAuthRequest {
+            id: id1.clone(),
+            device_id: uuid::Uuid::new_v4().to_string(),
+            validated: passcode.clone(),
+        })
+        .await?;
+    let auth2 = auth_client
+        .do_auth(DoAuthRequest {
+            id: id2.clone(),
+            device_id: uuid::Uuid::new_v4().to_string(),
+            validated: passcode,
+        })
+        .await?;
+    let mut client1 = EventServiceClient::connect(addr.clone()).await?;
+    let mut client2 = EventServiceClient::connect(remote_addr.clone()).await?;
+    let mut receive = client2
+        .receive_events(ReceiveEventsRequest {
+            token: Some(auth2.get_ref().clone()),
+        })
+        .await?;
+
+    let event_id = client1
+        .send_event(SendEventRequest {
+            token: Some(auth1.get_ref().clone()),
+            event: Some(Event {
+                event_id: "".to_string(),
+                ts: chrono::Utc::now().timestamp_millis() as u64,
+                sender: id1.clone(),
+                detail: Some(Detail::Message(Message {
+                    receiver_id: id2.clone(),
+                    receiver_server: remote_server.clone(),
+                    text: "eventually".to_string(),
+                    extensions: Default::default(),
+                })),
+            }),
+        })
+        .await?
+        .into_inner()
+        .event_id;
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    let mut admin_client = FederationAdminServiceClient::connect(addr).await?;
+    let denied = admin_client
+        .list_outbox(ListOutboxRequest {
+            admin_jwt: "not admin".to_string(),
+            destination: None,
+            dead_only: false,
+            count: 0,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(denied.code(), tonic::Code::PermissionDenied);
+    let entries = admin_client
+        .list_outbox(ListOutboxRequest {
+            admin_jwt: config.admin_jwt.clone(),
+            destination: Some(remote_server.clone()),
+            dead_only: false,
+            count: 0,
+        })
+        .await?
+        .into_inner()
+        .entries;
+    let entry = entries
+        .iter()
+        .find(|entry| entry.event_id == event_id)
+        .unwrap();
+    assert!(entry.attempts >= 1);
+    assert!(entry.last_error.is_some());
+    assert!(!entry.dead);
+
+    // the remote server is back
+    limit_server_federation::register_peer(Peer {
+        name: remote_server.clone(),
+        endpoint: remote_addr,
+        public_key: config.server_public_key.clone(),
+    });
+    let retried = admin_client
+        .retry_outbox(RetryOutboxRequest {
+            admin_jwt: config.admin_jwt.clone(),
+            seqs: vec![entry.seq],
+            destination: None,
+        })
+        .await?
+        .into_inner()
+        .retried;
+    assert_eq!(retried, 1);
+
+    let received = receive.get_mut().next().await.unwrap()?;
+    assert_eq!(received.event_id, event_id);
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+    let entries = admin_client
+        .list_outbox(ListOutboxRequest {
+            admin_jwt: config.admin_jwt.clone(),
+            destination: Some(remote_server),
+            dead_only: false,
+            count: 0,
+        })
+        .await?
+        .into_inner()
+        .entries;
+    assert!(entries.iter().all(|entry| entry.event_id != event_id));
+
+    tracing::info!("\t- test {}::test_outbox_retry finished", module_path!());
+    Ok(())
+}
+
+pub async fn test_policy(port: u16, remote_port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_policy started", module_path!());
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let remote_server = remote_server_name(remote_port);
+
+    let (_, user_pubkey) = limit_am::create_random_secret().unwrap();
+    let shared_key = limit_am::key_exchange(
+        limit_am::decode_secret(&config.server_secret_key).unwrap(),
+        limit_am::decode_public(&user_pubkey).unwrap(),
+    )
+    .unwrap();
+    let id1 = setup_user(&user_pubkey, &shared_key, false);
+    let id2 = setup_user(&user_pubkey, &shared_key, true);
+
+    let addr = format!("http://127.0.0.1:{port}");
+    let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
+    let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
+    let auth1 = auth_client
+        .do_auth(DoAuthRequest {
+            id: id1.clone(),
+            device_id: uuid::Uuid::new_v4().to_string(),
+            validated: passcode,
+        })
+        .await?;
+    let mut client1 = EventServiceClient::connect(addr.clone()).await?;
+    let mut admin_client = FederationAdminServiceClient::connect(addr).await?;
+    let send = || SendEventRequest {
+        token: Some(auth1.get_ref().clone()),
+        event: Some(Event {
+            event_id: "".to_string(),
+            ts: chrono::Utc::now().timestamp_millis() as u64,
+            sender: id1.clone(),
+            detail: Some(Detail::Message(Message {
+                receiver_id: id2.clone(),
+                receiver_server: remote_server.clone(),
+                text: "policy".to_string(),
+                extensions: Default::default(),
+            })),
+        }),
+    };
+    let rule = |pattern: &str, action: PolicyAction| PolicyRuleRequest {
+        admin_jwt: config.admin_jwt.clone(),
+        pattern: pattern.to_string(),
+        action: action as i32,
+    };
+
+    // a rule sent without an action
+    let status = admin_client
+        .add_policy_rule(rule(&config.url, PolicyAction::Unspecified))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    // outbound
+    let deny_remote = rule(&format!("127.0.0.1:{remote_port}*"), PolicyAction::Deny);
+    assert!(
+        admin_client
+            .add_policy_rule(deny_remote.clone())
+            .await?
+            .into_inner()
+            .changed
+    );
+    let rules = admin_client
+        .list_policy_rules(ListPolicyRulesRequest {
+            admin_jwt: config.admin_jwt.clone(),
+        })
+        .await?
+        .into_inner()
+        .rules;
+    assert!(
+        rules
+            .iter()
+            .any(|rule| rule.pattern == deny_remote.pattern && !rule.from_config)
+    );
+    let status = client1.send_event(send()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert!(status.message().contains("not allowed"));
+    assert!(
+        admin_client
+            .remove_policy_rule(deny_remote)
+            .await?
+            .into_inner()
+            .changed
+    );
+
+    // inbound, both servers share the policy but only the remote server
+    // checks the origin
+    let deny_origin = rule(&config.url, PolicyAction::Deny);
+    admin_client.add_policy_rule(deny_origin.clone()).await?;
+    limit_server_federation::register_peer(Peer {
+        name: remote_server.clone(),
+        endpoint: format!("http://{remote_server}"),
+        public_key: config.server_public_key.clone(),
+    });
+    let event_id = client1.send_event(send()).await?.into_inner().event_id;
+    tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
+    let entries = admin_client
+        .list_outbox(ListOutboxRequest {
+            admin_jwt: config.admin_jwt.clone(),
+            destination: Some(remote_server),
+            dead_only: true,
+            count: 0,
+        })
+        .await?
+        .into_inner()
+        .entries;
+    let entry = entries
+        .iter()
+        .find(|entry| entry.event_id == event_id)
+        .unwrap();
+    assert!(entry.last_error.as_ref().unwrap().contains("not allowed"));
+    admin_client.remove_policy_rule(deny_origin).await?;
+
+    tracing::info!("\t- test {}::test_policy finished", module_path!());
+    Ok(())
+}
+
+pub async fn test_backfill(port: u16, remote_port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_backfill started", module_path!());
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let remote_server = remote_server_name(remote_port);
+
+    let (_, user_pubkey) = limit_am::create_random_secret().unwrap();
+    let shared_key = limit_am::key_exchange(
+        limit_am::decode_secret(&config.server_secret_key).unwrap(),
+        limit_am::decode_public(&user_pubkey).unwrap(),
+    )
+    .unwrap();
+    let id1 = setup_user(&user_pubkey, &shared_key, false);
+    let id2 = setup_user(&user_pubkey, &shared_key, true);
+
+    let addr = format!("http://127.0.0.1:{port}");
+    let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
+    let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
+    let auth1 = auth_client
+        .do_auth(DoAuthRequest {
+            id: id1.clone(),
+            device_id: uuid::Uuid::new_v4().to_string(),
+            validated: passcode,
+        })
+        .await?;
+    let mut client1 = EventServiceClient::connect(addr).await?;
+    let mut event_ids = vec![];
+    for text in ["1", "2", "3"] {
+        let event_id = client1
+            .send_event(SendEventRequest {
+                token: Some(auth1.get_ref().clone()),
+                event: Some(Event {
+                    event_id: "".to_string(),
+                    ts: chrono::Utc::now().timestamp_millis() as u64,
+                    sender: id1.clone(),
+                    detail: Some(Detail::Message(Message {
+                        receiver_id: id2.clone(),
+                        receiver_server: remote_server.clone(),
+                        text: text.to_string(),
+                        extensions: Default::default(),
+                    })),
+                }),
+            })
+            .await?
+            .into_inner()
+            .event_id;
+        event_ids.push(event_id);
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
+
+    let request = |conversation_id: &str, before: Vec<String>, limit: u32| {
+        let mut req = BackfillRequest {
+            origin_server: config.url.clone(),
+            conversation_id: conversation_id.to_string(),
+            before,
+            limit,
+            signature: String::new(),
+        };
+        req.signature = limit_am::decode_secret(&config.server_secret_key)
+            .unwrap()
+            .sign(&limit_server_federation::backfill::request_payload(
+                &req,
+                &remote_server,
+            ))
+            .unwrap();
+        req
+    };
+    let texts = |events: Vec<SignedEvent>| {
+        events
+            .into_iter()
+            .map(|event| event.text)
+            .collect::<Vec<_>>()
+    };
+    let mut federation_client =
+        FederationServiceClient::connect(format!("http://{remote_server}")).await?;
+
+    // from the frontier, in topological order
+    let events = federation_client
+        .backfill(request(&id2, vec![], 0))
+        .await?
+        .into_inner()
+        .events;
+    assert_eq!(texts(events.clone()), ["1", "2", "3"]);
+    assert_eq!(events[1].prev_events, [event_ids[0].clone()]);
+    assert_eq!(events[2].depth, events[1].depth + 1);
+    let events = federation_client
+        .backfill(request(&id2, vec![event_ids[2].clone()], 0))
+        .await?
+        .into_inner()
+        .events;
+    assert_eq!(texts(events), ["1", "2"]);
+    let events = federation_client
+        .backfill(request(&id2, vec![event_ids[2].clone()], 1))
+        .await?
+        .into_inner()
+        .events;
+    assert_eq!(texts(events), ["2"]);
+
+    // the signature covers the request
+    let mut forged = request(&id2, vec![], 0);
+    forged.limit = 1;
+    let status = federation_client.backfill(forged).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    // conversations without events of the requesting server
+    let status = federation_client
+        .backfill(request(&uuid::Uuid::new_v4().to_string(), vec![], 0))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    // both servers share the database, the events are known already
+    let pool = DBPool::new(config);
+    assert_eq!(
+        limit_server_federation::backfill::backfill(&pool, &remote_server, &id2, vec![], 0).await?,
+        0
+    );
+
+    tracing::info!("\t- test {}::test_backfill finished", module_path!());
+    Ok(())
+}
+
+pub async fn test_group_state(remote_port: u16) -> anyhow::Result<()> {
+    tracing::info!("\t- test {}::test_group_state started", module_path!());
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let remote_server = remote_server_name(remote_port);
+    let pool = DBPool::new(config);
+
+    let group_id = uuid::Uuid::new_v4().to_string();
+    let member = Member {
+        user_id: uuid::Uuid::new_v4().to_string(),
+        server: remote_server.clone(),
+    };
+    let add = limit_server_federation::group::submit(
+        &pool,
+        &group_id,
+        "admin",
+        &GroupOp::AddMember {
+            member: member.clone(),
+        },
+    )?;
+    let rename = limit_server_federation::group::submit(
+        &pool,
+        &group_id,
+        "admin",
+        &GroupOp::SetName {
+            name: Some("limit".to_string()),
+        },
+    )?;
+    let (_, state) = limit_server_federation::group::load_group(&pool, &group_id)?;
+    assert_eq!(state.name.get().map(String::as_str), Some("limit"));
+    assert_eq!(state.role_of(&member.user_id), Some(Role::Member));
+    assert_eq!(add.id, group_id);
+    // only moderators of the group change it
+    let status = limit_server_federation::group::submit(
+        &pool,
+        &group_id,
+        "mallory",
+        &GroupOp::SetName {
+            name: Some("mallory".to_string()),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    let request = |group_id: &str, known: Vec<String>| {
+        let mut req = GroupSyncRequest {
+            origin_server: config.url.clone(),
+            group_id: group_id.to_string(),
+            known,
+            events: vec![],
+            signature: String::new(),
+        };
+        req.signature = limit_am::decode_secret(&config.server_secret_key)
+            .unwrap()
+            .sign(&limit_server_federation::group::request_payload(
+                &req,
+                &remote_server,
+            ))
+            .unwrap();
+        req
+    };
+    let mut federation_client =
+        FederationServiceClient::connect(format!("http://{remote_server}")).await?;
+    let res = federation_client
+        .sync_group_state(request(&group_id, vec![add.id.clone()]))
+        .await?
+        .into_inner();
+    assert_eq!(res.events.len(), 1);
+    assert_eq!(res.events[0].id, rename.id);
+    assert_eq!(res.known.len(), 2);
+    let res = federation_client
+        .sync_group_state(request(&group_id, vec![add.id, rename.id]))
+        .await?
+        .into_inner();
+    assert!(res.events.is_empty());
+
+    // the signature covers the request
+    let mut forged = request(&group_id, vec![]);
+    forged.known.push(uuid::Uuid::new_v4().to_string());
+    let status = federation_client
+        .sync_group_state(forged)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    // groups without events of the requesting server or members on it
+    let status = federation_client
+        .sync_group_state(request(&uuid::Uuid::new_v4().to_string(), vec![]))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    // both servers share the database, the events are known already
+    assert_eq!(
+        limit_server_federation::group::sync_group(&pool, &group_id, &remote_server).await?,
+        0
+    );
+
+    tracing::info!("\t- test {}::test_group_state finished", module_path!());
+    Ok(())
+}
+
+pub async fn test_account_migration(port: u16, remote_port: u16) -> anyhow::Result<()> {
+    tracing::info!(
+        "\t- test {}::test_account_migration started",
+        module_path!()
+    );
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let remote_server = remote_server_name(remote_port);
+    let pool = DBPool::new(config);
+
+    let (_, user_pubkey) = limit_am::create_random_secret().unwrap();
+    let shared_key = limit_am::key_exchange(
+        limit_am::decode_secret(&config.server_secret_key).unwrap(),
+        limit_am::decode_public(&user_pubkey).unwrap(),
+    )
+    .unwrap();
+    let id1 = setup_user(&user_pubkey, &shared_key, false);
+    let id2 = setup_user(&user_pubkey, &shared_key, true);
+
+    let addr = format!("http://127.0.0.1:{port}");
+    let mut auth_client = AuthServiceClient::connect(addr.clone()).await?;
+    let passcode = limit_am::aes256_encrypt_string(&shared_key, "123456").unwrap();
+    let mut auth = vec![];
+    for id in [&id1, &id2] {
+        auth.push(
+            auth_client
+                .do_auth(DoAuthRequest {
+                    id: id.clone(),
+                    device_id: uuid::Uuid::new_v4().to_string(),
+                    validated: passcode.clone(),
+                })
+                .await?
+                .into_inner(),
+        );
+    }
+    let export = || ExportAccountRequest {
+        jwt: auth[1].jwt.clone(),
+        destination: remote_server.clone(),
+    };
+    let mut account_client = AccountServiceClient::connect(addr.clone()).await?;
+    let mut remote_account_client =
+        AccountServiceClient::connect(format!("http://{remote_server}")).await?;
+
+    let bundle = account_client.export_account(export()).await?.into_inner();
+    assert_eq!(bundle.origin_server, config.url);
+    assert_eq!(bundle.user_id, id2);
+
+    // the signature covers the account
+    let mut forged = bundle.clone();
+    forged.account = forged.account.replace(&user_pubkey, "forged");
+    let status = remote_account_client
+        .import_account(forged)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    // bundles are imported where they were exported for
+    let status = account_client
+        .import_account(bundle.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    // both servers share the database, a user of the remote server can't be
+    // replaced
+    let status = remote_account_client
+        .import_account(bundle.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+    // the remote server doesn't know the user yet
+    run_sql!(
+        pool,
+        |mut con| diesel::delete(USER::table.find(&id2)).execute(&mut con),
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    let res = remote_account_client
+        .import_account(bundle)
+        .await?
+        .into_inner();
+    assert_eq!(res.user_id, format!("{id2}@{remote_server}"));
+    let moved = res.moved.unwrap();
+    assert_eq!(moved.from_server, config.url);
+    assert_eq!(moved.to_server, remote_server);
+    let pubkey = run_sql!(
+        pool,
+        |mut con| {
+            USER::table
+                .find(&id2)
+                .select(USER::PUBKEY)
+                .first::<String>(&mut con)
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    assert_eq!(pubkey, user_pubkey);
+    assert_eq!(
+        limit_server_federation::account::route(&pool, &UserId::from_parts(&id2, &config.url)?)?
+            .to_string(),
+        res.user_id
+    );
+    let status = account_client.export_account(export()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    // events for the old server name follow the user
+    let event_id = EventServiceClient::connect(addr)
+        .await?
+        .send_event(SendEventRequest {
+            token: Some(auth[0].clone()),
+            event: Some(Event {
+                event_id: "".to_string(),
+                ts: chrono::Utc::now().timestamp_millis() as u64,
+                sender: id1.clone(),
+                detail: Some(Detail::Message(Message {
+                    receiver_id: id2.clone(),
+                    receiver_server: config.url.clone(),
+                    text: "moved".to_string(),
+                    extensions: Default::default(),
+                })),
+            }),
+        })
+        .await?
+        .into_inner()
+        .event_id;
+    tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
+    // stored once here and on the remote server, which share the database
+    let stored = run_sql!(
+        pool,
+        |mut con| {
+            EVENT::table
+                .find(&event_id)
+                .count()
+                .get_result::<i64>(&mut con)
+        },
+        |e| anyhow::anyhow!("{e}")
+    )?;
+    assert_eq!(stored, 1);
+
+    // moved records are signed by the server the user left
+    let mut federation_client =
+        FederationServiceClient::connect(format!("http://{remote_server}")).await?;
+    let stored = federation_client
+        .publish_moved(PublishMovedRequest {
+            moved: Some(moved.clone()),
+        })
+        .await?
+        .into_inner()
+        .stored;
+    assert!(!stored);
+    let mut forged = moved;
+    forged.ts += 1;
+    let status = federation_client
+        .publish_moved(PublishMovedRequest {
+            moved: Some(forged),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    tracing::info!(
+        "\t- test {}::test_account_migration finished",
+        module_path!()
+    );
+    Ok(())
+}
+
+pub async fn integration_test() {
+    do_with_port(|port| async move {
+        do_with_port(|remote_port| async move {
+            // the tests register the remote server and change the policy, so they run
+            // one by one
+            let tasks: Vec<_> = vec![Box::pin(async move {
+                test_discovery(remote_port).await?;
+                test_remote_message(port, remote_port).await?;
+                test_outbox_retry(port, remote_port).await?;
+                test_policy(port, remote_port).await?;
+                test_backfill(port, remote_port).await?;
+                test_group_state(remote_port).await?;
+                test_account_migration(port, remote_port).await
+            })
+                as Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>];
+
+            let remote_addr = format!("127.0.0.1:{remote_port}").parse().unwrap();
+            let remote = tokio::spawn(
+                Server::builder()
+                    .layer(DBLayer)
+                    .add_service(EventServiceServer::new(EventService))
+                    .add_service(FederationServiceServer::new(
+                        FederationService::with_server_name(remote_server_name(remote_port)),
+                    ))
+                    .add_service(AccountServiceServer::new(AccountService::with_server_name(
+                        remote_server_name(remote_port),
+                    )))
+                    .serve(remote_addr),
+            );
+            test_service! {
+                port,
+                Server::builder()
+                    .layer(DBLayer)
+                    .add_service(AuthServiceServer::new(AuthService))
+                    .add_service(EventServiceServer::new(EventService))
+                    .add_service(FederationServiceServer::new(FederationService::new()))
+                    .add_service(AccountServiceServer::new(AccountService::new()))
+                    .add_service(FederationAdminServiceServer::new(FederationAdminService)),
+                tasks
+            };
+            remote.abort();
+        })
+        .await
+        .await
+    })
+    .await
+    .await;
+}
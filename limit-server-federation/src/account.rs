@@ -0,0 +1,514 @@
+//! Users leaving a server for another one.
+//!
+//! A user exports its account from its server as an [`AccountBundle`] signed
+//! for the new server, which imports it and asks the old server to record the
+//! move. The old server signs a [`SignedMoved`] record and publishes it to the
+//! servers it knows, every server then routes events for the user to where it
+//! lives now, see [`route`].
+
+use std::time::Duration;
+
+use anyhow::Context;
+use diesel::{QueryDsl, RunQueryDsl};
+use limit_config::GLOBAL_CONFIG;
+use limit_db::{
+    account::{Account, Moved},
+    get_db_layer,
+    id::{ServerName, UserId},
+    run_sql,
+    schema::USER,
+    DBPool, RedisClient,
+};
+use limit_deps::{metrics::increment_counter, *};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    account_service_server, federation_service_client::FederationServiceClient, outbox::now_millis,
+    policy, resolve_peer, resolver, AccountBundle, ExportAccountRequest, ImportAccountResponse,
+    MoveAccountRequest, PublishMovedRequest, PublishMovedResponse, SignedMoved,
+};
+
+/// how long an exported account can be imported
+pub const BUNDLE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+pub fn to_signed_moved(moved: &Moved) -> SignedMoved {
+    SignedMoved {
+        user_id: moved.id.clone(),
+        from_server: moved.from_server.clone(),
+        to_server: moved.to_server.clone(),
+        ts: moved.ts,
+        signature: moved.signature.clone(),
+    }
+}
+
+pub fn from_signed_moved(moved: SignedMoved) -> Moved {
+    Moved {
+        id: moved.user_id,
+        from_server: moved.from_server,
+        to_server: moved.to_server,
+        ts: moved.ts,
+        signature: moved.signature,
+    }
+}
+
+/// the bytes the exporting server signs
+pub fn bundle_payload(bundle: &AccountBundle) -> Vec<u8> {
+    [
+        bundle.origin_server.as_str(),
+        &bundle.destination,
+        &bundle.user_id,
+        &bundle.ts.to_string(),
+        &bundle.account,
+    ]
+    .join("\n")
+    .into_bytes()
+}
+
+/// the bytes the importing server signs to record a move on `destination`
+pub fn move_payload(req: &MoveAccountRequest, destination: &str) -> Vec<u8> {
+    let bundle_signature = req
+        .bundle
+        .as_ref()
+        .map(|bundle| bundle.signature.as_str())
+        .unwrap_or_default();
+    [req.origin_server.as_str(), destination, bundle_signature]
+        .join("\n")
+        .into_bytes()
+}
+
+fn sign(payload: &[u8]) -> Result<String, Status> {
+    limit_am::decode_secret(&GLOBAL_CONFIG.get().unwrap().server_secret_key)
+        .and_then(|key| key.sign(payload))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })
+}
+
+fn verify(public_key: &str, payload: &[u8], signature: &str) -> Result<(), Status> {
+    limit_am::decode_public(public_key)
+        .and_then(|key| key.verify(payload, signature))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::permission_denied("invalid server signature")
+        })
+}
+
+/// where `user` lives now, following the moved records known to this server
+pub fn route(pool: &DBPool, user: &UserId) -> Result<UserId, Status> {
+    run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::account::follow_moved(&mut conn, user).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// check `sender` of an event countersigned by `origin_server` is a user of
+/// that server, where it lives now following the moved records known to
+/// `server_name`. The users stored here are users of
+/// [`limit_config::Config::url`], they send from other servers only once they
+/// moved there.
+pub fn check_sender(
+    pool: &DBPool,
+    server_name: &str,
+    sender: &str,
+    origin_server: &str,
+) -> Result<(), Status> {
+    let home = if origin_server != server_name && ServerName::current().as_str() == server_name {
+        let exists = run_sql!(
+            pool,
+            |mut conn| {
+                USER::table
+                    .find(sender)
+                    .count()
+                    .get_result::<i64>(&mut conn)
+                    .map(|count| count > 0)
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?;
+        if exists { server_name } else { origin_server }
+    } else {
+        origin_server
+    };
+    let sender_id = UserId::from_parts(sender, home).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    if route(pool, &sender_id)?.server().as_str() != origin_server {
+        increment_counter!("federation_sender_rejected", "peer" => origin_server.to_string());
+        tracing::error!("{} sent an event of {}", origin_server, sender_id);
+        return Err(Status::permission_denied(
+            "sender is not a user of the origin server",
+        ));
+    }
+    Ok(())
+}
+
+fn insert_moved(pool: &DBPool, moved: &Moved) -> Result<bool, Status> {
+    run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::account::insert_moved(&mut conn, moved).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// the account of `user`, a user of this server, signed for `destination`
+pub fn export_account(
+    pool: &DBPool,
+    user: &UserId,
+    destination: &str,
+) -> Result<AccountBundle, Status> {
+    resolver::check_server_name(destination)?;
+    policy::check_peer(pool, destination)?;
+    if route(pool, user)? != *user {
+        tracing::error!("{} moved away", user);
+        return Err(Status::failed_precondition("user moved away"));
+    }
+    let id = user.uuid().to_string();
+    let account = run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::account::export(&mut conn, &id).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    let mut bundle = AccountBundle {
+        origin_server: user.server().to_string(),
+        destination: destination.to_string(),
+        user_id: id,
+        ts: now_millis(),
+        account: serde_json::to_string(&account).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?,
+        signature: String::new(),
+    };
+    bundle.signature = sign(&bundle_payload(&bundle))?;
+    Ok(bundle)
+}
+
+/// import an account exported for `server_name`, then have the exporting
+/// server record the move
+pub async fn import_account(
+    pool: &DBPool,
+    redis: &RedisClient,
+    server_name: &str,
+    bundle: AccountBundle,
+) -> Result<ImportAccountResponse, Status> {
+    if bundle.destination != server_name {
+        tracing::error!("account {} is not for this server", bundle.user_id);
+        return Err(Status::invalid_argument("destination mismatch"));
+    }
+    let age = now_millis() - bundle.ts;
+    if !(0..BUNDLE_LIFETIME.as_millis() as i64).contains(&age) {
+        tracing::error!("account {} expired", bundle.user_id);
+        return Err(Status::invalid_argument("account bundle expired"));
+    }
+    policy::check_peer(pool, &bundle.origin_server)?;
+    let peer = resolve_peer(pool, &bundle.origin_server).await?;
+    verify(
+        &peer.public_key,
+        &bundle_payload(&bundle),
+        &bundle.signature,
+    )?;
+    let account = serde_json::from_str::<Account>(&bundle.account).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    if account.id != bundle.user_id
+        || account
+            .subscriptions
+            .iter()
+            .any(|sub| sub.user_id != bundle.user_id)
+    {
+        return Err(Status::invalid_argument("account of another user"));
+    }
+    let user = UserId::from_parts(&bundle.user_id, server_name).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+
+    // a server can only hand over its own users, not ours
+    let exists = run_sql!(
+        pool,
+        |mut conn| {
+            USER::table
+                .find(&bundle.user_id)
+                .count()
+                .get_result::<i64>(&mut conn)
+                .map(|count| count > 0)
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    if exists && route(pool, &user)? == user {
+        tracing::error!("{} tried to import {}", bundle.origin_server, user);
+        return Err(Status::already_exists("user exists"));
+    }
+    // the shared key of the old server isn't ours
+    let server_secret_key =
+        limit_am::decode_secret(&GLOBAL_CONFIG.get().unwrap().server_secret_key).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+    let sharedkey = limit_am::decode_public(&account.pubkey)
+        .and_then(|pubkey| limit_am::key_exchange(server_secret_key, pubkey))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::invalid_argument(e.to_string())
+        })?;
+    run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::account::import(&mut conn, &account, &sharedkey).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    // what auth cached of a user moving back is stale
+    let mut conn = redis.get_async_connection().await.map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?;
+    redis::cmd("DEL")
+        .arg(format!("{}:sharedkey", bundle.user_id))
+        .arg(format!("{}:duration", bundle.user_id))
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+
+    let mut req = MoveAccountRequest {
+        origin_server: server_name.to_string(),
+        bundle: Some(bundle),
+        signature: String::new(),
+    };
+    req.signature = sign(&move_payload(&req, &peer.name))?;
+    let moved = FederationServiceClient::connect(peer.endpoint.clone())
+        .await
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::unavailable(e.to_string())
+        })?
+        .move_account(req)
+        .await?
+        .into_inner();
+    let moved = from_signed_moved(moved);
+    if moved.id != user.uuid().to_string()
+        || moved.from_server != peer.name
+        || moved.to_server != server_name
+    {
+        return Err(Status::internal("unexpected moved record"));
+    }
+    verify(&peer.public_key, &moved.canonical(), &moved.signature)?;
+    insert_moved(pool, &moved)?;
+    increment_counter!("federation_account_imported", "peer" => peer.name.clone());
+    Ok(ImportAccountResponse {
+        user_id: user.to_string(),
+        moved: Some(to_signed_moved(&moved)),
+    })
+}
+
+/// record that a user of `server_name` moved to the server which imported the
+/// account, then publish it in the background
+pub(crate) async fn serve_move(
+    pool: &DBPool,
+    server_name: &str,
+    req: MoveAccountRequest,
+) -> Result<SignedMoved, Status> {
+    let bundle = req
+        .bundle
+        .as_ref()
+        .ok_or_else(|| Status::invalid_argument("no bundle"))?;
+    // only accounts exported by this server, to the requesting one
+    if bundle.origin_server != server_name || bundle.destination != req.origin_server {
+        return Err(Status::invalid_argument("bundle mismatch"));
+    }
+    verify(
+        &GLOBAL_CONFIG.get().unwrap().server_public_key,
+        &bundle_payload(bundle),
+        &bundle.signature,
+    )?;
+    policy::check_peer(pool, &req.origin_server)?;
+    let peer = resolve_peer(pool, &req.origin_server).await?;
+    verify(
+        &peer.public_key,
+        &move_payload(&req, server_name),
+        &req.signature,
+    )?;
+
+    let user = UserId::from_parts(&bundle.user_id, server_name).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    if route(pool, &user)? != user {
+        tracing::error!("{} moved away before", user);
+        return Err(Status::failed_precondition("user moved away"));
+    }
+    let mut moved = Moved {
+        id: bundle.user_id.clone(),
+        from_server: server_name.to_string(),
+        to_server: req.origin_server.clone(),
+        ts: now_millis(),
+        signature: String::new(),
+    };
+    moved.signature = sign(&moved.canonical())?;
+    insert_moved(pool, &moved)?;
+    tracing::info!("{} moved to {}", user, moved.to_server);
+
+    let pool = pool.clone();
+    let published = moved.clone();
+    tokio::spawn(async move {
+        if let Err(status) = publish_moved(&pool, &published).await {
+            tracing::error!("publishing move of {} failed: {}", published.id, status);
+        }
+    });
+    Ok(to_signed_moved(&moved))
+}
+
+/// send a moved record to the servers this server knows, servers failing to
+/// receive it are skipped
+pub async fn publish_moved(pool: &DBPool, moved: &Moved) -> Result<(), Status> {
+    let current_server_url = GLOBAL_CONFIG.get().unwrap().url.as_str();
+    for server in resolver::known_peers(pool)? {
+        if server == current_server_url || server == moved.from_server || server == moved.to_server
+        {
+            continue;
+        }
+        let res = match policy::check_peer(pool, &server) {
+            Ok(()) => resolve_peer(pool, &server).await,
+            Err(status) => Err(status),
+        };
+        let res = match res {
+            Ok(peer) => match FederationServiceClient::connect(peer.endpoint).await {
+                Ok(mut client) => client
+                    .publish_moved(PublishMovedRequest {
+                        moved: Some(to_signed_moved(moved)),
+                    })
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(Status::unavailable(e.to_string())),
+            },
+            Err(status) => Err(status),
+        };
+        if let Err(status) = res {
+            tracing::warn!("publishing move to {} failed: {}", server, status);
+        }
+    }
+    Ok(())
+}
+
+/// store a moved record signed by the server the user left
+pub(crate) async fn serve_publish(
+    pool: &DBPool,
+    req: PublishMovedRequest,
+) -> Result<PublishMovedResponse, Status> {
+    let moved = from_signed_moved(
+        req.moved
+            .ok_or_else(|| Status::invalid_argument("no moved record"))?,
+    );
+    resolver::check_server_name(&moved.to_server)?;
+    policy::check_peer(pool, &moved.from_server)?;
+    let peer = resolve_peer(pool, &moved.from_server).await?;
+    verify(&peer.public_key, &moved.canonical(), &moved.signature)?;
+    Ok(PublishMovedResponse {
+        stored: insert_moved(pool, &moved)?,
+    })
+}
+
+#[derive(Debug, Clone)]
+// require db
+pub struct AccountService {
+    /// the server name accounts are imported for
+    server_name: String,
+}
+
+impl AccountService {
+    pub fn new() -> Self {
+        Self::with_server_name(GLOBAL_CONFIG.get().unwrap().url.clone())
+    }
+
+    /// import accounts exported for another server name than
+    /// [`limit_config::Config::url`]
+    pub fn with_server_name(server_name: impl Into<String>) -> Self {
+        Self {
+            server_name: server_name.into(),
+        }
+    }
+}
+
+impl Default for AccountService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tonic::async_trait]
+impl account_service_server::AccountService for AccountService {
+    async fn export_account(
+        &self,
+        req: Request<ExportAccountRequest>,
+    ) -> Result<Response<AccountBundle>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        let req = req.into_inner();
+        let sub = limit_server_auth::decode_jwt(&req.jwt)?.parse_sub()?;
+        let server_name = self.server_name.parse::<ServerName>().map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+        let user = UserId::new(sub.id.uuid(), server_name);
+        export_account(&db_pool, &user, &req.destination).map(Response::new)
+    }
+
+    async fn import_account(
+        &self,
+        req: Request<AccountBundle>,
+    ) -> Result<Response<ImportAccountResponse>, Status> {
+        let (_, redis, db_pool) = get_db_layer!(req);
+        import_account(&db_pool, &redis, &self.server_name, req.into_inner())
+            .await
+            .map(Response::new)
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::Context;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use limit_config::GLOBAL_CONFIG;
+use limit_db::{
+    federation::{
+        FederationPolicyRule, OutboxEvent, OUTBOX_STATE_DEAD, OUTBOX_STATE_PENDING,
+        POLICY_ACTION_ALLOW, POLICY_ACTION_DENY,
+    },
+    get_db_layer, run_sql,
+    schema::{FEDERATION_OUTBOX, FEDERATION_POLICY_RULE},
+};
+use limit_deps::*;
+use limit_server_cluster::check_admin;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    federation_admin_service_server, outbox, policy, ListOutboxRequest, ListOutboxResponse,
+    ListPolicyRulesRequest, ListPolicyRulesResponse, OutboxEntry, PolicyAction, PolicyRule,
+    PolicyRuleRequest, PolicyRuleResponse, RetryOutboxRequest, RetryOutboxResponse,
+};
+
+fn rule_of(req: &PolicyRuleRequest) -> Result<FederationPolicyRule, Status> {
+    if req.pattern.is_empty() {
+        return Err(Status::invalid_argument("empty pattern"));
+    }
+    let action = match PolicyAction::from_i32(req.action) {
+        Some(PolicyAction::Allow) => POLICY_ACTION_ALLOW,
+        Some(PolicyAction::Deny) => POLICY_ACTION_DENY,
+        Some(PolicyAction::Unspecified) | None => {
+            return Err(Status::invalid_argument("invalid action"));
+        }
+    };
+    Ok(FederationPolicyRule {
+        pattern: req.pattern.clone(),
+        action: action.to_string(),
+    })
+}
+
+#[derive(Debug, Clone)]
+// require db
+pub struct FederationAdminService;
+
+#[tonic::async_trait]
+impl federation_admin_service_server::FederationAdminService for FederationAdminService {
+    async fn list_outbox(
+        &self,
+        req: Request<ListOutboxRequest>,
+    ) -> Result<Response<ListOutboxResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+
+        let count = match req.count {
+            1..=1000 => req.count as i64,
+            _ => 100,
+        };
+        let mut sql = FEDERATION_OUTBOX::table
+            .order(FEDERATION_OUTBOX::SEQ.asc())
+            .limit(count)
+            .into_boxed();
+        if let Some(destination) = req.destination {
+            sql = sql.filter(FEDERATION_OUTBOX::DESTINATION.eq(destination));
+        }
+        if req.dead_only {
+            sql = sql.filter(FEDERATION_OUTBOX::STATE.eq(OUTBOX_STATE_DEAD));
+        }
+        let events = run_sql!(
+            db_pool,
+            |mut conn| {
+                sql.load::<OutboxEvent>(&mut conn).map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?;
+        Ok(Response::new(ListOutboxResponse {
+            entries: events
+                .into_iter()
+                .map(|event| OutboxEntry {
+                    seq: event.seq as i64,
+                    event_id: event.event_id,
+                    destination: event.destination,
+                    attempts: event.attempts as u32,
+                    next_attempt_at: event.next_attempt_at,
+                    last_error: event.last_error,
+                    dead: event.state == OUTBOX_STATE_DEAD,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn retry_outbox(
+        &self,
+        req: Request<RetryOutboxRequest>,
+    ) -> Result<Response<RetryOutboxResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        let req = req.into_inner();
+        check_admin(&req.admin_jwt)?;
+
+        let mut sql = diesel::update(FEDERATION_OUTBOX::table).into_boxed();
+        if !req.seqs.is_empty() {
+            let seqs = req.seqs.iter().map(|seq| *seq as i32).collect::<Vec<_>>();
+            sql = sql.filter(FEDERATION_OUTBOX::SEQ.eq_any(seqs));
+        }
+        match req.destination {
+            Some(destination) => {
+                sql = sql.filter(FEDERATION_OUTBOX::DESTINATION.eq(destination));
+            }
+            None if req.seqs.is_empty() => {
+                return Err(Status::invalid_argument("no seqs or destination"));
+            }
+            None => {}
+        }
+        let retried = run_sql!(
+            db_pool,
+            |mut conn| {
+                sql.set((
+                    FEDERATION_OUTBOX::ATTEMPTS.eq(0),
+                    FEDERATION_OUTBOX::NEXT_ATTEMPT_AT.eq(outbox::now_millis()),
+                    FEDERATION_OUTBOX::STATE.eq(OUTBOX_STATE_PENDING),
+                ))
+                .execute(&mut conn)
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?;
+        outbox::start_outbox_worker(db_pool);
+        outbox::wake_outbox_worker();
+        Ok(Response::new(RetryOutboxResponse {
+            retried: retried as u64,
+        }))
+    }
+
+    async fn list_policy_rules(
+        &self,
+        req: Request<ListPolicyRulesRequest>,
+    ) -> Result<Response<ListPolicyRulesResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        check_admin(&req.get_ref().admin_jwt)?;
+
+        let policy = &GLOBAL_CONFIG.get().unwrap().federation_policy;
+        let config_rules = policy
+            .allow
+            .iter()
+            .map(|pattern| (pattern, PolicyAction::Allow))
+            .chain(
+                policy
+                    .deny
+                    .iter()
+                    .map(|pattern| (pattern, PolicyAction::Deny)),
+            )
+            .map(|(pattern, action)| PolicyRule {
+                pattern: pattern.clone(),
+                action: action as i32,
+                from_config: true,
+            });
+        let admin_rules = policy::admin_rules(&db_pool)?
+            .into_iter()
+            .map(|rule| PolicyRule {
+                action: if rule.action == POLICY_ACTION_DENY {
+                    PolicyAction::Deny
+                } else {
+                    PolicyAction::Allow
+                } as i32,
+                pattern: rule.pattern,
+                from_config: false,
+            });
+        Ok(Response::new(ListPolicyRulesResponse {
+            rules: config_rules.chain(admin_rules).collect(),
+        }))
+    }
+
+    async fn add_policy_rule(
+        &self,
+        req: Request<PolicyRuleRequest>,
+    ) -> Result<Response<PolicyRuleResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        check_admin(&req.get_ref().admin_jwt)?;
+        let rule = rule_of(req.get_ref())?;
+
+        let changed = run_sql!(
+            db_pool,
+            |mut conn| {
+                diesel::insert_or_ignore_into(FEDERATION_POLICY_RULE::table)
+                    .values(rule)
+                    .execute(&mut conn)
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?;
+        policy::reload_admin_rules();
+        Ok(Response::new(PolicyRuleResponse {
+            changed: changed > 0,
+        }))
+    }
+
+    async fn remove_policy_rule(
+        &self,
+        req: Request<PolicyRuleRequest>,
+    ) -> Result<Response<PolicyRuleResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        check_admin(&req.get_ref().admin_jwt)?;
+        let rule = rule_of(req.get_ref())?;
+
+        let changed = run_sql!(
+            db_pool,
+            |mut conn| {
+                diesel::delete(
+                    FEDERATION_POLICY_RULE::table
+                        .filter(FEDERATION_POLICY_RULE::PATTERN.eq(&rule.pattern))
+                        .filter(FEDERATION_POLICY_RULE::ACTION.eq(&rule.action)),
+                )
+                .execute(&mut conn)
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?;
+        policy::reload_admin_rules();
+        Ok(Response::new(PolicyRuleResponse {
+            changed: changed > 0,
+        }))
+    }
+}
@@ -0,0 +1,288 @@
+//! History of conversations from before this server took part in them.
+//!
+//! The server of a conversation's receiver stores all of its events, other
+//! servers backfill from it. Backfilled events are verified against their
+//! origin servers like delivered ones and stored once, but not published.
+
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use diesel::{ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl};
+use limit_config::GLOBAL_CONFIG;
+use limit_db::{
+    run_sql,
+    schema::{EVENT_SIGNATURE, EVENT_SUBSCRIPTIONS, MESSAGE},
+    DBPool,
+};
+use limit_deps::{metrics::increment_counter, *};
+use once_cell::sync::Lazy;
+use tonic::Status;
+
+use crate::{
+    account, federation_service_client::FederationServiceClient, policy, resolve_peer, resolver,
+    store_event, to_signed_event, verify_signature, BackfillRequest, SignedEvent,
+};
+
+/// events returned when a request has no limit
+pub const DEFAULT_LIMIT: u32 = 50;
+/// events returned at most by one request
+pub const MAX_LIMIT: u32 = 500;
+/// events after a gap in history looked at by one synchronization
+const HORIZON_SIZE: i64 = 16;
+/// how long a gap which failed to backfill or had nothing to backfill isn't
+/// tried again
+pub const RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// a conversation and the events after a gap in its history
+type Gap = (String, Vec<String>);
+
+/// gaps which failed to backfill or had nothing to backfill, with the time
+/// they were tried
+static ATTEMPTS: Lazy<RwLock<HashMap<Gap, Instant>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// the bytes a server signs to request a backfill from `destination`
+pub fn request_payload(req: &BackfillRequest, destination: &str) -> Vec<u8> {
+    [
+        req.origin_server.as_str(),
+        destination,
+        &req.conversation_id,
+        &req.before.join(","),
+        &req.limit.to_string(),
+    ]
+    .join("\n")
+    .into_bytes()
+}
+
+/// whether `server` is the origin of any event of a conversation
+pub(crate) fn took_part(
+    pool: &DBPool,
+    conversation_id: &str,
+    server: &str,
+) -> Result<bool, Status> {
+    run_sql!(
+        pool,
+        |mut conn| {
+            EVENT_SIGNATURE::table
+                .inner_join(MESSAGE::table.on(MESSAGE::EVENT_ID.eq(EVENT_SIGNATURE::EVENT_ID)))
+                .filter(MESSAGE::RECEIVER_ID.eq(conversation_id))
+                .filter(EVENT_SIGNATURE::ORIGIN_SERVER.eq(server))
+                .count()
+                .get_result::<i64>(&mut conn)
+                .map(|count| count > 0)
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// up to `limit` events of a conversation which `before` follow, in
+/// topological order
+pub(crate) fn load_history(
+    pool: &DBPool,
+    conversation_id: &str,
+    before: &[String],
+    limit: u32,
+) -> Result<Vec<SignedEvent>, Status> {
+    let limit = match limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit.min(MAX_LIMIT),
+    };
+    let events = run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::dag::ancestors(&mut conn, conversation_id, before, limit as usize)
+                .and_then(|ids| limit_db::dag::load_events(&mut conn, &ids))
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    limit_db::dag::topological_order(events)
+        .iter()
+        .map(to_signed_event)
+        .collect()
+}
+
+/// fetch up to `limit` events of a conversation which `before` follow from
+/// `server_name`, the server of the conversation. Returns the number of new
+/// events, events failing verification are left out.
+pub async fn backfill(
+    pool: &DBPool,
+    server_name: &str,
+    conversation_id: &str,
+    before: Vec<String>,
+    limit: u32,
+) -> Result<usize, Status> {
+    resolver::check_server_name(server_name)?;
+    policy::check_peer(pool, server_name)?;
+    let peer = resolve_peer(pool, server_name).await?;
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let mut req = BackfillRequest {
+        origin_server: config.url.clone(),
+        conversation_id: conversation_id.to_string(),
+        before,
+        limit,
+        signature: String::new(),
+    };
+    req.signature = limit_am::decode_secret(&config.server_secret_key)
+        .and_then(|key| key.sign(&request_payload(&req, server_name)))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+    let mut client = FederationServiceClient::connect(peer.endpoint.clone())
+        .await
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::unavailable(e.to_string())
+        })?;
+    let events = client.backfill(req).await?.into_inner().events;
+
+    let mut verified = Vec::with_capacity(events.len());
+    for event in events {
+        let event_id = event.event_id.clone();
+        if event.receiver_id != conversation_id || event.receiver_server != server_name {
+            increment_counter!("federation_backfill_rejected", "peer" => server_name.to_string());
+            tracing::warn!(
+                "{} sent event {} of another conversation",
+                server_name,
+                event_id
+            );
+            continue;
+        }
+        // events are countersigned by their origin, not by the conversation's server
+        let origin = match policy::check_peer(pool, &event.origin_server) {
+            Ok(()) => resolve_peer(pool, &event.origin_server).await,
+            Err(status) => Err(status),
+        };
+        let verified_event = origin.and_then(|origin| {
+            let event = verify_signature(&origin, event)?;
+            account::check_sender(pool, &config.url, &event.head.sender, &origin.name)?;
+            Ok(event)
+        });
+        match verified_event {
+            Ok(event) => verified.push(event),
+            Err(status) => {
+                increment_counter!("federation_backfill_rejected", "peer" => server_name.to_string());
+                tracing::warn!("backfilled event {} rejected: {}", event_id, status);
+            }
+        }
+    }
+
+    let mut stored = 0;
+    for mut event in limit_db::dag::topological_order(verified) {
+        match store_event(pool, &mut event) {
+            Ok(true) => stored += 1,
+            Ok(false) => {}
+            Err(status) if status.code() == tonic::Code::InvalidArgument => {
+                increment_counter!("federation_backfill_rejected", "peer" => server_name.to_string());
+                tracing::warn!("backfilled event {} rejected: {}", event.head.id, status);
+            }
+            Err(status) => return Err(status),
+        }
+    }
+    metrics::counter!(
+        "federation_backfill_events",
+        stored as u64,
+        "peer" => server_name.to_string()
+    );
+    Ok(stored)
+}
+
+/// backfill the gaps in the history of the conversations `user_id` is
+/// subscribed to, or of `conversation` only, up to `limit` events each.
+/// Returns the number of new events, servers failing to backfill are skipped
+/// and gaps they failed on aren't tried again for [`RETRY_AFTER`].
+pub async fn backfill_subscriptions(
+    pool: &DBPool,
+    user_id: &str,
+    conversation: Option<&str>,
+    limit: u32,
+) -> Result<usize, Status> {
+    let horizon = run_sql!(
+        pool,
+        |mut conn| {
+            let mut conversations = EVENT_SUBSCRIPTIONS::table
+                .filter(EVENT_SUBSCRIPTIONS::USER_ID.eq(user_id))
+                .filter(EVENT_SUBSCRIPTIONS::CHANNEL_TYPE.eq("message"))
+                .select(EVENT_SUBSCRIPTIONS::SUBSCRIBED_TO)
+                .into_boxed();
+            if let Some(conversation) = conversation {
+                conversations =
+                    conversations.filter(EVENT_SUBSCRIPTIONS::SUBSCRIBED_TO.eq(conversation));
+            }
+            conversations
+                .load::<String>(&mut conn)
+                .and_then(|conversations| {
+                    limit_db::dag::horizon(&mut conn, &conversations, HORIZON_SIZE)
+                })
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+
+    let current_server_url = GLOBAL_CONFIG.get().unwrap().url.as_str();
+    let mut gaps = HashMap::<(String, String), Vec<String>>::new();
+    for (event_id, conversation_id, server) in horizon {
+        // the history of our own conversations is complete
+        if server != current_server_url {
+            gaps.entry((server, conversation_id))
+                .or_default()
+                .push(event_id);
+        }
+    }
+    let mut stored = 0;
+    for ((server, conversation_id), mut before) in gaps {
+        before.sort();
+        let attempt = (conversation_id, before);
+        let tried = ATTEMPTS
+            .read()
+            .unwrap()
+            .get(&attempt)
+            .map_or(false, |tried_at| tried_at.elapsed() < RETRY_AFTER);
+        if tried {
+            continue;
+        }
+        let (conversation_id, before) = &attempt;
+        match backfill(pool, &server, conversation_id, before.clone(), limit).await {
+            Ok(0) => {
+                ATTEMPTS.write().unwrap().insert(attempt, Instant::now());
+            }
+            Ok(n) => stored += n,
+            Err(status) => {
+                tracing::warn!(
+                    "backfill of {} from {} failed: {}",
+                    conversation_id,
+                    server,
+                    status
+                );
+                ATTEMPTS.write().unwrap().insert(attempt, Instant::now());
+            }
+        }
+    }
+    ATTEMPTS
+        .write()
+        .unwrap()
+        .retain(|_, tried_at| tried_at.elapsed() < RETRY_AFTER);
+    Ok(stored)
+}
@@ -0,0 +1,394 @@
+//! Anti-entropy of group state between the servers taking part in a group.
+//!
+//! Servers exchange the ids of the state events of a group they have and send
+//! each other the missing ones, [`limit_db::group`] merges them. Every event is
+//! signed by its origin server and verified like message events. A server
+//! which doesn't know a group yet is sent all of its events once a member is
+//! on it.
+
+use std::{collections::HashSet, time::Duration};
+
+use limit_config::{Peer, GLOBAL_CONFIG};
+use limit_db::{
+    group::{GroupOp, GroupState, GroupStateEvent},
+    run_sql, DBPool,
+};
+use limit_deps::{metrics::increment_counter, *};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::Notify;
+use tonic::{Code, Status};
+
+use crate::{
+    federation_service_client::FederationServiceClient, outbox::now_millis, policy, resolve_peer,
+    resolver, GroupSyncRequest, GroupSyncResponse, SignedGroupEvent,
+};
+
+/// time between two synchronizations of every group
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+static GROUP_SYNC_WORKER: OnceCell<()> = OnceCell::new();
+static GROUP_SYNC_NOTIFY: Lazy<Notify> = Lazy::new(Notify::new);
+
+pub fn to_signed_group_event(event: &GroupStateEvent) -> SignedGroupEvent {
+    SignedGroupEvent {
+        id: event.id.clone(),
+        group_id: event.group_id.clone(),
+        sender: event.sender.clone(),
+        origin_server: event.origin_server.clone(),
+        ts: event.ts,
+        op: event.op.clone(),
+        signature: event.signature.clone(),
+    }
+}
+
+pub fn from_signed_group_event(event: SignedGroupEvent) -> GroupStateEvent {
+    GroupStateEvent {
+        id: event.id,
+        group_id: event.group_id,
+        sender: event.sender,
+        origin_server: event.origin_server,
+        ts: event.ts,
+        op: event.op,
+        signature: event.signature,
+    }
+}
+
+/// the bytes a server signs to synchronize a group with `destination`
+pub fn request_payload(req: &GroupSyncRequest, destination: &str) -> Vec<u8> {
+    let events = req
+        .events
+        .iter()
+        .map(|event| event.id.as_str())
+        .collect::<Vec<_>>();
+    [
+        req.origin_server.as_str(),
+        destination,
+        &req.group_id,
+        &req.known.join(","),
+        &events.join(","),
+    ]
+    .join("\n")
+    .into_bytes()
+}
+
+/// check an event is signed by its origin server `peer` and holds an operation
+pub fn verify_group_event(peer: &Peer, event: &GroupStateEvent) -> Result<(), Status> {
+    event.op().map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    limit_am::decode_public(&peer.public_key)
+        .and_then(|key| key.verify(&event.canonical(), &event.signature))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::permission_denied("invalid server signature")
+        })
+}
+
+/// stored events of a group and the state they merge into
+pub fn load_group(
+    pool: &DBPool,
+    group_id: &str,
+) -> Result<(Vec<GroupStateEvent>, GroupState), Status> {
+    let events = run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::group::load_events(&mut conn, group_id).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    let state = GroupState::from_events(&events).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?;
+    Ok((events, state))
+}
+
+fn insert_events(pool: &DBPool, events: &[GroupStateEvent]) -> Result<usize, Status> {
+    run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::group::insert_events(&mut conn, events).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// change a group on behalf of `sender`, a user of this server. The first
+/// change creates the group. The event is synchronized to the other servers of
+/// the group in the background.
+pub fn submit(
+    pool: &DBPool,
+    group_id: &str,
+    sender: &str,
+    op: &GroupOp,
+) -> Result<GroupStateEvent, Status> {
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let (events, state) = load_group(pool, group_id)?;
+    let mut event = GroupStateEvent {
+        id: if events.is_empty() {
+            group_id.to_string()
+        } else {
+            uuid::Uuid::new_v4().to_string()
+        },
+        group_id: group_id.to_string(),
+        sender: sender.to_string(),
+        origin_server: config.url.clone(),
+        // after every event known, whatever the clocks of their servers say
+        ts: events
+            .iter()
+            .map(|event| event.ts + 1)
+            .fold(now_millis(), i64::max),
+        op: serde_json::to_string(op).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?,
+        signature: String::new(),
+    };
+    let permitted = state.permits(&event).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::internal(e.to_string())
+    })?;
+    if !permitted {
+        tracing::warn!("{} may not change group {}", sender, group_id);
+        return Err(Status::permission_denied(
+            "not permitted to change the group",
+        ));
+    }
+    event.signature = limit_am::decode_secret(&config.server_secret_key)
+        .and_then(|key| key.sign(&event.canonical()))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+    insert_events(pool, std::slice::from_ref(&event))?;
+    start_group_sync_worker(pool.clone());
+    wake_group_sync_worker();
+    Ok(event)
+}
+
+/// events of `group_id` verified against their origin servers, the others are
+/// left out
+async fn verify_events(
+    pool: &DBPool,
+    group_id: &str,
+    events: Vec<SignedGroupEvent>,
+) -> Vec<GroupStateEvent> {
+    let mut verified = Vec::with_capacity(events.len());
+    for event in events {
+        let event = from_signed_group_event(event);
+        let res = if event.group_id != group_id {
+            Err(Status::invalid_argument("group mismatch"))
+        } else {
+            match policy::check_peer(pool, &event.origin_server) {
+                Ok(()) => resolve_peer(pool, &event.origin_server)
+                    .await
+                    .and_then(|peer| verify_group_event(&peer, &event)),
+                Err(status) => Err(status),
+            }
+        };
+        match res {
+            Ok(()) => verified.push(event),
+            Err(status) => {
+                increment_counter!(
+                    "federation_group_event_rejected",
+                    "peer" => event.origin_server.clone()
+                );
+                tracing::warn!("group event {} rejected: {}", event.id, status);
+            }
+        }
+    }
+    verified
+}
+
+/// a request to synchronize `group_id` with `destination`, signed with the
+/// server key
+fn sync_request(
+    destination: &str,
+    group_id: &str,
+    known: Vec<String>,
+    events: Vec<SignedGroupEvent>,
+) -> Result<GroupSyncRequest, Status> {
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let mut req = GroupSyncRequest {
+        origin_server: config.url.clone(),
+        group_id: group_id.to_string(),
+        known,
+        events,
+        signature: String::new(),
+    };
+    req.signature = limit_am::decode_secret(&config.server_secret_key)
+        .and_then(|key| key.sign(&request_payload(&req, destination)))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?;
+    Ok(req)
+}
+
+/// exchange the events of a group with `server_name`, returns the number of
+/// new events
+pub async fn sync_group(pool: &DBPool, group_id: &str, server_name: &str) -> Result<usize, Status> {
+    resolver::check_server_name(server_name)?;
+    policy::check_peer(pool, server_name)?;
+    let peer = resolve_peer(pool, server_name).await?;
+    let mut client = FederationServiceClient::connect(peer.endpoint.clone())
+        .await
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::unavailable(e.to_string())
+        })?;
+
+    let (events, _) = load_group(pool, group_id)?;
+    let mut known = events
+        .iter()
+        .map(|event| event.id.clone())
+        .collect::<Vec<_>>();
+    let req = sync_request(server_name, group_id, known.clone(), vec![])?;
+    let res = match client.sync_group_state(req).await {
+        Ok(res) => res.into_inner(),
+        // the peer doesn't know the group yet, invite it
+        Err(status) if status.code() == Code::PermissionDenied => {
+            let events = events.iter().map(to_signed_group_event).collect();
+            let req = sync_request(server_name, group_id, known, events)?;
+            client.sync_group_state(req).await?;
+            return Ok(0);
+        }
+        Err(status) => return Err(status),
+    };
+    let received = verify_events(pool, group_id, res.events).await;
+    let stored = insert_events(pool, &received)?;
+
+    // send what the peer misses
+    let peer_known = res.known.into_iter().collect::<HashSet<_>>();
+    let missing = events
+        .iter()
+        .filter(|event| !peer_known.contains(&event.id))
+        .map(to_signed_group_event)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        known.extend(received.into_iter().map(|event| event.id));
+        let req = sync_request(server_name, group_id, known, missing)?;
+        client.sync_group_state(req).await?;
+    }
+    Ok(stored)
+}
+
+/// answer a [`GroupSyncRequest`] sent to `server_name`
+pub(crate) async fn serve_sync(
+    pool: &DBPool,
+    server_name: &str,
+    req: GroupSyncRequest,
+) -> Result<GroupSyncResponse, Status> {
+    policy::check_peer(pool, &req.origin_server)?;
+    let peer = resolve_peer(pool, &req.origin_server).await?;
+    limit_am::decode_public(&peer.public_key)
+        .and_then(|key| key.verify(&request_payload(&req, server_name), &req.signature))
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::permission_denied("invalid request signature")
+        })?;
+
+    let pushed = verify_events(pool, &req.group_id, req.events).await;
+    let (mut events, state) = load_group(pool, &req.group_id)?;
+    // only servers taking part in a group may read and change it, as far as
+    // this server knows. A group it doesn't know has to invite it.
+    let taking_part = if events.is_empty() {
+        // verified events hold an operation
+        let invited = GroupState::from_events(&pushed).unwrap();
+        let participants = limit_db::group::participants(&invited, &pushed);
+        participants.contains(server_name) && participants.contains(&req.origin_server)
+    } else {
+        limit_db::group::participants(&state, &events).contains(&req.origin_server)
+    };
+    if !taking_part {
+        tracing::warn!("{} tried to sync group {}", req.origin_server, req.group_id);
+        return Err(Status::permission_denied("not part of the group"));
+    }
+    insert_events(pool, &pushed)?;
+    events.extend(pushed);
+
+    let known = req.known.into_iter().collect::<HashSet<_>>();
+    let mut ids = HashSet::with_capacity(events.len());
+    let missing = events
+        .iter()
+        .filter(|event| ids.insert(event.id.clone()) && !known.contains(&event.id))
+        .map(to_signed_group_event)
+        .collect();
+    Ok(GroupSyncResponse {
+        events: missing,
+        known: ids.into_iter().collect(),
+    })
+}
+
+/// synchronize every group this server takes part in with the other servers
+/// of the group
+pub async fn sync_all_groups(pool: &DBPool) -> Result<(), Status> {
+    let current_server_url = GLOBAL_CONFIG.get().unwrap().url.as_str();
+    let group_ids = run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::group::group_ids(&mut conn).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    for group_id in group_ids {
+        let (events, state) = load_group(pool, &group_id)?;
+        let mut participants = limit_db::group::participants(&state, &events);
+        if !participants.remove(current_server_url) {
+            continue;
+        }
+        for server in participants {
+            if let Err(status) = sync_group(pool, &group_id, &server).await {
+                tracing::warn!(
+                    "sync of group {} with {} failed: {}",
+                    group_id,
+                    server,
+                    status
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// start synchronizing groups in the background, only the first call starts
+/// a worker
+pub fn start_group_sync_worker(pool: DBPool) {
+    GROUP_SYNC_WORKER.get_or_init(|| {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = sync_all_groups(&pool).await {
+                    tracing::error!("group sync failed: {}", e);
+                }
+                tokio::select! {
+                    _ = GROUP_SYNC_NOTIFY.notified() => {}
+                    _ = tokio::time::sleep(SYNC_INTERVAL) => {}
+                }
+            }
+        });
+    });
+}
+
+/// synchronize groups now instead of after [`SYNC_INTERVAL`]
+pub fn wake_group_sync_worker() {
+    GROUP_SYNC_NOTIFY.notify_one();
+}
@@ -0,0 +1,464 @@
+use anyhow::Context;
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use limit_config::{Peer, GLOBAL_CONFIG};
+use limit_db::{
+    event::{SREvent, MESSAGE_EVENT_TYPE, SENDER_KEY_DISTRIBUTION_EVENT_TYPE},
+    get_db_layer,
+    id::UserId,
+    run_sql,
+    schema::{EVENT, USER},
+    DBPool,
+};
+use limit_deps::{metrics::increment_counter, *};
+use tonic::{Request, Response, Status};
+pub use tonic_gen::federation::*;
+
+pub mod account;
+pub mod admin;
+pub mod backfill;
+pub mod group;
+pub mod outbox;
+pub mod policy;
+pub mod resolver;
+
+pub use resolver::{get_peer, register_peer, resolve_peer};
+
+/// features announced in [`ServerInfo`]
+pub const FEATURES: &[&str] = &[
+    MESSAGE_EVENT_TYPE,
+    SENDER_KEY_DISTRIBUTION_EVENT_TYPE,
+    "event_signatures",
+    "backfill",
+    "group_state",
+    "account_migration",
+];
+
+/// envelope of a countersigned message event
+pub fn to_signed_event(event: &SREvent) -> Result<SignedEvent, Status> {
+    let signature = event.signature.as_ref().ok_or_else(|| {
+        tracing::error!("event {} is not countersigned", event.head.id);
+        Status::internal("event is not countersigned")
+    })?;
+    let body = event.body.message();
+    Ok(SignedEvent {
+        event_id: event.head.id.clone(),
+        ts: event.head.timestamp as u64,
+        sender: event.head.sender.clone(),
+        event_type: event.head.event_type.clone(),
+        receiver_id: body.receiver_id.clone(),
+        receiver_server: body.receiver_server.clone(),
+        text: body.text.clone(),
+        extensions: serde_json::from_str(&body.extensions).map_err(|e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        })?,
+        sender_signature: signature.sender_signature.clone(),
+        server_signature: signature.server_signature.clone(),
+        origin_server: signature.origin_server.clone(),
+        prev_events: event.prev_events.clone(),
+        depth: event.head.depth,
+    })
+}
+
+pub fn from_signed_event(event: SignedEvent) -> Result<SREvent, Status> {
+    let mut res = SREvent::from((
+        limit_db::event::Event {
+            id: event.event_id.clone(),
+            timestamp: event.ts as i64,
+            sender: event.sender,
+            event_type: event.event_type,
+            device_id: None,
+            depth: event.depth,
+            received_at: chrono::Utc::now().timestamp_millis(),
+            seq: 0,
+        },
+        limit_db::event::Message {
+            event_id: event.event_id.clone(),
+            receiver_id: event.receiver_id,
+            receiver_server: event.receiver_server,
+            text: event.text,
+            extensions: serde_json::to_string(&event.extensions).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })?,
+        },
+    ));
+    res.prev_events = event.prev_events;
+    res.signature = Some(limit_db::event::EventSignature {
+        event_id: event.event_id,
+        sender_signature: event.sender_signature,
+        server_signature: event.server_signature,
+        origin_server: event.origin_server,
+    });
+    Ok(res)
+}
+
+/// check an event is countersigned by its origin server `peer`
+pub fn verify_signature(peer: &Peer, event: SignedEvent) -> Result<SREvent, Status> {
+    let event = from_signed_event(event)?;
+    limit_db::event_type::validate(&event.head.event_type, event.body.message()).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument(e.to_string())
+    })?;
+    let signature = event.signature.as_ref().unwrap();
+    event
+        .canonical()
+        .and_then(|canonical| {
+            limit_am::signature::verify_countersignature(
+                &limit_am::decode_public(&peer.public_key)?,
+                &event.countersigned(&canonical, signature.sender_signature.as_deref()),
+                &signature.server_signature,
+            )
+        })
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::permission_denied("invalid server signature")
+        })?;
+    Ok(event)
+}
+
+/// check the sender's signature of an event countersigned by `peer` against
+/// the key `peer` has for the sender, required with
+/// [`limit_config::Config::require_event_signatures`]
+async fn verify_sender_signature(peer: &Peer, event: &SREvent) -> Result<(), Status> {
+    let signature = event
+        .signature
+        .as_ref()
+        .and_then(|signature| signature.sender_signature.as_ref());
+    let Some(signature) = signature else {
+        if GLOBAL_CONFIG.get().unwrap().require_event_signatures {
+            tracing::error!("event {} is not signed by its sender", event.head.id);
+            return Err(Status::unauthenticated("event signature required"));
+        }
+        return Ok(());
+    };
+    let Some(pubkey) = get_user_key(peer, &event.head.sender).await? else {
+        tracing::error!("sender {} not found on {}", event.head.sender, peer.name);
+        return Err(Status::unauthenticated("sender not found"));
+    };
+    event
+        .canonical()
+        .and_then(|canonical| {
+            limit_am::signature::verify_event(
+                &limit_am::decode_public(&pubkey)?,
+                &canonical,
+                signature,
+            )
+        })
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::unauthenticated("invalid event signature")
+        })
+}
+
+/// store a verified event from another server with its place in the DAG, see
+/// [`limit_db::event::store`]. Returns false if it was stored before.
+pub(crate) fn store_event(pool: &DBPool, event: &mut SREvent) -> Result<bool, Status> {
+    // the depth can only be checked once the events it follows are known
+    let expected_depth = run_sql!(
+        pool,
+        |mut conn| {
+            limit_db::dag::expected_depth(&mut conn, &event.prev_events).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    if expected_depth.map_or(event.head.depth < 1, |depth| depth != event.head.depth) {
+        tracing::error!("event {} has an invalid depth", event.head.id);
+        return Err(Status::invalid_argument("invalid depth"));
+    }
+
+    run_sql!(
+        pool,
+        |mut conn| {
+            Connection::transaction(&mut conn, |conn| {
+                let exists = EVENT::table
+                    .filter(EVENT::ID.eq(&event.head.id))
+                    .count()
+                    .get_result::<i64>(conn)?
+                    > 0;
+                if !exists {
+                    limit_db::event::store(conn, event)?;
+                }
+                Ok::<_, diesel::result::Error>(!exists)
+            })
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// deliver events to the server of their receivers, returns the ids of the
+/// accepted events and the rejected ones
+pub async fn deliver_events(
+    peer: &Peer,
+    events: Vec<SignedEvent>,
+) -> Result<(Vec<String>, Vec<RejectedEvent>), Status> {
+    let mut client =
+        federation_service_client::FederationServiceClient::connect(peer.endpoint.clone())
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::unavailable(e.to_string())
+            })?;
+    client
+        .deliver_events(DeliverEventsRequest { events })
+        .await
+        .map(|res| {
+            let res = res.into_inner();
+            (res.accepted, res.rejected)
+        })
+}
+
+/// the key of a user of `peer`, none when it has no such user
+pub async fn get_user_key(peer: &Peer, user_id: &str) -> Result<Option<String>, Status> {
+    let mut client =
+        federation_service_client::FederationServiceClient::connect(peer.endpoint.clone())
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::unavailable(e.to_string())
+            })?;
+    match client
+        .get_user_key(GetUserKeyRequest {
+            user_id: user_id.to_string(),
+        })
+        .await
+    {
+        Ok(res) => Ok(Some(res.into_inner().public_key)),
+        Err(status) if status.code() == tonic::Code::NotFound => Ok(None),
+        Err(status) => Err(status),
+    }
+}
+
+#[derive(Debug, Clone)]
+// require db
+pub struct FederationService {
+    /// the server name events are accepted for
+    server_name: String,
+}
+
+impl FederationService {
+    pub fn new() -> Self {
+        Self::with_server_name(GLOBAL_CONFIG.get().unwrap().url.clone())
+    }
+
+    /// accept events sent to another server name than
+    /// [`limit_config::Config::url`]
+    pub fn with_server_name(server_name: impl Into<String>) -> Self {
+        Self {
+            server_name: server_name.into(),
+        }
+    }
+
+    /// check an event is meant for us, or for a user who moved here. Checked
+    /// before its origin server is resolved.
+    fn check_receiver(&self, pool: &DBPool, event: &SignedEvent) -> Result<(), Status> {
+        if event.receiver_server != self.server_name {
+            let receiver =
+                UserId::from_parts(&event.receiver_id, &event.receiver_server).map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::invalid_argument(e.to_string())
+                })?;
+            if account::route(pool, &receiver)?.server().as_str() != self.server_name {
+                tracing::error!("event {} is not for this server", event.event_id);
+                return Err(Status::invalid_argument("receiver server mismatch"));
+            }
+        }
+        Ok(())
+    }
+
+    /// check an event is countersigned by its origin server and sent by one of
+    /// its users, then its sender's signature like for events of our users
+    async fn verify(
+        &self,
+        pool: &DBPool,
+        peer: &Peer,
+        event: SignedEvent,
+    ) -> Result<SREvent, Status> {
+        let event = verify_signature(peer, event)?;
+        account::check_sender(pool, &self.server_name, &event.head.sender, &peer.name)?;
+        verify_sender_signature(peer, &event).await?;
+        Ok(event)
+    }
+}
+
+impl Default for FederationService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tonic::async_trait]
+impl federation_service_server::FederationService for FederationService {
+    async fn deliver_events(
+        &self,
+        req: Request<DeliverEventsRequest>,
+    ) -> Result<Response<DeliverEventsResponse>, Status> {
+        let (_, redis, db_pool) = get_db_layer!(req);
+        let events = req.into_inner().events;
+        let mut accepted = Vec::with_capacity(events.len());
+        let mut rejected = vec![];
+        // an invalid event doesn't hold back the others of its batch
+        for event in events {
+            let event_id = event.event_id.clone();
+            let origin_server = event.origin_server.clone();
+            let delivery = async {
+                policy::check_peer(&db_pool, &event.origin_server)?;
+                self.check_receiver(&db_pool, &event)?;
+                let peer = resolve_peer(&db_pool, &event.origin_server).await?;
+                let mut event = self.verify(&db_pool, &peer, event).await?;
+                let body = event.body.message();
+                policy::check_limits(
+                    &peer.name,
+                    policy::Direction::Inbound,
+                    policy::event_size(body),
+                )?;
+
+                // peers retry deliveries, store and publish every event once
+                if store_event(&db_pool, &mut event)? {
+                    limit_server_cluster::outbox::start_publisher(db_pool.clone(), redis.clone());
+                    limit_server_cluster::outbox::wake_publisher();
+                }
+                Ok::<_, Status>(())
+            };
+            match delivery.await {
+                Ok(()) => accepted.push(event_id),
+                Err(status) => {
+                    tracing::warn!(
+                        "event {} of {} rejected: {}",
+                        event_id,
+                        origin_server,
+                        status
+                    );
+                    increment_counter!("federation_events_rejected", "peer" => origin_server);
+                    rejected.push(RejectedEvent {
+                        event_id,
+                        code: status.code() as i32,
+                        message: status.message().to_string(),
+                    });
+                }
+            }
+        }
+        Ok(Response::new(DeliverEventsResponse { accepted, rejected }))
+    }
+
+    async fn get_server_info(
+        &self,
+        _req: Request<GetServerInfoRequest>,
+    ) -> Result<Response<ServerInfo>, Status> {
+        let config = GLOBAL_CONFIG.get().unwrap();
+        Ok(Response::new(ServerInfo {
+            server_name: self.server_name.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            public_key: config.server_public_key.clone(),
+            previous_public_keys: config.previous_public_keys.clone(),
+            previous_key_signatures: config.previous_key_signatures.clone(),
+        }))
+    }
+
+    async fn get_user_key(
+        &self,
+        req: Request<GetUserKeyRequest>,
+    ) -> Result<Response<UserKey>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        let user_id = req.into_inner().user_id;
+        let public_key = run_sql!(
+            db_pool,
+            |mut conn| {
+                USER::table
+                    .find(&user_id)
+                    .select(USER::PUBKEY)
+                    .first::<String>(&mut conn)
+                    .optional()
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })
+            },
+            |e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            }
+        )?;
+        public_key
+            .map(|public_key| Response::new(UserKey { public_key }))
+            .ok_or_else(|| Status::not_found("no such user"))
+    }
+
+    async fn backfill(
+        &self,
+        req: Request<BackfillRequest>,
+    ) -> Result<Response<BackfillResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        let req = req.into_inner();
+        policy::check_peer(&db_pool, &req.origin_server)?;
+        // only servers which took part in a conversation may read its history,
+        // checked before the origin is resolved
+        if !backfill::took_part(&db_pool, &req.conversation_id, &req.origin_server)? {
+            tracing::warn!(
+                "{} tried to backfill {}",
+                req.origin_server,
+                req.conversation_id
+            );
+            return Err(Status::permission_denied("not part of the conversation"));
+        }
+        let peer = resolve_peer(&db_pool, &req.origin_server).await?;
+        limit_am::decode_public(&peer.public_key)
+            .and_then(|key| {
+                key.verify(
+                    &backfill::request_payload(&req, &self.server_name),
+                    &req.signature,
+                )
+            })
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::permission_denied("invalid request signature")
+            })?;
+        let events =
+            backfill::load_history(&db_pool, &req.conversation_id, &req.before, req.limit)?;
+        Ok(Response::new(BackfillResponse { events }))
+    }
+
+    async fn sync_group_state(
+        &self,
+        req: Request<GroupSyncRequest>,
+    ) -> Result<Response<GroupSyncResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        group::serve_sync(&db_pool, &self.server_name, req.into_inner())
+            .await
+            .map(Response::new)
+    }
+
+    async fn move_account(
+        &self,
+        req: Request<MoveAccountRequest>,
+    ) -> Result<Response<SignedMoved>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        account::serve_move(&db_pool, &self.server_name, req.into_inner())
+            .await
+            .map(Response::new)
+    }
+
+    async fn publish_moved(
+        &self,
+        req: Request<PublishMovedRequest>,
+    ) -> Result<Response<PublishMovedResponse>, Status> {
+        let (_, _, db_pool) = get_db_layer!(req);
+        account::serve_publish(&db_pool, req.into_inner())
+            .await
+            .map(Response::new)
+    }
+}
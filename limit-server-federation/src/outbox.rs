@@ -0,0 +1,360 @@
+//! Durable queue of events to deliver to peer servers.
+//!
+//! Events are delivered in order per destination, a failing event holds back
+//! the later ones of its destination until it is delivered or dead-lettered.
+//! Destinations are delivered to concurrently, a slow or failing peer doesn't
+//! hold back the others.
+
+use std::time::Duration;
+
+use diesel::{ExpressionMethods, QueryDsl, QueryResult, RunQueryDsl};
+use limit_db::{
+    event::SREvent,
+    federation::{NewOutboxEvent, OutboxEvent, OUTBOX_STATE_DEAD, OUTBOX_STATE_PENDING},
+    run_sql,
+    schema::FEDERATION_OUTBOX,
+    DBPool, SqliteConn,
+};
+use limit_deps::{
+    metrics::{gauge, increment_counter},
+    *,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::Notify;
+use tonic::{Code, Status};
+
+/// attempts before an event is dead-lettered
+pub const MAX_ATTEMPTS: i32 = 12;
+/// delay after the first failed attempt, doubled on each further failure
+pub const BASE_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// events delivered in one request
+pub const BATCH_SIZE: usize = 50;
+/// time one delivery to a destination may take, it's retried later after
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+static OUTBOX_WORKER: OnceCell<()> = OnceCell::new();
+static OUTBOX_NOTIFY: Lazy<Notify> = Lazy::new(Notify::new);
+
+pub(crate) fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// delay before the next attempt after `attempts` failed ones
+pub fn backoff(attempts: i32) -> Duration {
+    let exp = attempts.saturating_sub(1).clamp(0, 31) as u32;
+    BASE_BACKOFF
+        .checked_mul(2u32.saturating_pow(exp))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+/// errors retrying won't fix
+fn is_permanent(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::InvalidArgument | Code::PermissionDenied | Code::Unimplemented
+    )
+}
+
+/// queue a countersigned event for `destination`, in the transaction storing
+/// it so it survives restarts. It's delivered once committed, see
+/// [`wake_outbox_worker`].
+pub fn enqueue(conn: &mut impl SqliteConn, event: &SREvent, destination: &str) -> QueryResult<()> {
+    diesel::insert_into(FEDERATION_OUTBOX::table)
+        .values(NewOutboxEvent {
+            event_id: event.head.id.clone(),
+            destination: destination.to_string(),
+            payload: serde_json::to_string(event)
+                .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?,
+            next_attempt_at: now_millis(),
+            state: OUTBOX_STATE_PENDING.to_string(),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// start delivering queued events in the background, only the first call
+/// starts a worker
+pub fn start_outbox_worker(pool: DBPool) {
+    OUTBOX_WORKER.get_or_init(|| {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = deliver_outbox(&pool).await {
+                    tracing::error!("outbox delivery failed: {}", e);
+                }
+                tokio::select! {
+                    _ = OUTBOX_NOTIFY.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        });
+    });
+}
+
+/// deliver due events now instead of at the next poll
+pub fn wake_outbox_worker() {
+    OUTBOX_NOTIFY.notify_one();
+}
+
+/// The next events to deliver to a destination
+pub(crate) struct Batch {
+    pub destination: String,
+    pub events: Vec<OutboxEvent>,
+}
+
+/// the due head of the pending events of each destination, in order. A
+/// destination whose first event waits for its next attempt is skipped, the
+/// events behind it wait too.
+pub(crate) fn due_batches(conn: &mut impl SqliteConn, now: i64) -> QueryResult<Vec<Batch>> {
+    let destinations = FEDERATION_OUTBOX::table
+        .filter(FEDERATION_OUTBOX::STATE.eq(OUTBOX_STATE_PENDING))
+        .group_by(FEDERATION_OUTBOX::DESTINATION)
+        .select((FEDERATION_OUTBOX::DESTINATION, diesel::dsl::count_star()))
+        .load::<(String, i64)>(conn)?;
+    let mut batches = vec![];
+    for (destination, pending) in destinations {
+        gauge!("federation_outbox_pending", pending as f64, "peer" => destination.clone());
+        let mut events = FEDERATION_OUTBOX::table
+            .filter(FEDERATION_OUTBOX::STATE.eq(OUTBOX_STATE_PENDING))
+            .filter(FEDERATION_OUTBOX::DESTINATION.eq(&destination))
+            .order(FEDERATION_OUTBOX::SEQ.asc())
+            .limit(BATCH_SIZE as i64)
+            .load::<OutboxEvent>(conn)?;
+        if events
+            .first()
+            .map_or(true, |head| head.next_attempt_at > now)
+        {
+            continue;
+        }
+        // an event failed before is sent alone, so a bad event can't fail
+        // the events behind it
+        if events[0].attempts > 0 {
+            events.truncate(1);
+        }
+        batches.push(Batch {
+            destination,
+            events,
+        });
+    }
+    Ok(batches)
+}
+
+/// one pass over the due events of every destination
+pub async fn deliver_outbox(pool: &DBPool) -> Result<(), Status> {
+    let batches = run_sql!(
+        pool,
+        |mut conn| {
+            due_batches(&mut conn, now_millis()).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+
+    let deliveries = batches.into_iter().map(|batch| async move {
+        if let Err(e) = deliver_batch(pool, &batch.destination, batch.events).await {
+            tracing::error!("outbox delivery to {} failed: {}", batch.destination, e);
+        }
+    });
+    futures::future::join_all(deliveries).await;
+    Ok(())
+}
+
+async fn deliver_batch(
+    pool: &DBPool,
+    destination: &str,
+    batch: Vec<OutboxEvent>,
+) -> Result<(), Status> {
+    let events = batch
+        .iter()
+        .map(|event| {
+            serde_json::from_str::<SREvent>(&event.payload)
+                .map_err(|e| Status::internal(e.to_string()))
+                .and_then(|event| crate::to_signed_event(&event))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // the policy may have changed since the events were queued
+    let delivery = async {
+        crate::policy::check_peer(pool, destination)?;
+        let peer = crate::resolve_peer(pool, destination).await?;
+        crate::deliver_events(&peer, events).await
+    };
+    let result = tokio::time::timeout(DELIVERY_TIMEOUT, delivery)
+        .await
+        .unwrap_or_else(|_| Err(Status::deadline_exceeded("delivery timed out")));
+
+    match result {
+        Ok((accepted, rejected)) => {
+            let delivered = batch
+                .iter()
+                .filter(|event| accepted.contains(&event.event_id))
+                .map(|event| event.seq)
+                .collect::<Vec<_>>();
+            run_sql!(
+                pool,
+                |mut conn| {
+                    diesel::delete(
+                        FEDERATION_OUTBOX::table.filter(FEDERATION_OUTBOX::SEQ.eq_any(&delivered)),
+                    )
+                    .execute(&mut conn)
+                    .map_err(|e| {
+                        tracing::error!("{}", e);
+                        Status::internal(e.to_string())
+                    })
+                },
+                |e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                }
+            )?;
+            metrics::counter!(
+                "federation_outbox_delivered",
+                delivered.len() as u64,
+                "peer" => destination.to_string()
+            );
+            for rejection in rejected {
+                let Some(event) = batch.iter().find(|event| event.event_id == rejection.event_id) else {
+                    continue;
+                };
+                let status = Status::new(Code::from(rejection.code), rejection.message);
+                tracing::warn!(
+                    "{} rejected event {}: {}",
+                    destination,
+                    event.event_id,
+                    status
+                );
+                record_failure(pool, destination, event, &status)?;
+            }
+        }
+        Err(status) => {
+            tracing::warn!("delivery to {} failed: {}", destination, status);
+            if status.code() == Code::Unavailable {
+                // the server may have moved
+                crate::resolver::forget_peer(destination);
+            }
+            record_failure(pool, destination, &batch[0], &status)?;
+        }
+    }
+    Ok(())
+}
+
+/// retry an event later, or dead-letter it once it failed too often or can't
+/// be delivered at all
+fn record_failure(
+    pool: &DBPool,
+    destination: &str,
+    event: &OutboxEvent,
+    status: &Status,
+) -> Result<(), Status> {
+    increment_counter!("federation_outbox_failed", "peer" => destination.to_string());
+    let attempts = event.attempts + 1;
+    let state = if attempts >= MAX_ATTEMPTS || is_permanent(status) {
+        increment_counter!("federation_outbox_dead_lettered", "peer" => destination.to_string());
+        OUTBOX_STATE_DEAD
+    } else {
+        OUTBOX_STATE_PENDING
+    };
+    let update = diesel::update(FEDERATION_OUTBOX::table.find(event.seq)).set((
+        FEDERATION_OUTBOX::ATTEMPTS.eq(attempts),
+        FEDERATION_OUTBOX::NEXT_ATTEMPT_AT.eq(now_millis() + backoff(attempts).as_millis() as i64),
+        FEDERATION_OUTBOX::LAST_ERROR.eq(Some(status.to_string())),
+        FEDERATION_OUTBOX::STATE.eq(state),
+    ));
+    run_sql!(
+        pool,
+        |mut conn| {
+            update.execute(&mut conn).map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_backoff() {
+    assert_eq!(backoff(1), BASE_BACKOFF);
+    assert_eq!(backoff(2), BASE_BACKOFF * 2);
+    assert_eq!(backoff(5), BASE_BACKOFF * 16);
+    assert_eq!(backoff(MAX_ATTEMPTS + 100), MAX_BACKOFF);
+}
+
+#[test]
+fn test_due_batches() {
+    use diesel::Connection;
+    use limit_db::federation::NewOutboxEvent;
+
+    let mut conn = diesel::sqlite::SqliteConnection::establish("../test.sqlite").unwrap();
+    let backed_up = format!("{}.example.com", uuid::Uuid::new_v4());
+    let healthy = format!("{}.example.com", uuid::Uuid::new_v4());
+    let now = now_millis();
+    let event = |destination: &str, next_attempt_at: i64| NewOutboxEvent {
+        event_id: uuid::Uuid::new_v4().to_string(),
+        destination: destination.to_string(),
+        payload: "{}".to_string(),
+        next_attempt_at,
+        state: OUTBOX_STATE_PENDING.to_string(),
+    };
+    // a long backlog of a peer down, waiting for its next attempt, queued
+    // before the events of a healthy peer
+    let backlog = (0..1500)
+        .map(|_| event(&backed_up, now + 60_000))
+        .collect::<Vec<_>>();
+    conn.transaction(|conn| {
+        diesel::insert_into(FEDERATION_OUTBOX::table)
+            .values(&backlog)
+            .execute(conn)
+    })
+    .unwrap();
+    let queued = (0..BATCH_SIZE + 1)
+        .map(|_| event(&healthy, now))
+        .collect::<Vec<_>>();
+    diesel::insert_into(FEDERATION_OUTBOX::table)
+        .values(&queued)
+        .execute(&mut conn)
+        .unwrap();
+
+    let batches = due_batches(&mut conn, now).unwrap();
+    assert!(batches.iter().all(|batch| batch.destination != backed_up));
+    let batch = batches
+        .iter()
+        .find(|batch| batch.destination == healthy)
+        .unwrap();
+    assert_eq!(
+        batch
+            .events
+            .iter()
+            .map(|event| &event.event_id)
+            .collect::<Vec<_>>(),
+        queued[..BATCH_SIZE]
+            .iter()
+            .map(|event| &event.event_id)
+            .collect::<Vec<_>>()
+    );
+
+    // the backlog is delivered once due
+    let batches = due_batches(&mut conn, now + 60_000).unwrap();
+    let batch = batches
+        .iter()
+        .find(|batch| batch.destination == backed_up)
+        .unwrap();
+    assert_eq!(batch.events[0].event_id, backlog[0].event_id);
+
+    diesel::delete(
+        FEDERATION_OUTBOX::table
+            .filter(FEDERATION_OUTBOX::DESTINATION.eq_any([&backed_up, &healthy])),
+    )
+    .execute(&mut conn)
+    .unwrap();
+}
@@ -0,0 +1,311 @@
+//! Which peer servers to federate with and how much.
+//!
+//! Allow and deny rules come from [`limit_config::FederationPolicy`] and from
+//! the admin api, deny rules win. Rules of the admin api are stored in the
+//! database, each node of a cluster caches them for [`ADMIN_RULES_TTL`]. Rate
+//! and size limits come from the config only, and are checked for both inbound
+//! and outbound events. Rates are counted by each node.
+
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use diesel::RunQueryDsl;
+use limit_config::{FederationPolicy, GLOBAL_CONFIG};
+use limit_db::{
+    federation::{FederationPolicyRule, POLICY_ACTION_ALLOW, POLICY_ACTION_DENY},
+    run_sql,
+    schema::FEDERATION_POLICY_RULE,
+    DBPool,
+};
+use limit_deps::{metrics::increment_counter, *};
+use once_cell::sync::Lazy;
+use tonic::Status;
+
+/// Whether events come from or go to a peer, each has its own rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+/// rules of the admin api are loaded again this long after they were loaded,
+/// another node of a cluster may have changed them
+pub const ADMIN_RULES_TTL: Duration = Duration::from_secs(10);
+
+/// rules of the admin api and when they were loaded from the database
+type AdminRules = (Instant, Vec<FederationPolicyRule>);
+
+static ADMIN_RULES: Lazy<RwLock<Option<AdminRules>>> = Lazy::new(|| RwLock::new(None));
+
+/// tokens left and the time they were counted
+type Bucket = (f64, Instant);
+
+/// token buckets by peer, tokens are events
+static BUCKETS: Lazy<RwLock<HashMap<(Direction, String), Bucket>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// whether `name` matches `pattern`, where `*` matches any characters
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    // there is always a first part
+    let first = parts.next().unwrap();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcard
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// rules of the admin api cached at `now`, none once they expired
+fn cached_admin_rules(now: Instant) -> Option<Vec<FederationPolicyRule>> {
+    match ADMIN_RULES.read().unwrap().as_ref() {
+        Some((loaded, rules)) if now.saturating_duration_since(*loaded) < ADMIN_RULES_TTL => {
+            Some(rules.clone())
+        }
+        _ => None,
+    }
+}
+
+/// rules of the admin api
+pub fn admin_rules(pool: &DBPool) -> Result<Vec<FederationPolicyRule>, Status> {
+    if let Some(rules) = cached_admin_rules(Instant::now()) {
+        return Ok(rules);
+    }
+    let rules = run_sql!(
+        pool,
+        |mut conn| {
+            FEDERATION_POLICY_RULE::table
+                .load::<FederationPolicyRule>(&mut conn)
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    *ADMIN_RULES.write().unwrap() = Some((Instant::now(), rules.clone()));
+    Ok(rules)
+}
+
+/// forget the cached rules of the admin api after they changed
+pub(crate) fn reload_admin_rules() {
+    *ADMIN_RULES.write().unwrap() = None;
+}
+
+fn is_allowed(policy: &FederationPolicy, admin_rules: &[FederationPolicyRule], name: &str) -> bool {
+    let rules_of = |action: &'static str| {
+        admin_rules
+            .iter()
+            .filter(move |rule| rule.action == action)
+            .map(|rule| rule.pattern.as_str())
+    };
+    let mut deny = policy
+        .deny
+        .iter()
+        .map(String::as_str)
+        .chain(rules_of(POLICY_ACTION_DENY));
+    if deny.any(|pattern| matches(pattern, name)) {
+        return false;
+    }
+    let mut allow = policy
+        .allow
+        .iter()
+        .map(String::as_str)
+        .chain(rules_of(POLICY_ACTION_ALLOW))
+        .peekable();
+    allow.peek().is_none() || allow.any(|pattern| matches(pattern, name))
+}
+
+/// rate limit and max event size of a peer
+pub fn limits_of(policy: &FederationPolicy, name: &str) -> (Option<u32>, Option<usize>) {
+    match policy
+        .peer_limits
+        .iter()
+        .find(|limits| matches(&limits.pattern, name))
+    {
+        Some(limits) => (
+            limits.rate_limit.or(policy.rate_limit),
+            limits.max_event_size.or(policy.max_event_size),
+        ),
+        None => (policy.rate_limit, policy.max_event_size),
+    }
+}
+
+/// size of an event checked against the size limits
+pub fn event_size(message: &limit_db::event::Message) -> usize {
+    message.text.len() + message.extensions.len()
+}
+
+/// check we federate with `name` at all
+pub fn check_peer(pool: &DBPool, name: &str) -> Result<(), Status> {
+    let policy = &GLOBAL_CONFIG.get().unwrap().federation_policy;
+    if !is_allowed(policy, &admin_rules(pool)?, name) {
+        increment_counter!("federation_policy_denied", "peer" => name.to_string());
+        tracing::warn!("federation with {} is not allowed", name);
+        return Err(Status::permission_denied(format!(
+            "federation with {name} is not allowed"
+        )));
+    }
+    Ok(())
+}
+
+/// check the size and rate limits of `name` for one event of `size` bytes
+pub fn check_limits(name: &str, direction: Direction, size: usize) -> Result<(), Status> {
+    let (rate_limit, max_event_size) =
+        limits_of(&GLOBAL_CONFIG.get().unwrap().federation_policy, name);
+    if let Some(max_event_size) = max_event_size {
+        if size > max_event_size {
+            increment_counter!(
+                "federation_policy_oversized",
+                "peer" => name.to_string(),
+                "direction" => direction.as_str()
+            );
+            return Err(Status::permission_denied(format!(
+                "event of {size} bytes exceeds the limit of {max_event_size} bytes for {name}"
+            )));
+        }
+    }
+    if let Some(rate_limit) = rate_limit {
+        if !take_token(name, direction, rate_limit, Instant::now()) {
+            increment_counter!(
+                "federation_policy_rate_limited",
+                "peer" => name.to_string(),
+                "direction" => direction.as_str()
+            );
+            return Err(Status::resource_exhausted(format!(
+                "more than {rate_limit} events per minute for {name}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// take one token from the bucket of a peer, which holds up to `rate_limit`
+/// tokens and refills `rate_limit` tokens a minute
+fn take_token(name: &str, direction: Direction, rate_limit: u32, now: Instant) -> bool {
+    let capacity = rate_limit as f64;
+    let mut buckets = BUCKETS.write().unwrap();
+    let (tokens, last) = buckets
+        .entry((direction, name.to_string()))
+        .or_insert((capacity, now));
+    let refilled = now.saturating_duration_since(*last).as_secs_f64() * capacity / 60.0;
+    *tokens = (*tokens + refilled).min(capacity);
+    *last = now;
+    if *tokens < 1.0 {
+        return false;
+    }
+    *tokens -= 1.0;
+    true
+}
+
+#[test]
+fn test_matches() {
+    assert!(matches("limit.example.com", "limit.example.com"));
+    assert!(matches("limit.example.com", "LIMIT.example.com"));
+    assert!(!matches("limit.example.com", "limit.example.com.evil"));
+    assert!(matches("*", "anything"));
+    assert!(matches("*.example.com", "limit.example.com"));
+    assert!(!matches("*.example.com", "example.com"));
+    assert!(!matches("*.example.com", "limit.example.org"));
+    assert!(matches("limit.*.com:*", "limit.example.com:1313"));
+    assert!(!matches("limit.*.com:*", "limit.example.org:1313"));
+    assert!(matches("a*a", "aa"));
+    assert!(!matches("a*a", "a"));
+}
+
+#[test]
+fn test_policy() {
+    let policy = FederationPolicy {
+        allow: vec!["*.example.com".to_string()],
+        deny: vec!["evil.example.com".to_string()],
+        rate_limit: Some(10),
+        max_event_size: Some(1024),
+        peer_limits: vec![limit_config::PeerLimits {
+            pattern: "big.example.com".to_string(),
+            rate_limit: None,
+            max_event_size: Some(65536),
+        }],
+    };
+    assert!(is_allowed(&policy, &[], "limit.example.com"));
+    assert!(!is_allowed(&policy, &[], "evil.example.com"));
+    assert!(!is_allowed(&policy, &[], "limit.example.org"));
+    let admin_rules = [
+        FederationPolicyRule {
+            pattern: "limit.example.org".to_string(),
+            action: POLICY_ACTION_ALLOW.to_string(),
+        },
+        FederationPolicyRule {
+            pattern: "limit.example.com".to_string(),
+            action: POLICY_ACTION_DENY.to_string(),
+        },
+    ];
+    assert!(is_allowed(&policy, &admin_rules, "limit.example.org"));
+    assert!(!is_allowed(&policy, &admin_rules, "limit.example.com"));
+    assert!(is_allowed(
+        &FederationPolicy::default(),
+        &[],
+        "limit.example.com"
+    ));
+
+    assert_eq!(
+        limits_of(&policy, "limit.example.com"),
+        (Some(10), Some(1024))
+    );
+    assert_eq!(
+        limits_of(&policy, "big.example.com"),
+        (Some(10), Some(65536))
+    );
+}
+
+#[test]
+fn test_take_token() {
+    let now = Instant::now();
+    let name = "test_take_token.example.com";
+    for _ in 0..3 {
+        assert!(take_token(name, Direction::Inbound, 3, now));
+    }
+    assert!(!take_token(name, Direction::Inbound, 3, now));
+    // directions have their own buckets
+    assert!(take_token(name, Direction::Outbound, 3, now));
+    // a token every 20 seconds
+    let later = now + std::time::Duration::from_secs(20);
+    assert!(take_token(name, Direction::Inbound, 3, later));
+    assert!(!take_token(name, Direction::Inbound, 3, later));
+}
+
+#[test]
+fn test_admin_rules_expire() {
+    let now = Instant::now();
+    *ADMIN_RULES.write().unwrap() = Some((now, vec![]));
+    assert!(cached_admin_rules(now + ADMIN_RULES_TTL / 2).is_some());
+    assert!(cached_admin_rules(now + ADMIN_RULES_TTL).is_none());
+    reload_admin_rules();
+    assert!(cached_admin_rules(now).is_none());
+}
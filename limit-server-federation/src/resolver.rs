@@ -0,0 +1,379 @@
+//! Finding peer servers and their keys.
+//!
+//! Peers from [`limit_config::Config::peers`] or [`register_peer`] are pinned.
+//! Other servers are discovered at `<federation_scheme>://<server name>` with
+//! [`GetServerInfo`](crate::federation_service_client::FederationServiceClient::get_server_info),
+//! and their key is trusted on first use. A later key is accepted only when
+//! the server lists the trusted key among its previous keys, each of them
+//! signing the key which replaced it.
+//!
+//! Requests from other servers name their origin before they are
+//! authenticated, so servers without a trusted key are discovered at most
+//! [`MAX_DISCOVERIES_PER_MINUTE`] times a minute, and a failed discovery isn't
+//! tried again for [`FAILED_DISCOVERY_TTL`].
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use limit_config::{Peer, GLOBAL_CONFIG};
+use limit_db::{
+    federation::PeerServerKey, id::ServerName, run_sql, schema::PEER_SERVER_KEY, DBPool,
+};
+use limit_deps::{metrics::increment_counter, *};
+use once_cell::sync::Lazy;
+use tonic::{Code, Status};
+
+use crate::{federation_service_client::FederationServiceClient, GetServerInfoRequest};
+
+/// how long a discovered peer is cached
+pub const RESOLVE_TTL: Duration = Duration::from_secs(60 * 60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// servers without a trusted key discovered in a minute at most
+pub const MAX_DISCOVERIES_PER_MINUTE: usize = 30;
+/// how long a failed discovery is answered with its error
+pub const FAILED_DISCOVERY_TTL: Duration = Duration::from_secs(60);
+
+/// pinned peers by name, seeded from [`limit_config::Config::peers`]
+static PEERS: Lazy<RwLock<HashMap<String, Peer>>> = Lazy::new(|| {
+    RwLock::new(
+        GLOBAL_CONFIG
+            .get()
+            .unwrap()
+            .peers
+            .iter()
+            .map(|peer| (peer.name.clone(), peer.clone()))
+            .collect(),
+    )
+});
+
+/// discovered peers with the time they were resolved
+static RESOLVED: Lazy<RwLock<HashMap<String, (Peer, Instant)>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// when the last discoveries of servers without a trusted key started
+static DISCOVERIES: Lazy<Mutex<VecDeque<Instant>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// A discovery which failed
+struct FailedDiscovery {
+    code: Code,
+    message: String,
+    failed_at: Instant,
+}
+
+/// failed discoveries by server name
+static FAILED: Lazy<RwLock<HashMap<String, FailedDiscovery>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// add or replace a pinned peer
+pub fn register_peer(peer: Peer) {
+    PEERS.write().unwrap().insert(peer.name.clone(), peer);
+}
+
+/// a pinned peer
+pub fn get_peer(name: &str) -> Option<Peer> {
+    PEERS.read().unwrap().get(name).cloned()
+}
+
+/// drop a discovered peer from the cache, it is resolved again on next use
+pub fn forget_peer(name: &str) {
+    RESOLVED.write().unwrap().remove(name);
+    FAILED.write().unwrap().remove(name);
+}
+
+/// count a discovery of a server without a trusted key, refused when there
+/// were [`MAX_DISCOVERIES_PER_MINUTE`] in the last minute
+fn check_discovery_rate() -> Result<(), Status> {
+    let mut discoveries = DISCOVERIES.lock().unwrap();
+    while discoveries.front().map_or(false, |started| {
+        started.elapsed() >= Duration::from_secs(60)
+    }) {
+        discoveries.pop_front();
+    }
+    if discoveries.len() >= MAX_DISCOVERIES_PER_MINUTE {
+        increment_counter!("federation_discovery_throttled");
+        return Err(Status::resource_exhausted("too many servers discovered"));
+    }
+    discoveries.push_back(Instant::now());
+    Ok(())
+}
+
+/// the key trusted for a server, if any
+fn trusted_key(pool: &DBPool, name: &str) -> Result<Option<PeerServerKey>, Status> {
+    run_sql!(
+        pool,
+        |mut conn| {
+            PEER_SERVER_KEY::table
+                .find(name)
+                .first::<PeerServerKey>(&mut conn)
+                .optional()
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )
+}
+
+/// names of the pinned peers and of the servers with a trusted key
+pub fn known_peers(pool: &DBPool) -> Result<BTreeSet<String>, Status> {
+    let mut names = run_sql!(
+        pool,
+        |mut conn| {
+            PEER_SERVER_KEY::table
+                .select(PEER_SERVER_KEY::SERVER_NAME)
+                .load::<String>(&mut conn)
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?
+    .into_iter()
+    .collect::<BTreeSet<_>>();
+    names.extend(PEERS.read().unwrap().keys().cloned());
+    Ok(names)
+}
+
+/// check a server name is a `host[:port]`
+pub fn check_server_name(name: &str) -> Result<(), Status> {
+    name.parse::<ServerName>().map(|_| ()).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::invalid_argument("invalid server name")
+    })
+}
+
+/// endpoint and key of a server, discovering it if it isn't pinned
+pub async fn resolve_peer(pool: &DBPool, name: &str) -> Result<Peer, Status> {
+    if let Some(peer) = get_peer(name) {
+        return Ok(peer);
+    }
+    let cached = RESOLVED
+        .read()
+        .unwrap()
+        .get(name)
+        .filter(|(_, resolved_at)| resolved_at.elapsed() < RESOLVE_TTL)
+        .map(|(peer, _)| peer.clone());
+    if let Some(peer) = cached {
+        return Ok(peer);
+    }
+
+    let failed = FAILED
+        .read()
+        .unwrap()
+        .get(name)
+        .filter(|failed| failed.failed_at.elapsed() < FAILED_DISCOVERY_TTL)
+        .map(|failed| Status::new(failed.code, failed.message.clone()));
+    if let Some(status) = failed {
+        return Err(status);
+    }
+
+    check_server_name(name)?;
+    if trusted_key(pool, name)?.is_none() {
+        check_discovery_rate()?;
+    }
+    discover(pool, name).await.map_err(|status| {
+        FAILED.write().unwrap().insert(
+            name.to_string(),
+            FailedDiscovery {
+                code: status.code(),
+                message: status.message().to_string(),
+                failed_at: Instant::now(),
+            },
+        );
+        status
+    })
+}
+
+/// ask a server for its info and check its key
+async fn discover(pool: &DBPool, name: &str) -> Result<Peer, Status> {
+    let endpoint = format!(
+        "{}://{}",
+        GLOBAL_CONFIG.get().unwrap().federation_scheme,
+        name
+    );
+    let channel = tonic::transport::Endpoint::from_shared(endpoint.clone())
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::invalid_argument(e.to_string())
+        })?
+        .connect_timeout(CONNECT_TIMEOUT)
+        .connect()
+        .await
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            Status::unavailable(e.to_string())
+        })?;
+    let info = FederationServiceClient::new(channel)
+        .get_server_info(GetServerInfoRequest {})
+        .await?
+        .into_inner();
+    if info.server_name != name {
+        tracing::error!("{} claims to be {}", name, info.server_name);
+        return Err(Status::permission_denied("server name mismatch"));
+    }
+
+    let public_key = trust_key(
+        pool,
+        name,
+        &info.public_key,
+        &info.previous_public_keys,
+        &info.previous_key_signatures,
+    )?;
+    let peer = Peer {
+        name: name.to_string(),
+        endpoint,
+        public_key,
+    };
+    RESOLVED
+        .write()
+        .unwrap()
+        .insert(name.to_string(), (peer.clone(), Instant::now()));
+    Ok(peer)
+}
+
+/// whether `public_key` replaced `trusted` through the rotations of
+/// `previous_public_keys`, newest first, each signed by the key it replaced
+pub fn rotated_from(
+    trusted: &str,
+    public_key: &str,
+    previous_public_keys: &[String],
+    previous_key_signatures: &[String],
+) -> bool {
+    let Some(trusted) = previous_public_keys.iter().position(|key| key == trusted) else {
+        return false;
+    };
+    (0..=trusted).all(|i| {
+        let replaced_by = match i {
+            0 => public_key,
+            i => &previous_public_keys[i - 1],
+        };
+        previous_key_signatures.get(i).map_or(false, |signature| {
+            limit_am::decode_public(&previous_public_keys[i])
+                .and_then(|key| {
+                    limit_am::signature::verify_key_rotation(&key, replaced_by, signature)
+                })
+                .is_ok()
+        })
+    })
+}
+
+/// trust on first use, then follow rotations signed by the trusted key
+fn trust_key(
+    pool: &DBPool,
+    name: &str,
+    public_key: &str,
+    previous_public_keys: &[String],
+    previous_key_signatures: &[String],
+) -> Result<String, Status> {
+    limit_am::decode_public(public_key).map_err(|e| {
+        tracing::error!("{}", e);
+        Status::permission_denied("invalid server key")
+    })?;
+    let now = chrono::Utc::now().timestamp_millis();
+    run_sql!(
+        pool,
+        |mut conn| {
+            let trusted = PEER_SERVER_KEY::table
+                .find(name)
+                .first::<PeerServerKey>(&mut conn)
+                .optional()
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    Status::internal(e.to_string())
+                })?;
+            match trusted {
+                None => {
+                    tracing::info!("trusting key of {} on first use", name);
+                    diesel::insert_into(PEER_SERVER_KEY::table)
+                        .values(PeerServerKey {
+                            server_name: name.to_string(),
+                            public_key: public_key.to_string(),
+                            first_seen: now,
+                            updated_at: now,
+                        })
+                        .execute(&mut conn)
+                }
+                Some(trusted) if trusted.public_key == public_key => Ok(0),
+                Some(trusted)
+                    if rotated_from(
+                        &trusted.public_key,
+                        public_key,
+                        previous_public_keys,
+                        previous_key_signatures,
+                    ) =>
+                {
+                    tracing::info!("key of {} rotated", name);
+                    diesel::update(PEER_SERVER_KEY::table.find(name))
+                        .set((
+                            PEER_SERVER_KEY::PUBLIC_KEY.eq(public_key),
+                            PEER_SERVER_KEY::UPDATED_AT.eq(now),
+                        ))
+                        .execute(&mut conn)
+                }
+                Some(_) => {
+                    increment_counter!("federation_peer_key_mismatch", "peer" => name.to_string());
+                    tracing::error!("key of {} changed without rotation", name);
+                    return Err(Status::permission_denied("server key changed"));
+                }
+            }
+            .map_err(|e| {
+                tracing::error!("{}", e);
+                Status::internal(e.to_string())
+            })
+        },
+        |e| {
+            tracing::error!("{}", e);
+            Status::internal(e.to_string())
+        }
+    )?;
+    Ok(public_key.to_string())
+}
+
+#[test]
+fn test_check_server_name() {
+    assert!(check_server_name("limit.example.com").is_ok());
+    assert!(check_server_name("127.0.0.1:1313").is_ok());
+    assert!(check_server_name("[::1]:1313").is_ok());
+    assert!(check_server_name("not a server").is_err());
+    assert!(check_server_name("limit.example.com/path").is_err());
+    assert!(check_server_name("user@limit.example.com").is_err());
+    assert!(check_server_name("").is_err());
+}
+
+#[test]
+fn test_rotated_from() {
+    use limit_am::{signature::sign_key_rotation, KeyType, SecretKey};
+
+    let keys = (0..3)
+        .map(|_| SecretKey::random(KeyType::Ed25519))
+        .collect::<Vec<_>>();
+    let public = keys
+        .iter()
+        .map(|key| key.public_key().encode())
+        .collect::<Vec<_>>();
+    // rotated from 0 to 1 to 2
+    let previous = vec![public[1].clone(), public[0].clone()];
+    let signatures = vec![
+        sign_key_rotation(&keys[1], &public[2]).unwrap(),
+        sign_key_rotation(&keys[0], &public[1]).unwrap(),
+    ];
+    assert!(rotated_from(&public[0], &public[2], &previous, &signatures));
+    assert!(rotated_from(&public[1], &public[2], &previous, &signatures));
+    // a server claiming a trusted key as its previous one without its signature
+    let forged = SecretKey::random(KeyType::Ed25519).public_key().encode();
+    assert!(!rotated_from(&public[0], &forged, &previous, &signatures));
+    assert!(!rotated_from(&public[0], &public[2], &previous, &[]));
+    assert!(!rotated_from(&forged, &public[2], &previous, &signatures));
+}
@@ -12,16 +12,23 @@ pub fn mock_config() -> limit_config::Config {
             let (server_secret_key, server_public_key) = limit_am::create_random_secret().unwrap();
             Config {
                 url: "127.0.0.1:1313".parse().unwrap(),
+                deploy_mode: DeployMode::StandAlone {
+                    addr: "127.0.0.1:1313".parse().unwrap(),
+                },
                 database: Database::Sqlite {
                     path: "test.sqlite".parse().unwrap(),
                 },
+                keystore: None,
                 jwt_secret: "mock".to_string(),
                 database_pool_thread_count: 3,
                 admin_jwt: jsonwebtoken::encode(
                     &jsonwebtoken::Header::default(),
                     &JWTClaim::new(
                         JWTSub {
-                            id: Uuid::new_v4(),
+                            id: limit_db::id::UserId::new(
+                                Uuid::new_v4(),
+                                "127.0.0.1:1313".parse().unwrap(),
+                            ),
                             device_id: Uuid::new_v4().to_string(),
                         },
                         chrono::Duration::days(1),
@@ -32,7 +39,16 @@ pub fn mock_config() -> limit_config::Config {
                 metrics: Metrics::Terminal,
                 server_secret_key,
                 server_public_key,
+                previous_public_keys: vec![],
+                previous_key_signatures: vec![],
                 per_user_message_on_the_fly_limit: 100,
+                require_event_signatures: false,
+                fast_ack: false,
+                transaction_id_window: 86400,
+                peers: vec![],
+                federation_scheme: "http".to_string(),
+                federation_policy: FederationPolicy::default(),
+                event_stream_max_len: 10000,
             }
         })
         .clone()
@@ -114,6 +130,21 @@ macro_rules! test_service {
     };
 }
 
+/// wait until a server accepts connections on `port`
+pub async fn wait_for_port(port: u16) {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
+    while tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_err()
+    {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "nothing listens on port {port}"
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+}
+
 pub async fn do_with_port<F, T: Send>(f: F) -> T
 where
     F: FnOnce(u16) -> T,
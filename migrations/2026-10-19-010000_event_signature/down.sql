@@ -0,0 +1 @@
+DROP TABLE EVENT_SIGNATURE;
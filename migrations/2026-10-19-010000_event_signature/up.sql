@@ -0,0 +1,11 @@
+CREATE TABLE EVENT_SIGNATURE(
+    EVENT_ID VARCHAR PRIMARY KEY NOT NULL,
+    -- BASE64, NULL WHEN THE SENDER DIDN'T SIGN
+    SENDER_SIGNATURE VARCHAR,
+    -- BASE64
+    SERVER_SIGNATURE VARCHAR NOT NULL,
+    -- SERVER WHICH COUNTERSIGNED THE EVENT
+    ORIGIN_SERVER VARCHAR NOT NULL,
+
+    FOREIGN KEY(EVENT_ID) REFERENCES EVENT(ID)
+);
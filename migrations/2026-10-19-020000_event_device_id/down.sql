@@ -0,0 +1 @@
+ALTER TABLE EVENT DROP COLUMN DEVICE_ID;
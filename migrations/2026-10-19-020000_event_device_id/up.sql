@@ -0,0 +1,2 @@
+-- DEVICE WHICH SENT THE EVENT, NULL FOR FEDERATED EVENTS
+ALTER TABLE EVENT ADD COLUMN DEVICE_ID VARCHAR;
@@ -0,0 +1 @@
+DROP TABLE FEDERATION_OUTBOX;
@@ -0,0 +1,17 @@
+CREATE TABLE FEDERATION_OUTBOX(
+    -- DELIVERY ORDER WITHIN A DESTINATION
+    SEQ INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- NOT IN EVENT, REMOTE EVENTS ARE STORED BY THEIR RECEIVING SERVER
+    EVENT_ID VARCHAR NOT NULL,
+    -- NAME OF THE PEER SERVER
+    DESTINATION VARCHAR NOT NULL,
+    -- JSON OF THE COUNTERSIGNED EVENT
+    PAYLOAD VARCHAR NOT NULL,
+    ATTEMPTS INTEGER NOT NULL DEFAULT 0,
+    -- UNIX TIMESTAMP IN MILLISECONDS
+    NEXT_ATTEMPT_AT BIGINT NOT NULL,
+    LAST_ERROR VARCHAR,
+    -- PENDING OR DEAD
+    STATE VARCHAR NOT NULL
+);
+CREATE INDEX FEDERATION_OUTBOX_DESTINATION ON FEDERATION_OUTBOX(DESTINATION, STATE, SEQ);
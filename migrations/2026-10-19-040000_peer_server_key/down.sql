@@ -0,0 +1 @@
+DROP TABLE PEER_SERVER_KEY;
@@ -0,0 +1,9 @@
+-- KEYS OF DISCOVERED SERVERS, TRUSTED ON FIRST USE
+CREATE TABLE PEER_SERVER_KEY(
+    SERVER_NAME VARCHAR PRIMARY KEY NOT NULL,
+    PUBLIC_KEY VARCHAR NOT NULL,
+    -- UNIX TIMESTAMP IN MILLISECONDS
+    FIRST_SEEN BIGINT NOT NULL,
+    -- UNIX TIMESTAMP IN MILLISECONDS OF THE LAST ROTATION
+    UPDATED_AT BIGINT NOT NULL
+);
@@ -0,0 +1 @@
+DROP TABLE FEDERATION_POLICY_RULE;
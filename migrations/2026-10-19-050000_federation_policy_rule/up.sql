@@ -0,0 +1,9 @@
+-- ALLOW AND DENY RULES ADDED WITH THE ADMIN API
+CREATE TABLE FEDERATION_POLICY_RULE(
+    -- SERVER NAME, `*` MATCHES ANYTHING
+    PATTERN VARCHAR NOT NULL,
+    -- ALLOW OR DENY
+    ACTION VARCHAR NOT NULL,
+
+    PRIMARY KEY(PATTERN, ACTION)
+);
@@ -0,0 +1,3 @@
+DROP TABLE CONVERSATION_FRONTIER;
+DROP TABLE EVENT_EDGES;
+ALTER TABLE EVENT DROP COLUMN DEPTH;
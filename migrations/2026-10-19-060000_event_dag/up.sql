@@ -0,0 +1,20 @@
+-- LONGEST PATH FROM THE FIRST EVENT OF THE CONVERSATION, STARTING AT 1
+ALTER TABLE EVENT ADD COLUMN DEPTH BIGINT NOT NULL DEFAULT 0;
+
+-- AN EVENT FOLLOWS EVERY EVENT OF ITS CONVERSATION'S FRONTIER WHEN CREATED
+CREATE TABLE EVENT_EDGES(
+    EVENT_ID VARCHAR NOT NULL,
+    PREV_EVENT_ID VARCHAR NOT NULL,
+
+    PRIMARY KEY(EVENT_ID, PREV_EVENT_ID)
+);
+CREATE INDEX EVENT_EDGES_PREV_EVENT_ID ON EVENT_EDGES(PREV_EVENT_ID);
+
+-- EVENTS OF A CONVERSATION WITHOUT SUCCESSORS YET
+CREATE TABLE CONVERSATION_FRONTIER(
+    CONVERSATION_ID VARCHAR NOT NULL,
+    EVENT_ID VARCHAR NOT NULL,
+    DEPTH BIGINT NOT NULL,
+
+    PRIMARY KEY(CONVERSATION_ID, EVENT_ID)
+);
@@ -0,0 +1 @@
+DROP TABLE GROUP_STATE_EVENT;
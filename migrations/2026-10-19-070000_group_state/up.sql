@@ -0,0 +1,15 @@
+-- STATE EVENTS OF GROUPS, THE GROUP STATE IS THEIR MERGE
+CREATE TABLE GROUP_STATE_EVENT(
+    ID VARCHAR NOT NULL PRIMARY KEY,
+    GROUP_ID VARCHAR NOT NULL,
+    SENDER VARCHAR NOT NULL,
+    -- THE SERVER WHICH ACCEPTED THE EVENT FROM ITS SENDER
+    ORIGIN_SERVER VARCHAR NOT NULL,
+    -- UNIX TIMESTAMP IN MILLISECONDS OF THE ORIGIN SERVER
+    TS BIGINT NOT NULL,
+    -- JSON OF THE OPERATION
+    OP VARCHAR NOT NULL,
+    -- SIGNATURE OF THE ORIGIN SERVER
+    SIGNATURE VARCHAR NOT NULL
+);
+CREATE INDEX GROUP_STATE_EVENT_GROUP_ID ON GROUP_STATE_EVENT(GROUP_ID);
@@ -0,0 +1,11 @@
+-- USERS WHO MOVED TO ANOTHER SERVER, SIGNED BY THE SERVER THEY LEFT
+CREATE TABLE USER_MOVED(
+    ID VARCHAR NOT NULL,
+    FROM_SERVER VARCHAR NOT NULL,
+    TO_SERVER VARCHAR NOT NULL,
+    -- UNIX TIMESTAMP IN MILLISECONDS OF THE SERVER THE USER LEFT
+    TS BIGINT NOT NULL,
+    -- SIGNATURE OF THE SERVER THE USER LEFT
+    SIGNATURE VARCHAR NOT NULL,
+    PRIMARY KEY (ID, FROM_SERVER)
+);
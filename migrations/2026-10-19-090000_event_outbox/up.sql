@@ -0,0 +1,9 @@
+CREATE TABLE EVENT_OUTBOX(
+    -- PUBLICATION ORDER
+    SEQ INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    EVENT_ID VARCHAR NOT NULL REFERENCES EVENT(ID),
+    -- THE CHANNEL IS message:RECEIVER_ID
+    RECEIVER_ID VARCHAR NOT NULL,
+    -- JSON OF THE COUNTERSIGNED EVENT
+    PAYLOAD VARCHAR NOT NULL
+);
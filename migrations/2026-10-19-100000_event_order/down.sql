@@ -0,0 +1,4 @@
+DROP TABLE CONVERSATION_SEQUENCE;
+ALTER TABLE EVENT DROP COLUMN SEQ;
+DROP INDEX EVENT_RECEIVED_AT;
+ALTER TABLE EVENT DROP COLUMN RECEIVED_AT;
@@ -0,0 +1,21 @@
+-- WHEN THIS SERVER RECEIVED THE EVENT, TS IS THE TIME ITS SENDER CLAIMS
+ALTER TABLE EVENT ADD COLUMN RECEIVED_AT BIGINT NOT NULL DEFAULT 0;
+UPDATE EVENT SET RECEIVED_AT = TS;
+CREATE INDEX EVENT_RECEIVED_AT ON EVENT(RECEIVED_AT, ID);
+
+-- POSITION OF THE EVENT IN ITS CONVERSATION ON THIS SERVER, FROM 1 WITHOUT GAPS
+ALTER TABLE EVENT ADD COLUMN SEQ BIGINT NOT NULL DEFAULT 0;
+UPDATE EVENT SET SEQ = (
+    SELECT COUNT(*) FROM EVENT AS E
+    INNER JOIN MESSAGE AS M ON M.EVENT_ID = E.ID
+    WHERE M.RECEIVER_ID = (SELECT RECEIVER_ID FROM MESSAGE WHERE EVENT_ID = EVENT.ID)
+    AND (E.RECEIVED_AT, E.ID) <= (EVENT.RECEIVED_AT, EVENT.ID)
+);
+
+-- SEQ OF THE LAST EVENT OF A CONVERSATION
+CREATE TABLE CONVERSATION_SEQUENCE(
+    CONVERSATION_ID VARCHAR PRIMARY KEY NOT NULL,
+    SEQ BIGINT NOT NULL
+);
+INSERT INTO CONVERSATION_SEQUENCE(CONVERSATION_ID, SEQ)
+    SELECT RECEIVER_ID, COUNT(*) FROM MESSAGE GROUP BY RECEIVER_ID;
@@ -0,0 +1 @@
+DROP TABLE DEVICE_DELIVERY;
@@ -0,0 +1,10 @@
+-- WHAT EACH DEVICE OF A USER RECEIVED, EVENTS UP TO THE CURSOR WERE DELIVERED
+CREATE TABLE DEVICE_DELIVERY(
+    USER_ID VARCHAR NOT NULL,
+    DEVICE_ID VARCHAR NOT NULL,
+    -- RECEIVED_AT AND ID OF THE LAST EVENT DELIVERED TO THE DEVICE
+    RECEIVED_AT BIGINT NOT NULL,
+    EVENT_ID VARCHAR NOT NULL,
+    UPDATED_AT BIGINT NOT NULL,
+    PRIMARY KEY(USER_ID, DEVICE_ID)
+);
@@ -21,7 +21,9 @@ fn integration_test() {
     tokio_run!(async {
         let tasks = vec![
             tokio::spawn(limit_server_auth_test::integration_test()),
+            tokio::spawn(limit_server_cluster_test::integration_test()),
             tokio::spawn(limit_server_event_test::integration_test()),
+            tokio::spawn(limit_server_federation_test::integration_test()),
         ];
         futures::future::join_all(tasks).await
     })
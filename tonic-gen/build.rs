@@ -15,4 +15,18 @@ fn main() {
             &["../idl"],
         )
         .unwrap();
+
+    // server to server apis
+    tonic_build::configure()
+        .build_client(true)
+        .build_server(true)
+        .compile(&["proto/federation.proto"], &["proto"])
+        .unwrap();
+
+    // between the nodes of a cluster
+    tonic_build::configure()
+        .build_client(true)
+        .build_server(true)
+        .compile(&["proto/cluster.proto"], &["proto"])
+        .unwrap();
 }
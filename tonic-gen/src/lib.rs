@@ -2,6 +2,11 @@ pub mod auth {
     tonic::include_proto!("limit.auth");
 }
 
+pub mod cluster {
+    tonic::include_proto!("limit.cluster");
+}
+
+#[allow(clippy::module_inception)]
 pub mod event {
     tonic::include_proto!("limit.event");
     pub mod types {
@@ -16,6 +21,10 @@ pub mod subs {
     }
 }
 
+pub mod federation {
+    tonic::include_proto!("limit.federation");
+}
+
 pub mod utils {
     tonic::include_proto!("limit.utils");
 }